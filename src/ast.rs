@@ -1,3 +1,16 @@
+/// A line and column in Pickup source, both starting at 1
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Position {
+    pub line: usize,
+    pub column: usize,
+}
+
+impl std::fmt::Display for Position {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}:{}", self.line, self.column)
+    }
+}
+
 /// Abstract syntax tree node types for Pickup.
 #[derive(Debug, Clone)]
 pub enum AstNode {
@@ -13,20 +26,29 @@ pub enum AstNode {
     Boolean(bool),
     /// Nil value
     Nil,
-    /// Assignment operation (identifier, value)
-    Assignment(Box<AstNode>, Box<AstNode>),
-    /// Local variable assignment (identifier name, value)
-    LocalAssignment(String, Box<AstNode>),
+    /// Assignment operation (targets, values)
+    Assignment(Vec<AstNode>, Vec<AstNode>),
+    /// Local variable assignment (targets, values). Each target is an
+    /// `Identifier` or a destructuring pattern.
+    LocalAssignment(Vec<AstNode>, Vec<AstNode>),
     /// Binary operations (left, operator, right)
     BinaryOp(Box<AstNode>, String, Box<AstNode>),
     /// Unary not operation
     Not(Box<AstNode>),
+    /// Other prefix operations: `-`, `#` and `~` (operator, operand)
+    UnaryOp(String, Box<AstNode>),
     /// Function call (function name, arguments)
     FunctionCall(String, Vec<AstNode>),
-    /// Method call (object, method chain, arguments)
-    MethodCall(Box<AstNode>, Vec<String>, Vec<AstNode>),
-    /// Print statement
-    Print(Box<AstNode>),
+    /// Method call (object, method chain, arguments, whether the object the
+    /// method is found on is passed as `self`, as in `acct:deposit(10)`)
+    MethodCall(Box<AstNode>, Vec<String>, Vec<AstNode>, bool),
+    /// Call of any other expression that yields a function (callee, arguments)
+    Call(Box<AstNode>, Vec<AstNode>),
+    /// Parenthesized expression, which keeps only the first value of a
+    /// call or `...`
+    Paren(Box<AstNode>),
+    /// Print statement, with the values to print separated by spaces
+    Print(Vec<AstNode>),
     /// Table/Array literal [element1, element2, ...]
     Table(Vec<AstNode>),
     /// Dictionary/Object literal { key = value, ... }
@@ -43,18 +65,79 @@ pub enum AstNode {
     While(Box<AstNode>, Vec<AstNode>),
     /// For loop (variable, start, end, step, body)
     For(String, Box<AstNode>, Box<AstNode>, Option<Box<AstNode>>, Vec<AstNode>),
-    /// Function definition (name, parameters, body)
-    FunctionDef(String, Vec<String>, Vec<AstNode>),
+    /// Generic for loop (variables or destructuring patterns, iterable, body)
+    ForIn(Vec<AstNode>, Box<AstNode>, Vec<AstNode>),
+    /// Function definition (name, parameters, body). The name may be a path
+    /// such as `Account.new` or `Account:deposit`; methods defined with `:`
+    /// have `self` as their first parameter.
+    FunctionDef(String, Params, Vec<AstNode>),
+    /// Class declaration (name, base class, members). Methods are
+    /// `FunctionDef`s named like `Point:area` with `self` as their first
+    /// parameter; other members are field values shared by every instance.
+    ClassDef(String, Option<Box<AstNode>>, Vec<(String, AstNode)>),
     /// Lambda/Anonymous function (parameters, body)
-    Lambda(Vec<String>, Vec<AstNode>),
-    /// Return statement (optional value)
-    Return(Option<Box<AstNode>>),
+    Lambda(Params, Vec<AstNode>),
+    /// `name = value` in a call's argument list, after the positional arguments
+    NamedArg(String, Box<AstNode>),
+    /// `...`, the extra arguments of the enclosing function
+    Varargs,
+    /// The value before `?.`, `?[` or `?.(` in an optional chain; when it is
+    /// nil, the whole chain is nil
+    Optional(Box<AstNode>),
+    /// A postfix chain containing `Optional` links, which end here
+    OptionalChain(Box<AstNode>),
+    /// Array destructuring target `[a, b = 1, ...rest]` (element targets
+    /// with optional defaults, name for the remaining elements)
+    ArrayPattern(Vec<(AstNode, Option<AstNode>)>, Option<String>),
+    /// Dictionary destructuring target `{name, age = 0, address: {city}}`
+    /// (key, target the value is bound to, optional default)
+    DictPattern(Vec<(String, AstNode, Option<AstNode>)>),
+    /// Return statement (values, possibly none)
+    Return(Vec<AstNode>),
     /// Break statement
     Break,
     /// Continue statement
     Continue,
-    /// Try-catch statement (try_block, catch_var, catch_block)
-    TryCatch(Vec<AstNode>, Option<String>, Vec<AstNode>),
+    /// Try statement (try_block, catch_var, catch_block, finally_block).
+    /// At least one of the catch and finally blocks is present.
+    TryCatch(Vec<AstNode>, Option<String>, Option<Vec<AstNode>>, Option<Vec<AstNode>>),
+    /// Match statement (subject, arms). Each arm has a pattern, an optional
+    /// guard and a body; the first arm that matches runs.
+    Match(Box<AstNode>, Vec<(Pattern, Option<AstNode>, Vec<AstNode>)>),
     /// Throw statement (error value)
     Throw(Box<AstNode>),
+    /// A statement or expression tagged with where it starts in the source
+    Located(Position, Box<AstNode>),
+}
+
+/// The parameters of a function: names with optional default values,
+/// then optionally a name for the table of extra arguments (`...rest`),
+/// which is `...` for a bare `...`. A parameter written as a destructuring
+/// pattern is named by its source text and listed in `patterns` with its
+/// position.
+#[derive(Debug, Clone, Default)]
+pub struct Params {
+    pub names: Vec<(String, Option<AstNode>)>,
+    pub rest: Option<String>,
+    pub patterns: Vec<(usize, AstNode)>,
+}
+
+/// A pattern in a `match` arm
+#[derive(Debug, Clone)]
+pub enum Pattern {
+    /// `_`, which matches anything
+    Wildcard,
+    /// A name, which matches anything and binds it
+    Binding(String),
+    /// A number, string, boolean or nil, which matches an equal value
+    Literal(AstNode),
+    /// `lo..hi`, which matches a number between the bounds, inclusive
+    Range(f64, f64),
+    /// `[a, b, ...rest]` (element patterns, name for the remaining
+    /// elements). Without a rest name the table must have exactly as many
+    /// elements as there are patterns.
+    Array(Vec<Pattern>, Option<String>),
+    /// `{kind = "user", name = n}`, which matches a dictionary that has
+    /// every key, each value matching its pattern; `{name}` binds `name`
+    Dict(Vec<(String, Pattern)>),
 }
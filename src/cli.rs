@@ -14,6 +14,10 @@ pub struct Args {
     /// Enable verbose debug output
     #[arg(long = "noise", default_value_t = false)]
     pub verbose_output: bool,
+
+    /// Treat bad operand types as nil instead of raising a TypeError
+    #[arg(long, default_value_t = false)]
+    pub lenient: bool,
 }
 
 pub fn parse_args() -> Args {
@@ -1,5 +1,8 @@
-use crate::ast::AstNode;
+use crate::ast::{AstNode, Params, Pattern, Position};
+use crate::parser::ParseError;
+use std::cell::RefCell;
 use std::collections::HashMap;
+use std::rc::Rc;
 
 /// Bytecode instructions for the Pickup VM.
 #[derive(Debug, Clone, PartialEq)]
@@ -8,14 +11,28 @@ pub enum Instruction {
     PushString(String),
     PushBoolean(bool),
     PushNil,
-    LoadVar(String),
-    StoreVar(String),
-    StoreLocal(String),
+    LoadGlobal(String),     // Load a variable from the global table
+    StoreGlobal(String),    // Assign a variable in the global table
+    LoadLocal(usize),       // Load the local in a slot of the running function
+    StoreLocal(usize),      // Assign to the local in a slot of the running function
+    DefineLocal(usize),     // Declare a local in a slot, giving it a fresh variable cell
+    LoadUpvalue(usize),     // Load a variable captured by the running closure
+    StoreUpvalue(usize),    // Assign to a variable captured by the running closure
     Add,
     Sub,
     Mul,
     Div,
     Mod,
+    Pow,
+    IntDiv,                 // Division rounded down to a whole number
+    BitAnd,
+    BitOr,
+    BitXor,
+    ShiftLeft,
+    ShiftRight,
+    Neg,
+    Len,                    // Length of a string, table or dictionary
+    BitNot,
     Concat,
     Equal,
     NotEqual,
@@ -23,32 +40,121 @@ pub enum Instruction {
     GreaterThan,
     LessEqual,
     GreaterEqual,
-    And,
-    Or,
     Not,
-    Print,
-    MakeTable(usize),       // Create a table with n elements from stack
+    Print(Arity),           // Print the values on the stack, separated by spaces
+    MakeTable(Arity),       // Create a table from the elements on the stack
     MakeDict(usize),        // Create a dictionary with n key-value pairs from stack
-    GetIndex,               // Get value at index from table
-    SetIndex,               // Set value at index in table
-    GetMember(String),      // Get member by name from object/module
-    SetMember(String),      // Set member by name in object/module
+    GetIndex(Option<String>), // Get value at index from table; names the indexed expression for errors
+    SetIndex(Option<String>), // Set value at index in table
+    GetMember(String, Option<String>), // Get member by name from object/module; names the object for errors
+    SetMember(String, Option<String>), // Set member by name in a dictionary; names the object for errors
     LoadModule(String),     // Load standard library module
     Jump(usize),            // Unconditional jump to instruction index
     JumpIfFalse(usize),     // Jump to instruction index if top of stack is false
-    Call(String, usize),    // Call function (name, arg_count)
-    CallMethod(Vec<String>, usize), // Call method (member chain, arg_count)
+    JumpIfFalseKeep(usize), // Jump, leaving the value on the stack, if it is false; pop it otherwise
+    JumpIfTrueKeep(usize),  // Jump, leaving the value on the stack, if it is true; pop it otherwise
+    JumpIfNotNilKeep(usize), // Jump, leaving the value on the stack, if it is not nil; pop it otherwise
+    JumpIfNil(usize),       // Jump if the value on the stack is nil, leaving it there either way
+    Call(String, Arity, Results), // Call the global function loaded below the arguments (name, arguments, results wanted)
+    CallValue(Arity, Results), // Call the function value below the arguments
+    FindMethod(Vec<String>), // Push the method a member chain leads to from the object on the stack, or nil, above the object
+    CallMethod(Option<String>, Vec<String>, Arity, Results), // Call the method found below the arguments (object name for errors, member chain, arguments, results wanted)
+    LookupMethod(String),   // Push the named method of the object on the stack, or nil, above the object
+    Invoke(String, Arity, Results), // Call the method looked up below the arguments, passing the object as self
     CallNative(String, String, usize), // Call native function (module, func, arg_count)
-    Return,                 // Return from function
-    DefineFunction(String, Vec<String>, usize), // Define function (name, params, body_len)
-    DefineLambda(Vec<String>, usize), // Define anonymous function (params, body_len)
+    Return(Arity),          // Return from function with the values on the stack
+    DefineFunction(String, Signature, Vec<Capture>, usize), // Push named closure (name, params, captures, body_len)
+    DefineLambda(Signature, Vec<Capture>, usize), // Push anonymous closure (params, captures, body_len)
+    MakeClass(String, bool), // Turn the dictionary of members on the stack into a class (name, whether a base class is above it)
+    MatchRange(f64, f64),   // Replace the value on the stack with whether it is a number in the range, inclusive
+    MatchTable(usize, bool), // Replace the value on the stack with whether it is a table of exactly n elements, or at least n if set
+    MatchDict,              // Replace the value on the stack with whether it is a dictionary
+    TableTail(usize),       // Replace the table on the stack with a new table of its elements from an index on
+    NoMatch,                // Raise a MatchError for the value on the stack
+    Unpack(Results),        // Replace the table on the stack with its elements, like the results of a call
     Pop,                    // Pop value from stack
     Break,                  // Break from loop
     Continue,               // Continue loop
     SetupTry(usize),        // Setup try block with jump target on error
     PopTry,                 // Pop try handler
     Throw,                  // Throw an exception
+    SaveError(usize),       // Store the error a handler caught in a local slot, keeping it for Rethrow
+    Rethrow(usize),         // Raise the error saved in a local slot again, unchanged
     Dup,                    // Duplicate top of stack
+    ForPrep(usize, usize),  // Check numeric for bounds and store them in hidden slots (first slot, exit target)
+    ForLoop(usize, usize),  // Step a numeric for loop, jumping back while in range (first slot, body start)
+    IterPrep,               // Turn the value on the stack into something a for-in loop can step through
+    IterCall(usize, usize), // Call the iterator function in a local slot, if it is one (slot, count)
+    IterNext(usize, usize, usize), // Push the next values of the iterator in a slot (slot, count, exit target)
+}
+
+/// How many values an instruction takes from the stack: `fixed` values,
+/// then, if `open` is set, every result of the call evaluated last, whose
+/// count that call left on top of the stack. A call with named arguments
+/// has `named` name and value pairs above the others.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Arity {
+    pub fixed: usize,
+    pub open: bool,
+    pub named: usize,
+}
+
+impl Arity {
+    pub fn fixed(count: usize) -> Self {
+        Arity { fixed: count, open: false, named: 0 }
+    }
+}
+
+/// The parameters of a function: their names, how many of them must be
+/// passed (the ones up to the last without a default), and the name of the
+/// rest parameter that collects extra arguments into a table
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Signature {
+    pub names: Vec<String>,
+    pub required: usize,
+    pub rest: Option<String>,
+}
+
+impl Signature {
+    /// Whether a call with `count` positional arguments fills the parameters
+    pub fn accepts(&self, count: usize) -> bool {
+        self.required <= count && (self.rest.is_some() || count <= self.names.len())
+    }
+
+    /// How many arguments a function takes, as in "expects 1 to 3 arguments"
+    pub fn describe(&self) -> String {
+        match (self.rest.is_some(), self.required == self.names.len()) {
+            (true, _) => format!("at least {}", self.required),
+            (false, true) => self.required.to_string(),
+            (false, false) => format!("{} to {}", self.required, self.names.len()),
+        }
+    }
+}
+
+impl std::fmt::Display for Signature {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let mut names = self.names.clone();
+        match self.rest.as_deref() {
+            Some("...") => names.push("...".to_string()),
+            Some(rest) => names.push(format!("...{}", rest)),
+            None => {}
+        }
+        write!(f, "{}", names.join(", "))
+    }
+}
+
+/// How many results a call leaves on the stack
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Results {
+    Exactly(usize), // Extra results are dropped, missing ones are nil
+    All,            // Every result, followed by how many there were
+}
+
+/// Where a new closure finds each variable it captures
+#[derive(Debug, Clone, PartialEq)]
+pub enum Capture {
+    Local(usize),   // A local slot of the function creating the closure
+    Upvalue(usize), // A variable the creating function captured itself
 }
 
 /// Loop context for break/continue
@@ -59,24 +165,107 @@ struct LoopContext {
     continue_target: Option<usize>,  // For for loops, this points to increment section
     continue_jumps: Vec<usize>,      // Positions of continue jumps to patch
     break_jumps: Vec<usize>,
+    tries: usize,                    // Try blocks already entered when the loop began
+}
+
+/// A try block whose handler is installed while the code inside it runs.
+/// Leaving it with break, continue or return pops the handler and runs
+/// the finally block first.
+#[derive(Debug, Clone)]
+struct TryContext {
+    finally_body: Option<Vec<AstNode>>,
+    loops: usize, // Loops already entered when the try block began
+}
+
+/// A local variable declared in the function being compiled
+#[derive(Debug)]
+struct Local {
+    name: String,
+    depth: usize, // Block nesting depth the local was declared at
+}
+
+/// Names visible to the function currently being compiled. A local's slot
+/// is its position in `locals`; slots are reused once a block ends.
+#[derive(Debug, Default)]
+struct FunctionScope {
+    locals: Vec<Local>,
+    depth: usize,
+    captures: Vec<(String, Capture)>,
+    lines: Vec<(usize, Position)>, // Line table for this function's instructions
+    varargs: Option<usize>,        // Slot of the rest parameter, which `...` unpacks
+}
+
+impl FunctionScope {
+    fn slot_of(&self, name: &str) -> Option<usize> {
+        self.locals.iter().rposition(|local| local.name == name)
+    }
+}
+
+/// How an identifier is resolved at compile time
+enum Resolved {
+    Local(usize),
+    Upvalue(usize),
+    Global(String),
+}
+
+/// Compiled bytecode together with the source positions it came from
+#[derive(Debug, Clone, Default)]
+pub struct Chunk {
+    pub name: String, // Script name used in error locations
+    pub code: Vec<Instruction>,
+    pub lines: Vec<(usize, Position)>, // (first instruction, source position), ascending
+}
+
+impl Chunk {
+    /// Wrap bytecode that has no source positions
+    pub fn new(name: impl Into<String>, code: Vec<Instruction>) -> Self {
+        Chunk { name: name.into(), code, lines: Vec::new() }
+    }
+
+    /// Source position of the instruction at `pc`
+    pub fn position(&self, pc: usize) -> Option<Position> {
+        let entry = self.lines.partition_point(|(start, _)| *start <= pc);
+        entry.checked_sub(1).map(|i| self.lines[i].1)
+    }
 }
 
 /// Compile an AST to bytecode instructions.
 pub struct Compiler {
     loop_stack: Vec<LoopContext>,
+    try_stack: Vec<TryContext>,
+    functions: Vec<FunctionScope>,
+    positions: Vec<Position>, // Enclosing located nodes, innermost last
+    call_results: Results,    // Results wanted from the call compiled next
+    optional_exits: Vec<Vec<usize>>, // Nil jumps of the optional chains being compiled, innermost last
 }
 
+/// A compiled function: its signature, the variables it captures, its
+/// instructions and their line table
+type CompiledFunction = (Signature, Vec<Capture>, Vec<Instruction>, Vec<(usize, Position)>);
+
 impl Compiler {
     pub fn compile(ast: &AstNode, verbose: bool) -> Vec<Instruction> {
+        Self::compile_chunk(ast, "<script>", verbose).code
+    }
+
+    /// Compile an AST along with its line table. `name` is reported in
+    /// runtime error locations, typically the script path.
+    pub fn compile_chunk(ast: &AstNode, name: &str, verbose: bool) -> Chunk {
         let mut compiler = Compiler {
             loop_stack: Vec::new(),
+            try_stack: Vec::new(),
+            functions: vec![FunctionScope::default()],
+            positions: Vec::new(),
+            call_results: Results::Exactly(1),
+            optional_exits: Vec::new(),
         };
         let mut code = Vec::new();
         compiler.compile_node(ast, &mut code);
         if verbose {
             println!("Generated bytecode: {:?}", code);
         }
-        code
+        let lines = compiler.functions.pop().unwrap_or_default().lines;
+        Chunk { name: name.to_string(), code, lines }
     }
 
     fn compile_node(&mut self, node: &AstNode, code: &mut Vec<Instruction>) {
@@ -90,33 +279,50 @@ impl Compiler {
             AstNode::String(s) => code.push(Instruction::PushString(s.clone())),
             AstNode::Boolean(b) => code.push(Instruction::PushBoolean(*b)),
             AstNode::Nil => code.push(Instruction::PushNil),
-            AstNode::Identifier(id) => code.push(Instruction::LoadVar(id.clone())),
-            AstNode::Assignment(target, expr) => {
-                self.compile_node(expr, code);
-                match &**target {
-                    AstNode::Identifier(name) => {
-                        code.push(Instruction::StoreVar(name.clone()));
-                    }
-                    AstNode::MemberAccess(base, members) => {
-                        self.compile_node(base, code);
-                        // Navigate to the parent object
-                        for i in 0..members.len() - 1 {
-                            code.push(Instruction::GetMember(members[i].clone()));
-                        }
-                        // Set the final member
-                        code.push(Instruction::SetMember(members.last().unwrap().clone()));
-                    }
-                    AstNode::Index(base, index) => {
-                        self.compile_node(base, code);
-                        self.compile_node(index, code);
-                        code.push(Instruction::SetIndex);
-                    }
-                    _ => {}
+            AstNode::Identifier(id) => self.emit_load(id, code),
+            AstNode::Assignment(targets, values) => {
+                if let ([target], [value]) = (targets.as_slice(), values.as_slice()) {
+                    self.compile_assign(target, code, |this, code| this.compile_node(value, code));
+                } else {
+                    // Evaluate every target's container and index, then every
+                    // value, before assigning any, so `a, b = b, a` swaps and
+                    // `i, t[i] = i + 1, v` writes the element at the old `i`
+                    self.begin_scope();
+                    let places: Vec<Vec<usize>> = targets.iter().map(|target| self.compile_place(target, code)).collect();
+                    self.compile_adjusted(values, targets.len(), code);
+                    let slots = self.define_temporaries(targets.len(), "(assigned value)", code);
+                    for ((target, place), slot) in targets.iter().zip(places).zip(slots) {
+                        self.store_place(target, &place, slot, code);
+                    }
+                    self.end_scope();
                 }
             }
-            AstNode::LocalAssignment(name, expr) => {
-                self.compile_node(expr, code);
-                code.push(Instruction::StoreLocal(name.clone()));
+            AstNode::LocalAssignment(targets, values) => {
+                // The initializers are compiled first so `local x = x` sees the outer x
+                self.compile_adjusted(values, targets.len(), code);
+                self.define_targets(targets, code);
+            }
+            AstNode::BinaryOp(left, op, right) if op == "and" || op == "or" => {
+                // The right side only runs when the left does not decide the
+                // result, and the result is whichever operand decided it
+                self.compile_node(left, code);
+                let jump = code.len();
+                code.push(Instruction::Jump(0));
+                self.compile_node(right, code);
+                let end = code.len();
+                code[jump] = if op == "and" {
+                    Instruction::JumpIfFalseKeep(end)
+                } else {
+                    Instruction::JumpIfTrueKeep(end)
+                };
+            }
+            AstNode::BinaryOp(left, op, right) if op == "??" => {
+                // The right side only runs when the left side is nil
+                self.compile_node(left, code);
+                let jump = code.len();
+                code.push(Instruction::JumpIfNotNilKeep(0));
+                self.compile_node(right, code);
+                code[jump] = Instruction::JumpIfNotNilKeep(code.len());
             }
             AstNode::BinaryOp(left, op, right) => {
                 self.compile_node(left, code);
@@ -127,6 +333,13 @@ impl Compiler {
                     "*" => code.push(Instruction::Mul),
                     "/" => code.push(Instruction::Div),
                     "%" => code.push(Instruction::Mod),
+                    "^" => code.push(Instruction::Pow),
+                    "//" => code.push(Instruction::IntDiv),
+                    "&" => code.push(Instruction::BitAnd),
+                    "|" => code.push(Instruction::BitOr),
+                    "~" => code.push(Instruction::BitXor),
+                    "<<" => code.push(Instruction::ShiftLeft),
+                    ">>" => code.push(Instruction::ShiftRight),
                     ".." => code.push(Instruction::Concat),
                     "==" => code.push(Instruction::Equal),
                     "~=" => code.push(Instruction::NotEqual),
@@ -134,8 +347,6 @@ impl Compiler {
                     ">" => code.push(Instruction::GreaterThan),
                     "<=" => code.push(Instruction::LessEqual),
                     ">=" => code.push(Instruction::GreaterEqual),
-                    "and" => code.push(Instruction::And),
-                    "or" => code.push(Instruction::Or),
                     _ => {}
                 }
             }
@@ -143,15 +354,23 @@ impl Compiler {
                 self.compile_node(expr, code);
                 code.push(Instruction::Not);
             }
-            AstNode::Print(expr) => {
+            AstNode::UnaryOp(op, expr) => {
                 self.compile_node(expr, code);
-                code.push(Instruction::Print);
+                match op.as_str() {
+                    "-" => code.push(Instruction::Neg),
+                    "#" => code.push(Instruction::Len),
+                    "~" => code.push(Instruction::BitNot),
+                    _ => {}
+                }
+            }
+            AstNode::Print(values) => {
+                // `print(f())` prints every result of f
+                let arity = self.compile_values(values, code);
+                code.push(Instruction::Print(arity));
             }
             AstNode::Table(elements) => {
-                for elem in elements {
-                    self.compile_node(elem, code);
-                }
-                code.push(Instruction::MakeTable(elements.len()));
+                let arity = self.compile_values(elements, code);
+                code.push(Instruction::MakeTable(arity));
             }
             AstNode::Dictionary(entries) => {
                 for (key, value) in entries {
@@ -163,23 +382,83 @@ impl Compiler {
             AstNode::Index(base, index) => {
                 self.compile_node(base, code);
                 self.compile_node(index, code);
-                code.push(Instruction::GetIndex);
+                code.push(Instruction::GetIndex(Self::describe(base)));
             }
             AstNode::MemberAccess(base, members) => {
                 self.compile_node(base, code);
-                for member in members {
-                    code.push(Instruction::GetMember(member.clone()));
-                }
+                self.emit_members(base, members, code);
+            }
+            AstNode::MethodCall(base, members, args, true) if Self::is_super_call(base) && self.resolves_locally("super") => {
+                // `super:init(x)` runs the base class method on this instance
+                let results = std::mem::replace(&mut self.call_results, Results::Exactly(1));
+                self.emit_load("super", code);
+                code.push(Instruction::FindMethod(members.clone()));
+                self.emit_load("self", code);
+                let mut arity = self.compile_args(args, code);
+                arity.fixed += 1;
+                code.push(Instruction::CallMethod(Some("super".to_string()), members.clone(), arity, results));
+            }
+            AstNode::MethodCall(base, members, args, true) => {
+                let results = std::mem::replace(&mut self.call_results, Results::Exactly(1));
+                // The receiver and its method go below the arguments, found
+                // before they are evaluated; the receiver is passed as `self`
+                self.compile_node(base, code);
+                self.emit_members(base, &members[..members.len() - 1], code);
+                let method = members.last().cloned().unwrap_or_default();
+                code.push(Instruction::LookupMethod(method.clone()));
+                let arity = self.compile_args(args, code);
+                code.push(Instruction::Invoke(method, arity, results));
+            }
+            AstNode::MethodCall(base, members, args, false) => {
+                let results = std::mem::replace(&mut self.call_results, Results::Exactly(1));
+                // The base object and the method go below the arguments,
+                // found before they are evaluated
+                self.compile_node(base, code);
+                code.push(Instruction::FindMethod(members.clone()));
+                let arity = self.compile_args(args, code);
+                code.push(Instruction::CallMethod(Self::describe(base), members.clone(), arity, results));
             }
-            AstNode::MethodCall(base, members, args) => {
-                // Push arguments first
-                for arg in args {
-                    self.compile_node(arg, code);
+            AstNode::Call(callee, args) => {
+                let results = std::mem::replace(&mut self.call_results, Results::Exactly(1));
+                self.compile_node(callee, code);
+                let arity = self.compile_args(args, code);
+                code.push(Instruction::CallValue(arity, results));
+            }
+            // Not multivalued, so a call or `...` inside gives one value
+            AstNode::Paren(inner) => self.compile_node(inner, code),
+            AstNode::Varargs => {
+                let results = std::mem::replace(&mut self.call_results, Results::Exactly(1));
+                match self.current_function().varargs {
+                    Some(slot) => code.push(Instruction::LoadLocal(slot)),
+                    None => code.push(Instruction::MakeTable(Arity::fixed(0))),
+                }
+                code.push(Instruction::Unpack(results));
+            }
+            AstNode::OptionalChain(chain) => {
+                self.optional_exits.push(Vec::new());
+                self.compile_node(chain, code);
+                let end = code.len();
+                for jump in self.optional_exits.pop().unwrap_or_default() {
+                    code[jump] = Instruction::JumpIfNil(end);
                 }
-                // Push base object
+            }
+            AstNode::Optional(base) => {
+                // A nil value skips the rest of the chain and is its result
                 self.compile_node(base, code);
-                // Call method
-                code.push(Instruction::CallMethod(members.clone(), args.len()));
+                let jump = code.len();
+                code.push(Instruction::JumpIfNil(0));
+                self.optional_exits
+                    .last_mut()
+                    .expect("optional link outside of a chain")
+                    .push(jump);
+            }
+            AstNode::ArrayPattern(..) | AstNode::DictPattern(..) => {
+                // Patterns are compiled by the declaration they belong to
+                panic!("destructuring pattern outside of a declaration");
+            }
+            AstNode::NamedArg(name, _) => {
+                // Named arguments are compiled by the call they belong to
+                panic!("named argument '{}' outside of a call", name);
             }
             AstNode::Import(module) => {
                 code.push(Instruction::LoadModule(module.clone()));
@@ -193,9 +472,7 @@ impl Compiler {
                 code.push(Instruction::JumpIfFalse(0));
 
                 // Compile then block
-                for stmt in then_block {
-                    self.compile_node(stmt, code);
-                }
+                self.compile_block(then_block, code);
 
                 // Reserve space for Jump to end (skip else blocks)
                 let jump_to_end = code.len();
@@ -213,9 +490,7 @@ impl Compiler {
                     let elseif_jump = code.len();
                     code.push(Instruction::JumpIfFalse(0));
 
-                    for stmt in elseif_block {
-                        self.compile_node(stmt, code);
-                    }
+                    self.compile_block(elseif_block, code);
 
                     let elseif_end_jump = code.len();
                     code.push(Instruction::Jump(0));
@@ -227,9 +502,7 @@ impl Compiler {
 
                 // Compile else block
                 if let Some(else_stmts) = else_block {
-                    for stmt in else_stmts {
-                        self.compile_node(stmt, code);
-                    }
+                    self.compile_block(else_stmts, code);
                 }
 
                 // Patch all jumps to end
@@ -247,6 +520,7 @@ impl Compiler {
                     continue_target: Some(loop_start),  // Continue jumps to condition check
                     continue_jumps: Vec::new(),
                     break_jumps: Vec::new(),
+                    tries: self.try_stack.len(),
                 });
 
                 // Compile condition
@@ -257,9 +531,7 @@ impl Compiler {
                 code.push(Instruction::JumpIfFalse(0));
 
                 // Compile body
-                for stmt in body {
-                    self.compile_node(stmt, code);
-                }
+                self.compile_block(body, code);
 
                 // Jump back to start
                 code.push(Instruction::Jump(loop_start));
@@ -280,65 +552,102 @@ impl Compiler {
                 }
             }
             AstNode::For(var, start, end, step, body) => {
-                // Initialize loop variable
+                // Start, limit and step are evaluated once, into hidden locals
+                self.begin_scope();
                 self.compile_node(start, code);
-                code.push(Instruction::StoreVar(var.clone()));
-
-                let loop_start = code.len();
+                self.compile_node(end, code);
+                if let Some(step_expr) = step {
+                    self.compile_node(step_expr, code);
+                } else {
+                    code.push(Instruction::PushNumber(1.0));
+                }
+                let base = self.declare_local("(for index)");
+                self.declare_local("(for limit)");
+                self.declare_local("(for step)");
+                let prep_pos = code.len();
+                code.push(Instruction::ForPrep(base, 0)); // Placeholder
 
-                // Push loop context - continue_target will be set after body compilation
+                let body_start = code.len();
                 self.loop_stack.push(LoopContext {
-                    start: loop_start,
-                    continue_target: None,  // Will be patched to increment section
+                    start: body_start,
+                    continue_target: None,  // Will be patched to the ForLoop step
                     continue_jumps: Vec::new(),
                     break_jumps: Vec::new(),
+                    tries: self.try_stack.len(),
                 });
 
-                // Check condition: var <= end (or >= for negative step)
-                code.push(Instruction::LoadVar(var.clone()));
-                self.compile_node(end, code);
-                code.push(Instruction::LessEqual);
+                // The loop variable is a fresh local each pass, copied from the counter
+                self.begin_scope();
+                let slot = self.declare_local(var);
+                code.push(Instruction::LoadLocal(base));
+                code.push(Instruction::DefineLocal(slot));
+                self.compile_block(body, code);
+                self.end_scope();
 
-                let jump_to_end = code.len();
-                code.push(Instruction::JumpIfFalse(0));
+                let step_pos = code.len();
+                code.push(Instruction::ForLoop(base, body_start));
 
-                // Compile body
-                for stmt in body {
-                    self.compile_node(stmt, code);
+                let end_pos = code.len();
+                code[prep_pos] = Instruction::ForPrep(base, end_pos);
+
+                // Pop loop context and patch break/continue jumps
+                if let Some(loop_ctx) = self.loop_stack.pop() {
+                    for break_jump in loop_ctx.break_jumps {
+                        code[break_jump] = Instruction::Jump(end_pos);
+                    }
+                    // For for loops, continue jumps to the step
+                    for continue_jump in loop_ctx.continue_jumps {
+                        code[continue_jump] = Instruction::Jump(step_pos);
+                    }
                 }
+                self.end_scope();
+            }
+            AstNode::ForIn(vars, iterable, body) => {
+                // The iterator lives in a hidden local for the whole loop
+                self.begin_scope();
+                self.compile_node(iterable, code);
+                code.push(Instruction::IterPrep);
+                let iter_slot = self.declare_local("(for iterator)");
+                code.push(Instruction::DefineLocal(iter_slot));
+
+                let loop_start = code.len();
+                self.loop_stack.push(LoopContext {
+                    start: loop_start,
+                    continue_target: Some(loop_start),
+                    continue_jumps: Vec::new(),
+                    break_jumps: Vec::new(),
+                    tries: self.try_stack.len(),
+                });
 
-                // Mark increment section start for continue statements
-                let increment_start = code.len();
+                code.push(Instruction::IterCall(iter_slot, vars.len()));
+                let next_pos = code.len();
+                code.push(Instruction::IterNext(iter_slot, vars.len(), 0)); // Placeholder
 
-                // Increment loop variable
-                code.push(Instruction::LoadVar(var.clone()));
-                if let Some(step_expr) = step {
-                    self.compile_node(step_expr, code);
-                } else {
-                    code.push(Instruction::PushNumber(1.0));
-                }
-                code.push(Instruction::Add);
-                code.push(Instruction::StoreVar(var.clone()));
+                // Fresh loop variables each pass, so closures keep their own values
+                self.begin_scope();
+                self.define_targets(vars, code);
+                self.compile_block(body, code);
+                self.end_scope();
 
-                // Jump back to start
                 code.push(Instruction::Jump(loop_start));
 
-                // Patch the JumpIfFalse
                 let end_pos = code.len();
-                code[jump_to_end] = Instruction::JumpIfFalse(end_pos);
+                code[next_pos] = Instruction::IterNext(iter_slot, vars.len(), end_pos);
 
-                // Pop loop context and patch break/continue jumps
                 if let Some(loop_ctx) = self.loop_stack.pop() {
                     for break_jump in loop_ctx.break_jumps {
                         code[break_jump] = Instruction::Jump(end_pos);
                     }
-                    // For for loops, continue jumps to increment section
                     for continue_jump in loop_ctx.continue_jumps {
-                        code[continue_jump] = Instruction::Jump(increment_start);
+                        code[continue_jump] = Instruction::Jump(loop_start);
                     }
                 }
+                self.end_scope();
             }
             AstNode::Break => {
+                if let Some(tries) = self.loop_stack.last().map(|loop_ctx| loop_ctx.tries) {
+                    self.exit_tries(tries, code);
+                }
                 // Add a jump instruction and record it for patching later
                 let jump_pos = code.len();
                 code.push(Instruction::Jump(0)); // Placeholder, will be patched
@@ -347,6 +656,9 @@ impl Compiler {
                 }
             }
             AstNode::Continue => {
+                if let Some(tries) = self.loop_stack.last().map(|loop_ctx| loop_ctx.tries) {
+                    self.exit_tries(tries, code);
+                }
                 // Add a jump instruction and record it for patching later
                 let jump_pos = code.len();
                 code.push(Instruction::Jump(0)); // Placeholder, will be patched
@@ -355,501 +667,1986 @@ impl Compiler {
                 }
             }
             AstNode::FunctionDef(name, params, body) => {
-                // Compile function body separately
-                let mut func_code = Vec::new();
-                let mut func_compiler = Compiler {
-                    loop_stack: Vec::new(),
-                };
-                for stmt in body {
-                    func_compiler.compile_node(stmt, &mut func_code);
+                let (signature, captures, func_code, lines) = self.compile_function(params, body);
+
+                // Push the closure, then bind it like an assignment; `a.b:m`
+                // is stored in member m of a.b, keeping its full name
+                let target = Self::function_target(name);
+                self.compile_assign(&target, code, |this, code| {
+                    code.push(Instruction::DefineFunction(name.clone(), signature, captures, func_code.len()));
+                    // Store the function instructions after the definition
+                    this.append_body(func_code, lines, code);
+                });
+            }
+            AstNode::ClassDef(name, base, members) => {
+                // Methods capture the base class as the local `super`
+                self.begin_scope();
+                if let Some(base) = base {
+                    self.compile_node(base, code);
+                    let slot = self.declare_local("super");
+                    code.push(Instruction::DefineLocal(slot));
+                }
+                for (key, value) in members {
+                    code.push(Instruction::PushString(key.clone()));
+                    match value {
+                        AstNode::FunctionDef(method_name, params, body) => {
+                            let (signature, captures, func_code, lines) = self.compile_function(params, body);
+                            code.push(Instruction::DefineFunction(method_name.clone(), signature, captures, func_code.len()));
+                            self.append_body(func_code, lines, code);
+                        }
+                        value => self.compile_node(value, code),
+                    }
                 }
-                // Add implicit return nil if no explicit return
-                func_code.push(Instruction::PushNil);
-                func_code.push(Instruction::Return);
-
-                // Define the function with parameters
-                code.push(Instruction::DefineFunction(name.clone(), params.clone(), func_code.len()));
-                // Store the function instructions after the definition
-                code.extend(func_code);
+                code.push(Instruction::MakeDict(members.len()));
+                if base.is_some() {
+                    self.emit_load("super", code);
+                }
+                code.push(Instruction::MakeClass(name.clone(), base.is_some()));
+                self.emit_store(name, code);
+                self.end_scope();
             }
             AstNode::Lambda(params, body) => {
-                // Compile lambda body separately
-                let mut lambda_code = Vec::new();
-                let mut lambda_compiler = Compiler {
-                    loop_stack: Vec::new(),
-                };
-                for stmt in body {
-                    lambda_compiler.compile_node(stmt, &mut lambda_code);
-                }
-                // Add implicit return nil if no explicit return
-                lambda_code.push(Instruction::PushNil);
-                lambda_code.push(Instruction::Return);
+                let (signature, captures, lambda_code, lines) = self.compile_function(params, body);
 
                 // Define the lambda
-                code.push(Instruction::DefineLambda(params.clone(), lambda_code.len()));
+                code.push(Instruction::DefineLambda(signature, captures, lambda_code.len()));
                 // Store the lambda instructions after the definition
-                code.extend(lambda_code);
+                self.append_body(lambda_code, lines, code);
             }
-            AstNode::Return(expr) => {
-                if let Some(value) = expr {
-                    self.compile_node(value, code);
-                } else {
-                    code.push(Instruction::PushNil);
-                }
-                code.push(Instruction::Return);
+            AstNode::Return(values) => {
+                // `return f()` passes on every result of f
+                let arity = self.compile_values(values, code);
+                self.exit_tries(0, code);
+                code.push(Instruction::Return(arity));
             }
             AstNode::FunctionCall(name, args) => {
-                // Push arguments onto stack
-                for arg in args {
-                    self.compile_node(arg, code);
+                let results = std::mem::replace(&mut self.call_results, Results::Exactly(1));
+                match self.resolve(name) {
+                    Resolved::Global(name) => {
+                        // The function is loaded before the arguments are evaluated
+                        code.push(Instruction::LoadGlobal(name.clone()));
+                        let arity = self.compile_args(args, code);
+                        // Call the function by name
+                        code.push(Instruction::Call(name, arity, results));
+                    }
+                    _ => {
+                        // Locals and captured variables are called through the value itself
+                        self.emit_load(name, code);
+                        let arity = self.compile_args(args, code);
+                        code.push(Instruction::CallValue(arity, results));
+                    }
                 }
-                // Call the function by name
-                code.push(Instruction::Call(name.clone(), args.len()));
             }
-            AstNode::TryCatch(try_body, catch_var, catch_body) => {
+            AstNode::TryCatch(try_body, catch_var, catch_body, finally_body) => {
                 // Setup try with jump to catch
                 let setup_try_pos = code.len();
                 code.push(Instruction::SetupTry(0)); // Placeholder
 
                 // Compile try block
-                for stmt in try_body {
-                    self.compile_node(stmt, code);
-                }
+                let try_ctx = TryContext { finally_body: finally_body.clone(), loops: self.loop_stack.len() };
+                self.try_stack.push(try_ctx.clone());
+                self.compile_block(try_body, code);
+                self.try_stack.pop();
 
                 // Pop try handler after successful execution
                 code.push(Instruction::PopTry);
 
                 // Jump past catch block
-                let jump_past_catch = code.len();
+                let mut jumps_to_end = vec![code.len()];
                 code.push(Instruction::Jump(0)); // Placeholder
 
                 // Patch SetupTry to point to catch block
                 let catch_start = code.len();
                 code[setup_try_pos] = Instruction::SetupTry(catch_start);
 
-                // Store error in catch variable if provided
-                if let Some(var) = catch_var {
-                    code.push(Instruction::StoreVar(var.clone()));
-                } else {
-                    code.push(Instruction::Pop); // Discard error if no variable
+                if let Some(catch_body) = catch_body {
+                    // An error raised by the catch block still runs the finally block
+                    let setup_rethrow_pos = code.len();
+                    if finally_body.is_some() {
+                        code.push(Instruction::SetupTry(0)); // Placeholder
+                    }
+
+                    // The catch variable is a local of the catch block
+                    self.begin_scope();
+                    if let Some(var) = catch_var {
+                        let slot = self.declare_local(var);
+                        code.push(Instruction::DefineLocal(slot));
+                    } else {
+                        code.push(Instruction::Pop); // Discard error if no variable
+                    }
+
+                    // Compile catch block, inside the rethrow handler if there is one
+                    if finally_body.is_some() {
+                        self.try_stack.push(try_ctx);
+                    }
+                    for stmt in catch_body {
+                        self.compile_node(stmt, code);
+                    }
+                    if finally_body.is_some() {
+                        self.try_stack.pop();
+                    }
+                    self.end_scope();
+
+                    if finally_body.is_some() {
+                        code.push(Instruction::PopTry);
+                        jumps_to_end.push(code.len());
+                        code.push(Instruction::Jump(0)); // Placeholder
+                        code[setup_rethrow_pos] = Instruction::SetupTry(code.len());
+                    }
                 }
 
-                // Compile catch block
-                for stmt in catch_body {
-                    self.compile_node(stmt, code);
+                if let Some(finally_body) = finally_body {
+                    // An error nothing caught: run the finally block, then re-raise it
+                    self.begin_scope();
+                    let slot = self.declare_local("(error)");
+                    code.push(Instruction::SaveError(slot));
+                    self.compile_block(finally_body, code);
+                    code.push(Instruction::Rethrow(slot));
+                    self.end_scope();
                 }
 
-                // Patch jump past catch
+                // Patch jumps past catch
                 let after_catch = code.len();
-                code[jump_past_catch] = Instruction::Jump(after_catch);
+                for jump in jumps_to_end {
+                    code[jump] = Instruction::Jump(after_catch);
+                }
+
+                // Normal completion of the try or catch block runs the finally block
+                if let Some(finally_body) = finally_body {
+                    self.compile_block(finally_body, code);
+                }
+            }
+            AstNode::Located(position, inner) => {
+                self.mark_position(*position, code.len());
+                self.positions.push(*position);
+                self.compile_node(inner, code);
+                self.positions.pop();
+                // Code after a nested node belongs to the enclosing one again
+                if let Some(outer) = self.positions.last().copied() {
+                    self.mark_position(outer, code.len());
+                }
             }
             AstNode::Throw(expr) => {
                 self.compile_node(expr, code);
                 code.push(Instruction::Throw);
             }
-        }
-    }
-}
+            AstNode::Match(subject, arms) => {
+                self.begin_scope();
+                self.compile_node(subject, code);
+                let subject_slot = self.declare_local("(match subject)");
+                code.push(Instruction::DefineLocal(subject_slot));
+
+                let mut jumps_to_end = Vec::new();
+                for (pattern, guard, body) in arms {
+                    // The names a pattern binds are visible in its guard and body
+                    self.begin_scope();
+                    let mut failures = Vec::new();
+                    self.compile_pattern(pattern, subject_slot, &mut failures, code);
+                    if let Some(guard) = guard {
+                        self.compile_node(guard, code);
+                        failures.push(code.len());
+                        code.push(Instruction::JumpIfFalse(0));
+                    }
+                    for stmt in body {
+                        self.compile_node(stmt, code);
+                    }
+                    jumps_to_end.push(code.len());
+                    code.push(Instruction::Jump(0));
+                    self.end_scope();
 
-/// Value types for the Pickup VM.
-#[derive(Debug, Clone)]
-pub enum Value {
-    Number(f64),
-    String(String),
-    Boolean(bool),
-    Table(Vec<Value>),
-    Dictionary(HashMap<String, Value>),
-    Function(Vec<String>, Vec<Instruction>), // Parameters and compiled body
-    NativeFunction(String, String), // Module name, function name
-    Module(HashMap<String, Value>),
-    Nil,
-}
+                    let next_arm = code.len();
+                    for failure in failures {
+                        code[failure] = Instruction::JumpIfFalse(next_arm);
+                    }
+                }
+                code.push(Instruction::LoadLocal(subject_slot));
+                code.push(Instruction::NoMatch);
 
-impl PartialEq for Value {
-    fn eq(&self, other: &Self) -> bool {
-        match (self, other) {
-            (Value::Number(a), Value::Number(b)) => a == b,
-            (Value::String(a), Value::String(b)) => a == b,
-            (Value::Boolean(a), Value::Boolean(b)) => a == b,
-            (Value::Nil, Value::Nil) => true,
-            (Value::Table(a), Value::Table(b)) => a == b,
-            (Value::Dictionary(a), Value::Dictionary(b)) => a == b,
-            _ => false,
+                let end = code.len();
+                for jump in jumps_to_end {
+                    code[jump] = Instruction::Jump(end);
+                }
+                self.end_scope();
+            }
         }
     }
-}
 
-impl std::fmt::Display for Value {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        match self {
-            Value::Number(n) => {
-                if n.fract() == 0.0 {
-                    write!(f, "{}", *n as i64)
-                } else {
-                    write!(f, "{}", n)
-                }
+    /// Test the value in `slot` against `pattern`, binding the names it
+    /// declares as locals. Each test leaves the stack as it found it and
+    /// records a `JumpIfFalse` in `failures` for the caller to point at
+    /// the next arm.
+    fn compile_pattern(&mut self, pattern: &Pattern, slot: usize, failures: &mut Vec<usize>, code: &mut Vec<Instruction>) {
+        match pattern {
+            Pattern::Wildcard => {}
+            Pattern::Binding(name) => {
+                code.push(Instruction::LoadLocal(slot));
+                let binding = self.declare_local(name);
+                code.push(Instruction::DefineLocal(binding));
             }
-            Value::String(s) => write!(f, "{}", s),
-            Value::Boolean(b) => write!(f, "{}", b),
-            Value::Table(elems) => {
-                let items: Vec<String> = elems.iter().map(|v| v.to_string()).collect();
-                write!(f, "[{}]", items.join(", "))
+            Pattern::Literal(literal) => {
+                code.push(Instruction::LoadLocal(slot));
+                self.compile_node(literal, code);
+                code.push(Instruction::Equal);
+                failures.push(code.len());
+                code.push(Instruction::JumpIfFalse(0));
             }
-            Value::Dictionary(map) => {
-                let items: Vec<String> = map.iter()
-                    .map(|(k, v)| format!("{} = {}", k, v))
-                    .collect();
-                write!(f, "{{{}}}", items.join(", "))
+            Pattern::Range(low, high) => {
+                code.push(Instruction::LoadLocal(slot));
+                code.push(Instruction::MatchRange(*low, *high));
+                failures.push(code.len());
+                code.push(Instruction::JumpIfFalse(0));
             }
-            Value::Function(params, _) => write!(f, "<function({})>", params.join(", ")),
-            Value::NativeFunction(module, name) => write!(f, "<native:{}.{}>", module, name),
-            Value::Module(members) => {
-                let keys: Vec<&String> = members.keys().collect();
-                write!(f, "<module: {}>", keys.iter().map(|s| s.as_str()).collect::<Vec<_>>().join(", "))
+            Pattern::Array(elements, rest) => {
+                code.push(Instruction::LoadLocal(slot));
+                code.push(Instruction::MatchTable(elements.len(), rest.is_some()));
+                failures.push(code.len());
+                code.push(Instruction::JumpIfFalse(0));
+                for (i, element) in elements.iter().enumerate() {
+                    if let Pattern::Wildcard = element {
+                        continue;
+                    }
+                    code.push(Instruction::LoadLocal(slot));
+                    code.push(Instruction::PushNumber(i as f64));
+                    code.push(Instruction::GetIndex(None));
+                    let element_slot = self.declare_local("(match element)");
+                    code.push(Instruction::DefineLocal(element_slot));
+                    self.compile_pattern(element, element_slot, failures, code);
+                }
+                if let Some(rest) = rest {
+                    code.push(Instruction::LoadLocal(slot));
+                    code.push(Instruction::TableTail(elements.len()));
+                    let rest_slot = self.declare_local(rest);
+                    code.push(Instruction::DefineLocal(rest_slot));
+                }
+            }
+            Pattern::Dict(entries) => {
+                code.push(Instruction::LoadLocal(slot));
+                code.push(Instruction::MatchDict);
+                failures.push(code.len());
+                code.push(Instruction::JumpIfFalse(0));
+                for (key, entry) in entries {
+                    // Every key in the pattern must be present
+                    code.push(Instruction::LoadLocal(slot));
+                    code.push(Instruction::GetMember(key.clone(), None));
+                    let entry_slot = self.declare_local("(match entry)");
+                    code.push(Instruction::DefineLocal(entry_slot));
+                    code.push(Instruction::LoadLocal(entry_slot));
+                    code.push(Instruction::PushNil);
+                    code.push(Instruction::NotEqual);
+                    failures.push(code.len());
+                    code.push(Instruction::JumpIfFalse(0));
+                    self.compile_pattern(entry, entry_slot, failures, code);
+                }
             }
-            Value::Nil => write!(f, "nil"),
         }
     }
-}
 
-impl Value {
-    /// Convert value to boolean for conditional evaluation
-    pub fn is_truthy(&self) -> bool {
-        match self {
-            Value::Nil => false,
-            Value::Boolean(b) => *b,
-            Value::Number(n) => *n != 0.0,
-            Value::String(s) => !s.is_empty(),
-            _ => true,
+    /// Declare locals for the values on top of the stack, one per target.
+    /// A destructuring target takes its value in a hidden local and then
+    /// declares the names inside it.
+    fn define_targets(&mut self, targets: &[AstNode], code: &mut Vec<Instruction>) {
+        let slots: Vec<usize> = targets
+            .iter()
+            .map(|target| match target {
+                AstNode::Identifier(name) => self.declare_local(name),
+                _ => self.declare_local("(destructured value)"),
+            })
+            .collect();
+        for slot in slots.iter().rev() {
+            code.push(Instruction::DefineLocal(*slot));
+        }
+        for (target, slot) in targets.iter().zip(slots) {
+            self.compile_destructure(target, slot, code);
         }
     }
-}
 
-/// Runtime error type
-#[derive(Debug, Clone)]
-pub struct RuntimeError {
-    pub message: String,
-}
+    /// Declare the names in a destructuring pattern, reading each part of
+    /// the value in `slot` with `GetIndex` or `GetMember`. A part that is
+    /// nil takes its default, if it has one.
+    fn compile_destructure(&mut self, pattern: &AstNode, slot: usize, code: &mut Vec<Instruction>) {
+        match pattern {
+            AstNode::ArrayPattern(elements, rest) => {
+                for (i, (target, default)) in elements.iter().enumerate() {
+                    code.push(Instruction::LoadLocal(slot));
+                    code.push(Instruction::PushNumber(i as f64));
+                    code.push(Instruction::GetIndex(None));
+                    self.compile_default(default, code);
+                    self.define_targets(std::slice::from_ref(target), code);
+                }
+                if let Some(rest) = rest {
+                    code.push(Instruction::LoadLocal(slot));
+                    code.push(Instruction::TableTail(elements.len()));
+                    let rest_slot = self.declare_local(rest);
+                    code.push(Instruction::DefineLocal(rest_slot));
+                }
+            }
+            AstNode::DictPattern(entries) => {
+                for (key, target, default) in entries {
+                    code.push(Instruction::LoadLocal(slot));
+                    code.push(Instruction::GetMember(key.clone(), None));
+                    self.compile_default(default, code);
+                    self.define_targets(std::slice::from_ref(target), code);
+                }
+            }
+            _ => {}
+        }
+    }
 
-impl RuntimeError {
-    pub fn new(message: impl Into<String>) -> Self {
-        RuntimeError { message: message.into() }
+    /// Replace a nil value on the stack with `default`
+    fn compile_default(&mut self, default: &Option<AstNode>, code: &mut Vec<Instruction>) {
+        let Some(default) = default else { return };
+        code.push(Instruction::Dup);
+        code.push(Instruction::PushNil);
+        code.push(Instruction::Equal);
+        let skip = code.len();
+        code.push(Instruction::JumpIfFalse(0));
+        code.push(Instruction::Pop);
+        self.compile_node(default, code);
+        code[skip] = Instruction::JumpIfFalse(code.len());
     }
-}
 
-impl std::fmt::Display for RuntimeError {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "RuntimeError: {}", self.message)
+    /// Compile a function body in its own scope. Captures refer to the
+    /// variables of enclosing functions.
+    fn compile_function(
+        &mut self,
+        params: &Params,
+        body: &[AstNode],
+    ) -> CompiledFunction {
+        let names: Vec<String> = params.names.iter().map(|(name, _)| name.clone()).collect();
+        let signature = Signature {
+            required: params.names.iter().rposition(|(_, default)| default.is_none()).map_or(0, |i| i + 1),
+            names: names.clone(),
+            rest: params.rest.clone(),
+        };
+        // The rest parameter takes the slot after the named ones
+        let locals = names.into_iter().chain(params.rest.clone());
+        self.functions.push(FunctionScope {
+            locals: locals.map(|name| Local { name, depth: 0 }).collect(),
+            depth: 0,
+            captures: Vec::new(),
+            lines: Vec::new(),
+            varargs: params.rest.as_ref().map(|_| params.names.len()),
+        });
+        // break/continue never cross a function boundary, nor does return leave outer try blocks
+        let outer_loops = std::mem::take(&mut self.loop_stack);
+        let outer_tries = std::mem::take(&mut self.try_stack);
+
+        let mut func_code = Vec::new();
+        // A parameter left nil by the caller takes its default
+        for (slot, (_, default)) in params.names.iter().enumerate() {
+            let Some(default) = default else { continue };
+            func_code.push(Instruction::LoadLocal(slot));
+            func_code.push(Instruction::PushNil);
+            func_code.push(Instruction::Equal);
+            let skip = func_code.len();
+            func_code.push(Instruction::JumpIfFalse(0));
+            self.compile_node(default, &mut func_code);
+            func_code.push(Instruction::StoreLocal(slot));
+            func_code[skip] = Instruction::JumpIfFalse(func_code.len());
+        }
+        for (slot, pattern) in &params.patterns {
+            self.compile_destructure(pattern, *slot, &mut func_code);
+        }
+        for stmt in body {
+            self.compile_node(stmt, &mut func_code);
+        }
+        // Add implicit return if no explicit return
+        func_code.push(Instruction::Return(Arity::fixed(0)));
+
+        self.loop_stack = outer_loops;
+        self.try_stack = outer_tries;
+        let scope = self.functions.pop().unwrap_or_default();
+        let captures = scope.captures.into_iter().map(|(_, capture)| capture).collect();
+        (signature, captures, func_code, scope.lines)
     }
-}
 
-/// Try handler for exception handling
+    /// Inline a compiled function body after its definition instruction,
+    /// shifting its line table to where the body lands
+    fn append_body(&mut self, body: Vec<Instruction>, lines: Vec<(usize, Position)>, code: &mut Vec<Instruction>) {
+        let offset = code.len();
+        code.extend(body);
+        self.current_function()
+            .lines
+            .extend(lines.into_iter().map(|(pc, position)| (pc + offset, position)));
+    }
+
+    /// Record that instructions from `pc` on come from `position`
+    fn mark_position(&mut self, position: Position, pc: usize) {
+        let lines = &mut self.current_function().lines;
+        match lines.last_mut() {
+            Some(last) if last.0 == pc => last.1 = position,
+            Some(last) if last.1 == position => {}
+            _ => lines.push((pc, position)),
+        }
+    }
+
+    /// Compile statements inside their own block scope
+    /// Leave the try blocks entered since the first `depth` ones, innermost
+    /// first: pop each handler and run the finally block. A finally block
+    /// runs where its try statement is, outside the loops and try blocks
+    /// inside it.
+    fn exit_tries(&mut self, depth: usize, code: &mut Vec<Instruction>) {
+        for index in (depth..self.try_stack.len()).rev() {
+            code.push(Instruction::PopTry);
+            let TryContext { finally_body, loops } = self.try_stack[index].clone();
+            if let Some(finally_body) = finally_body {
+                let inner_tries = self.try_stack.split_off(index);
+                let inner_loops = self.loop_stack.split_off(loops);
+                self.compile_block(&finally_body, code);
+                self.loop_stack.extend(inner_loops);
+                self.try_stack.extend(inner_tries);
+            }
+        }
+    }
+
+    fn compile_block(&mut self, stmts: &[AstNode], code: &mut Vec<Instruction>) {
+        self.begin_scope();
+        for stmt in stmts {
+            self.compile_node(stmt, code);
+        }
+        self.end_scope();
+    }
+
+    fn begin_scope(&mut self) {
+        self.current_function().depth += 1;
+    }
+
+    /// Leave a block, dropping the locals declared inside it
+    fn end_scope(&mut self) {
+        let scope = self.current_function();
+        scope.depth -= 1;
+        let depth = scope.depth;
+        while scope.locals.last().is_some_and(|local| local.depth > depth) {
+            scope.locals.pop();
+        }
+    }
+
+    fn current_function(&mut self) -> &mut FunctionScope {
+        self.functions.last_mut().expect("function scope")
+    }
+
+    /// Declare a local in the current block, returning its slot
+    fn declare_local(&mut self, name: &str) -> usize {
+        let scope = self.current_function();
+        scope.locals.push(Local {
+            name: name.to_string(),
+            depth: scope.depth,
+        });
+        scope.locals.len() - 1
+    }
+
+    /// Resolve a name to a local slot, a captured upvalue or a global
+    fn resolve(&mut self, name: &str) -> Resolved {
+        let depth = self.functions.len() - 1;
+        if let Some(slot) = self.functions[depth].slot_of(name) {
+            return Resolved::Local(slot);
+        }
+        match self.resolve_upvalue(depth, name) {
+            Some(index) => Resolved::Upvalue(index),
+            None => Resolved::Global(name.to_string()),
+        }
+    }
+
+    /// Find or add the capture of `name` for the function at `depth`
+    fn resolve_upvalue(&mut self, depth: usize, name: &str) -> Option<usize> {
+        if depth == 0 {
+            return None;
+        }
+        if let Some(index) = self.functions[depth].captures.iter().position(|(n, _)| n == name) {
+            return Some(index);
+        }
+        let capture = if let Some(slot) = self.functions[depth - 1].slot_of(name) {
+            Capture::Local(slot)
+        } else {
+            Capture::Upvalue(self.resolve_upvalue(depth - 1, name)?)
+        };
+        let captures = &mut self.functions[depth].captures;
+        captures.push((name.to_string(), capture));
+        Some(captures.len() - 1)
+    }
+
+    /// Compile `exprs` as a list of values. A call in last position is left
+    /// open and contributes all of its results.
+    fn compile_values(&mut self, exprs: &[AstNode], code: &mut Vec<Instruction>) -> Arity {
+        for (i, expr) in exprs.iter().enumerate() {
+            if i + 1 == exprs.len() && Self::is_multivalued(expr) {
+                self.call_results = Results::All;
+                self.compile_node(expr, code);
+                return Arity { fixed: i, open: true, named: 0 };
+            }
+            self.compile_node(expr, code);
+        }
+        Arity::fixed(exprs.len())
+    }
+
+    /// Compile the arguments of a call. Named arguments follow the
+    /// positional ones as name and value pairs, and a trailing call among
+    /// positional arguments is only left open when there are none.
+    fn compile_args(&mut self, args: &[AstNode], code: &mut Vec<Instruction>) -> Arity {
+        let positional = args.iter().take_while(|arg| !matches!(arg, AstNode::NamedArg(..))).count();
+        if positional == args.len() {
+            return self.compile_values(args, code);
+        }
+        for arg in &args[..positional] {
+            self.compile_node(arg, code);
+        }
+        for arg in &args[positional..] {
+            if let AstNode::NamedArg(name, value) = arg {
+                code.push(Instruction::PushString(name.clone()));
+                self.compile_node(value, code);
+            }
+        }
+        Arity { fixed: positional, open: false, named: args.len() - positional }
+    }
+
+    /// Compile `exprs` to exactly `count` values. A trailing call supplies
+    /// as many as are missing; otherwise missing values are nil, and extra
+    /// ones are evaluated and dropped.
+    fn compile_adjusted(&mut self, exprs: &[AstNode], count: usize, code: &mut Vec<Instruction>) {
+        for (i, expr) in exprs.iter().enumerate() {
+            if i + 1 == exprs.len() && i < count && Self::is_multivalued(expr) {
+                self.call_results = Results::Exactly(count - i);
+                self.compile_node(expr, code);
+                return;
+            }
+            self.compile_node(expr, code);
+            if i >= count {
+                code.push(Instruction::Pop);
+            }
+        }
+        for _ in exprs.len()..count {
+            code.push(Instruction::PushNil);
+        }
+    }
+
+    /// Whether a method call's receiver is the `super` of a class method
+    fn is_super_call(base: &AstNode) -> bool {
+        matches!(base, AstNode::Identifier(name) if name == "super")
+    }
+
+    /// Whether `name` is a local or captured variable rather than a global
+    fn resolves_locally(&mut self, name: &str) -> bool {
+        !matches!(self.resolve(name), Resolved::Global(_))
+    }
+
+    /// Whether an expression can produce several values: a call or `...`
+    fn is_multivalued(node: &AstNode) -> bool {
+        match node {
+            AstNode::FunctionCall(..) | AstNode::MethodCall(..) | AstNode::Call(..) | AstNode::Varargs => true,
+            AstNode::Located(_, inner) => Self::is_multivalued(inner),
+            _ => false,
+        }
+    }
+
+    /// Assign to a variable, member or index; `value` compiles the value
+    fn compile_assign<F>(&mut self, target: &AstNode, code: &mut Vec<Instruction>, value: F)
+    where
+        F: FnOnce(&mut Self, &mut Vec<Instruction>),
+    {
+        match target {
+            AstNode::Identifier(name) => {
+                value(self, code);
+                self.emit_store(name, code);
+            }
+            AstNode::MemberAccess(base, members) => {
+                // Navigate to the parent object, which is shared by reference
+                self.compile_node(base, code);
+                self.emit_members(base, &members[..members.len() - 1], code);
+                // Set the final member
+                value(self, code);
+                let (member, path) = members.split_last().unwrap();
+                code.push(Instruction::SetMember(member.clone(), Self::describe_chain(base, path)));
+            }
+            AstNode::Index(base, index) => {
+                self.compile_node(base, code);
+                self.compile_node(index, code);
+                value(self, code);
+                code.push(Instruction::SetIndex(Self::describe(base)));
+            }
+            _ => {}
+        }
+    }
+
+    /// Evaluate the container of a member or index target, and the index,
+    /// into temporaries; the slots are for `store_place`
+    fn compile_place(&mut self, target: &AstNode, code: &mut Vec<Instruction>) -> Vec<usize> {
+        match target {
+            AstNode::MemberAccess(base, members) => {
+                self.compile_node(base, code);
+                self.emit_members(base, &members[..members.len() - 1], code);
+                self.define_temporaries(1, "(assigned object)", code)
+            }
+            AstNode::Index(base, index) => {
+                self.compile_node(base, code);
+                self.compile_node(index, code);
+                self.define_temporaries(2, "(assigned object)", code)
+            }
+            _ => Vec::new(),
+        }
+    }
+
+    /// Store the value in local `slot` into a target prepared by `compile_place`
+    fn store_place(&mut self, target: &AstNode, place: &[usize], slot: usize, code: &mut Vec<Instruction>) {
+        for temporary in place {
+            code.push(Instruction::LoadLocal(*temporary));
+        }
+        code.push(Instruction::LoadLocal(slot));
+        match target {
+            AstNode::Identifier(name) => self.emit_store(name, code),
+            AstNode::MemberAccess(base, members) => {
+                let (member, path) = members.split_last().unwrap();
+                code.push(Instruction::SetMember(member.clone(), Self::describe_chain(base, path)));
+            }
+            AstNode::Index(base, _) => code.push(Instruction::SetIndex(Self::describe(base))),
+            _ => code.push(Instruction::Pop),
+        }
+    }
+
+    /// Move the top `count` stack values into new locals, the deepest first
+    fn define_temporaries(&mut self, count: usize, name: &str, code: &mut Vec<Instruction>) -> Vec<usize> {
+        let slots: Vec<usize> = (0..count).map(|_| self.declare_local(name)).collect();
+        for slot in slots.iter().rev() {
+            code.push(Instruction::DefineLocal(*slot));
+        }
+        slots
+    }
+
+    fn emit_load(&mut self, name: &str, code: &mut Vec<Instruction>) {
+        code.push(match self.resolve(name) {
+            Resolved::Local(slot) => Instruction::LoadLocal(slot),
+            Resolved::Upvalue(index) => Instruction::LoadUpvalue(index),
+            Resolved::Global(name) => Instruction::LoadGlobal(name),
+        });
+    }
+
+    fn emit_store(&mut self, name: &str, code: &mut Vec<Instruction>) {
+        code.push(match self.resolve(name) {
+            Resolved::Local(slot) => Instruction::StoreLocal(slot),
+            Resolved::Upvalue(index) => Instruction::StoreUpvalue(index),
+            Resolved::Global(name) => Instruction::StoreGlobal(name),
+        });
+    }
+
+    /// Where `function name()` stores the function: a variable, or a member
+    /// for paths like `Account.new` and `Account:deposit`
+    fn function_target(name: &str) -> AstNode {
+        let mut path = name.split(['.', ':']).map(str::to_string);
+        let base = AstNode::Identifier(path.next().unwrap_or_default());
+        let members: Vec<String> = path.collect();
+        if members.is_empty() {
+            base
+        } else {
+            AstNode::MemberAccess(Box::new(base), members)
+        }
+    }
+
+    /// Read `members` in turn from the value of `base` on the stack, each
+    /// named after the chain before it for errors
+    fn emit_members(&mut self, base: &AstNode, members: &[String], code: &mut Vec<Instruction>) {
+        for (i, member) in members.iter().enumerate() {
+            code.push(Instruction::GetMember(member.clone(), Self::describe_chain(base, &members[..i])));
+        }
+    }
+
+    /// Source text of a variable or member chain, for naming it in errors
+    fn describe(node: &AstNode) -> Option<String> {
+        match node {
+            AstNode::Identifier(name) => Some(name.clone()),
+            AstNode::MemberAccess(base, members) => Self::describe_chain(base, members),
+            AstNode::Located(_, inner) => Self::describe(inner),
+            _ => None,
+        }
+    }
+
+    /// `describe` of `base` followed by the members in `path`
+    fn describe_chain(base: &AstNode, path: &[String]) -> Option<String> {
+        let base = Self::describe(base)?;
+        Some(path.iter().fold(base, |chain, member| format!("{}.{}", chain, member)))
+    }
+}
+
+/// Value types for the Pickup VM.
+#[derive(Debug, Clone)]
+pub enum Value {
+    Number(f64),
+    String(String),
+    Boolean(bool),
+    Table(Rc<RefCell<Vec<Value>>>), // Shared, mutable array
+    Dictionary(Rc<RefCell<Dict>>), // Shared, mutable key-value map
+    Function(Rc<Closure>), // Compiled body plus captured variables
+    NativeFunction(String, String), // Module name (empty for host globals), function name
+    Module(HashMap<String, Value>),
+    Iterator(Rc<RefCell<Cursor>>), // Position of a for-in loop, or the result of `pairs`/`ipairs`
+    Coroutine(Rc<RefCell<Coroutine>>), // A function that can suspend itself with `coroutine.yield`
+    Wrapped(Rc<RefCell<Coroutine>>), // The function `coroutine.wrap` returns, resuming its coroutine when called
+    Nil,
+}
+
+/// A shared variable cell; closures hold on to the cells they capture
+pub type Upvalue = Rc<RefCell<Value>>;
+
+/// The entries of a dictionary, and the metatable `setmetatable` gave it.
+/// Dereferences to the entries, so it reads like a `HashMap`.
+#[derive(Clone, Default)]
+pub struct Dict {
+    pub entries: HashMap<String, Value>,
+    pub metatable: Option<Rc<RefCell<Dict>>>,
+}
+
+impl Dict {
+    /// The field `event` of the metatable, such as `__add` or `__index`
+    pub fn metamethod(&self, event: &str) -> Option<Value> {
+        self.metatable.as_ref()?.borrow().entries.get(event).cloned()
+    }
+}
+
+impl std::ops::Deref for Dict {
+    type Target = HashMap<String, Value>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.entries
+    }
+}
+
+impl std::ops::DerefMut for Dict {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.entries
+    }
+}
+
+impl PartialEq for Dict {
+    fn eq(&self, other: &Self) -> bool {
+        self.entries == other.entries
+    }
+}
+
+impl std::fmt::Debug for Dict {
+    // Metatables usually refer back to the objects they describe, so only
+    // whether there is one is printed
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Dict")
+            .field("entries", &self.entries)
+            .field("metatable", &self.metatable.is_some())
+            .finish()
+    }
+}
+
+/// A function provided by the host application, registered with
+/// `Vm::register_function` or `Vm::register_native`
+pub type NativeFn = Rc<dyn Fn(&mut Vm, Vec<Value>) -> Result<Value, Error>>;
+
+/// A function value: parameters, compiled body and captured variables.
+/// The body is a range of the chunk it was compiled into, so creating a
+/// closure never copies code.
+pub struct Closure {
+    pub name: Option<String>,
+    pub params: Signature,
+    pub chunk: Rc<Chunk>,
+    pub start: usize,
+    pub len: usize,
+    pub upvalues: Vec<Upvalue>,
+}
+
+/// A walk over the entries of a table, dictionary or string. Every step
+/// yields a key and a value: the index and element for tables and strings,
+/// the key and value for dictionaries.
+#[derive(Debug)]
+pub struct Cursor {
+    source: Value,
+    keys: Vec<String>,  // Dictionary keys, sorted when the walk starts
+    pos: usize,
+    values_first: bool, // A loop with one variable gets the element, not the index
+}
+
+impl Cursor {
+    /// Start walking `source`, or None if it is not a container
+    pub fn new(source: Value, values_first: bool) -> Option<Cursor> {
+        let mut keys = Vec::new();
+        match &source {
+            Value::Table(_) | Value::String(_) => {}
+            Value::Dictionary(map) => {
+                keys = map.borrow().keys().cloned().collect();
+                keys.sort();
+            }
+            _ => return None,
+        }
+        Some(Cursor { source, keys, pos: 0, values_first })
+    }
+
+    /// The next key and value, or None once every entry has been visited
+    fn step(&mut self) -> Option<(Value, Value)> {
+        let pos = self.pos;
+        self.pos += 1;
+        match &self.source {
+            Value::Table(elems) => elems.borrow().get(pos).map(|v| (Value::Number(pos as f64), v.clone())),
+            Value::String(s) => s.chars().nth(pos).map(|c| (Value::Number(pos as f64), Value::String(c.to_string()))),
+            Value::Dictionary(map) => {
+                let map = map.borrow();
+                // Keys removed since the walk started are skipped
+                while let Some(key) = self.keys.get(self.pos - 1) {
+                    if let Some(value) = map.get(key) {
+                        return Some((Value::String(key.clone()), value.clone()));
+                    }
+                    self.pos += 1;
+                }
+                None
+            }
+            _ => None,
+        }
+    }
+}
+
+impl std::fmt::Debug for Closure {
+    // Captured cells may refer back to this closure, so they are not printed
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Closure")
+            .field("name", &self.name)
+            .field("params", &self.params)
+            .field("upvalues", &self.upvalues.len())
+            .finish()
+    }
+}
+
+impl PartialEq for Value {
+    fn eq(&self, other: &Self) -> bool {
+        match (self, other) {
+            (Value::Number(a), Value::Number(b)) => a == b,
+            (Value::String(a), Value::String(b)) => a == b,
+            (Value::Boolean(a), Value::Boolean(b)) => a == b,
+            (Value::Nil, Value::Nil) => true,
+            (Value::Function(a), Value::Function(b)) => Rc::ptr_eq(a, b),
+            (Value::Iterator(a), Value::Iterator(b)) => Rc::ptr_eq(a, b),
+            (Value::Coroutine(a), Value::Coroutine(b)) => Rc::ptr_eq(a, b),
+            (Value::Wrapped(a), Value::Wrapped(b)) => Rc::ptr_eq(a, b),
+            // Tables and dictionaries are shared references, equal only to themselves
+            (Value::Table(a), Value::Table(b)) => Rc::ptr_eq(a, b),
+            (Value::Dictionary(a), Value::Dictionary(b)) => Rc::ptr_eq(a, b),
+            _ => false,
+        }
+    }
+}
+
+thread_local! {
+    /// Tables and dictionaries being displayed, innermost last
+    static DISPLAYING: RefCell<Vec<usize>> = const { RefCell::new(Vec::new()) };
+}
+
+/// Display a container with `write`, or as `nested` if it is already being
+/// displayed further out, as in a class whose `__index` is itself
+fn display_once<F>(ptr: usize, f: &mut std::fmt::Formatter<'_>, nested: &str, write: F) -> std::fmt::Result
+where
+    F: FnOnce(&mut std::fmt::Formatter<'_>) -> std::fmt::Result,
+{
+    if DISPLAYING.with(|d| d.borrow().contains(&ptr)) {
+        return write!(f, "{}", nested);
+    }
+    DISPLAYING.with(|d| d.borrow_mut().push(ptr));
+    let result = write(f);
+    DISPLAYING.with(|d| d.borrow_mut().pop());
+    result
+}
+
+impl std::fmt::Display for Value {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Value::Number(n) => {
+                if n.fract() == 0.0 {
+                    write!(f, "{}", *n as i64)
+                } else {
+                    write!(f, "{}", n)
+                }
+            }
+            Value::String(s) => write!(f, "{}", s),
+            Value::Boolean(b) => write!(f, "{}", b),
+            Value::Table(elems) => display_once(Rc::as_ptr(elems) as usize, f, "[...]", |f| {
+                let items: Vec<String> = elems.borrow().iter().map(|v| v.to_string()).collect();
+                write!(f, "[{}]", items.join(", "))
+            }),
+            Value::Dictionary(map) => display_once(Rc::as_ptr(map) as usize, f, "{...}", |f| {
+                let items: Vec<String> = map.borrow().iter()
+                    .map(|(k, v)| format!("{} = {}", k, v))
+                    .collect();
+                write!(f, "{{{}}}", items.join(", "))
+            }),
+            Value::Function(closure) => write!(f, "<function({})>", closure.params),
+            Value::NativeFunction(module, name) if module.is_empty() => write!(f, "<native:{}>", name),
+            Value::NativeFunction(module, name) => write!(f, "<native:{}.{}>", module, name),
+            Value::Module(members) => {
+                let keys: Vec<&String> = members.keys().collect();
+                write!(f, "<module: {}>", keys.iter().map(|s| s.as_str()).collect::<Vec<_>>().join(", "))
+            }
+            Value::Iterator(_) => write!(f, "<iterator>"),
+            Value::Coroutine(_) => write!(f, "<coroutine>"),
+            Value::Wrapped(_) => write!(f, "<native:coroutine.wrap>"),
+            Value::Nil => write!(f, "nil"),
+        }
+    }
+}
+
+impl Value {
+    /// Wrap a vector of values in a new shared table
+    pub fn table(elems: Vec<Value>) -> Value {
+        Value::Table(Rc::new(RefCell::new(elems)))
+    }
+
+    /// Wrap a map in a new shared dictionary
+    pub fn dictionary(map: HashMap<String, Value>) -> Value {
+        Value::Dictionary(Rc::new(RefCell::new(Dict { entries: map, metatable: None })))
+    }
+
+    /// The metamethod `event` of a dictionary that has a metatable
+    pub fn metamethod(&self, event: &str) -> Option<Value> {
+        match self {
+            Value::Dictionary(dict) => dict.borrow().metamethod(event),
+            _ => None,
+        }
+    }
+
+    /// The type name `type.typeof` reports and error messages use
+    pub fn type_name(&self) -> &'static str {
+        match self {
+            Value::Number(_) => "number",
+            Value::String(_) => "string",
+            Value::Boolean(_) => "boolean",
+            Value::Table(_) => "table",
+            Value::Dictionary(_) => "dictionary",
+            Value::Function(_) | Value::NativeFunction(..) | Value::Wrapped(_) => "function",
+            Value::Module(_) => "module",
+            Value::Iterator(_) => "iterator",
+            Value::Coroutine(_) => "coroutine",
+            Value::Nil => "nil",
+        }
+    }
+
+    /// The integer a number stands for, if it is whole and in range, as
+    /// bitwise operators require
+    pub fn to_integer(&self) -> Option<i64> {
+        match self {
+            Value::Number(n) if n.fract() == 0.0 && *n >= i64::MIN as f64 && *n < i64::MAX as f64 => Some(*n as i64),
+            _ => None,
+        }
+    }
+
+    /// Convert value to boolean for conditional evaluation
+    pub fn is_truthy(&self) -> bool {
+        match self {
+            Value::Nil => false,
+            Value::Boolean(b) => *b,
+            Value::Number(n) => *n != 0.0,
+            Value::String(s) => !s.is_empty(),
+            _ => true,
+        }
+    }
+}
+
+/// Shift the bits of `x` left by `n` places, or right when `n` is negative.
+/// Vacated bits are filled with zeros, so shifting by 64 or more gives 0.
+fn shift_left(x: i64, n: i64) -> i64 {
+    match n {
+        n if n <= -64 || n >= 64 => 0,
+        n if n >= 0 => ((x as u64) << n) as i64,
+        n => ((x as u64) >> -n) as i64,
+    }
+}
+
+/// How many `__index` or `__newindex` dictionaries a lookup may pass through
+const MAX_META_CHAIN: usize = 100;
+
+/// How many Pickup calls may be active at once
+const MAX_CALL_DEPTH: usize = 100_000;
+
+/// How many nested `call`s and host functions may be in progress; each one
+/// uses the Rust stack
+const MAX_HOST_CALLS: usize = 200;
+
+/// Stack left below which a nested `call` or resume moves to a new stack
+/// segment, and the size of that segment
+const STACK_RED_ZONE: usize = 256 * 1024;
+const STACK_GROWTH: usize = 4 * 1024 * 1024;
+
+/// How many traceback entries are shown at each end of a long traceback
+const TRACEBACK_ENDS: usize = 10;
+
+/// What kind of failure a `RuntimeError` is
+#[derive(Debug, Clone, PartialEq)]
+pub enum ErrorKind {
+    TypeError,      // An operation was applied to a value of the wrong type
+    NameError,      // A function or module that does not exist
+    ArityError,     // A function called with the wrong number of arguments
+    DivisionByZero, // Division or modulo by zero
+    ValueError,     // A value of the right type that an operation cannot use, like a zero loop step
+    MatchError,     // A `match` statement with no arm for its value
+    StackOverflow,  // Calls nested deeper than the VM allows
+    UserThrow(Value), // A `throw` nothing caught, with the thrown value
+}
+
+impl std::fmt::Display for ErrorKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ErrorKind::TypeError => write!(f, "TypeError"),
+            ErrorKind::NameError => write!(f, "NameError"),
+            ErrorKind::ArityError => write!(f, "ArityError"),
+            ErrorKind::DivisionByZero => write!(f, "DivisionByZero"),
+            ErrorKind::ValueError => write!(f, "ValueError"),
+            ErrorKind::MatchError => write!(f, "MatchError"),
+            ErrorKind::StackOverflow => write!(f, "StackOverflow"),
+            ErrorKind::UserThrow(_) => write!(f, "Error"),
+        }
+    }
+}
+
+/// One active call at the time a runtime error was raised
+#[derive(Debug, Clone, PartialEq)]
+pub struct TraceEntry {
+    pub function: Option<String>, // None for the top-level chunk
+    pub chunk: String,
+    pub position: Option<Position>,
+}
+
+impl std::fmt::Display for TraceEntry {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.position {
+            Some(position) => write!(f, "{}:{}: ", self.chunk, position)?,
+            None => write!(f, "{}: ", self.chunk)?,
+        }
+        match &self.function {
+            Some(name) => write!(f, "in function '{}'", name),
+            None => write!(f, "in main chunk"),
+        }
+    }
+}
+
+/// Runtime error type
+#[derive(Debug, Clone)]
+pub struct RuntimeError {
+    pub kind: ErrorKind,
+    pub message: String,
+    pub traceback: Vec<TraceEntry>, // Innermost call first; empty until the error goes uncaught
+}
+
+impl RuntimeError {
+    pub fn new(kind: ErrorKind, message: impl Into<String>) -> Self {
+        RuntimeError { kind, message: message.into(), traceback: Vec::new() }
+    }
+
+    /// Where the error was raised, as `script.up:42:7`
+    pub fn location(&self) -> Option<String> {
+        let entry = self.traceback.first()?;
+        let position = entry.position?;
+        Some(format!("{}:{}", entry.chunk, position))
+    }
+
+    /// The error raised by `throw value`
+    /// A rethrown error dictionary keeps its original message.
+    pub fn thrown(value: Value) -> Self {
+        let message = match &value {
+            Value::Dictionary(map) => match map.borrow().get("message") {
+                Some(Value::String(message)) => message.clone(),
+                _ => value.to_string(),
+            },
+            _ => value.to_string(),
+        };
+        RuntimeError::new(ErrorKind::UserThrow(value), message)
+    }
+
+    /// The value a `catch` clause receives for this error: the thrown value
+    /// itself, or for errors raised by the VM a dictionary with `message`,
+    /// `kind`, `line` and `traceback` fields
+    pub fn value(&self) -> Value {
+        if let ErrorKind::UserThrow(value) = &self.kind {
+            return value.clone();
+        }
+        let line = match self.traceback.first().and_then(|entry| entry.position) {
+            Some(position) => Value::Number(position.line as f64),
+            None => Value::Nil,
+        };
+        let traceback = self.traceback.iter().map(|entry| Value::String(entry.to_string())).collect();
+        let mut fields = HashMap::new();
+        fields.insert("message".to_string(), Value::String(self.message.clone()));
+        fields.insert("kind".to_string(), Value::String(self.kind.to_string()));
+        fields.insert("line".to_string(), line);
+        fields.insert("traceback".to_string(), Value::table(traceback));
+        Value::dictionary(fields)
+    }
+}
+
+impl std::fmt::Display for RuntimeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if let Some(location) = self.location() {
+            write!(f, "{}: ", location)?;
+        }
+        match self.kind {
+            ErrorKind::UserThrow(_) => write!(f, "Uncaught error: {}", self.message)?,
+            _ => write!(f, "{}: {}", self.kind, self.message)?,
+        }
+        if !self.traceback.is_empty() {
+            write!(f, "\nstack traceback:")?;
+            let len = self.traceback.len();
+            for (i, entry) in self.traceback.iter().enumerate() {
+                if len > 2 * TRACEBACK_ENDS + 1 && i >= TRACEBACK_ENDS && i < len - TRACEBACK_ENDS {
+                    if i == TRACEBACK_ENDS {
+                        write!(f, "\n  ... (skipping {} levels)", len - 2 * TRACEBACK_ENDS)?;
+                    }
+                    continue;
+                }
+                write!(f, "\n  {}", entry)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for RuntimeError {}
+
+/// Error returned by the embedding API: the source failed to parse, or
+/// raised an error nothing caught while running
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("{0}")]
+    Parse(#[from] ParseError),
+    #[error("{0}")]
+    Runtime(#[from] RuntimeError),
+}
+
+/// Try handler for exception handling
 #[derive(Debug, Clone)]
 struct TryHandler {
     catch_pc: usize,
     stack_depth: usize,
+    frame_depth: usize, // Index of the call frame that installed the handler
 }
 
-/// Call frame for function execution
-#[derive(Debug, Clone)]
+/// An active call: the code being run, where to resume it, and where its
+/// locals and temporaries begin. The top-level script is the bottom frame.
 struct CallFrame {
-    return_pc: usize,
-    local_vars: HashMap<String, Value>,
+    closure: Option<Rc<Closure>>, // None for the top-level script
+    chunk: Rc<Chunk>,
+    start: usize,      // Offset of the body within the chunk
+    len: usize,        // Number of instructions in the body
+    pc: usize,         // Next instruction, relative to `start`; the return address while suspended
+    base: usize,       // First local slot of this call
+    stack_base: usize, // Value stack height when the call began
+    results: Results,  // What the caller wants back
+    errors: Vec<(usize, RuntimeError)>, // Errors saved for a finally block to raise again, by local slot
+}
+
+/// Where a coroutine is in its life, as `coroutine.status` reports it
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CoroutineStatus {
+    Suspended, // Not started yet, or stopped in `yield`
+    Running,
+    Normal,    // Resumed another coroutine and waits for it
+    Dead,      // Returned or raised an error
+}
+
+impl std::fmt::Display for CoroutineStatus {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CoroutineStatus::Suspended => write!(f, "suspended"),
+            CoroutineStatus::Running => write!(f, "running"),
+            CoroutineStatus::Normal => write!(f, "normal"),
+            CoroutineStatus::Dead => write!(f, "dead"),
+        }
+    }
+}
+
+/// A function with call frames and stacks of its own. While it is not
+/// running they are kept here, so `coroutine.resume` can swap them back
+/// into the VM and continue where `coroutine.yield` stopped.
+pub struct Coroutine {
+    body: Option<Value>, // The function to run, until the first resume
+    status: CoroutineStatus,
+    stack: Vec<Value>,
+    slots: Vec<Upvalue>,
+    frames: Vec<CallFrame>,
+    try_handlers: Vec<TryHandler>,
+    yield_results: Results, // What the suspended `yield` call wants back
+    host_calls: usize,      // `Vm::host_calls` when it was last resumed
+}
+
+impl Coroutine {
+    fn new(body: Value) -> Self {
+        Coroutine {
+            body: Some(body),
+            status: CoroutineStatus::Suspended,
+            stack: Vec::new(),
+            slots: Vec::new(),
+            frames: Vec::new(),
+            try_handlers: Vec::new(),
+            yield_results: Results::Exactly(0),
+            host_calls: 0,
+        }
+    }
+
+    pub fn status(&self) -> CoroutineStatus {
+        self.status
+    }
+}
+
+impl std::fmt::Debug for Coroutine {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Coroutine").field("status", &self.status).finish()
+    }
 }
 
 /// Simple bytecode interpreter.
-pub struct Vm;
+///
+/// Every call runs in the same dispatch loop: calling a Pickup function
+/// pushes a `CallFrame` and returning pops it, so deep recursion never grows
+/// the Rust stack.
+///
+/// A `Vm` keeps its globals between runs, so a host can seed it with
+/// `set_global`, `eval` scripts into it, `call_function` the functions they
+/// define and read results back with `get_global`.
+pub struct Vm {
+    stack: Vec<Value>,
+    globals: HashMap<String, Value>,
+    slots: Vec<Upvalue>, // Local variable cells of every active call
+    frames: Vec<CallFrame>,
+    try_handlers: Vec<TryHandler>,
+    modules: HashMap<String, Value>, // Everything `import` can load
+    natives: HashMap<(String, String), NativeFn>,
+    verbose: bool,
+    lenient: bool, // Bad operands produce nil instead of a TypeError
+    coroutines: Vec<Rc<RefCell<Coroutine>>>, // Running coroutine last, then the ones that resumed it
+    yielded: Option<Vec<Value>>, // Values passed to `coroutine.yield`, until `resume` picks them up
+    host_calls: usize, // Nested `call`s and host functions in progress; `yield` cannot cross them
+    caught: Option<RuntimeError>, // The error a try handler just caught, until SaveError keeps it
+}
+
+impl Default for Vm {
+    fn default() -> Self {
+        Vm::new()
+    }
+}
 
 impl Vm {
-    pub fn execute(code: &[Instruction], verbose: bool) {
-        let mut stack: Vec<Value> = Vec::new();
-        let mut vars: HashMap<String, Value> = HashMap::new();
-        let mut call_stack: Vec<CallFrame> = Vec::new();
-        let mut try_handlers: Vec<TryHandler> = Vec::new();
-        let mut pc = 0; // Program counter
+    /// Create an interpreter with no globals and the standard library
+    /// available to `import`
+    pub fn new() -> Self {
+        let mut vm = Vm {
+            stack: Vec::new(),
+            globals: HashMap::new(),
+            slots: Vec::new(),
+            frames: Vec::new(),
+            try_handlers: Vec::new(),
+            modules: crate::stdlib::create_stdlib(),
+            natives: HashMap::new(),
+            verbose: false,
+            lenient: false,
+            coroutines: Vec::new(),
+            yielded: None,
+            host_calls: 0,
+            caught: None,
+        };
+        vm.register_function("pairs", |_vm, args| Self::iterate("pairs", args));
+        vm.register_function("ipairs", |_vm, args| Self::iterate("ipairs", args));
+        vm.register_function("setmetatable", |_vm, args| Self::metatable_builtin("setmetatable", args));
+        vm.register_function("getmetatable", |_vm, args| Self::metatable_builtin("getmetatable", args));
+        vm.register_function("rawget", |_vm, args| Self::metatable_builtin("rawget", args));
+        vm.register_function("rawset", |_vm, args| Self::metatable_builtin("rawset", args));
+        vm.natives.insert(("class".to_string(), "new".to_string()), Rc::new(|vm, args| Ok(vm.construct(args)?)));
+        for name in ["create", "resume", "yield", "status", "wrap", "running"] {
+            vm.register_module_value("coroutine", name, Value::NativeFunction("coroutine".to_string(), name.to_string()));
+        }
+        vm.register_function("select", |_vm, args| Self::select(args));
+        vm.register_function("tostring", |vm, args| {
+            let value = args.into_iter().next().unwrap_or(Value::Nil);
+            Ok(Value::String(vm.tostring(&value)?))
+        });
+        vm
+    }
+
+    /// `select("#", ...)` counts the values after the first argument, and
+    /// `select(i, ...)` returns the one at index `i`, from the end if negative
+    fn select(args: Vec<Value>) -> Result<Value, Error> {
+        let mut args = args.into_iter();
+        let selector = args.next().unwrap_or(Value::Nil);
+        let values: Vec<Value> = args.collect();
+        match selector {
+            Value::String(s) if s == "#" => Ok(Value::Number(values.len() as f64)),
+            Value::Number(n) if n.fract() == 0.0 => {
+                let index = if n < 0.0 { values.len() as f64 + n } else { n };
+                Ok(values.get(index as usize).filter(|_| index >= 0.0).cloned().unwrap_or(Value::Nil))
+            }
+            other => {
+                let message = format!("bad argument #1 to select (number or '#' expected, got {})", other.type_name());
+                Err(RuntimeError::new(ErrorKind::TypeError, message).into())
+            }
+        }
+    }
+
+    /// `setmetatable(dict, meta)`, `getmetatable(dict)` and `rawget`/`rawset`,
+    /// which read and write a dictionary without its metamethods
+    fn metatable_builtin(name: &str, args: Vec<Value>) -> Result<Value, Error> {
+        let mut args = args.into_iter();
+        let dict = match args.next() {
+            Some(Value::Dictionary(dict)) => dict,
+            other => {
+                let got = other.map_or("no value", |v| v.type_name());
+                let message = format!("bad argument #1 to {} (dictionary expected, got {})", name, got);
+                return Err(RuntimeError::new(ErrorKind::TypeError, message).into());
+            }
+        };
+        let result = match (name, args.next(), args.next()) {
+            ("setmetatable", meta, _) => {
+                dict.borrow_mut().metatable = match meta {
+                    Some(Value::Dictionary(meta)) => Some(meta),
+                    None | Some(Value::Nil) => None,
+                    Some(other) => {
+                        let message = format!("bad argument #2 to setmetatable (nil or dictionary expected, got {})", other.type_name());
+                        return Err(RuntimeError::new(ErrorKind::TypeError, message).into());
+                    }
+                };
+                Value::Dictionary(dict)
+            }
+            ("getmetatable", _, _) => dict.borrow().metatable.clone().map_or(Value::Nil, Value::Dictionary),
+            ("rawget", Some(Value::String(key)), _) => dict.borrow().get(&key).cloned().unwrap_or(Value::Nil),
+            ("rawset", Some(Value::String(key)), value) => {
+                dict.borrow_mut().insert(key, value.unwrap_or(Value::Nil));
+                Value::Dictionary(dict)
+            }
+            (_, key, _) => {
+                let got = key.map_or("no value", |v| v.type_name());
+                let message = format!("bad argument #2 to {} (string expected, got {})", name, got);
+                return Err(RuntimeError::new(ErrorKind::TypeError, message).into());
+            }
+        };
+        Ok(result)
+    }
+
+    /// Turn a dictionary of methods and fields into a class: it becomes the
+    /// metatable of its instances, and is itself callable through a
+    /// metatable that holds the base class as `__index`. Metamethods the
+    /// class does not define are inherited from the base.
+    fn make_class(&mut self, name: &str, members: Value, base: Option<Value>) -> Result<Value, RuntimeError> {
+        let Value::Dictionary(class) = members else {
+            return Err(RuntimeError::new(ErrorKind::TypeError, format!("invalid body for class {}", name)));
+        };
+        let mut meta = HashMap::new();
+        meta.insert("__call".to_string(), Value::NativeFunction("class".to_string(), "new".to_string()));
+        match base {
+            Some(Value::Dictionary(base)) => {
+                let mut class = class.borrow_mut();
+                for (key, value) in base.borrow().iter() {
+                    if key.starts_with("__") && key != "__index" && key != "__name" && !class.contains_key(key) {
+                        class.insert(key.clone(), value.clone());
+                    }
+                }
+                meta.insert("__index".to_string(), Value::Dictionary(base));
+            }
+            Some(other) => {
+                let message = format!("class {} cannot extend {} value", name, other.type_name());
+                return Err(RuntimeError::new(ErrorKind::TypeError, message));
+            }
+            None => {}
+        }
+        {
+            let mut class_mut = class.borrow_mut();
+            class_mut.insert("__index".to_string(), Value::Dictionary(Rc::clone(&class)));
+            class_mut.insert("__name".to_string(), Value::String(name.to_string()));
+            class_mut.metatable = Some(Rc::new(RefCell::new(Dict { entries: meta, metatable: None })));
+        }
+        Ok(Value::Dictionary(class))
+    }
+
+    /// Call a class: create an instance whose metatable is the class and
+    /// run its `init` method, if it has one, with the remaining arguments
+    fn construct(&mut self, args: Vec<Value>) -> Result<Value, RuntimeError> {
+        let mut args = args.into_iter();
+        let class = match args.next() {
+            Some(Value::Dictionary(class)) => class,
+            other => {
+                let got = other.map_or("no value", |v| v.type_name());
+                return Err(RuntimeError::new(ErrorKind::TypeError, format!("attempt to instantiate {} value", got)));
+            }
+        };
+        let instance = Value::Dictionary(Rc::new(RefCell::new(Dict { entries: HashMap::new(), metatable: Some(Rc::clone(&class)) })));
+        let init = self.lookup(&Value::Dictionary(class), "init")?;
+        if init != Value::Nil {
+            let mut init_args = vec![instance.clone()];
+            init_args.extend(args);
+            self.call(init, init_args)?;
+        }
+        Ok(instance)
+    }
+
+    /// `pairs(container)` and `ipairs(table)`: a cursor yielding keys (or
+    /// indices) together with values
+    fn iterate(name: &str, args: Vec<Value>) -> Result<Value, Error> {
+        let source = args.into_iter().next().unwrap_or(Value::Nil);
+        let expected = if name == "ipairs" { matches!(source, Value::Table(_)) } else { matches!(source, Value::Table(_) | Value::Dictionary(_)) };
+        match Cursor::new(source.clone(), false) {
+            Some(cursor) if expected => Ok(Value::Iterator(Rc::new(RefCell::new(cursor)))),
+            _ => {
+                let message = format!("bad argument to {} (table expected, got {})", name, source.type_name());
+                Err(RuntimeError::new(ErrorKind::TypeError, message).into())
+            }
+        }
+    }
+
+    /// Log every compiled instruction and VM step
+    pub fn set_verbose(&mut self, verbose: bool) {
+        self.verbose = verbose;
+    }
+
+    /// Opt back into the old forgiving behaviour: arithmetic on non-numbers,
+    /// indexing a non-container and calling an unknown native function give
+    /// nil (comparisons give false) instead of raising a `TypeError`
+    pub fn set_lenient(&mut self, lenient: bool) {
+        self.lenient = lenient;
+    }
+
+    /// Run bytecode in a fresh VM. Returns the value of a top-level
+    /// `return`, or the error that stopped execution.
+    pub fn execute(code: &[Instruction], verbose: bool) -> Result<Value, RuntimeError> {
+        Self::execute_chunk(Chunk::new("<script>", code.to_vec()), verbose)
+    }
+
+    /// Like `execute`, for a chunk whose line table locates runtime errors
+    pub fn execute_chunk(chunk: Chunk, verbose: bool) -> Result<Value, RuntimeError> {
+        let mut vm = Vm::new();
+        vm.set_verbose(verbose);
+
+        if verbose {
+            println!("\n--- VM Execution Log ---");
+        }
+
+        let result = vm.run_chunk(chunk);
+
+        if verbose {
+            println!("--- VM Execution Completed ---\n");
+        }
+        result
+    }
+
+    /// Parse, compile and run `source` against this VM's globals. Returns the
+    /// value of a top-level `return`, or nil.
+    pub fn eval(&mut self, source: &str) -> Result<Value, Error> {
+        self.eval_named("<eval>", source)
+    }
+
+    /// Like `eval`, reporting runtime error locations against `name`
+    pub fn eval_named(&mut self, name: &str, source: &str) -> Result<Value, Error> {
+        let tokens = crate::parser::tokenize(source, self.verbose)?;
+        let ast = crate::parser::parse_to_ast(tokens, self.verbose)?;
+        let chunk = Compiler::compile_chunk(&ast, name, self.verbose);
+        Ok(self.run_chunk(chunk)?)
+    }
+
+    /// Run already compiled bytecode against this VM's globals
+    pub fn run_code(&mut self, code: &[Instruction]) -> Result<Value, RuntimeError> {
+        self.run_chunk(Chunk::new("<script>", code.to_vec()))
+    }
+
+    /// Run a compiled chunk against this VM's globals
+    pub fn run_chunk(&mut self, chunk: Chunk) -> Result<Value, RuntimeError> {
+        let depth = self.frames.len();
+        let len = chunk.code.len();
+        self.frames.push(CallFrame {
+            closure: None,
+            chunk: Rc::new(chunk),
+            start: 0,
+            len,
+            pc: 0,
+            base: self.slots.len(),
+            stack_base: self.stack.len(),
+            results: Results::Exactly(1),
+            errors: Vec::new(),
+        });
+        self.run(depth)?;
+        Ok(self.pop())
+    }
+
+    /// Call the global function `name` with `args` and return its result
+    pub fn call_function(&mut self, name: &str, args: &[Value]) -> Result<Value, Error> {
+        match self.globals.get(name).cloned() {
+            Some(func @ (Value::Function(_) | Value::NativeFunction(_, _) | Value::Wrapped(_))) => Ok(self.call(func, args.to_vec())?),
+            Some(other) => Err(RuntimeError::new(ErrorKind::TypeError, format!("{} is not a function", other)).into()),
+            None => Err(RuntimeError::new(ErrorKind::NameError, format!("Function {} not found", name)).into()),
+        }
+    }
+
+    /// Set a global variable, visible to every script run in this VM
+    pub fn set_global(&mut self, name: impl Into<String>, value: Value) {
+        self.globals.insert(name.into(), value);
+    }
+
+    /// Read a global variable back
+    pub fn get_global(&self, name: &str) -> Option<Value> {
+        self.globals.get(name).cloned()
+    }
+
+    /// All global variables, including imported modules
+    pub fn globals(&self) -> &HashMap<String, Value> {
+        &self.globals
+    }
+
+    /// Register a host function as a global, callable from scripts by name
+    pub fn register_function<F>(&mut self, name: &str, func: F)
+    where
+        F: Fn(&mut Vm, Vec<Value>) -> Result<Value, Error> + 'static,
+    {
+        self.natives.insert((String::new(), name.to_string()), Rc::new(func));
+        self.globals.insert(name.to_string(), Value::NativeFunction(String::new(), name.to_string()));
+    }
+
+    /// Register a host function as `module.name`, creating the module if
+    /// needed. Scripts reach it after `import "module"`, like the standard
+    /// library; a host function replaces a built-in one of the same name.
+    pub fn register_native<F>(&mut self, module: &str, name: &str, func: F)
+    where
+        F: Fn(&mut Vm, Vec<Value>) -> Result<Value, Error> + 'static,
+    {
+        self.natives.insert((module.to_string(), name.to_string()), Rc::new(func));
+        self.register_module_value(module, name, Value::NativeFunction(module.to_string(), name.to_string()));
+    }
+
+    /// Add a plain value such as a constant to `module`, creating it if needed
+    pub fn register_module_value(&mut self, module: &str, name: &str, value: Value) {
+        let entry = self
+            .modules
+            .entry(module.to_string())
+            .or_insert_with(|| Value::Module(HashMap::new()));
+        if let Value::Module(members) = entry {
+            members.insert(name.to_string(), value);
+        }
+    }
+
+    /// Call a function value to completion from outside the dispatch loop
+    pub fn call(&mut self, func: Value, args: Vec<Value>) -> Result<Value, RuntimeError> {
+        if self.host_calls >= MAX_HOST_CALLS {
+            return Err(RuntimeError::new(ErrorKind::StackOverflow, "stack overflow"));
+        }
+        let depth = self.frames.len();
+        self.host_calls += 1;
+        let result = stacker::maybe_grow(STACK_RED_ZONE, STACK_GROWTH, || {
+            self.call_value(func, args, Results::Exactly(1)).and_then(|()| self.run(depth))
+        });
+        self.host_calls -= 1;
+        result?;
+        Ok(self.pop())
+    }
+
+    /// Run until every frame above `depth` has returned, leaving the result
+    /// of the last one on the stack. On an uncaught error those frames are
+    /// discarded and the VM is ready to run again.
+    fn run(&mut self, depth: usize) -> Result<(), RuntimeError> {
+        let result = self.dispatch(depth);
+        if result.is_err() && self.frames.len() > depth {
+            let entry = &self.frames[depth];
+            self.stack.truncate(entry.stack_base);
+            self.slots.truncate(entry.base);
+            self.frames.truncate(depth);
+            self.try_handlers.retain(|h| h.frame_depth < depth);
+        }
+        result
+    }
+
+    /// The dispatch loop behind `run`
+    fn dispatch(&mut self, depth: usize) -> Result<(), RuntimeError> {
+        let verbose = self.verbose;
 
-        // Initialize standard library
-        let stdlib = crate::stdlib::create_stdlib();
+        while self.frames.len() > depth {
+            if self.yielded.is_some() {
+                // A coroutine suspended itself; its frames stay put until `resume`
+                return Ok(());
+            }
+            let frame = self.frame_mut();
+            if frame.pc >= frame.len {
+                // Ran off the end of a body: an implicit `return nil`
+                self.return_from_call(Vec::new());
+                continue;
+            }
 
-        if verbose {
-            println!("\n--- VM Execution Log ---");
-        }
+            // Advance first, so a call leaves the caller pointing at its return address
+            let chunk = Rc::clone(&frame.chunk);
+            let at = frame.start + frame.pc;
+            frame.pc += 1;
 
-        while pc < code.len() {
             if verbose {
-                println!("Instruction {}: {:?}", pc, code[pc]);
+                println!("Instruction {}: {:?}", at, chunk.code[at]);
             }
 
-            match &code[pc] {
+            match &chunk.code[at] {
                 Instruction::PushNumber(n) => {
-                    stack.push(Value::Number(*n));
+                    self.stack.push(Value::Number(*n));
                     if verbose {
                         println!("  Pushed number {}", n);
                     }
-                    pc += 1;
                 }
                 Instruction::PushString(s) => {
-                    stack.push(Value::String(s.clone()));
+                    self.stack.push(Value::String(s.clone()));
                     if verbose {
                         println!("  Pushed string \"{}\"", s);
                     }
-                    pc += 1;
                 }
                 Instruction::PushBoolean(b) => {
-                    stack.push(Value::Boolean(*b));
+                    self.stack.push(Value::Boolean(*b));
                     if verbose {
                         println!("  Pushed boolean {}", b);
                     }
-                    pc += 1;
                 }
                 Instruction::PushNil => {
-                    stack.push(Value::Nil);
+                    self.stack.push(Value::Nil);
                     if verbose {
                         println!("  Pushed nil");
                     }
-                    pc += 1;
                 }
-                Instruction::LoadVar(name) => {
-                    let val = vars.get(name).cloned().unwrap_or(Value::Nil);
-                    stack.push(val.clone());
+                Instruction::LoadGlobal(name) => {
+                    let val = self.globals.get(name).cloned().unwrap_or(Value::Nil);
                     if verbose {
-                        println!("  Loaded var {} = {:?}", name, val);
+                        println!("  Loaded global {} = {:?}", name, val);
                     }
-                    pc += 1;
+                    self.stack.push(val);
                 }
-                Instruction::StoreVar(name) => {
-                    if let Some(val) = stack.pop() {
+                Instruction::StoreGlobal(name) => {
+                    if let Some(val) = self.stack.pop() {
                         if verbose {
-                            println!("  Stored {:?} in var {}", val, name);
+                            println!("  Stored {:?} in global {}", val, name);
                         }
-                        vars.insert(name.clone(), val);
+                        self.globals.insert(name.clone(), val);
                     }
-                    pc += 1;
                 }
-                Instruction::StoreLocal(name) => {
-                    if let Some(val) = stack.pop() {
-                        if verbose {
-                            println!("  Stored {:?} in local var {}", val, name);
-                        }
-                        vars.insert(name.clone(), val);
+                Instruction::LoadLocal(slot) => {
+                    let val = self.local_cell(*slot).borrow().clone();
+                    if verbose {
+                        println!("  Loaded local slot {} = {:?}", slot, val);
+                    }
+                    self.stack.push(val);
+                }
+                Instruction::StoreLocal(slot) => {
+                    let val = self.pop();
+                    if verbose {
+                        println!("  Stored {:?} in local slot {}", val, slot);
+                    }
+                    // Write through the cell so closures see the change
+                    self.local_cell(*slot).replace(val);
+                }
+                Instruction::DefineLocal(slot) => {
+                    let val = self.pop();
+                    if verbose {
+                        println!("  Defined local slot {} = {:?}", slot, val);
+                    }
+                    self.define_local(*slot, val);
+                }
+                Instruction::LoadUpvalue(index) => {
+                    let val = self.upvalue(*index).map(|cell| cell.borrow().clone()).unwrap_or(Value::Nil);
+                    if verbose {
+                        println!("  Loaded upvalue {} = {:?}", index, val);
+                    }
+                    self.stack.push(val);
+                }
+                Instruction::StoreUpvalue(index) => {
+                    let val = self.pop();
+                    if verbose {
+                        println!("  Stored {:?} in upvalue {}", val, index);
+                    }
+                    if let Some(cell) = self.upvalue(*index) {
+                        cell.replace(val);
                     }
-                    pc += 1;
                 }
                 Instruction::Add => {
-                    let b = stack.pop().unwrap_or(Value::Nil);
-                    let a = stack.pop().unwrap_or(Value::Nil);
+                    let b = self.pop();
+                    let a = self.pop();
                     match (&a, &b) {
                         (Value::Number(x), Value::Number(y)) => {
                             let res = x + y;
-                            stack.push(Value::Number(res));
+                            self.stack.push(Value::Number(res));
                             if verbose {
                                 println!("  Add: {} + {} = {}", x, y, res);
                             }
                         }
-                        _ => {
-                            stack.push(Value::Nil);
-                            if verbose {
-                                println!("  Add: type error");
-                            }
-                        }
+                        _ => self.arithmetic_fallback("__add", "add", &a, &b, depth)?,
                     }
-                    pc += 1;
                 }
                 Instruction::Sub => {
-                    let b = stack.pop().unwrap_or(Value::Nil);
-                    let a = stack.pop().unwrap_or(Value::Nil);
-                    if let (Value::Number(x), Value::Number(y)) = (a, b) {
+                    let b = self.pop();
+                    let a = self.pop();
+                    if let (&Value::Number(x), &Value::Number(y)) = (&a, &b) {
                         let res = x - y;
-                        stack.push(Value::Number(res));
+                        self.stack.push(Value::Number(res));
                         if verbose {
                             println!("  Sub: {} - {} = {}", x, y, res);
                         }
                     } else {
-                        stack.push(Value::Nil);
+                        self.arithmetic_fallback("__sub", "subtract", &a, &b, depth)?;
                     }
-                    pc += 1;
                 }
                 Instruction::Mul => {
-                    let b = stack.pop().unwrap_or(Value::Nil);
-                    let a = stack.pop().unwrap_or(Value::Nil);
-                    if let (Value::Number(x), Value::Number(y)) = (a, b) {
+                    let b = self.pop();
+                    let a = self.pop();
+                    if let (&Value::Number(x), &Value::Number(y)) = (&a, &b) {
                         let res = x * y;
-                        stack.push(Value::Number(res));
+                        self.stack.push(Value::Number(res));
                         if verbose {
                             println!("  Mul: {} * {} = {}", x, y, res);
                         }
                     } else {
-                        stack.push(Value::Nil);
+                        self.arithmetic_fallback("__mul", "multiply", &a, &b, depth)?;
                     }
-                    pc += 1;
                 }
                 Instruction::Div => {
-                    let b = stack.pop().unwrap_or(Value::Nil);
-                    let a = stack.pop().unwrap_or(Value::Nil);
-                    if let (Value::Number(x), Value::Number(y)) = (a, b) {
+                    let b = self.pop();
+                    let a = self.pop();
+                    if let (&Value::Number(x), &Value::Number(y)) = (&a, &b) {
                         if y == 0.0 {
-                            Self::handle_error(&mut stack, &mut try_handlers, &mut pc, "Division by zero");
+                            self.handle_error(RuntimeError::new(ErrorKind::DivisionByZero, "Division by zero"), depth)?;
                             continue;
                         }
                         let res = x / y;
-                        stack.push(Value::Number(res));
+                        self.stack.push(Value::Number(res));
                         if verbose {
                             println!("  Div: {} / {} = {}", x, y, res);
                         }
                     } else {
-                        stack.push(Value::Nil);
+                        self.arithmetic_fallback("__div", "divide", &a, &b, depth)?;
                     }
-                    pc += 1;
                 }
                 Instruction::Mod => {
-                    let b = stack.pop().unwrap_or(Value::Nil);
-                    let a = stack.pop().unwrap_or(Value::Nil);
-                    if let (Value::Number(x), Value::Number(y)) = (a, b) {
+                    let b = self.pop();
+                    let a = self.pop();
+                    if let (&Value::Number(x), &Value::Number(y)) = (&a, &b) {
                         if y == 0.0 {
-                            Self::handle_error(&mut stack, &mut try_handlers, &mut pc, "Modulo by zero");
+                            self.handle_error(RuntimeError::new(ErrorKind::DivisionByZero, "Modulo by zero"), depth)?;
                             continue;
                         }
                         let res = x % y;
-                        stack.push(Value::Number(res));
+                        self.stack.push(Value::Number(res));
                         if verbose {
                             println!("  Mod: {} % {} = {}", x, y, res);
                         }
                     } else {
-                        stack.push(Value::Nil);
+                        self.arithmetic_fallback("__mod", "take the modulo of", &a, &b, depth)?;
+                    }
+                }
+                Instruction::Pow => {
+                    let b = self.pop();
+                    let a = self.pop();
+                    if let (&Value::Number(x), &Value::Number(y)) = (&a, &b) {
+                        let res = x.powf(y);
+                        self.stack.push(Value::Number(res));
+                        if verbose {
+                            println!("  Pow: {} ^ {} = {}", x, y, res);
+                        }
+                    } else {
+                        self.arithmetic_fallback("__pow", "exponentiate", &a, &b, depth)?;
+                    }
+                }
+                Instruction::IntDiv => {
+                    let b = self.pop();
+                    let a = self.pop();
+                    if let (&Value::Number(x), &Value::Number(y)) = (&a, &b) {
+                        if y == 0.0 {
+                            self.handle_error(RuntimeError::new(ErrorKind::DivisionByZero, "Division by zero"), depth)?;
+                            continue;
+                        }
+                        let res = (x / y).floor();
+                        self.stack.push(Value::Number(res));
+                        if verbose {
+                            println!("  IntDiv: {} // {} = {}", x, y, res);
+                        }
+                    } else {
+                        self.arithmetic_fallback("__idiv", "divide", &a, &b, depth)?;
+                    }
+                }
+                op @ (Instruction::BitAnd
+                | Instruction::BitOr
+                | Instruction::BitXor
+                | Instruction::ShiftLeft
+                | Instruction::ShiftRight) => {
+                    let b = self.pop();
+                    let a = self.pop();
+                    let (x, y) = match (a.to_integer(), b.to_integer()) {
+                        (Some(x), Some(y)) => (x, y),
+                        _ => {
+                            self.bitwise_error(&[&a, &b], depth)?;
+                            continue;
+                        }
+                    };
+                    let res = match op {
+                        Instruction::BitAnd => x & y,
+                        Instruction::BitOr => x | y,
+                        Instruction::BitXor => x ^ y,
+                        Instruction::ShiftLeft => shift_left(x, y),
+                        _ => shift_left(x, y.wrapping_neg()),
+                    };
+                    self.stack.push(Value::Number(res as f64));
+                    if verbose {
+                        println!("  {:?}: {} {} = {}", op, x, y, res);
+                    }
+                }
+                Instruction::Neg => {
+                    let a = self.pop();
+                    if let Value::Number(x) = a {
+                        self.stack.push(Value::Number(-x));
+                        if verbose {
+                            println!("  Neg: -{} = {}", x, -x);
+                        }
+                    } else if let Some(handler) = a.metamethod("__unm") {
+                        // Unary metamethods take just the operand, like `__len`
+                        let result = self.call(handler, vec![a]);
+                        self.push_result(result, depth)?;
+                    } else {
+                        let message = format!("attempt to negate {} value", a.type_name());
+                        self.lenient_error(RuntimeError::new(ErrorKind::TypeError, message), vec![Value::Nil], depth)?;
+                    }
+                }
+                Instruction::Len => {
+                    let a = self.pop();
+                    if let Some(handler) = a.metamethod("__len") {
+                        let result = self.call(handler, vec![a]);
+                        self.push_result(result, depth)?;
+                        continue;
+                    }
+                    let len = match &a {
+                        Value::String(s) => s.chars().count(),
+                        Value::Table(t) => t.borrow().len(),
+                        Value::Dictionary(d) => d.borrow().len(),
+                        _ => {
+                            let message = format!("attempt to get length of {} value", a.type_name());
+                            self.lenient_error(RuntimeError::new(ErrorKind::TypeError, message), vec![Value::Nil], depth)?;
+                            continue;
+                        }
+                    };
+                    self.stack.push(Value::Number(len as f64));
+                    if verbose {
+                        println!("  Len: #{:?} = {}", a, len);
+                    }
+                }
+                Instruction::BitNot => {
+                    let a = self.pop();
+                    if let Some(x) = a.to_integer() {
+                        self.stack.push(Value::Number(!x as f64));
+                        if verbose {
+                            println!("  BitNot: ~{} = {}", x, !x);
+                        }
+                    } else {
+                        self.bitwise_error(&[&a], depth)?;
                     }
-                    pc += 1;
                 }
                 Instruction::Concat => {
-                    let b = stack.pop().unwrap_or(Value::Nil);
-                    let a = stack.pop().unwrap_or(Value::Nil);
-                    let result = format!("{}{}", a, b);
-                    stack.push(Value::String(result.clone()));
+                    let b = self.pop();
+                    let a = self.pop();
+                    let result = match self.binary_metamethod("__concat", &a, &b) {
+                        Some(result) => result,
+                        None => self.tostring(&a).and_then(|x| Ok(Value::String(x + &self.tostring(&b)?))),
+                    };
                     if verbose {
-                        println!("  Concat: \"{}\" .. \"{}\" = \"{}\"", a, b, result);
+                        println!("  Concat: \"{}\" .. \"{}\" = {:?}", a, b, result);
                     }
-                    pc += 1;
+                    self.push_result(result, depth)?;
                 }
                 Instruction::Equal => {
-                    let b = stack.pop().unwrap_or(Value::Nil);
-                    let a = stack.pop().unwrap_or(Value::Nil);
-                    let result = a == b;
-                    stack.push(Value::Boolean(result));
+                    let b = self.pop();
+                    let a = self.pop();
+                    let result = match self.equals(&a, &b) {
+                        Ok(result) => result,
+                        Err(e) => {
+                            self.handle_error(e, depth)?;
+                            continue;
+                        }
+                    };
+                    self.stack.push(Value::Boolean(result));
                     if verbose {
                         println!("  Equal: {:?} == {:?} = {}", a, b, result);
                     }
-                    pc += 1;
                 }
                 Instruction::NotEqual => {
-                    let b = stack.pop().unwrap_or(Value::Nil);
-                    let a = stack.pop().unwrap_or(Value::Nil);
-                    let result = a != b;
-                    stack.push(Value::Boolean(result));
+                    let b = self.pop();
+                    let a = self.pop();
+                    let result = match self.equals(&a, &b) {
+                        Ok(result) => !result,
+                        Err(e) => {
+                            self.handle_error(e, depth)?;
+                            continue;
+                        }
+                    };
+                    self.stack.push(Value::Boolean(result));
                     if verbose {
                         println!("  NotEqual: {:?} ~= {:?} = {}", a, b, result);
                     }
-                    pc += 1;
                 }
                 Instruction::LessThan => {
-                    let b = stack.pop().unwrap_or(Value::Nil);
-                    let a = stack.pop().unwrap_or(Value::Nil);
+                    let b = self.pop();
+                    let a = self.pop();
                     let result = if let (Value::Number(x), Value::Number(y)) = (&a, &b) {
                         x < y
                     } else if let (Value::String(x), Value::String(y)) = (&a, &b) {
                         x < y
                     } else {
-                        false
+                        self.compare_fallback("__lt", &a, &b, false, depth)?;
+                        continue;
                     };
-                    stack.push(Value::Boolean(result));
+                    self.stack.push(Value::Boolean(result));
                     if verbose {
                         println!("  LessThan result: {}", result);
                     }
-                    pc += 1;
                 }
                 Instruction::GreaterThan => {
-                    let b = stack.pop().unwrap_or(Value::Nil);
-                    let a = stack.pop().unwrap_or(Value::Nil);
+                    let b = self.pop();
+                    let a = self.pop();
                     let result = if let (Value::Number(x), Value::Number(y)) = (&a, &b) {
                         x > y
                     } else if let (Value::String(x), Value::String(y)) = (&a, &b) {
                         x > y
                     } else {
-                        false
+                        self.compare_fallback("__lt", &a, &b, true, depth)?;
+                        continue;
                     };
-                    stack.push(Value::Boolean(result));
+                    self.stack.push(Value::Boolean(result));
                     if verbose {
                         println!("  GreaterThan result: {}", result);
                     }
-                    pc += 1;
                 }
                 Instruction::LessEqual => {
-                    let b = stack.pop().unwrap_or(Value::Nil);
-                    let a = stack.pop().unwrap_or(Value::Nil);
+                    let b = self.pop();
+                    let a = self.pop();
                     let result = if let (Value::Number(x), Value::Number(y)) = (&a, &b) {
                         x <= y
                     } else if let (Value::String(x), Value::String(y)) = (&a, &b) {
                         x <= y
                     } else {
-                        false
+                        self.compare_fallback("__le", &a, &b, false, depth)?;
+                        continue;
                     };
-                    stack.push(Value::Boolean(result));
+                    self.stack.push(Value::Boolean(result));
                     if verbose {
                         println!("  LessEqual result: {}", result);
                     }
-                    pc += 1;
                 }
                 Instruction::GreaterEqual => {
-                    let b = stack.pop().unwrap_or(Value::Nil);
-                    let a = stack.pop().unwrap_or(Value::Nil);
+                    let b = self.pop();
+                    let a = self.pop();
                     let result = if let (Value::Number(x), Value::Number(y)) = (&a, &b) {
                         x >= y
                     } else if let (Value::String(x), Value::String(y)) = (&a, &b) {
                         x >= y
                     } else {
-                        false
+                        self.compare_fallback("__le", &a, &b, true, depth)?;
+                        continue;
                     };
-                    stack.push(Value::Boolean(result));
+                    self.stack.push(Value::Boolean(result));
                     if verbose {
                         println!("  GreaterEqual result: {}", result);
                     }
-                    pc += 1;
-                }
-                Instruction::And => {
-                    let b = stack.pop().unwrap_or(Value::Nil);
-                    let a = stack.pop().unwrap_or(Value::Nil);
-                    let result = a.is_truthy() && b.is_truthy();
-                    stack.push(Value::Boolean(result));
-                    if verbose {
-                        println!("  And: {} and {} = {}", a.is_truthy(), b.is_truthy(), result);
-                    }
-                    pc += 1;
-                }
-                Instruction::Or => {
-                    let b = stack.pop().unwrap_or(Value::Nil);
-                    let a = stack.pop().unwrap_or(Value::Nil);
-                    let result = a.is_truthy() || b.is_truthy();
-                    stack.push(Value::Boolean(result));
-                    if verbose {
-                        println!("  Or: {} or {} = {}", a.is_truthy(), b.is_truthy(), result);
-                    }
-                    pc += 1;
                 }
                 Instruction::Not => {
-                    let a = stack.pop().unwrap_or(Value::Nil);
+                    let a = self.pop();
                     let result = !a.is_truthy();
-                    stack.push(Value::Boolean(result));
+                    self.stack.push(Value::Boolean(result));
                     if verbose {
                         println!("  Not: not {} = {}", a.is_truthy(), result);
                     }
-                    pc += 1;
                 }
                 Instruction::Jump(target) => {
                     if verbose {
                         println!("  Jump to {}", target);
                     }
-                    pc = *target;
+                    self.frame_mut().pc = *target;
                 }
                 Instruction::JumpIfFalse(target) => {
-                    let condition = stack.pop().unwrap_or(Value::Nil);
+                    let condition = self.pop();
                     if verbose {
                         println!("  JumpIfFalse: condition = {:?}", condition);
                     }
@@ -857,861 +2654,1286 @@ impl Vm {
                         if verbose {
                             println!("  Jumping to {}", target);
                         }
-                        pc = *target;
+                        self.frame_mut().pc = *target;
+                    }
+                }
+                Instruction::JumpIfFalseKeep(target) | Instruction::JumpIfTrueKeep(target) => {
+                    let wanted = matches!(&chunk.code[at], Instruction::JumpIfTrueKeep(_));
+                    let decides = self.stack.last().is_some_and(|v| v.is_truthy() == wanted);
+                    if verbose {
+                        println!("  {:?}: value = {:?}", chunk.code[at], self.stack.last());
+                    }
+                    if decides {
+                        self.frame_mut().pc = *target;
                     } else {
-                        pc += 1;
+                        self.pop();
                     }
                 }
-                Instruction::MakeTable(size) => {
-                    let mut elements = Vec::new();
-                    for _ in 0..*size {
-                        if let Some(val) = stack.pop() {
-                            elements.push(val);
-                        }
+                Instruction::JumpIfNotNilKeep(target) => {
+                    if self.stack.last().is_some_and(|v| *v != Value::Nil) {
+                        self.frame_mut().pc = *target;
+                    } else {
+                        self.pop();
+                    }
+                }
+                Instruction::JumpIfNil(target) => {
+                    if self.stack.last().is_none_or(|v| *v == Value::Nil) {
+                        self.frame_mut().pc = *target;
                     }
-                    elements.reverse();
-                    stack.push(Value::Table(elements));
+                }
+                Instruction::MakeTable(arity) => {
+                    let size = self.take_count(*arity);
+                    let elements = Self::pop_args(&mut self.stack, size);
+                    self.stack.push(Value::table(elements));
                     if verbose {
                         println!("  Created table with {} elements", size);
                     }
-                    pc += 1;
                 }
                 Instruction::MakeDict(size) => {
                     let mut entries = HashMap::new();
                     for _ in 0..*size {
-                        let value = stack.pop().unwrap_or(Value::Nil);
-                        let key = match stack.pop().unwrap_or(Value::Nil) {
+                        let value = self.pop();
+                        let key = match self.pop() {
                             Value::String(s) => s,
                             v => v.to_string(),
                         };
                         entries.insert(key, value);
                     }
-                    stack.push(Value::Dictionary(entries));
+                    self.stack.push(Value::dictionary(entries));
                     if verbose {
                         println!("  Created dictionary with {} entries", size);
                     }
-                    pc += 1;
                 }
-                Instruction::GetIndex => {
-                    let index = stack.pop().unwrap_or(Value::Nil);
-                    let container = stack.pop().unwrap_or(Value::Nil);
-
-                    match (&container, &index) {
-                        (Value::Table(elems), Value::Number(idx)) => {
-                            let i = *idx as usize;
-                            if i < elems.len() {
-                                stack.push(elems[i].clone());
-                                if verbose {
-                                    println!("  Got element at index {}", i);
-                                }
-                            } else {
-                                stack.push(Value::Nil);
-                                if verbose {
-                                    println!("  Index {} out of bounds", i);
-                                }
-                            }
-                        }
-                        (Value::Dictionary(map), Value::String(key)) => {
-                            let val = map.get(key).cloned().unwrap_or(Value::Nil);
-                            stack.push(val);
-                            if verbose {
-                                println!("  Got dictionary value for key '{}'", key);
-                            }
-                        }
-                        (Value::String(s), Value::Number(idx)) => {
-                            let i = *idx as usize;
-                            if i < s.len() {
-                                stack.push(Value::String(s.chars().nth(i).unwrap().to_string()));
-                            } else {
-                                stack.push(Value::Nil);
-                            }
-                        }
-                        _ => {
-                            stack.push(Value::Nil);
-                        }
+                Instruction::MakeClass(name, has_base) => {
+                    let base = if *has_base { Some(self.pop()) } else { None };
+                    let members = self.pop();
+                    let class = self.make_class(name, members, base);
+                    self.push_result(class, depth)?;
+                    if verbose {
+                        println!("  Created class {}", name);
                     }
-                    pc += 1;
                 }
-                Instruction::SetIndex => {
-                    let value = stack.pop().unwrap_or(Value::Nil);
-                    let index = stack.pop().unwrap_or(Value::Nil);
-                    let container = stack.pop().unwrap_or(Value::Nil);
-
-                    // Note: This is a simplified version; proper implementation would modify in place
-                    match (container, index) {
-                        (Value::Table(mut elems), Value::Number(idx)) => {
-                            let i = idx as usize;
-                            if i < elems.len() {
-                                elems[i] = value;
-                            }
-                            // Would need to update the variable reference here
-                        }
-                        (Value::Dictionary(mut map), Value::String(key)) => {
-                            map.insert(key, value);
-                            // Would need to update the variable reference here
+                Instruction::MatchRange(low, high) => {
+                    let matched = matches!(self.pop(), Value::Number(n) if *low <= n && n <= *high);
+                    self.stack.push(Value::Boolean(matched));
+                }
+                Instruction::MatchTable(count, open) => {
+                    let matched = match self.pop() {
+                        Value::Table(elems) => {
+                            let len = elems.borrow().len();
+                            len == *count || (*open && len > *count)
                         }
-                        _ => {}
+                        _ => false,
+                    };
+                    self.stack.push(Value::Boolean(matched));
+                }
+                Instruction::MatchDict => {
+                    let matched = matches!(self.pop(), Value::Dictionary(_));
+                    self.stack.push(Value::Boolean(matched));
+                }
+                Instruction::TableTail(start) => {
+                    let tail = match self.pop() {
+                        Value::Table(elems) => elems.borrow().iter().skip(*start).cloned().collect(),
+                        _ => Vec::new(),
+                    };
+                    self.stack.push(Value::table(tail));
+                }
+                Instruction::NoMatch => {
+                    let value = self.pop();
+                    let message = format!("no pattern matches {}", value);
+                    self.handle_error(RuntimeError::new(ErrorKind::MatchError, message), depth)?;
+                }
+                Instruction::Unpack(results) => {
+                    let values = match self.pop() {
+                        Value::Table(elems) => elems.borrow().clone(),
+                        _ => Vec::new(),
+                    };
+                    self.push_results(values, *results);
+                }
+                Instruction::GetIndex(name) => {
+                    let index = self.pop();
+                    let container = self.pop();
+                    if let (Value::Dictionary(_), Value::String(key)) = (&container, &index) {
+                        let result = self.lookup(&container, key);
+                        self.push_result(result, depth)?;
+                        continue;
+                    }
+                    let Some(val) = Self::get_index(&container, &index) else {
+                        self.index_error(&container, name, vec![Value::Nil], depth)?;
+                        continue;
+                    };
+                    if verbose {
+                        println!("  GetIndex [{}] = {:?}", index, val);
                     }
-                    pc += 1;
+                    self.stack.push(val);
                 }
-                Instruction::GetMember(name) => {
-                    let obj = stack.pop().unwrap_or(Value::Nil);
-                    match obj {
-                        Value::Module(members) => {
-                            let val = members.get(name).cloned().unwrap_or(Value::Nil);
-                            stack.push(val);
-                            if verbose {
-                                println!("  Got module member '{}'", name);
-                            }
-                        }
-                        Value::Dictionary(map) => {
-                            let val = map.get(name).cloned().unwrap_or(Value::Nil);
-                            stack.push(val);
-                            if verbose {
-                                println!("  Got dictionary member '{}'", name);
-                            }
+                Instruction::SetIndex(name) => {
+                    let value = self.pop();
+                    let index = self.pop();
+                    let container = self.pop();
+                    if verbose {
+                        println!("  SetIndex [{}] = {:?}", index, value);
+                    }
+                    if let (Value::Dictionary(_), Value::String(key)) = (&container, &index) {
+                        if let Err(e) = self.assign(&container, key, value) {
+                            self.handle_error(e, depth)?;
                         }
-                        _ => {
-                            stack.push(Value::Nil);
-                            if verbose {
-                                println!("  Cannot get member '{}' from {:?}", name, obj);
-                            }
+                    } else {
+                        match Self::set_index(&container, index, value) {
+                            Ok(true) => {}
+                            Ok(false) => self.index_error(&container, name, Vec::new(), depth)?,
+                            Err(e) => self.handle_error(e, depth)?,
                         }
                     }
-                    pc += 1;
                 }
-                Instruction::SetMember(name) => {
-                    let value = stack.pop().unwrap_or(Value::Nil);
-                    let obj = stack.pop().unwrap_or(Value::Nil);
-                    // Note: Would need variable reference to modify in place
+                Instruction::GetMember(name, object) => {
+                    let obj = self.pop();
+                    if !matches!(obj, Value::Dictionary(_) | Value::Module(_) | Value::Table(_) | Value::String(_)) {
+                        let message = match object {
+                            Some(object) => format!("attempt to get member '{}' of {} value '{}'", name, obj.type_name(), object),
+                            None => format!("attempt to get member '{}' of {} value", name, obj.type_name()),
+                        };
+                        self.lenient_error(RuntimeError::new(ErrorKind::TypeError, message), vec![Value::Nil], depth)?;
+                        continue;
+                    }
+                    let result = self.lookup(&obj, name);
+                    if verbose {
+                        println!("  Got member '{}' = {:?}", name, result);
+                    }
+                    self.push_result(result, depth)?;
+                }
+                Instruction::SetMember(name, object) => {
+                    let value = self.pop();
+                    let obj = self.pop();
                     if verbose {
                         println!("  Set member '{}' = {:?}", name, value);
                     }
-                    pc += 1;
+                    if !matches!(obj, Value::Dictionary(_)) {
+                        let message = match object {
+                            Some(object) => format!("attempt to set member '{}' of {} value '{}'", name, obj.type_name(), object),
+                            None => format!("attempt to set member '{}' of {} value", name, obj.type_name()),
+                        };
+                        self.lenient_error(RuntimeError::new(ErrorKind::TypeError, message), Vec::new(), depth)?;
+                        continue;
+                    }
+                    if let Err(e) = self.assign(&obj, name, value) {
+                        self.handle_error(e, depth)?;
+                    }
                 }
                 Instruction::LoadModule(name) => {
-                    if let Some(module) = stdlib.get(name) {
-                        vars.insert(name.clone(), module.clone());
+                    if let Some(module) = self.modules.get(name) {
+                        self.globals.insert(name.clone(), module.clone());
                         if verbose {
                             println!("  Loaded module {}", name);
                         }
                     } else {
-                        eprintln!("Module '{}' not found", name);
+                        let error = RuntimeError::new(ErrorKind::NameError, format!("Module '{}' not found", name));
+                        self.handle_error(error, depth)?;
                     }
-                    pc += 1;
                 }
-                Instruction::Print => {
-                    if let Some(val) = stack.pop() {
-                        if verbose {
-                            println!("  Print: {:?}", val);
-                            println!(">> {}", val);
-                        } else {
-                            println!("{}", val);
+                Instruction::Print(arity) => {
+                    let count = self.take_count(*arity);
+                    let values = Self::pop_args(&mut self.stack, count);
+                    let text = match values.iter().map(|val| self.tostring(val)).collect::<Result<Vec<_>, _>>() {
+                        Ok(texts) => texts.join(" "),
+                        Err(e) => {
+                            self.handle_error(e, depth)?;
+                            continue;
                         }
+                    };
+                    if verbose {
+                        println!("  Print: {:?}", values);
+                        println!(">> {}", text);
+                    } else {
+                        println!("{}", text);
                     }
-                    pc += 1;
                 }
-                Instruction::DefineFunction(name, params, body_len) => {
-                    // Extract function body from the code
-                    let body_start = pc + 1;
-                    let body_end = body_start + body_len;
-                    let body = code[body_start..body_end].to_vec();
-
-                    // Store function with parameters
-                    let func = Value::Function(params.clone(), body);
-                    vars.insert(name.clone(), func);
+                Instruction::DefineFunction(name, params, captures, body_len) => {
+                    let func = self.make_closure(Some(name), params, captures, &chunk, at + 1, *body_len);
+                    self.stack.push(func);
 
                     if verbose {
-                        println!("  Defined function {} with {} params: {:?}", name, params.len(), params);
+                        println!("  Defined function {} with {} params: {:?}", name, params.names.len(), params);
                     }
 
                     // Skip over the function body
-                    pc = body_end;
+                    self.frame_mut().pc += body_len;
                 }
-                Instruction::DefineLambda(params, body_len) => {
-                    // Extract lambda body from the code
-                    let body_start = pc + 1;
-                    let body_end = body_start + body_len;
-                    let body = code[body_start..body_end].to_vec();
-
+                Instruction::DefineLambda(params, captures, body_len) => {
                     // Push lambda as a value on the stack
-                    let func = Value::Function(params.clone(), body);
-                    stack.push(func);
+                    let func = self.make_closure(None, params, captures, &chunk, at + 1, *body_len);
+                    self.stack.push(func);
 
                     if verbose {
-                        println!("  Created lambda with {} params", params.len());
+                        println!("  Created lambda with {} params", params.names.len());
                     }
 
                     // Skip over the lambda body
-                    pc = body_end;
-                }
-                Instruction::Call(func_name, arg_count) => {
-                    // Get the function from variables
-                    if let Some(func_val) = vars.get(func_name).cloned() {
-                        match func_val {
-                            Value::Function(params, body) => {
-                                if params.len() != *arg_count {
-                                    eprintln!("Error: Function {} expects {} arguments, got {}", func_name, params.len(), arg_count);
-                                    pc += 1;
+                    self.frame_mut().pc += body_len;
+                }
+                Instruction::Call(func_name, arity, results) => {
+                    let (args, named) = self.take_args(*arity);
+                    let arg_count = args.len();
+                    // The global function was loaded before the arguments
+                    match Some(self.pop()).filter(|func_val| *func_val != Value::Nil) {
+                        Some(func_val) => {
+                            let args = match Self::bind_named(&func_val, args, named) {
+                                Ok(args) => args,
+                                Err(e) => {
+                                    self.handle_error(e, depth)?;
                                     continue;
                                 }
-
-                                // Pop arguments from stack in reverse order
-                                let mut args = Vec::new();
-                                for _ in 0..*arg_count {
-                                    if let Some(arg) = stack.pop() {
-                                        args.push(arg);
-                                    }
-                                }
-                                args.reverse();
-
-                                // Create new call frame
-                                let mut local_vars = HashMap::new();
-
-                                // Bind parameters to arguments
-                                for (i, param_name) in params.iter().enumerate() {
-                                    if i < args.len() {
-                                        local_vars.insert(param_name.clone(), args[i].clone());
-                                    }
-                                }
-
-                                // Save current state
-                                call_stack.push(CallFrame {
-                                    return_pc: pc + 1,
-                                    local_vars: vars.clone(),
-                                });
-
-                                // Set up function scope - merge local vars with parameters
-                                for (k, v) in local_vars.iter() {
-                                    vars.insert(k.clone(), v.clone());
-                                }
-
-                                if verbose {
-                                    println!("  Calling function {} with {} args", func_name, arg_count);
-                                    println!("  Parameters bound: {:?}", local_vars);
-                                }
-
-                                // Execute function body - create new code context
-                                Self::execute_function(&body, &mut stack, &mut vars, verbose);
-
-                                // Restore state
-                                if let Some(frame) = call_stack.pop() {
-                                    pc = frame.return_pc;
-                                    vars = frame.local_vars;
-                                } else {
-                                    pc += 1;
-                                }
-                            }
-                            _ => {
-                                eprintln!("Error: {} is not a function", func_name);
-                                pc += 1;
+                            };
+                            if verbose {
+                                println!("  Calling function {} with {} args", func_name, arg_count);
                             }
+                            self.call_value(func_val, args, *results).or_else(|e| self.handle_error(e, depth))?;
+                        }
+                        None => {
+                            let error = RuntimeError::new(ErrorKind::NameError, format!("Function {} not found", func_name));
+                            self.handle_error(error, depth)?;
                         }
-                    } else {
-                        eprintln!("Error: Function {} not found", func_name);
-                        pc += 1;
                     }
                 }
-                Instruction::CallMethod(members, arg_count) => {
-                    // Pop base object
-                    let base = stack.pop().unwrap_or(Value::Nil);
-
-                    // Pop arguments
-                    let mut args = Vec::new();
-                    for _ in 0..*arg_count {
-                        if let Some(arg) = stack.pop() {
-                            args.push(arg);
-                        }
+                Instruction::CallValue(arity, results) => {
+                    let (args, named) = self.take_args(*arity);
+                    let func_val = self.pop();
+                    if verbose {
+                        println!("  Calling {} with {} args", func_val, args.len() + named.len());
                     }
-                    args.reverse();
-
-                    // Navigate to method
-                    let mut current = base.clone();
-                    let method_name = members.last().cloned().unwrap_or_default();
-
-                    // Navigate through intermediate members
-                    for i in 0..members.len() - 1 {
-                        current = match current {
-                            Value::Module(ref map) => map.get(&members[i]).cloned().unwrap_or(Value::Nil),
-                            Value::Dictionary(ref map) => map.get(&members[i]).cloned().unwrap_or(Value::Nil),
-                            _ => Value::Nil,
-                        };
+                    Self::bind_named(&func_val, args, named)
+                        .and_then(|args| self.call_value(func_val, args, *results))
+                        .or_else(|e| self.handle_error(e, depth))?;
+                }
+                Instruction::FindMethod(members) => {
+                    let base = self.peek();
+                    match self.find_method(&base, members) {
+                        Ok(method) => self.stack.push(method.unwrap_or(Value::Nil)),
+                        Err(e) => self.handle_error(e, depth)?,
                     }
+                }
+                Instruction::CallMethod(name, members, arity, results) => {
+                    // Pop the arguments, then the method and the base object beneath them
+                    let (args, named) = self.take_args(*arity);
+                    let method = Some(self.pop()).filter(|method| *method != Value::Nil);
+                    let base = self.pop();
 
-                    // Get the method/function
-                    let method = match &current {
-                        Value::Module(map) => map.get(&method_name).cloned(),
-                        Value::Dictionary(map) => map.get(&method_name).cloned(),
-                        _ => None,
-                    };
-
-                    // Execute method
+                    let method_name = members.last().cloned().unwrap_or_default();
                     if let Some(method_val) = method {
-                        match method_val {
-                            Value::NativeFunction(module, func) => {
-                                let result = crate::stdlib::call_native(&module, &func, args, verbose);
-                                stack.push(result);
-                            }
-                            Value::Function(params, body) => {
-                                // Bind parameters
-                                let mut func_vars = vars.clone();
-                                for (i, param) in params.iter().enumerate() {
-                                    if i < args.len() {
-                                        func_vars.insert(param.clone(), args[i].clone());
-                                    }
-                                }
-                                // Execute function
-                                Self::execute_function(&body, &mut stack, &mut func_vars, verbose);
-                            }
-                            _ => {
-                                stack.push(Value::Nil);
-                            }
+                        Self::bind_named(&method_val, args, named)
+                            .and_then(|args| self.call_value(method_val, args, *results))
+                            .or_else(|e| self.handle_error(e, depth))?;
+                    } else if !named.is_empty() {
+                        let error = RuntimeError::new(ErrorKind::TypeError, format!("{} does not take named arguments", method_name));
+                        self.handle_error(error, depth)?;
+                    } else if let Some(module_name) = Self::get_module_name(&base, &self.globals) {
+                        // Try calling as native based on module structure
+                        match crate::stdlib::call_native(&module_name, &method_name, args, verbose) {
+                            Ok(result) => self.push_results(vec![result], *results),
+                            Err(e) => self.lenient_error(e, Self::adjust(vec![Value::Nil], *results), depth)?,
                         }
                     } else {
-                        // Try calling as native based on module structure
-                        if let Value::Module(_) = &base {
-                            if let Some(module_name) = Self::get_module_name(&base, &vars) {
-                                let result = crate::stdlib::call_native(&module_name, &method_name, args, verbose);
-                                stack.push(result);
-                            } else {
-                                stack.push(Value::Nil);
+                        // Name the object the chain starts from, as written in the source
+                        let chain = match name {
+                            Some(name) => format!("{}.{}", name, members.join(".")),
+                            None => members.join("."),
+                        };
+                        let first = members.first().cloned().unwrap_or_default();
+                        let message = match (&base, name) {
+                            (Value::Dictionary(_) | Value::Module(_) | Value::Table(_) | Value::String(_), _) => {
+                                format!("attempt to call nil value '{}'", chain)
                             }
-                        } else {
-                            stack.push(Value::Nil);
-                        }
+                            (base, Some(name)) => format!("attempt to get member '{}' of {} value '{}'", first, base.type_name(), name),
+                            (base, None) => format!("attempt to get member '{}' of {} value", first, base.type_name()),
+                        };
+                        let fallback = Self::adjust(vec![Value::Nil], *results);
+                        self.lenient_error(RuntimeError::new(ErrorKind::TypeError, message), fallback, depth)?;
+                    }
+                }
+                Instruction::LookupMethod(method_name) => {
+                    let receiver = self.peek();
+                    match self.lookup(&receiver, method_name) {
+                        Ok(method) => self.stack.push(method),
+                        Err(e) => self.handle_error(e, depth)?,
+                    }
+                }
+                Instruction::Invoke(method_name, arity, results) => {
+                    let (mut args, named) = self.take_args(*arity);
+                    let arg_count = args.len() + named.len();
+                    let method = self.pop();
+                    let receiver = self.pop();
+                    if verbose {
+                        println!("  Invoking method {} on {} with {} args", method_name, receiver, arg_count);
                     }
 
-                    pc += 1;
+                    if let Value::Nil = method {
+                        let message = match receiver {
+                            Value::Dictionary(_) | Value::Module(_) => format!("attempt to call nil value (method '{}')", method_name),
+                            _ => format!("attempt to call method '{}' on {} value", method_name, receiver.type_name()),
+                        };
+                        let fallback = Self::adjust(vec![Value::Nil], *results);
+                        self.lenient_error(RuntimeError::new(ErrorKind::TypeError, message), fallback, depth)?;
+                    } else {
+                        args.insert(0, receiver);
+                        Self::bind_named(&method, args, named)
+                            .and_then(|args| self.call_value(method, args, *results))
+                            .or_else(|e| self.handle_error(e, depth))?;
+                    }
                 }
                 Instruction::CallNative(module, func, arg_count) => {
-                    let mut args = Vec::new();
-                    for _ in 0..*arg_count {
-                        if let Some(arg) = stack.pop() {
-                            args.push(arg);
-                        }
+                    let args = Self::pop_args(&mut self.stack, *arg_count);
+                    match crate::stdlib::call_native(module, func, args, verbose) {
+                        Ok(result) => self.stack.push(result),
+                        Err(e) => self.lenient_error(e, vec![Value::Nil], depth)?,
                     }
-                    args.reverse();
-
-                    let result = crate::stdlib::call_native(module, func, args, verbose);
-                    stack.push(result);
-                    pc += 1;
                 }
-                Instruction::Return => {
+                Instruction::Return(arity) => {
                     if verbose {
                         println!("  Return from function");
                     }
-                    // For function return, the calling context will handle it
-                    // This ends the function execution
-                    break;
+                    let count = self.take_count(*arity);
+                    let values = Self::pop_args(&mut self.stack, count);
+                    self.return_from_call(values);
                 }
                 Instruction::SetupTry(catch_pc) => {
-                    try_handlers.push(TryHandler {
+                    self.try_handlers.push(TryHandler {
                         catch_pc: *catch_pc,
-                        stack_depth: stack.len(),
+                        stack_depth: self.stack.len(),
+                        frame_depth: self.frames.len() - 1,
                     });
                     if verbose {
                         println!("  Setup try handler, jump to {} on error", catch_pc);
                     }
-                    pc += 1;
                 }
                 Instruction::PopTry => {
-                    try_handlers.pop();
+                    self.try_handlers.pop();
                     if verbose {
                         println!("  Popped try handler");
                     }
-                    pc += 1;
                 }
                 Instruction::Throw => {
-                    let error = stack.pop().unwrap_or(Value::String("Unknown error".to_string()));
-                    let error_msg = error.to_string();
-
-                    if let Some(handler) = try_handlers.pop() {
-                        // Restore stack to handler depth
-                        while stack.len() > handler.stack_depth {
-                            stack.pop();
+                    let error = self.stack.pop().unwrap_or(Value::String("Unknown error".to_string()));
+                    self.handle_error(RuntimeError::thrown(error), depth)?;
+                }
+                Instruction::SaveError(slot) => {
+                    let value = self.pop();
+                    let error = self.caught.take().unwrap_or_else(|| RuntimeError::thrown(value.clone()));
+                    // Entries at or above the slot belong to finally blocks that were left
+                    let errors = &mut self.frame_mut().errors;
+                    errors.retain(|(saved, _)| saved < slot);
+                    errors.push((*slot, error));
+                    self.define_local(*slot, value);
+                }
+                Instruction::Rethrow(slot) => {
+                    let errors = &mut self.frame_mut().errors;
+                    let error = match errors.iter().rposition(|(saved, _)| saved == slot) {
+                        Some(index) => errors.remove(index).1,
+                        None => RuntimeError::thrown(self.local_cell(*slot).borrow().clone()),
+                    };
+                    self.handle_error(error, depth)?;
+                }
+                Instruction::ForPrep(slot, exit) => {
+                    let step = self.pop();
+                    let limit = self.pop();
+                    let start = self.pop();
+                    let (start, limit, step) = match (&start, &limit, &step) {
+                        (&Value::Number(start), &Value::Number(limit), &Value::Number(step)) => (start, limit, step),
+                        _ => {
+                            let (what, value) = match (&start, &limit) {
+                                (Value::Number(_), Value::Number(_)) => ("step", step),
+                                (Value::Number(_), _) => ("limit", limit),
+                                _ => ("initial value", start),
+                            };
+                            let message = format!("'for' {} must be a number, got {}", what, value.type_name());
+                            self.handle_error(RuntimeError::new(ErrorKind::TypeError, message), depth)?;
+                            continue;
                         }
-                        // Push error value
-                        stack.push(Value::String(error_msg));
-                        pc = handler.catch_pc;
-                        if verbose {
-                            println!("  Caught error, jumping to {}", handler.catch_pc);
+                    };
+                    if step == 0.0 {
+                        self.handle_error(RuntimeError::new(ErrorKind::ValueError, "'for' step is zero"), depth)?;
+                        continue;
+                    }
+                    self.define_local(*slot, Value::Number(start));
+                    self.define_local(slot + 1, Value::Number(limit));
+                    self.define_local(slot + 2, Value::Number(step));
+                    let runs = if step > 0.0 { start <= limit } else { start >= limit };
+                    if verbose {
+                        println!("  ForPrep {} to {} step {}", start, limit, step);
+                    }
+                    if !runs {
+                        self.frame_mut().pc = *exit;
+                    }
+                }
+                Instruction::ForLoop(slot, body_start) => {
+                    let number = |cell: &Upvalue| match *cell.borrow() {
+                        Value::Number(n) => n,
+                        _ => 0.0,
+                    };
+                    let index = number(self.local_cell(*slot));
+                    let limit = number(self.local_cell(slot + 1));
+                    let step = number(self.local_cell(slot + 2));
+                    let next = index + step;
+                    let runs = if step > 0.0 { next <= limit } else { next >= limit };
+                    if runs {
+                        self.local_cell(*slot).replace(Value::Number(next));
+                        self.frame_mut().pc = *body_start;
+                    }
+                }
+                Instruction::IterPrep => {
+                    let iterable = match self.pop() {
+                        value @ (Value::Iterator(_) | Value::Function(_) | Value::NativeFunction(..) | Value::Wrapped(_) | Value::Coroutine(_)) => value,
+                        // Looping over a dictionary with one variable visits its keys
+                        value => match Cursor::new(value.clone(), !matches!(value, Value::Dictionary(_))) {
+                            Some(cursor) => Value::Iterator(Rc::new(RefCell::new(cursor))),
+                            None => {
+                                let message = format!("attempt to iterate over {} value", value.type_name());
+                                self.handle_error(RuntimeError::new(ErrorKind::TypeError, message), depth)?;
+                                continue;
+                            }
+                        },
+                    };
+                    self.stack.push(iterable);
+                }
+                Instruction::IterCall(slot, count) => {
+                    // Iterator functions are called with no arguments; their
+                    // result is picked up by the IterNext that follows
+                    let iterator = self.local_cell(*slot).borrow().clone();
+                    match iterator {
+                        Value::Iterator(_) => {}
+                        Value::Coroutine(co) => {
+                            // A generator's loop ends when it finishes, whatever it returns
+                            let status = co.borrow().status;
+                            let values = match status {
+                                CoroutineStatus::Dead => Ok(Vec::new()),
+                                _ => self.resume(&co, Vec::new()),
+                            };
+                            match values {
+                                Ok(_) if co.borrow().status == CoroutineStatus::Dead => self.push_results(Vec::new(), Results::Exactly(*count)),
+                                Ok(values) => self.push_results(values, Results::Exactly(*count)),
+                                Err(e) => self.handle_error(e, depth)?,
+                            }
                         }
-                    } else {
-                        eprintln!("Uncaught error: {}", error_msg);
-                        break;
+                        iterator => {
+                            self.call_value(iterator, Vec::new(), Results::Exactly(*count)).or_else(|e| self.handle_error(e, depth))?;
+                        }
+                    }
+                }
+                Instruction::IterNext(slot, count, exit) => {
+                    let iterator = self.local_cell(*slot).borrow().clone();
+                    let values = match iterator {
+                        Value::Iterator(cursor) => {
+                            let mut cursor = cursor.borrow_mut();
+                            cursor.step().map(|(key, value)| {
+                                if *count == 1 && cursor.values_first { vec![value] } else { vec![key, value] }
+                            })
+                        }
+                        _ => {
+                            let values = Self::pop_args(&mut self.stack, *count);
+                            values.first().is_some_and(|first| *first != Value::Nil).then_some(values)
+                        }
+                    };
+                    match values {
+                        Some(mut values) => {
+                            values.resize(*count, Value::Nil);
+                            self.stack.extend(values);
+                        }
+                        None => self.frame_mut().pc = *exit,
                     }
                 }
                 Instruction::Dup => {
-                    if let Some(val) = stack.last().cloned() {
-                        stack.push(val);
+                    if let Some(val) = self.stack.last().cloned() {
+                        self.stack.push(val);
                     }
-                    pc += 1;
                 }
                 Instruction::Pop => {
-                    stack.pop();
+                    self.stack.pop();
                     if verbose {
                         println!("  Pop");
                     }
-                    pc += 1;
                 }
                 Instruction::Break => {
                     // Break is compiled as a Jump, so this shouldn't be reached
                     if verbose {
                         println!("  Break (unexpected)");
                     }
-                    pc += 1;
                 }
                 Instruction::Continue => {
                     // Continue is compiled as a Jump, so this shouldn't be reached
                     if verbose {
                         println!("  Continue (unexpected)");
                     }
-                    pc += 1;
                 }
             }
 
             if verbose {
-                println!("  Stack: {:?}", stack);
-                println!("  Vars: {:?}", vars);
-                println!("");
+                println!("  Stack: {:?}", self.stack);
+                println!("  Globals: {:?}", self.globals);
+                println!();
+            }
+        }
+        Ok(())
+    }
+
+    /// The frame currently executing
+    fn frame(&self) -> &CallFrame {
+        self.frames.last().expect("no active call frame")
+    }
+
+    fn frame_mut(&mut self) -> &mut CallFrame {
+        self.frames.last_mut().expect("no active call frame")
+    }
+
+    /// Pop the top of the value stack, treating an empty stack as nil
+    fn pop(&mut self) -> Value {
+        self.stack.pop().unwrap_or(Value::Nil)
+    }
+
+    /// A copy of the top of the value stack, nil if it is empty
+    fn peek(&self) -> Value {
+        self.stack.last().cloned().unwrap_or(Value::Nil)
+    }
+
+    /// Captured variable `index` of the running closure
+    fn upvalue(&self, index: usize) -> Option<Upvalue> {
+        self.frame().closure.as_ref().and_then(|closure| closure.upvalues.get(index).cloned())
+    }
+
+    /// The cell behind a local slot of the current frame, growing it if needed
+    fn local_cell(&mut self, slot: usize) -> &Upvalue {
+        let index = self.frame().base + slot;
+        if index >= self.slots.len() {
+            self.slots.resize_with(index + 1, || Rc::new(RefCell::new(Value::Nil)));
+        }
+        &self.slots[index]
+    }
+
+    /// Give a local slot a fresh cell holding `val`, leaving closures that
+    /// captured an earlier variable in the same slot untouched
+    fn define_local(&mut self, slot: usize, val: Value) {
+        self.local_cell(slot);
+        let index = self.frame().base + slot;
+        self.slots[index] = Rc::new(RefCell::new(val));
+    }
+
+    /// Call a function value. Pickup functions get a new frame that the
+    /// dispatch loop continues in; native functions run to completion and
+    /// leave their result on the stack.
+    fn call_value(&mut self, func: Value, args: Vec<Value>, results: Results) -> Result<(), RuntimeError> {
+        match func {
+            Value::Function(closure) => {
+                if !closure.params.accepts(args.len()) {
+                    let function = closure.name.as_deref().unwrap_or("<lambda>");
+                    let expects = closure.params.describe();
+                    let noun = if expects == "1" { "argument" } else { "arguments" };
+                    let message = format!("Function {} expects {} {}, got {}", function, expects, noun, args.len());
+                    return Err(RuntimeError::new(ErrorKind::ArityError, message));
+                }
+                if self.frames.len() >= MAX_CALL_DEPTH {
+                    return Err(RuntimeError::new(ErrorKind::StackOverflow, "stack overflow"));
+                }
+                // Parameters occupy the first local slots of the new call,
+                // followed by a table of the extra arguments if it takes them
+                let base = self.slots.len();
+                let mut args = args.into_iter();
+                for _ in &closure.params.names {
+                    self.slots.push(Rc::new(RefCell::new(args.next().unwrap_or(Value::Nil))));
+                }
+                if closure.params.rest.is_some() {
+                    let rest = Value::Table(Rc::new(RefCell::new(args.collect())));
+                    self.slots.push(Rc::new(RefCell::new(rest)));
+                }
+                self.frames.push(CallFrame {
+                    chunk: Rc::clone(&closure.chunk),
+                    start: closure.start,
+                    len: closure.len,
+                    pc: 0,
+                    base,
+                    stack_base: self.stack.len(),
+                    closure: Some(closure),
+                    results,
+                    errors: Vec::new(),
+                });
+            }
+            Value::NativeFunction(module, func) if module == "coroutine" => {
+                return self.coroutine_builtin(&func, args, results);
+            }
+            Value::NativeFunction(module, func) => {
+                let key = (module, func);
+                let result = match self.natives.get(&key).cloned() {
+                    Some(host) => {
+                        self.host_calls += 1;
+                        let result = host(self, args);
+                        self.host_calls -= 1;
+                        result.map_err(|e| match e {
+                            Error::Runtime(e) => e,
+                            Error::Parse(e) => RuntimeError::thrown(Value::String(e.to_string())),
+                        })?
+                    }
+                    None => match crate::stdlib::call_native(&key.0, &key.1, args, self.verbose) {
+                        Err(_) if self.lenient => Value::Nil,
+                        result => result?,
+                    },
+                };
+                self.push_results(vec![result], results);
+            }
+            Value::Wrapped(co) => {
+                // An error in the coroutine is raised in the caller
+                let values = self.resume(&co, args)?;
+                self.push_results(values, results);
+            }
+            object @ Value::Dictionary(_) if object.metamethod("__call").is_some() => {
+                // A callable object receives itself before the arguments
+                let handler = object.metamethod("__call").unwrap_or(Value::Nil);
+                let mut args = args;
+                args.insert(0, object);
+                return self.call_value(handler, args, results);
+            }
+            other => {
+                return Err(RuntimeError::new(ErrorKind::TypeError, format!("{} is not a function", other)));
+            }
+        }
+        Ok(())
+    }
+
+    /// `coroutine.create`, `resume`, `yield`, `status`, `wrap` and `running`,
+    /// which work on the VM's frames rather than just their arguments
+    fn coroutine_builtin(&mut self, name: &str, args: Vec<Value>, results: Results) -> Result<(), RuntimeError> {
+        let mut args = args.into_iter();
+        let values = match name {
+            "create" | "wrap" => match args.next() {
+                Some(body @ (Value::Function(_) | Value::NativeFunction(..) | Value::Wrapped(_))) => {
+                    let co = Rc::new(RefCell::new(Coroutine::new(body)));
+                    vec![if name == "wrap" { Value::Wrapped(co) } else { Value::Coroutine(co) }]
+                }
+                other => {
+                    let got = other.map_or("no value", |v| v.type_name());
+                    let message = format!("bad argument #1 to {} (function expected, got {})", name, got);
+                    return Err(RuntimeError::new(ErrorKind::TypeError, message));
+                }
+            },
+            "resume" => {
+                let co = Self::coroutine_arg(name, args.next())?;
+                match self.resume(&co, args.collect()) {
+                    Ok(mut values) => {
+                        values.insert(0, Value::Boolean(true));
+                        values
+                    }
+                    Err(e) => vec![Value::Boolean(false), e.value()],
+                }
+            }
+            "yield" => {
+                let Some(current) = self.coroutines.last() else {
+                    return Err(RuntimeError::new(ErrorKind::TypeError, "attempt to yield from outside a coroutine"));
+                };
+                if current.borrow().host_calls != self.host_calls {
+                    let message = "attempt to yield across a metamethod or host function call";
+                    return Err(RuntimeError::new(ErrorKind::TypeError, message));
+                }
+                // The dispatch loop stops at the next instruction; `resume`
+                // hands the yielded values back and later answers this call
+                current.borrow_mut().yield_results = results;
+                self.yielded = Some(args.collect());
+                return Ok(());
+            }
+            "status" => {
+                let co = Self::coroutine_arg(name, args.next())?;
+                let status = co.borrow().status;
+                vec![Value::String(status.to_string())]
+            }
+            "running" => vec![self.coroutines.last().map_or(Value::Nil, |co| Value::Coroutine(Rc::clone(co)))],
+            _ => {
+                return Err(RuntimeError::new(ErrorKind::NameError, format!("coroutine.{} not found", name)));
+            }
+        };
+        self.push_results(values, results);
+        Ok(())
+    }
+
+    fn coroutine_arg(name: &str, arg: Option<Value>) -> Result<Rc<RefCell<Coroutine>>, RuntimeError> {
+        match arg {
+            Some(Value::Coroutine(co)) => Ok(co),
+            other => {
+                let got = other.map_or("no value", |v| v.type_name());
+                let message = format!("bad argument #1 to {} (coroutine expected, got {})", name, got);
+                Err(RuntimeError::new(ErrorKind::TypeError, message))
+            }
+        }
+    }
+
+    /// Run `co` until it yields or returns, on its own frames and stacks.
+    /// The values it yielded or returned come back; an error it does not
+    /// catch kills it and is returned instead.
+    fn resume(&mut self, co: &Rc<RefCell<Coroutine>>, args: Vec<Value>) -> Result<Vec<Value>, RuntimeError> {
+        // Each nested resume runs the dispatch loop again on the Rust stack
+        if self.coroutines.len() >= MAX_HOST_CALLS {
+            return Err(RuntimeError::new(ErrorKind::StackOverflow, "stack overflow"));
+        }
+        let (body, yield_results) = {
+            let mut state = co.borrow_mut();
+            if state.status != CoroutineStatus::Suspended {
+                let message = format!("cannot resume {} coroutine", state.status);
+                return Err(RuntimeError::new(ErrorKind::ValueError, message));
+            }
+            state.status = CoroutineStatus::Running;
+            state.host_calls = self.host_calls;
+            self.swap_state(&mut state);
+            (state.body.take(), state.yield_results)
+        };
+        if let Some(resumer) = self.coroutines.last() {
+            resumer.borrow_mut().status = CoroutineStatus::Normal;
+        }
+        self.coroutines.push(Rc::clone(co));
+
+        // The first resume calls the body; later ones answer the pending `yield`
+        let outcome = stacker::maybe_grow(STACK_RED_ZONE, STACK_GROWTH, || match body {
+            Some(body) => self.call_value(body, args, Results::All).and_then(|()| self.run(0)),
+            None => {
+                self.push_results(args, yield_results);
+                self.run(0)
+            }
+        });
+        let yielded = self.yielded.take();
+        let finished = yielded.is_none();
+        let values = match (&outcome, yielded) {
+            (Ok(()), Some(values)) => values,
+            (Ok(()), None) => {
+                // The body's results, followed by their count
+                let count = self.take_count(Arity { fixed: 0, open: true, named: 0 });
+                Self::pop_args(&mut self.stack, count)
             }
+            (Err(_), _) => Vec::new(),
+        };
+
+        self.coroutines.pop();
+        if let Some(resumer) = self.coroutines.last() {
+            resumer.borrow_mut().status = CoroutineStatus::Running;
+        }
+        let mut state = co.borrow_mut();
+        self.swap_state(&mut state);
+        if outcome.is_ok() && !finished {
+            state.status = CoroutineStatus::Suspended;
+        } else {
+            state.status = CoroutineStatus::Dead;
+            state.stack.clear();
+            state.slots.clear();
+            state.frames.clear();
+            state.try_handlers.clear();
         }
+        outcome.map(|()| values)
+    }
 
-        if verbose {
-            println!("--- VM Execution Completed ---\n");
+    /// Exchange the running frames and stacks with a coroutine's
+    fn swap_state(&mut self, co: &mut Coroutine) {
+        std::mem::swap(&mut self.stack, &mut co.stack);
+        std::mem::swap(&mut self.slots, &mut co.slots);
+        std::mem::swap(&mut self.frames, &mut co.frames);
+        std::mem::swap(&mut self.try_handlers, &mut co.try_handlers);
+    }
+
+    /// Raise `error` as `handle_error` does. In lenient mode the error is
+    /// ignored and `fallback` stands in for the failed operation's results.
+    fn lenient_error(&mut self, error: RuntimeError, fallback: Vec<Value>, depth: usize) -> Result<(), RuntimeError> {
+        if self.lenient {
+            self.stack.extend(fallback);
+            return Ok(());
         }
+        self.handle_error(error, depth)
     }
 
-    /// Try to get the module name from a value
-    fn get_module_name(val: &Value, vars: &HashMap<String, Value>) -> Option<String> {
-        for (name, v) in vars {
-            if std::ptr::eq(val, v) {
-                return Some(name.clone());
+    /// Push the result of an operation, or raise its error as `handle_error` does
+    fn push_result(&mut self, result: Result<Value, RuntimeError>, depth: usize) -> Result<(), RuntimeError> {
+        match result {
+            Ok(value) => {
+                self.stack.push(value);
+                Ok(())
             }
+            Err(e) => self.handle_error(e, depth),
         }
-        // Check against known module names
-        match val {
-            Value::Module(_) => {
-                for (name, v) in vars {
-                    if let Value::Module(_) = v {
-                        if format!("{:?}", val) == format!("{:?}", v) {
-                            return Some(name.clone());
-                        }
-                    }
+    }
+
+    /// Call the metamethod `event` of the first operand that has one, with
+    /// both operands; None if neither does
+    fn binary_metamethod(&mut self, event: &str, a: &Value, b: &Value) -> Option<Result<Value, RuntimeError>> {
+        let handler = a.metamethod(event).or_else(|| b.metamethod(event))?;
+        Some(self.call(handler, vec![a.clone(), b.clone()]))
+    }
+
+    /// Arithmetic on something other than two numbers: the operands'
+    /// metamethod for `event` if one has it, otherwise a TypeError
+    fn arithmetic_fallback(&mut self, event: &str, verb: &str, a: &Value, b: &Value, depth: usize) -> Result<(), RuntimeError> {
+        match self.binary_metamethod(event, a, b) {
+            Some(result) => self.push_result(result, depth),
+            None => self.operand_error(verb, a, b, depth),
+        }
+    }
+
+    /// Ordering comparison between values that are not both numbers or both
+    /// strings, through `__lt` or `__le`. `a > b` is answered as `b < a`,
+    /// so `reversed` swaps the operands passed to the metamethod.
+    fn compare_fallback(&mut self, event: &str, a: &Value, b: &Value, reversed: bool, depth: usize) -> Result<(), RuntimeError> {
+        let (x, y) = if reversed { (b, a) } else { (a, b) };
+        match self.binary_metamethod(event, x, y) {
+            Some(result) => self.push_result(result.map(|v| Value::Boolean(v.is_truthy())), depth),
+            None => self.compare_error(a, b, depth),
+        }
+    }
+
+    /// `a == b`: two different dictionaries are compared by their `__eq`
+    /// metamethod when one has it
+    fn equals(&mut self, a: &Value, b: &Value) -> Result<bool, RuntimeError> {
+        if let (Value::Dictionary(x), Value::Dictionary(y)) = (a, b) {
+            if !Rc::ptr_eq(x, y) {
+                if let Some(result) = self.binary_metamethod("__eq", a, b) {
+                    return Ok(result?.is_truthy());
                 }
             }
-            _ => {}
         }
-        None
+        Ok(a == b)
+    }
+
+    /// The text `print` and `..` show for a value: the result of its
+    /// `__tostring` metamethod when it has one, which also applies to the
+    /// values inside tables and dictionaries. `Value`'s `Display` runs no
+    /// metamethods, so hosts should show script values through this.
+    pub fn tostring(&mut self, value: &Value) -> Result<String, RuntimeError> {
+        self.render(value, &mut Vec::new())
     }
 
-    /// Handle runtime error
-    fn handle_error(stack: &mut Vec<Value>, try_handlers: &mut Vec<TryHandler>, pc: &mut usize, message: &str) {
-        if let Some(handler) = try_handlers.pop() {
-            while stack.len() > handler.stack_depth {
-                stack.pop();
+    /// `tostring` of a value inside the containers in `outer`, which are
+    /// shown as `[...]` or `{...}` if the value leads back to them
+    fn render(&mut self, value: &Value, outer: &mut Vec<usize>) -> Result<String, RuntimeError> {
+        if let Some(handler) = value.metamethod("__tostring") {
+            return Ok(self.call(handler, vec![value.clone()])?.to_string());
+        }
+        match value {
+            Value::Table(elems) => {
+                let ptr = Rc::as_ptr(elems) as usize;
+                if outer.contains(&ptr) {
+                    return Ok("[...]".to_string());
+                }
+                outer.push(ptr);
+                let elems = elems.borrow().clone();
+                let items = elems.iter().map(|v| self.render(v, outer)).collect::<Result<Vec<_>, _>>()?;
+                outer.pop();
+                Ok(format!("[{}]", items.join(", ")))
             }
-            stack.push(Value::String(message.to_string()));
-            *pc = handler.catch_pc;
-        } else {
-            eprintln!("Runtime error: {}", message);
-            *pc = usize::MAX; // Stop execution
+            Value::Dictionary(map) => {
+                let ptr = Rc::as_ptr(map) as usize;
+                if outer.contains(&ptr) {
+                    return Ok("{...}".to_string());
+                }
+                outer.push(ptr);
+                let entries: Vec<(String, Value)> = map.borrow().iter().map(|(k, v)| (k.clone(), v.clone())).collect();
+                let items = entries
+                    .iter()
+                    .map(|(k, v)| Ok(format!("{} = {}", k, self.render(v, outer)?)))
+                    .collect::<Result<Vec<_>, RuntimeError>>()?;
+                outer.pop();
+                Ok(format!("{{{}}}", items.join(", ")))
+            }
+            other => Ok(other.to_string()),
         }
     }
 
-    /// Execute a function body (used for function calls)
-    fn execute_function(code: &[Instruction], stack: &mut Vec<Value>, vars: &mut HashMap<String, Value>, verbose: bool) {
-        let mut pc = 0;
-        let mut try_handlers: Vec<TryHandler> = Vec::new();
+    /// Read member `key`. A dictionary without the key defers to the
+    /// `__index` field of its metatable: a dictionary there is searched in
+    /// turn, a function is called with the dictionary and the key.
+    fn lookup(&mut self, obj: &Value, key: &str) -> Result<Value, RuntimeError> {
+        let found = self.lookup_chain(obj, key)?;
+        // `Point.new(x, y)` calls the class itself, like `Point(x, y)`,
+        // unless the class has a `new` of its own; instances don't see it
+        if key == "new" && matches!(found, Value::Nil) && Self::is_class(obj) {
+            return Ok(obj.clone());
+        }
+        Ok(found)
+    }
 
-        while pc < code.len() {
-            if verbose {
-                println!("  [Function] Instruction {}: {:?}", pc, code[pc]);
+    /// Whether `value` is a class built by `make_class`, i.e. a dictionary
+    /// that is its own `__index`
+    fn is_class(value: &Value) -> bool {
+        match value {
+            Value::Dictionary(dict) => matches!(
+                dict.borrow().get("__index"),
+                Some(Value::Dictionary(index)) if Rc::ptr_eq(index, dict)
+            ),
+            _ => false,
+        }
+    }
+
+    fn lookup_chain(&mut self, obj: &Value, key: &str) -> Result<Value, RuntimeError> {
+        let mut current = obj.clone();
+        for _ in 0..MAX_META_CHAIN {
+            let handler = match &current {
+                Value::Dictionary(dict) => {
+                    let dict = dict.borrow();
+                    if let Some(value) = dict.get(key) {
+                        return Ok(value.clone());
+                    }
+                    match dict.metamethod("__index") {
+                        Some(handler) => handler,
+                        None => return Ok(Value::Nil),
+                    }
+                }
+                other => return Ok(Self::get_member(other, key)),
+            };
+            match handler {
+                Value::Dictionary(_) | Value::Module(_) => current = handler,
+                handler => return self.call(handler, vec![current, Value::String(key.to_string())]),
             }
+        }
+        Err(RuntimeError::new(ErrorKind::ValueError, "'__index' chain too long; possible loop"))
+    }
 
-            match &code[pc] {
-                Instruction::PushNumber(n) => {
-                    stack.push(Value::Number(*n));
-                    pc += 1;
-                }
-                Instruction::PushString(s) => {
-                    stack.push(Value::String(s.clone()));
-                    pc += 1;
-                }
-                Instruction::PushBoolean(b) => {
-                    stack.push(Value::Boolean(*b));
-                    pc += 1;
-                }
-                Instruction::PushNil => {
-                    stack.push(Value::Nil);
-                    pc += 1;
-                }
-                Instruction::LoadVar(name) => {
-                    let val = vars.get(name).cloned().unwrap_or(Value::Nil);
-                    stack.push(val);
-                    pc += 1;
-                }
-                Instruction::StoreVar(name) => {
-                    if let Some(val) = stack.pop() {
-                        vars.insert(name.clone(), val);
-                    }
-                    pc += 1;
-                }
-                Instruction::StoreLocal(name) => {
-                    if let Some(val) = stack.pop() {
-                        vars.insert(name.clone(), val);
-                    }
-                    pc += 1;
-                }
-                Instruction::Add => {
-                    let b = stack.pop().unwrap_or(Value::Nil);
-                    let a = stack.pop().unwrap_or(Value::Nil);
-                    if let (Value::Number(x), Value::Number(y)) = (a, b) {
-                        stack.push(Value::Number(x + y));
-                    } else {
-                        stack.push(Value::Nil);
-                    }
-                    pc += 1;
-                }
-                Instruction::Sub => {
-                    let b = stack.pop().unwrap_or(Value::Nil);
-                    let a = stack.pop().unwrap_or(Value::Nil);
-                    if let (Value::Number(x), Value::Number(y)) = (a, b) {
-                        stack.push(Value::Number(x - y));
-                    } else {
-                        stack.push(Value::Nil);
-                    }
-                    pc += 1;
-                }
-                Instruction::Mul => {
-                    let b = stack.pop().unwrap_or(Value::Nil);
-                    let a = stack.pop().unwrap_or(Value::Nil);
-                    if let (Value::Number(x), Value::Number(y)) = (a, b) {
-                        stack.push(Value::Number(x * y));
-                    } else {
-                        stack.push(Value::Nil);
-                    }
-                    pc += 1;
-                }
-                Instruction::Div => {
-                    let b = stack.pop().unwrap_or(Value::Nil);
-                    let a = stack.pop().unwrap_or(Value::Nil);
-                    if let (Value::Number(x), Value::Number(y)) = (a, b) {
-                        if y == 0.0 {
-                            stack.push(Value::Nil);
-                        } else {
-                            stack.push(Value::Number(x / y));
-                        }
-                    } else {
-                        stack.push(Value::Nil);
-                    }
-                    pc += 1;
-                }
-                Instruction::Mod => {
-                    let b = stack.pop().unwrap_or(Value::Nil);
-                    let a = stack.pop().unwrap_or(Value::Nil);
-                    if let (Value::Number(x), Value::Number(y)) = (a, b) {
-                        if y == 0.0 {
-                            stack.push(Value::Nil);
-                        } else {
-                            stack.push(Value::Number(x % y));
-                        }
-                    } else {
-                        stack.push(Value::Nil);
-                    }
-                    pc += 1;
-                }
-                Instruction::Concat => {
-                    let b = stack.pop().unwrap_or(Value::Nil);
-                    let a = stack.pop().unwrap_or(Value::Nil);
-                    let result = format!("{}{}", a, b);
-                    stack.push(Value::String(result));
-                    pc += 1;
-                }
-                Instruction::Equal => {
-                    let b = stack.pop().unwrap_or(Value::Nil);
-                    let a = stack.pop().unwrap_or(Value::Nil);
-                    stack.push(Value::Boolean(a == b));
-                    pc += 1;
-                }
-                Instruction::NotEqual => {
-                    let b = stack.pop().unwrap_or(Value::Nil);
-                    let a = stack.pop().unwrap_or(Value::Nil);
-                    stack.push(Value::Boolean(a != b));
-                    pc += 1;
-                }
-                Instruction::LessThan => {
-                    let b = stack.pop().unwrap_or(Value::Nil);
-                    let a = stack.pop().unwrap_or(Value::Nil);
-                    let result = if let (Value::Number(x), Value::Number(y)) = (&a, &b) {
-                        x < y
-                    } else {
-                        false
-                    };
-                    stack.push(Value::Boolean(result));
-                    pc += 1;
-                }
-                Instruction::GreaterThan => {
-                    let b = stack.pop().unwrap_or(Value::Nil);
-                    let a = stack.pop().unwrap_or(Value::Nil);
-                    let result = if let (Value::Number(x), Value::Number(y)) = (&a, &b) {
-                        x > y
-                    } else {
-                        false
-                    };
-                    stack.push(Value::Boolean(result));
-                    pc += 1;
-                }
-                Instruction::LessEqual => {
-                    let b = stack.pop().unwrap_or(Value::Nil);
-                    let a = stack.pop().unwrap_or(Value::Nil);
-                    let result = if let (Value::Number(x), Value::Number(y)) = (&a, &b) {
-                        x <= y
-                    } else {
-                        false
+    /// Write member `key` of a dictionary. A key the dictionary lacks goes
+    /// through the `__newindex` field of its metatable: a dictionary there
+    /// receives the write instead, a function is called with the
+    /// dictionary, the key and the value.
+    fn assign(&mut self, obj: &Value, key: &str, value: Value) -> Result<(), RuntimeError> {
+        let mut current = obj.clone();
+        for _ in 0..MAX_META_CHAIN {
+            let handler = match &current {
+                Value::Dictionary(dict) => {
+                    let handler = {
+                        let dict = dict.borrow();
+                        if dict.contains_key(key) { None } else { dict.metamethod("__newindex") }
                     };
-                    stack.push(Value::Boolean(result));
-                    pc += 1;
-                }
-                Instruction::GreaterEqual => {
-                    let b = stack.pop().unwrap_or(Value::Nil);
-                    let a = stack.pop().unwrap_or(Value::Nil);
-                    let result = if let (Value::Number(x), Value::Number(y)) = (&a, &b) {
-                        x >= y
-                    } else {
-                        false
-                    };
-                    stack.push(Value::Boolean(result));
-                    pc += 1;
-                }
-                Instruction::And => {
-                    let b = stack.pop().unwrap_or(Value::Nil);
-                    let a = stack.pop().unwrap_or(Value::Nil);
-                    stack.push(Value::Boolean(a.is_truthy() && b.is_truthy()));
-                    pc += 1;
-                }
-                Instruction::Or => {
-                    let b = stack.pop().unwrap_or(Value::Nil);
-                    let a = stack.pop().unwrap_or(Value::Nil);
-                    stack.push(Value::Boolean(a.is_truthy() || b.is_truthy()));
-                    pc += 1;
-                }
-                Instruction::Not => {
-                    let a = stack.pop().unwrap_or(Value::Nil);
-                    stack.push(Value::Boolean(!a.is_truthy()));
-                    pc += 1;
-                }
-                Instruction::Jump(target) => {
-                    pc = *target;
-                }
-                Instruction::JumpIfFalse(target) => {
-                    let condition = stack.pop().unwrap_or(Value::Nil);
-                    if !condition.is_truthy() {
-                        pc = *target;
-                    } else {
-                        pc += 1;
-                    }
-                }
-                Instruction::Print => {
-                    if let Some(val) = stack.pop() {
-                        println!("{}", val);
-                    }
-                    pc += 1;
-                }
-                Instruction::MakeTable(size) => {
-                    let mut elements = Vec::new();
-                    for _ in 0..*size {
-                        if let Some(val) = stack.pop() {
-                            elements.push(val);
+                    match handler {
+                        Some(handler) => handler,
+                        None => {
+                            dict.borrow_mut().insert(key.to_string(), value);
+                            return Ok(());
                         }
                     }
-                    elements.reverse();
-                    stack.push(Value::Table(elements));
-                    pc += 1;
                 }
-                Instruction::MakeDict(size) => {
-                    let mut entries = HashMap::new();
-                    for _ in 0..*size {
-                        let value = stack.pop().unwrap_or(Value::Nil);
-                        let key = match stack.pop().unwrap_or(Value::Nil) {
-                            Value::String(s) => s,
-                            v => v.to_string(),
-                        };
-                        entries.insert(key, value);
-                    }
-                    stack.push(Value::Dictionary(entries));
-                    pc += 1;
+                other => {
+                    let message = format!("attempt to set member '{}' of {} value", key, other.type_name());
+                    return Err(RuntimeError::new(ErrorKind::TypeError, message));
                 }
-                Instruction::GetIndex => {
-                    let index = stack.pop().unwrap_or(Value::Nil);
-                    let table = stack.pop().unwrap_or(Value::Nil);
-
-                    match (table, index) {
-                        (Value::Table(elems), Value::Number(idx)) => {
-                            let i = idx as usize;
-                            if i < elems.len() {
-                                stack.push(elems[i].clone());
-                            } else {
-                                stack.push(Value::Nil);
-                            }
-                        }
-                        (Value::Dictionary(map), Value::String(key)) => {
-                            stack.push(map.get(&key).cloned().unwrap_or(Value::Nil));
-                        }
-                        (Value::String(s), Value::Number(idx)) => {
-                            let i = idx as usize;
-                            if i < s.len() {
-                                stack.push(Value::String(s.chars().nth(i).unwrap().to_string()));
-                            } else {
-                                stack.push(Value::Nil);
-                            }
-                        }
-                        _ => stack.push(Value::Nil),
-                    }
-                    pc += 1;
+            };
+            match handler {
+                Value::Dictionary(_) => current = handler,
+                handler => {
+                    self.call(handler, vec![current, Value::String(key.to_string()), value])?;
+                    return Ok(());
                 }
-                Instruction::GetMember(name) => {
-                    let obj = stack.pop().unwrap_or(Value::Nil);
-                    match obj {
-                        Value::Module(members) => {
-                            stack.push(members.get(name).cloned().unwrap_or(Value::Nil));
-                        }
-                        Value::Dictionary(map) => {
-                            stack.push(map.get(name).cloned().unwrap_or(Value::Nil));
-                        }
-                        _ => stack.push(Value::Nil),
-                    }
-                    pc += 1;
-                }
-                Instruction::Call(func_name, arg_count) => {
-                    // Handle function calls within functions (including recursion)
-                    if let Some(func_val) = vars.get(func_name).cloned() {
-                        match func_val {
-                            Value::Function(params, body) => {
-                                if params.len() != *arg_count {
-                                    eprintln!("Error: Function {} expects {} arguments, got {}", func_name, params.len(), arg_count);
-                                    pc += 1;
-                                    continue;
-                                }
+            }
+        }
+        Err(RuntimeError::new(ErrorKind::ValueError, "'__newindex' chain too long; possible loop"))
+    }
 
-                                // Pop arguments from stack
-                                let mut args = Vec::new();
-                                for _ in 0..*arg_count {
-                                    if let Some(arg) = stack.pop() {
-                                        args.push(arg);
-                                    }
-                                }
-                                args.reverse();
-
-                                // Create local scope with parameters bound to arguments
-                                let mut func_vars = vars.clone();
-                                for (i, param_name) in params.iter().enumerate() {
-                                    if i < args.len() {
-                                        func_vars.insert(param_name.clone(), args[i].clone());
-                                    }
-                                }
+    /// Follow `members` from `base` to the function the last one names,
+    /// through `__index` metamethods. None unless the last object is a
+    /// module or dictionary holding a value under that name.
+    fn find_method(&mut self, base: &Value, members: &[String]) -> Result<Option<Value>, RuntimeError> {
+        let Some((method, path)) = members.split_last() else {
+            return Ok(None);
+        };
+        let mut current = base.clone();
+        for member in path {
+            current = self.lookup(&current, member)?;
+        }
+        if !matches!(current, Value::Module(_) | Value::Dictionary(_)) {
+            return Ok(None);
+        }
+        let found = self.lookup(&current, method)?;
+        Ok(Some(found).filter(|v| *v != Value::Nil))
+    }
 
-                                // Execute function recursively
-                                Self::execute_function(&body, stack, &mut func_vars, verbose);
+    /// Arithmetic on something other than two numbers
+    fn operand_error(&mut self, verb: &str, a: &Value, b: &Value, depth: usize) -> Result<(), RuntimeError> {
+        let message = format!("attempt to {} {} and {}", verb, a.type_name(), b.type_name());
+        self.lenient_error(RuntimeError::new(ErrorKind::TypeError, message), vec![Value::Nil], depth)
+    }
 
-                                // Return value is left on stack by the function
-                            }
-                            _ => {
-                                eprintln!("Error: {} is not a function", func_name);
-                                stack.push(Value::Nil);
-                            }
-                        }
-                    } else {
-                        eprintln!("Error: Function {} not found in function context", func_name);
-                        stack.push(Value::Nil);
-                    }
-                    pc += 1;
-                }
-                Instruction::CallMethod(members, arg_count) => {
-                    let base = stack.pop().unwrap_or(Value::Nil);
-                    let mut args = Vec::new();
-                    for _ in 0..*arg_count {
-                        if let Some(arg) = stack.pop() {
-                            args.push(arg);
-                        }
-                    }
-                    args.reverse();
+    /// Bitwise operation on something other than whole numbers
+    fn bitwise_error(&mut self, operands: &[&Value], depth: usize) -> Result<(), RuntimeError> {
+        let error = if operands.iter().all(|v| matches!(v, Value::Number(_))) {
+            RuntimeError::new(ErrorKind::ValueError, "number has no integer representation")
+        } else {
+            let types: Vec<&str> = operands.iter().map(|v| v.type_name()).collect();
+            let noun = if operands.len() == 1 { " value" } else { "" };
+            let message = format!("attempt to perform bitwise operation on {}{}", types.join(" and "), noun);
+            RuntimeError::new(ErrorKind::TypeError, message)
+        };
+        self.lenient_error(error, vec![Value::Nil], depth)
+    }
 
-                    let mut current = base;
-                    let method_name = members.last().cloned().unwrap_or_default();
+    /// Ordering comparison between values that are not both numbers or both strings
+    fn compare_error(&mut self, a: &Value, b: &Value, depth: usize) -> Result<(), RuntimeError> {
+        let message = format!("attempt to compare {} with {}", a.type_name(), b.type_name());
+        self.lenient_error(RuntimeError::new(ErrorKind::TypeError, message), vec![Value::Boolean(false)], depth)
+    }
 
-                    for i in 0..members.len() - 1 {
-                        current = match current {
-                            Value::Module(ref map) => map.get(&members[i]).cloned().unwrap_or(Value::Nil),
-                            Value::Dictionary(ref map) => map.get(&members[i]).cloned().unwrap_or(Value::Nil),
-                            _ => Value::Nil,
-                        };
-                    }
+    /// Indexing a value that is not a container; `name` is the indexed
+    /// expression as written in the source, when it is a plain name
+    fn index_error(&mut self, container: &Value, name: &Option<String>, fallback: Vec<Value>, depth: usize) -> Result<(), RuntimeError> {
+        let message = match name {
+            Some(name) => format!("attempt to index {} value '{}'", container.type_name(), name),
+            None => format!("attempt to index {} value", container.type_name()),
+        };
+        self.lenient_error(RuntimeError::new(ErrorKind::TypeError, message), fallback, depth)
+    }
 
-                    let method = match &current {
-                        Value::Module(map) => map.get(&method_name).cloned(),
-                        Value::Dictionary(map) => map.get(&method_name).cloned(),
-                        _ => None,
-                    };
+    /// Pop the current frame, discarding its locals, temporaries and try
+    /// handlers, and hand `values` to the caller
+    fn return_from_call(&mut self, values: Vec<Value>) {
+        let mut results = Results::Exactly(1);
+        if let Some(frame) = self.frames.pop() {
+            self.stack.truncate(frame.stack_base);
+            self.slots.truncate(frame.base);
+            results = frame.results;
+        }
+        while self.try_handlers.last().is_some_and(|h| h.frame_depth >= self.frames.len()) {
+            self.try_handlers.pop();
+        }
+        self.push_results(values, results);
+    }
 
-                    if let Some(method_val) = method {
-                        match method_val {
-                            Value::NativeFunction(module, func) => {
-                                let result = crate::stdlib::call_native(&module, &func, args, verbose);
-                                stack.push(result);
-                            }
-                            Value::Function(params, body) => {
-                                let mut func_vars = vars.clone();
-                                for (i, param) in params.iter().enumerate() {
-                                    if i < args.len() {
-                                        func_vars.insert(param.clone(), args[i].clone());
-                                    }
-                                }
-                                Self::execute_function(&body, stack, &mut func_vars, verbose);
-                            }
-                            _ => stack.push(Value::Nil),
-                        }
-                    } else {
-                        stack.push(Value::Nil);
-                    }
-                    pc += 1;
-                }
-                Instruction::SetupTry(catch_pc) => {
-                    try_handlers.push(TryHandler {
-                        catch_pc: *catch_pc,
-                        stack_depth: stack.len(),
-                    });
-                    pc += 1;
-                }
-                Instruction::PopTry => {
-                    try_handlers.pop();
-                    pc += 1;
-                }
-                Instruction::Throw => {
-                    let error = stack.pop().unwrap_or(Value::String("Unknown error".to_string()));
-                    if let Some(handler) = try_handlers.pop() {
-                        while stack.len() > handler.stack_depth {
-                            stack.pop();
-                        }
-                        stack.push(error);
-                        pc = handler.catch_pc;
-                    } else {
-                        eprintln!("Uncaught error: {}", error);
-                        break;
+    /// Shape the values a call produced into what its caller asked for
+    fn adjust(mut values: Vec<Value>, results: Results) -> Vec<Value> {
+        match results {
+            Results::Exactly(count) => values.resize(count, Value::Nil),
+            Results::All => {
+                let count = values.len();
+                values.push(Value::Number(count as f64));
+            }
+        }
+        values
+    }
+
+    fn push_results(&mut self, values: Vec<Value>, results: Results) {
+        let values = Self::adjust(values, results);
+        self.stack.extend(values);
+    }
+
+    /// Pop the arguments of a call: the positional ones, and the name and
+    /// value pairs of its named arguments
+    fn take_args(&mut self, arity: Arity) -> (Vec<Value>, Vec<(String, Value)>) {
+        let pairs = Self::pop_args(&mut self.stack, arity.named * 2);
+        let named = pairs
+            .chunks(2)
+            .map(|pair| (pair[0].to_string(), pair[1].clone()))
+            .collect();
+        let count = self.take_count(arity);
+        (Self::pop_args(&mut self.stack, count), named)
+    }
+
+    /// Put named arguments in the positions of the parameters they name.
+    /// Parameters that are skipped get nil, and so their default.
+    fn bind_named(func: &Value, args: Vec<Value>, named: Vec<(String, Value)>) -> Result<Vec<Value>, RuntimeError> {
+        if named.is_empty() {
+            return Ok(args);
+        }
+        let Value::Function(closure) = func else {
+            return Err(RuntimeError::new(ErrorKind::TypeError, format!("{} does not take named arguments", func)));
+        };
+        let function = closure.name.clone().unwrap_or_else(|| "<lambda>".to_string());
+        let given = args.len();
+        let mut bound: Vec<Option<Value>> = args.into_iter().map(Some).collect();
+        for (name, value) in named {
+            let Some(index) = closure.params.names.iter().position(|param| *param == name) else {
+                let message = format!("Function {} has no parameter named '{}'", function, name);
+                return Err(RuntimeError::new(ErrorKind::ArityError, message));
+            };
+            if index >= bound.len() {
+                bound.resize(index + 1, None);
+            }
+            if bound[index].is_some() {
+                let source = if index < given { "by position and by name" } else { "twice" };
+                let message = format!("Function {} got argument '{}' {}", function, name, source);
+                return Err(RuntimeError::new(ErrorKind::ArityError, message));
+            }
+            bound[index] = Some(value);
+        }
+        Ok(bound.into_iter().map(|value| value.unwrap_or(Value::Nil)).collect())
+    }
+
+    /// How many values an instruction with `arity` takes, popping the count
+    /// an open call left on top of the stack
+    fn take_count(&mut self, arity: Arity) -> usize {
+        let open = if arity.open {
+            match self.pop() {
+                Value::Number(n) => n as usize,
+                _ => 0,
+            }
+        } else {
+            0
+        };
+        arity.fixed + open
+    }
+
+    /// Unwind to the innermost try handler, possibly in a calling frame, and
+    /// jump to its catch block. Handlers installed below `depth` belong to an
+    /// outer `run` and are left alone. Returns false when nothing catches the error.
+    fn throw(&mut self, error: Value, depth: usize) -> bool {
+        let Some(handler) = self.try_handlers.pop_if(|h| h.frame_depth >= depth) else {
+            return false;
+        };
+        while self.frames.len() > handler.frame_depth + 1 {
+            if let Some(frame) = self.frames.pop() {
+                self.slots.truncate(frame.base);
+            }
+        }
+        self.stack.truncate(handler.stack_depth);
+        self.stack.push(error);
+        self.frame_mut().pc = handler.catch_pc;
+        if self.verbose {
+            println!("  Caught error, jumping to {}", handler.catch_pc);
+        }
+        true
+    }
+
+    /// Handle runtime error, failing when no try block catches it
+    fn handle_error(&mut self, mut error: RuntimeError, depth: usize) -> Result<(), RuntimeError> {
+        // Errors from a nested `run` already carry the whole call chain
+        if error.traceback.is_empty() {
+            error.traceback = self.traceback();
+        }
+        if self.throw(error.value(), depth) {
+            self.caught = Some(error);
+            return Ok(());
+        }
+        Err(error)
+    }
+
+    /// The active calls, innermost first, each at the instruction it is
+    /// executing (for callers, the call in progress)
+    fn traceback(&self) -> Vec<TraceEntry> {
+        self.frames
+            .iter()
+            .rev()
+            .map(|frame| TraceEntry {
+                function: frame
+                    .closure
+                    .as_ref()
+                    .map(|closure| closure.name.clone().unwrap_or_else(|| "<anonymous>".to_string())),
+                chunk: frame.chunk.name.clone(),
+                position: frame.chunk.position(frame.start + frame.pc.saturating_sub(1)),
+            })
+            .collect()
+    }
+
+    /// Build a closure over the body at `start..start + len` of `chunk`,
+    /// capturing variable cells from the current frame
+    fn make_closure(
+        &mut self,
+        name: Option<&String>,
+        params: &Signature,
+        captures: &[Capture],
+        chunk: &Rc<Chunk>,
+        start: usize,
+        len: usize,
+    ) -> Value {
+        let captured = captures
+            .iter()
+            .map(|capture| match capture {
+                Capture::Local(slot) => self.local_cell(*slot).clone(),
+                Capture::Upvalue(index) => self
+                    .upvalue(*index)
+                    .unwrap_or_else(|| Rc::new(RefCell::new(Value::Nil))),
+            })
+            .collect();
+        Value::Function(Rc::new(Closure {
+            name: name.cloned(),
+            params: params.clone(),
+            chunk: Rc::clone(chunk),
+            start,
+            len,
+            upvalues: captured,
+        }))
+    }
+
+    /// Pop `count` values, returning them in push order
+    fn pop_args(stack: &mut Vec<Value>, count: usize) -> Vec<Value> {
+        let split = stack.len().saturating_sub(count);
+        stack.split_off(split)
+    }
+
+    /// Read `container[index]` for tables, dictionaries, strings and modules.
+    /// Returns None when `container` cannot be indexed at all.
+    fn get_index(container: &Value, index: &Value) -> Option<Value> {
+        let val = match (container, index) {
+            (Value::Table(elems), Value::Number(idx)) => {
+                elems.borrow().get(*idx as usize).cloned().unwrap_or(Value::Nil)
+            }
+            (Value::Dictionary(map), Value::String(key)) => {
+                map.borrow().get(key).cloned().unwrap_or(Value::Nil)
+            }
+            (Value::String(s), Value::Number(idx)) => s
+                .chars()
+                .nth(*idx as usize)
+                .map(|c| Value::String(c.to_string()))
+                .unwrap_or(Value::Nil),
+            (Value::Module(members), Value::String(key)) => members.get(key).cloned().unwrap_or(Value::Nil),
+            (Value::Table(_) | Value::Dictionary(_) | Value::String(_) | Value::Module(_), _) => Value::Nil,
+            _ => return None,
+        };
+        Some(val)
+    }
+
+    /// Write `container[index] = value` in place. Assigning one past the end of
+    /// a table appends; any other index must already be in the table. Returns
+    /// false when `container` is not a table or dictionary.
+    fn set_index(container: &Value, index: Value, value: Value) -> Result<bool, RuntimeError> {
+        match (container, index) {
+            (Value::Table(elems), Value::Number(idx)) => {
+                let mut elems = elems.borrow_mut();
+                let len = elems.len();
+                let i = match Value::Number(idx).to_integer() {
+                    Some(i) if (0..=len as i64).contains(&i) => i as usize,
+                    _ => {
+                        let message = format!("table index {} is out of range (length {})", Value::Number(idx), len);
+                        return Err(RuntimeError::new(ErrorKind::ValueError, message));
                     }
+                };
+                if i == len {
+                    elems.push(value);
+                } else {
+                    elems[i] = value;
                 }
-                Instruction::Return => {
-                    // Return from function - the value on top of stack is the return value
-                    if verbose {
-                        println!("  [Function] Return");
+            }
+            (Value::Dictionary(map), Value::String(key)) => {
+                map.borrow_mut().insert(key, value);
+            }
+            (Value::Table(_), index) => {
+                let message = format!("table index must be a number, got {}", index.type_name());
+                return Err(RuntimeError::new(ErrorKind::TypeError, message));
+            }
+            (Value::Dictionary(_), index) => {
+                let message = format!("dictionary key must be a string, got {}", index.type_name());
+                return Err(RuntimeError::new(ErrorKind::TypeError, message));
+            }
+            _ => return Ok(false),
+        }
+        Ok(true)
+    }
+
+    /// Read a named member from a module or dictionary
+    fn get_member(obj: &Value, name: &str) -> Value {
+        match obj {
+            Value::Module(members) => members.get(name).cloned().unwrap_or(Value::Nil),
+            Value::Dictionary(map) => map.borrow().get(name).cloned().unwrap_or(Value::Nil),
+            _ => Value::Nil,
+        }
+    }
+
+    /// Try to get the module name from a value
+    fn get_module_name(val: &Value, vars: &HashMap<String, Value>) -> Option<String> {
+        for (name, v) in vars {
+            if std::ptr::eq(val, v) {
+                return Some(name.clone());
+            }
+        }
+        // Check against known module names
+        if let Value::Module(_) = val {
+            for (name, v) in vars {
+                if let Value::Module(_) = v {
+                    if format!("{:?}", val) == format!("{:?}", v) {
+                        return Some(name.clone());
                     }
-                    break;
-                }
-                _ => {
-                    // For other instructions, just advance
-                    pc += 1;
                 }
             }
         }
+        None
     }
 }
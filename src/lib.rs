@@ -6,7 +6,7 @@ pub mod repl;
 pub mod stdlib;
 
 // Re-export commonly used items
-pub use ast::AstNode;
-pub use compiler::{Compiler, Instruction, Value, Vm};
+pub use ast::{AstNode, Position};
+pub use compiler::{Arity, Chunk, Compiler, Coroutine, CoroutineStatus, Dict, Error, ErrorKind, Instruction, NativeFn, Results, RuntimeError, Signature, TraceEntry, Value, Vm};
 pub use parser::{parse_to_ast, tokenize, ParseError};
 pub use stdlib::{call_native, create_stdlib};
@@ -1,9 +1,6 @@
-mod ast;
 mod cli;
-mod compiler;
-mod parser;
-mod repl;
-mod stdlib;
+
+use pickup_lang::{compiler, parser, repl};
 
 fn main() {
     let args = cli::parse_args();
@@ -11,7 +8,7 @@ fn main() {
 
     if let Some(path) = args.script {
         // File execution path
-        let source = std::fs::read_to_string(path).expect("Failed to read script");
+        let source = std::fs::read_to_string(&path).expect("Failed to read script");
         if verbose {
             println!("Parsing source: {} bytes", source.len());
         }
@@ -20,10 +17,23 @@ fn main() {
         if verbose {
             println!("Compiled AST: {:?}", ast);
         }
-        let bytecode = compiler::Compiler::compile(&ast, verbose);
-        compiler::Vm::execute(&bytecode, verbose);
+        let chunk = compiler::Compiler::compile_chunk(&ast, &path, verbose);
+        let mut vm = compiler::Vm::new();
+        vm.set_verbose(verbose);
+        vm.set_lenient(args.lenient);
+        if verbose {
+            println!("\n--- VM Execution Log ---");
+        }
+        let result = vm.run_chunk(chunk);
+        if verbose {
+            println!("--- VM Execution Completed ---\n");
+        }
+        if let Err(e) = result {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
     } else {
         // REPL path
-        repl::run_repl(verbose);
+        repl::run_repl(verbose, args.lenient);
     }
 }
@@ -1,4 +1,4 @@
-use crate::ast::AstNode;
+use crate::ast::{AstNode, Params, Pattern, Position};
 use pest::iterators::{Pair, Pairs};
 use pest::Parser;
 use pest_derive::Parser;
@@ -16,7 +16,7 @@ pub enum ParseError {
     AstError(String),
 }
 
-pub fn tokenize(source: &str, verbose: bool) -> Result<Pairs<Rule>, ParseError> {
+pub fn tokenize(source: &str, verbose: bool) -> Result<Pairs<'_, Rule>, ParseError> {
     if verbose {
         println!("Tokenizing source:\n{}", source);
     }
@@ -31,15 +31,12 @@ pub fn parse_to_ast(pairs: Pairs<Rule>, verbose: bool) -> Result<AstNode, ParseE
     let mut statements = Vec::new();
 
     for pair in pairs {
-        match pair.as_rule() {
-            Rule::program => {
-                for inner_pair in pair.into_inner() {
-                    if let Some(stmt) = parse_statement(inner_pair, verbose)? {
-                        statements.push(stmt);
-                    }
+        if pair.as_rule() == Rule::program {
+            for inner_pair in pair.into_inner() {
+                if let Some(stmt) = parse_statement(inner_pair, verbose)? {
+                    statements.push(stmt);
                 }
             }
-            _ => {}
         }
     }
 
@@ -47,6 +44,21 @@ pub fn parse_to_ast(pairs: Pairs<Rule>, verbose: bool) -> Result<AstNode, ParseE
 }
 
 fn parse_statement(pair: Pair<Rule>, verbose: bool) -> Result<Option<AstNode>, ParseError> {
+    let position = position_of(&pair);
+    Ok(parse_statement_node(pair, verbose)?.map(|stmt| located(position, stmt)))
+}
+
+/// Where a pair starts in the source
+fn position_of(pair: &Pair<Rule>) -> Position {
+    let (line, column) = pair.line_col();
+    Position { line, column }
+}
+
+fn located(position: Position, node: AstNode) -> AstNode {
+    AstNode::Located(position, Box::new(node))
+}
+
+fn parse_statement_node(pair: Pair<Rule>, verbose: bool) -> Result<Option<AstNode>, ParseError> {
     if verbose {
         println!("Parsing statement: {:?}", pair);
     }
@@ -56,10 +68,8 @@ fn parse_statement(pair: Pair<Rule>, verbose: bool) -> Result<Option<AstNode>, P
             let module_path = inner.next().ok_or_else(|| {
                 ParseError::AstError("Missing module path in import statement".into())
             })?;
-            // Remove quotes from string
-            let path_str = module_path.as_str();
-            let module = &path_str[1..path_str.len() - 1];
-            Ok(Some(AstNode::Import(module.to_string())))
+            let module = literal_string(module_path, verbose)?;
+            Ok(Some(AstNode::Import(module)))
         }
         Rule::assignment => {
             let inner: Vec<_> = pair.into_inner().collect();
@@ -67,15 +77,19 @@ fn parse_statement(pair: Pair<Rule>, verbose: bool) -> Result<Option<AstNode>, P
                 println!("Assignment inner: {:?}", inner);
             }
 
-            // First element is the target (identifier, member_access, or index_access)
-            let target_node = parse_term(inner[0].clone(), verbose)?;
+            // Targets (names, or expressions ending in a member or index) come before the values
+            let split = inner.iter().position(|p| p.as_rule() == Rule::expression).unwrap_or(inner.len());
+            let targets = inner[..split]
+                .iter()
+                .map(|p| parse_term(p.clone(), verbose))
+                .collect::<Result<Vec<_>, _>>()?;
 
-            // Parse the right-hand side as a full expression
-            let value_node = parse_expression(inner[1].clone(), verbose)?;
-            Ok(Some(AstNode::Assignment(
-                Box::new(target_node),
-                Box::new(value_node),
-            )))
+            // Parse each right-hand side as a full expression
+            let values = inner[split..]
+                .iter()
+                .map(|p| parse_expression(p.clone(), verbose))
+                .collect::<Result<Vec<_>, _>>()?;
+            Ok(Some(AstNode::Assignment(targets, values)))
         }
         Rule::local_assignment => {
             let inner: Vec<_> = pair.into_inner().collect();
@@ -83,28 +97,24 @@ fn parse_statement(pair: Pair<Rule>, verbose: bool) -> Result<Option<AstNode>, P
                 println!("Local assignment inner: {:?}", inner);
             }
 
-            let var_name = inner[0].as_str().to_string();
-            let value_node = parse_expression(inner[1].clone(), verbose)?;
-            Ok(Some(AstNode::LocalAssignment(var_name, Box::new(value_node))))
-        }
-        Rule::function_call => {
-            let function_call = parse_function_call(pair, verbose)?;
-            match function_call {
-                AstNode::Print(_) => Ok(Some(function_call)),
-                _ => Ok(Some(function_call)),
-            }
-        }
-        Rule::method_call => {
-            let method_call = parse_method_call(pair, verbose)?;
-            Ok(Some(method_call))
-        }
+            let (targets, values): (Vec<_>, Vec<_>) = inner.into_iter().partition(|p| is_binding(p.as_rule()));
+            let targets = targets
+                .into_iter()
+                .map(|p| parse_binding(p, verbose))
+                .collect::<Result<Vec<_>, _>>()?;
+            let values = values
+                .into_iter()
+                .map(|p| parse_expression(p, verbose))
+                .collect::<Result<Vec<_>, _>>()?;
+            Ok(Some(AstNode::LocalAssignment(targets, values)))
+        }
+        Rule::call_expr => Ok(Some(parse_postfix(pair, verbose)?)),
         Rule::print_stmt => {
-            let mut inner = pair.into_inner();
-            let expr = inner.next().ok_or_else(|| {
-                ParseError::AstError("Missing expression in print statement".into())
-            })?;
-            let expr_node = parse_expression(expr, verbose)?;
-            Ok(Some(AstNode::Print(Box::new(expr_node))))
+            let values = pair
+                .into_inner()
+                .map(|p| parse_expression(p, verbose))
+                .collect::<Result<Vec<_>, _>>()?;
+            Ok(Some(AstNode::Print(values)))
         }
         Rule::if_stmt => {
             let inner: Vec<_> = pair.into_inner().collect();
@@ -138,8 +148,8 @@ fn parse_statement(pair: Pair<Rule>, verbose: bool) -> Result<Option<AstNode>, P
                 let elseif_inner: Vec<_> = inner[idx].clone().into_inner().collect();
                 let elseif_cond = parse_expression(elseif_inner[0].clone(), verbose)?;
                 let mut elseif_stmts = Vec::new();
-                for i in 1..elseif_inner.len() {
-                    if let Some(stmt) = parse_statement(elseif_inner[i].clone(), verbose)? {
+                for stmt_pair in elseif_inner.iter().skip(1) {
+                    if let Some(stmt) = parse_statement(stmt_pair.clone(), verbose)? {
                         elseif_stmts.push(stmt);
                     }
                 }
@@ -167,8 +177,8 @@ fn parse_statement(pair: Pair<Rule>, verbose: bool) -> Result<Option<AstNode>, P
             let inner: Vec<_> = pair.into_inner().collect();
             let condition = parse_expression(inner[0].clone(), verbose)?;
             let mut body = Vec::new();
-            for i in 1..inner.len() {
-                if let Some(stmt) = parse_statement(inner[i].clone(), verbose)? {
+            for stmt_pair in inner.iter().skip(1) {
+                if let Some(stmt) = parse_statement(stmt_pair.clone(), verbose)? {
                     body.push(stmt);
                 }
             }
@@ -196,50 +206,87 @@ fn parse_statement(pair: Pair<Rule>, verbose: bool) -> Result<Option<AstNode>, P
             };
 
             let mut body = Vec::new();
-            for i in body_start..inner.len() {
-                if let Some(stmt) = parse_statement(inner[i].clone(), verbose)? {
+            for stmt_pair in inner.iter().skip(body_start) {
+                if let Some(stmt) = parse_statement(stmt_pair.clone(), verbose)? {
                     body.push(stmt);
                 }
             }
             Ok(Some(AstNode::For(var_name, Box::new(start), Box::new(end), step, body)))
         }
+        Rule::for_in_stmt => {
+            let mut inner = pair.into_inner().peekable();
+            let mut vars = Vec::new();
+            while let Some(var) = inner.next_if(|p| is_binding(p.as_rule())) {
+                vars.push(parse_binding(var, verbose)?);
+            }
+            let iterable = inner.next().ok_or_else(|| {
+                ParseError::AstError("Missing expression in for-in loop".into())
+            })?;
+            let iterable = parse_expression(iterable, verbose)?;
+
+            let mut body = Vec::new();
+            for stmt_pair in inner {
+                if let Some(stmt) = parse_statement(stmt_pair, verbose)? {
+                    body.push(stmt);
+                }
+            }
+            Ok(Some(AstNode::ForIn(vars, Box::new(iterable), body)))
+        }
         Rule::function_def => {
             let inner: Vec<_> = pair.into_inner().collect();
             let func_name = inner[0].as_str().to_string();
 
-            // Parse parameters
-            let mut params = Vec::new();
-            let mut body_start = 1;
-
-            // Collect parameters until we hit a statement
-            while body_start < inner.len() {
-                match inner[body_start].as_rule() {
-                    Rule::identifier => {
-                        params.push(inner[body_start].as_str().to_string());
-                        body_start += 1;
-                    }
-                    _ => break
-                }
+            // Parse parameters; a method defined with `:` receives its object as `self`
+            let mut params = parse_params(inner[1].clone(), verbose)?;
+            if func_name.contains(':') {
+                params.names.insert(0, ("self".to_string(), None));
             }
 
             // Parse body
             let mut body = Vec::new();
-            for i in body_start..inner.len() {
-                if let Some(stmt) = parse_statement(inner[i].clone(), verbose)? {
+            for stmt_pair in inner.iter().skip(2) {
+                if let Some(stmt) = parse_statement(stmt_pair.clone(), verbose)? {
                     body.push(stmt);
                 }
             }
 
             Ok(Some(AstNode::FunctionDef(func_name, params, body)))
         }
-        Rule::return_stmt => {
+        Rule::class_def => parse_class(pair, verbose).map(Some),
+        Rule::match_stmt => {
             let mut inner = pair.into_inner();
-            let value = if let Some(expr) = inner.next() {
-                Some(Box::new(parse_expression(expr, verbose)?))
-            } else {
-                None
-            };
-            Ok(Some(AstNode::Return(value)))
+            let subject = inner
+                .next()
+                .ok_or_else(|| ParseError::AstError("Missing match subject".into()))?;
+            let subject = parse_expression(subject, verbose)?;
+            let mut arms = Vec::new();
+            for arm in inner {
+                // The first pair is the `case` keyword
+                let mut parts = arm.into_inner().skip(1).peekable();
+                let pattern = parts
+                    .next()
+                    .ok_or_else(|| ParseError::AstError("Missing pattern".into()))?;
+                let pattern = parse_pattern(pattern, verbose)?;
+                let guard = parts
+                    .next_if(|p| p.as_rule() == Rule::expression)
+                    .map(|guard| parse_expression(guard, verbose))
+                    .transpose()?;
+                let mut body = Vec::new();
+                for stmt_pair in parts {
+                    if let Some(stmt) = parse_statement(stmt_pair, verbose)? {
+                        body.push(stmt);
+                    }
+                }
+                arms.push((pattern, guard, body));
+            }
+            Ok(Some(AstNode::Match(Box::new(subject), arms)))
+        }
+        Rule::return_stmt => {
+            let values = pair
+                .into_inner()
+                .map(|expr| parse_expression(expr, verbose))
+                .collect::<Result<Vec<_>, _>>()?;
+            Ok(Some(AstNode::Return(values)))
         }
         Rule::break_stmt => {
             Ok(Some(AstNode::Break))
@@ -256,8 +303,8 @@ fn parse_statement(pair: Pair<Rule>, verbose: bool) -> Result<Option<AstNode>, P
             let mut try_body = Vec::new();
             let mut idx = 0;
 
-            // Parse try block statements until catch clause
-            while idx < inner.len() && inner[idx].as_rule() != Rule::catch_clause {
+            // Parse try block statements until the catch or finally clause
+            while idx < inner.len() && !matches!(inner[idx].as_rule(), Rule::catch_clause | Rule::finally_clause) {
                 if let Some(stmt) = parse_statement(inner[idx].clone(), verbose)? {
                     try_body.push(stmt);
                 }
@@ -265,9 +312,10 @@ fn parse_statement(pair: Pair<Rule>, verbose: bool) -> Result<Option<AstNode>, P
             }
 
             // Parse catch clause
-            let (catch_var, catch_body) = if idx < inner.len() && inner[idx].as_rule() == Rule::catch_clause {
+            let mut catch_var = None;
+            let mut catch_body = None;
+            if idx < inner.len() && inner[idx].as_rule() == Rule::catch_clause {
                 let catch_inner: Vec<_> = inner[idx].clone().into_inner().collect();
-                let mut catch_var = None;
                 let mut catch_stmts = Vec::new();
                 let mut catch_idx = 0;
 
@@ -278,18 +326,30 @@ fn parse_statement(pair: Pair<Rule>, verbose: bool) -> Result<Option<AstNode>, P
                 }
 
                 // Parse catch block statements
-                for i in catch_idx..catch_inner.len() {
-                    if let Some(stmt) = parse_statement(catch_inner[i].clone(), verbose)? {
+                for stmt_pair in catch_inner.iter().skip(catch_idx) {
+                    if let Some(stmt) = parse_statement(stmt_pair.clone(), verbose)? {
                         catch_stmts.push(stmt);
                     }
                 }
 
-                (catch_var, catch_stmts)
+                catch_body = Some(catch_stmts);
+                idx += 1;
+            }
+
+            // Parse finally clause
+            let finally_body = if idx < inner.len() && inner[idx].as_rule() == Rule::finally_clause {
+                let mut finally_stmts = Vec::new();
+                for stmt_pair in inner[idx].clone().into_inner() {
+                    if let Some(stmt) = parse_statement(stmt_pair, verbose)? {
+                        finally_stmts.push(stmt);
+                    }
+                }
+                Some(finally_stmts)
             } else {
-                (None, Vec::new())
+                None
             };
 
-            Ok(Some(AstNode::TryCatch(try_body, catch_var, catch_body)))
+            Ok(Some(AstNode::TryCatch(try_body, catch_var, catch_body, finally_body)))
         }
         Rule::throw_stmt => {
             let mut inner = pair.into_inner();
@@ -307,112 +367,298 @@ fn parse_statement(pair: Pair<Rule>, verbose: bool) -> Result<Option<AstNode>, P
     }
 }
 
-fn parse_function_call(pair: Pair<Rule>, verbose: bool) -> Result<AstNode, ParseError> {
+/// Fold a primary expression and its calls, indexes and member accesses,
+/// left to right, into one node. A call through a member chain becomes a
+/// `MethodCall`, so module functions like `math.floor(x)` keep working, and
+/// a call of a plain name a `FunctionCall`.
+fn parse_postfix(pair: Pair<Rule>, verbose: bool) -> Result<AstNode, ParseError> {
     if verbose {
-        println!("Parsing function call: {:?}", pair);
+        println!("Parsing postfix expression: {:?}", pair);
     }
-    let inner: Vec<_> = pair.into_inner().collect();
-
-    if inner.is_empty() {
-        return Err(ParseError::AstError("Empty function call".into()));
+    let position = position_of(&pair);
+    let mut inner = pair.into_inner();
+    let primary = inner
+        .next()
+        .ok_or_else(|| ParseError::AstError("Empty postfix expression".into()))?;
+    let parenthesized = primary.as_rule() == Rule::expression;
+    let mut node = parse_term(primary, verbose)?;
+    if parenthesized {
+        node = AstNode::Paren(Box::new(node));
     }
-
-    // First item is the function name
-    let func_name = &inner[0];
-
-    // Special case for 'print' with string concatenation
-    if func_name.as_str() == "print" && inner.len() >= 4 {
-        if inner.len() == 4 && inner[2].as_rule() == Rule::op_concat {
-            // Handle print with concatenation: print(str .. var)
-            let left = parse_term(inner[1].clone(), verbose)?;
-            let right = parse_term(inner[3].clone(), verbose)?;
-            let concat = AstNode::BinaryOp(Box::new(left), "..".to_string(), Box::new(right));
-            return Ok(AstNode::Print(Box::new(concat)));
-        }
-    }
-
-    // Regular function call or print with single argument
-    if inner.len() >= 2 {
-        let arg = parse_expression(inner[1].clone(), verbose)?;
-
-        if func_name.as_str() == "print" {
-            Ok(AstNode::Print(Box::new(arg)))
-        } else {
-            let mut args = Vec::new();
-            args.push(arg);
-
-            // Add any remaining arguments
-            for i in 2..inner.len() {
-                args.push(parse_expression(inner[i].clone(), verbose)?);
+    let mut optional = false;
+
+    for suffix in inner {
+        node = match suffix.as_rule() {
+            Rule::optional_member | Rule::optional_index | Rule::optional_call | Rule::optional_method => {
+                let rule = suffix.as_rule();
+                let mut parts = suffix.into_inner();
+                let part = parts
+                    .next()
+                    .ok_or_else(|| ParseError::AstError("Invalid optional chain".into()))?;
+                optional = true;
+                let base = Box::new(AstNode::Optional(Box::new(node)));
+                match rule {
+                    Rule::optional_member => AstNode::MemberAccess(base, vec![part.as_str().to_string()]),
+                    Rule::optional_index => AstNode::Index(base, Box::new(parse_expression(part, verbose)?)),
+                    Rule::optional_method => {
+                        let args = parts
+                            .next()
+                            .map(|args| parse_call_args(args, verbose))
+                            .transpose()?
+                            .unwrap_or_default();
+                        located(position, AstNode::MethodCall(base, vec![part.as_str().to_string()], args, true))
+                    }
+                    _ => located(position, AstNode::Call(base, parse_call_args(part, verbose)?)),
+                }
+            }
+            Rule::member_suffix => {
+                let member = suffix
+                    .into_inner()
+                    .next()
+                    .ok_or_else(|| ParseError::AstError("Missing member name".into()))?
+                    .as_str()
+                    .to_string();
+                match node {
+                    AstNode::MemberAccess(base, mut members) => {
+                        members.push(member);
+                        AstNode::MemberAccess(base, members)
+                    }
+                    base => AstNode::MemberAccess(Box::new(base), vec![member]),
+                }
+            }
+            Rule::method_suffix => {
+                let mut parts = suffix.into_inner();
+                let method = parts
+                    .next()
+                    .ok_or_else(|| ParseError::AstError("Missing method name".into()))?
+                    .as_str()
+                    .to_string();
+                let args = parts
+                    .next()
+                    .map(|args| parse_call_args(args, verbose))
+                    .transpose()?
+                    .unwrap_or_default();
+                located(position, AstNode::MethodCall(Box::new(node), vec![method], args, true))
+            }
+            Rule::index_suffix => {
+                let index = suffix
+                    .into_inner()
+                    .next()
+                    .ok_or_else(|| ParseError::AstError("Invalid index access".into()))?;
+                AstNode::Index(Box::new(node), Box::new(parse_expression(index, verbose)?))
+            }
+            Rule::call_args => {
+                let args = parse_call_args(suffix, verbose)?;
+                let call = match node {
+                    AstNode::Identifier(name) if name == "print" && !args.iter().any(|arg| matches!(arg, AstNode::NamedArg(..))) => {
+                        AstNode::Print(args)
+                    }
+                    AstNode::Identifier(name) => AstNode::FunctionCall(name, args),
+                    AstNode::MemberAccess(base, members) => AstNode::MethodCall(base, members, args, false),
+                    callee => AstNode::Call(Box::new(callee), args),
+                };
+                located(position, call)
+            }
+            rule => {
+                return Err(ParseError::AstError(format!("Unexpected postfix rule: {:?}", rule)));
             }
+        };
+    }
 
-            Ok(AstNode::FunctionCall(func_name.as_str().to_string(), args))
-        }
-    } else {
-        // Function call with no arguments
-        Ok(AstNode::FunctionCall(
-            func_name.as_str().to_string(),
-            vec![],
-        ))
+    if optional {
+        node = AstNode::OptionalChain(Box::new(node));
     }
+    Ok(node)
 }
 
-fn parse_method_call(pair: Pair<Rule>, verbose: bool) -> Result<AstNode, ParseError> {
-    if verbose {
-        println!("Parsing method call: {:?}", pair);
-    }
-    let inner: Vec<_> = pair.into_inner().collect();
+/// A class body becomes a list of members: each method is a `FunctionDef`
+/// named after the class that takes the instance as `self`
+fn parse_class(pair: Pair<Rule>, verbose: bool) -> Result<AstNode, ParseError> {
+    let mut inner = pair.into_inner().peekable();
+    let name = inner
+        .next()
+        .ok_or_else(|| ParseError::AstError("Missing class name".into()))?
+        .as_str()
+        .to_string();
+    let base = inner
+        .next_if(|p| p.as_rule() == Rule::postfix_expr)
+        .map(|base| parse_postfix(base, verbose))
+        .transpose()?;
 
-    if inner.is_empty() {
-        return Err(ParseError::AstError("Empty method call".into()));
+    let mut members = Vec::new();
+    for member in inner {
+        let rule = member.as_rule();
+        let mut parts = member.into_inner();
+        let member_name = parts
+            .next()
+            .ok_or_else(|| ParseError::AstError("Missing class member name".into()))?
+            .as_str()
+            .to_string();
+        let value = if rule == Rule::class_method {
+            let mut params = match parts.next() {
+                Some(list) => parse_params(list, verbose)?,
+                None => Params::default(),
+            };
+            params.names.insert(0, ("self".to_string(), None));
+            let mut body = Vec::new();
+            for part in parts {
+                if let Some(stmt) = parse_statement(part, verbose)? {
+                    body.push(stmt);
+                }
+            }
+            AstNode::FunctionDef(format!("{}:{}", name, member_name), params, body)
+        } else {
+            let value = parts
+                .next()
+                .ok_or_else(|| ParseError::AstError("Missing class field value".into()))?;
+            parse_expression(value, verbose)?
+        };
+        members.push((member_name, value));
     }
 
-    // First element should be member_access
-    let member_access_pair = inner[0].clone();
-    let member_inner: Vec<_> = member_access_pair.into_inner().collect();
-
-    // Get base object
-    let base = parse_term(member_inner[0].clone(), verbose)?;
+    Ok(AstNode::ClassDef(name, base.map(Box::new), members))
+}
 
-    // Get member chain
-    let mut members = Vec::new();
-    for i in 1..member_inner.len() {
-        if member_inner[i].as_rule() == Rule::identifier {
-            members.push(member_inner[i].as_str().to_string());
+fn parse_pattern(pair: Pair<Rule>, verbose: bool) -> Result<Pattern, ParseError> {
+    match pair.as_rule() {
+        Rule::wildcard_pattern => Ok(Pattern::Wildcard),
+        Rule::identifier => Ok(Pattern::Binding(pair.as_str().to_string())),
+        Rule::literal_pattern => {
+            let literal = pair
+                .into_inner()
+                .next()
+                .ok_or_else(|| ParseError::AstError("Empty literal pattern".into()))?;
+            let node = match literal.as_rule() {
+                Rule::pattern_number => AstNode::Number(parse_pattern_number(&literal)?),
+                Rule::string => AstNode::String(literal_string(literal, verbose)?),
+                Rule::boolean => AstNode::Boolean(literal.as_str() == "true"),
+                _ => AstNode::Nil,
+            };
+            Ok(Pattern::Literal(node))
         }
+        Rule::range_pattern => {
+            let bounds = pair.into_inner().map(|bound| parse_pattern_number(&bound)).collect::<Result<Vec<_>, _>>()?;
+            match bounds.as_slice() {
+                [low, high] => Ok(Pattern::Range(*low, *high)),
+                _ => Err(ParseError::AstError("Invalid range pattern".into())),
+            }
+        }
+        Rule::array_pattern => {
+            let mut elements = Vec::new();
+            let mut rest = None;
+            for part in pair.into_inner() {
+                if part.as_rule() == Rule::rest_pattern {
+                    rest = part.into_inner().next().map(|name| name.as_str().to_string());
+                } else {
+                    elements.push(parse_pattern(part, verbose)?);
+                }
+            }
+            Ok(Pattern::Array(elements, rest))
+        }
+        Rule::dict_pattern => {
+            let mut entries = Vec::new();
+            for entry in pair.into_inner() {
+                let mut parts = entry.into_inner();
+                let key = parts
+                    .next()
+                    .ok_or_else(|| ParseError::AstError("Missing key in dictionary pattern".into()))?
+                    .as_str()
+                    .to_string();
+                let pattern = match parts.next() {
+                    Some(pattern) => parse_pattern(pattern, verbose)?,
+                    None => Pattern::Binding(key.clone()),
+                };
+                entries.push((key, pattern));
+            }
+            Ok(Pattern::Dict(entries))
+        }
+        rule => Err(ParseError::AstError(format!("Unexpected pattern: {:?}", rule))),
     }
+}
 
-    // Parse arguments
-    let mut args = Vec::new();
-    for i in 1..inner.len() {
-        args.push(parse_expression(inner[i].clone(), verbose)?);
-    }
-
-    Ok(AstNode::MethodCall(Box::new(base), members, args))
+fn parse_pattern_number(pair: &Pair<Rule>) -> Result<f64, ParseError> {
+    pair.as_str()
+        .parse::<f64>()
+        .map_err(|_| ParseError::AstError(format!("Failed to parse number: {}", pair.as_str())))
 }
 
-fn parse_member_access(pair: Pair<Rule>, verbose: bool) -> Result<AstNode, ParseError> {
-    if verbose {
-        println!("Parsing member access: {:?}", pair);
-    }
-    let inner: Vec<_> = pair.into_inner().collect();
+fn is_binding(rule: Rule) -> bool {
+    matches!(rule, Rule::identifier | Rule::array_binding | Rule::dict_binding)
+}
 
-    if inner.is_empty() {
-        return Err(ParseError::AstError("Empty member access".into()));
+/// A target of `local`, a `for ... in` head or a parameter: a name, or a
+/// destructuring pattern whose parts are targets with optional defaults
+fn parse_binding(pair: Pair<Rule>, verbose: bool) -> Result<AstNode, ParseError> {
+    // The target comes first and the default, if any, second
+    let target_and_default = |part: Pair<Rule>| -> Result<(AstNode, Option<AstNode>), ParseError> {
+        let mut inner = part.into_inner();
+        let target = inner
+            .next()
+            .ok_or_else(|| ParseError::AstError("Missing destructuring target".into()))?;
+        let default = inner.next().map(|value| parse_expression(value, verbose)).transpose()?;
+        Ok((parse_binding(target, verbose)?, default))
+    };
+    match pair.as_rule() {
+        Rule::identifier => Ok(AstNode::Identifier(pair.as_str().to_string())),
+        Rule::array_binding => {
+            let mut elements = Vec::new();
+            let mut rest = None;
+            for part in pair.into_inner() {
+                if part.as_rule() == Rule::rest_pattern {
+                    rest = part.into_inner().next().map(|name| name.as_str().to_string());
+                } else {
+                    elements.push(target_and_default(part)?);
+                }
+            }
+            Ok(AstNode::ArrayPattern(elements, rest))
+        }
+        Rule::dict_binding => {
+            let mut entries = Vec::new();
+            for entry in pair.into_inner() {
+                let mut inner = entry.into_inner().peekable();
+                let key = inner
+                    .next()
+                    .ok_or_else(|| ParseError::AstError("Missing key in destructuring pattern".into()))?
+                    .as_str()
+                    .to_string();
+                // `{name}` binds the value to a variable named like its key
+                let target = match inner.next_if(|p| is_binding(p.as_rule())) {
+                    Some(target) => parse_binding(target, verbose)?,
+                    None => AstNode::Identifier(key.clone()),
+                };
+                let default = inner.next().map(|value| parse_expression(value, verbose)).transpose()?;
+                entries.push((key, target, default));
+            }
+            Ok(AstNode::DictPattern(entries))
+        }
+        rule => Err(ParseError::AstError(format!("Unexpected destructuring target: {:?}", rule))),
     }
+}
 
-    // Get base object
-    let base = parse_term(inner[0].clone(), verbose)?;
-
-    // Get member chain
-    let mut members = Vec::new();
-    for i in 1..inner.len() {
-        if inner[i].as_rule() == Rule::identifier {
-            members.push(inner[i].as_str().to_string());
+/// The arguments of a call; named arguments come after all positional ones
+fn parse_call_args(pair: Pair<Rule>, verbose: bool) -> Result<Vec<AstNode>, ParseError> {
+    let mut args = Vec::new();
+    let mut named = false;
+    for arg in pair.into_inner() {
+        if arg.as_rule() == Rule::named_arg {
+            let mut parts = arg.into_inner();
+            let name = parts
+                .next()
+                .ok_or_else(|| ParseError::AstError("Missing argument name".into()))?
+                .as_str()
+                .to_string();
+            let value = parts
+                .next()
+                .ok_or_else(|| ParseError::AstError("Missing argument value".into()))?;
+            args.push(AstNode::NamedArg(name, Box::new(parse_expression(value, verbose)?)));
+            named = true;
+        } else if named {
+            return Err(ParseError::AstError("Positional argument follows named argument".into()));
+        } else {
+            args.push(parse_expression(arg, verbose)?);
         }
     }
-
-    Ok(AstNode::MemberAccess(Box::new(base), members))
+    Ok(args)
 }
 
 fn parse_lambda(pair: Pair<Rule>, verbose: bool) -> Result<AstNode, ParseError> {
@@ -422,24 +668,12 @@ fn parse_lambda(pair: Pair<Rule>, verbose: bool) -> Result<AstNode, ParseError>
     let inner: Vec<_> = pair.into_inner().collect();
 
     // Parse parameters
-    let mut params = Vec::new();
-    let mut body_start = 0;
-
-    // Collect parameters until we hit a statement
-    while body_start < inner.len() {
-        match inner[body_start].as_rule() {
-            Rule::identifier => {
-                params.push(inner[body_start].as_str().to_string());
-                body_start += 1;
-            }
-            _ => break
-        }
-    }
+    let params = parse_params(inner[0].clone(), verbose)?;
 
     // Parse body
     let mut body = Vec::new();
-    for i in body_start..inner.len() {
-        if let Some(stmt) = parse_statement(inner[i].clone(), verbose)? {
+    for stmt_pair in inner.iter().skip(1) {
+        if let Some(stmt) = parse_statement(stmt_pair.clone(), verbose)? {
             body.push(stmt);
         }
     }
@@ -447,6 +681,29 @@ fn parse_lambda(pair: Pair<Rule>, verbose: bool) -> Result<AstNode, ParseError>
     Ok(AstNode::Lambda(params, body))
 }
 
+/// A parameter list: names with optional defaults, then the rest parameter
+fn parse_params(pair: Pair<Rule>, verbose: bool) -> Result<Params, ParseError> {
+    let mut params = Params::default();
+    for param in pair.into_inner() {
+        let rule = param.as_rule();
+        let mut parts = param.into_inner();
+        if rule == Rule::rest_param {
+            params.rest = Some(parts.next().map_or("...".to_string(), |name| name.as_str().to_string()));
+            continue;
+        }
+        let target = parts
+            .next()
+            .ok_or_else(|| ParseError::AstError("Missing parameter name".into()))?;
+        let name = target.as_str().to_string();
+        if target.as_rule() != Rule::identifier {
+            params.patterns.push((params.names.len(), parse_binding(target, verbose)?));
+        }
+        let default = parts.next().map(|value| parse_expression(value, verbose)).transpose()?;
+        params.names.push((name, default));
+    }
+    Ok(params)
+}
+
 fn parse_dictionary(pair: Pair<Rule>, verbose: bool) -> Result<AstNode, ParseError> {
     if verbose {
         println!("Parsing dictionary: {:?}", pair);
@@ -460,8 +717,7 @@ fn parse_dictionary(pair: Pair<Rule>, verbose: bool) -> Result<AstNode, ParseErr
             if entry_inner.len() >= 2 {
                 // Key can be identifier or string
                 let key = if entry_inner[0].as_rule() == Rule::string {
-                    let text = entry_inner[0].as_str();
-                    text[1..text.len() - 1].to_string() // Remove quotes
+                    literal_string(entry_inner[0].clone(), verbose)?
                 } else {
                     entry_inner[0].as_str().to_string()
                 };
@@ -474,20 +730,32 @@ fn parse_dictionary(pair: Pair<Rule>, verbose: bool) -> Result<AstNode, ParseErr
     Ok(AstNode::Dictionary(entries))
 }
 
-// Operator precedence levels (higher number = higher precedence)
-fn get_precedence(op: &str) -> u8 {
-    match op {
-        "or" => 1,
-        "and" => 2,
-        "==" | "~=" => 3,
-        "<" | ">" | "<=" | ">=" => 4,
-        ".." => 5,
-        "+" | "-" => 6,
-        "*" | "/" | "%" => 7,
-        _ => 0,
-    }
+// Binding power of each binary operator on its left and right side (higher
+// binds tighter). Right-associative operators bind less tightly on the right,
+// so `a .. b .. c` groups as `a .. (b .. c)`.
+fn infix_binding_power(op: &str) -> Option<(u8, u8)> {
+    let power = match op {
+        "or" => (1, 2),
+        "and" => (3, 4),
+        "==" | "~=" | "<" | ">" | "<=" | ">=" => (5, 6),
+        "|" => (7, 8),
+        "~" => (9, 10),
+        "&" => (11, 12),
+        "<<" | ">>" => (13, 14),
+        "??" => (6, 6),
+        ".." => (16, 15),
+        "+" | "-" => (17, 18),
+        "*" | "/" | "//" | "%" => (19, 20),
+        "^" => (24, 23),
+        _ => return None,
+    };
+    Some(power)
 }
 
+// Prefix operators bind tighter than every binary operator except `^`, so
+// `-x ^ 2` is `-(x ^ 2)` and `#t + 1` is `(#t) + 1`
+const UNARY_BINDING_POWER: u8 = 21;
+
 fn parse_expression(pair: Pair<Rule>, verbose: bool) -> Result<AstNode, ParseError> {
     if verbose {
         println!("Parsing expression: {:?}", pair);
@@ -504,82 +772,74 @@ fn parse_expression(pair: Pair<Rule>, verbose: bool) -> Result<AstNode, ParseErr
                 return Err(ParseError::AstError("Empty expression".into()));
             }
 
-            // Handle binary expressions with precedence
-            if pairs.len() >= 3 {
-                return parse_expression_with_precedence(&pairs, 0, verbose);
+            let mut pos = 0;
+            let node = parse_expression_with_precedence(&pairs, &mut pos, 0, verbose)?;
+            if pos < pairs.len() {
+                return Err(ParseError::AstError(format!(
+                    "Unexpected operator '{}' in expression",
+                    pairs[pos].as_str()
+                )));
             }
-
-            // Single term
-            parse_term(pairs[0].clone(), verbose)
+            Ok(node)
         }
         Rule::term => parse_term(pair, verbose),
         _ => parse_term(pair, verbose), // Try parsing as a term
     }
 }
 
-// Parse expression with operator precedence using precedence climbing
+// Parse the operators and terms of an expression from `pos` onwards (Pratt
+// parsing), stopping at the first binary operator that binds less tightly
+// than `min_power`
 fn parse_expression_with_precedence(
     pairs: &[Pair<Rule>],
-    min_precedence: u8,
+    pos: &mut usize,
+    min_power: u8,
     verbose: bool,
 ) -> Result<AstNode, ParseError> {
-    if pairs.is_empty() {
-        return Err(ParseError::AstError("Empty expression in precedence parsing".into()));
-    }
-
-    // Parse the first term
-    let mut left = parse_term(pairs[0].clone(), verbose)?;
-    let mut i = 1;
-
-    // Process operators and terms
-    while i < pairs.len() {
-        if i + 1 >= pairs.len() {
-            break;
-        }
-
-        let op = pairs[i].as_str();
-        let precedence = get_precedence(op);
-
-        if precedence < min_precedence {
+    let first = pairs
+        .get(*pos)
+        .ok_or_else(|| ParseError::AstError("Empty expression in precedence parsing".into()))?
+        .clone();
+    *pos += 1;
+
+    let mut left = match first.as_rule() {
+        Rule::op_not | Rule::op_neg | Rule::op_len | Rule::op_bnot => {
+            let operand = parse_expression_with_precedence(pairs, pos, UNARY_BINDING_POWER, verbose)?;
+            parse_unary(first, operand)
+        }
+        _ => parse_term(first, verbose)?,
+    };
+
+    while let Some(op) = pairs.get(*pos) {
+        let (left_power, right_power) = match infix_binding_power(op.as_str()) {
+            Some(power) => power,
+            None => break,
+        };
+        if left_power < min_power {
             break;
         }
 
-        let op_str = op.to_string();
-        i += 1; // Move past operator
+        let op_str = op.as_str().to_string();
+        let position = position_of(op);
+        *pos += 1; // Move past operator
 
-        // Parse the right side with higher precedence
-        let mut right = parse_term(pairs[i].clone(), verbose)?;
-        i += 1; // Move past right term
-
-        // Look ahead for higher precedence operators
-        while i < pairs.len() {
-            if i + 1 >= pairs.len() {
-                break;
-            }
-
-            let next_op = pairs[i].as_str();
-            let next_precedence = get_precedence(next_op);
-
-            if next_precedence <= precedence {
-                break;
-            }
-
-            // Build the right subtree with remaining tokens
-            let remaining = &pairs[i - 1..];
-            right = parse_expression_with_precedence(remaining, precedence + 1, verbose)?;
-
-            // Calculate how many tokens were consumed
-            // This is simplified - we consumed everything from i-1 onwards into right
-            i = pairs.len();
-            break;
-        }
-
-        left = AstNode::BinaryOp(Box::new(left), op_str, Box::new(right));
+        let right = parse_expression_with_precedence(pairs, pos, right_power, verbose)?;
+        left = located(position, AstNode::BinaryOp(Box::new(left), op_str, Box::new(right)));
     }
 
     Ok(left)
 }
 
+// Apply a prefix operator. Negative number literals are folded into the
+// literal, so `-1` is still a plain number.
+fn parse_unary(op: Pair<Rule>, operand: AstNode) -> AstNode {
+    match (op.as_rule(), operand) {
+        (Rule::op_not, operand) => AstNode::Not(Box::new(operand)),
+        (Rule::op_neg, AstNode::Number(n)) => AstNode::Number(-n),
+        (_, operand) => located(position_of(&op), AstNode::UnaryOp(op.as_str().to_string(), Box::new(operand))),
+    }
+}
+
 fn parse_term(pair: Pair<Rule>, verbose: bool) -> Result<AstNode, ParseError> {
     match pair.as_rule() {
         Rule::number => {
@@ -588,28 +848,15 @@ fn parse_term(pair: Pair<Rule>, verbose: bool) -> Result<AstNode, ParseError> {
             })?;
             Ok(AstNode::Number(value))
         }
-        Rule::string => {
-            // Remove the quotes from the string literal
-            let text = pair.as_str();
-            let value = &text[1..text.len() - 1];
-            Ok(AstNode::String(value.to_string()))
-        }
+        Rule::string => parse_string(pair, verbose),
         Rule::boolean => {
             let value = pair.as_str() == "true";
             Ok(AstNode::Boolean(value))
         }
         Rule::nil => Ok(AstNode::Nil),
-        Rule::not_expr => {
-            let inner = pair.into_inner().next().ok_or_else(|| {
-                ParseError::AstError("Missing operand for not expression".into())
-            })?;
-            let operand = parse_term(inner, verbose)?;
-            Ok(AstNode::Not(Box::new(operand)))
-        }
+        Rule::varargs => Ok(AstNode::Varargs),
         Rule::identifier => Ok(AstNode::Identifier(pair.as_str().to_string())),
-        Rule::function_call => parse_function_call(pair, verbose),
-        Rule::method_call => parse_method_call(pair, verbose),
-        Rule::member_access => parse_member_access(pair, verbose),
+        Rule::postfix_expr | Rule::call_expr | Rule::assign_target => parse_postfix(pair, verbose),
         Rule::lambda => parse_lambda(pair, verbose),
         Rule::dictionary => parse_dictionary(pair, verbose),
         Rule::table => {
@@ -619,15 +866,7 @@ fn parse_term(pair: Pair<Rule>, verbose: bool) -> Result<AstNode, ParseError> {
             }
             Ok(AstNode::Table(elements))
         }
-        Rule::index_access => {
-            let inner: Vec<_> = pair.into_inner().collect();
-            if inner.len() < 2 {
-                return Err(ParseError::AstError("Invalid index access".into()));
-            }
-            let base = parse_term(inner[0].clone(), verbose)?;
-            let index = parse_expression(inner[1].clone(), verbose)?;
-            Ok(AstNode::Index(Box::new(base), Box::new(index)))
-        }
+        Rule::expression => parse_expression(pair, verbose),
         Rule::op_add | Rule::op_sub | Rule::op_mul | Rule::op_div | Rule::op_mod | Rule::op_concat => {
             // Binary operators are handled in parse_expression, this should not happen
             Err(ParseError::AstError(format!(
@@ -649,3 +888,76 @@ fn parse_term(pair: Pair<Rule>, verbose: bool) -> Result<AstNode, ParseError> {
         }
     }
 }
+
+/// Build a string literal: its text with escapes decoded, joined with `..`
+/// to any `${...}` interpolations. Long strings are taken verbatim, minus a
+/// newline directly after the opening `[=[`.
+fn parse_string(pair: Pair<Rule>, verbose: bool) -> Result<AstNode, ParseError> {
+    let mut parts = Vec::new();
+    let mut text = String::new();
+    for part in pair.into_inner() {
+        match part.as_rule() {
+            Rule::dq_text | Rule::sq_text => text.push_str(part.as_str()),
+            Rule::escape => text.push(unescape(part.as_str())?),
+            Rule::long_string => {
+                let raw = part.as_str();
+                let level = raw[1..].find('[').unwrap_or(0) + 2;
+                let body = &raw[level..raw.len() - level];
+                let body = body.strip_prefix("\r\n").or_else(|| body.strip_prefix('\n')).unwrap_or(body);
+                text.push_str(body);
+            }
+            Rule::interpolation => {
+                if !text.is_empty() {
+                    parts.push(AstNode::String(std::mem::take(&mut text)));
+                }
+                let expr = part.into_inner().next().ok_or_else(|| {
+                    ParseError::AstError("Missing expression in string interpolation".into())
+                })?;
+                parts.push(parse_expression(expr, verbose)?);
+            }
+            _ => {}
+        }
+    }
+    if !text.is_empty() || parts.is_empty() {
+        parts.push(AstNode::String(text));
+    }
+
+    let mut parts = parts.into_iter();
+    // Starting from a string makes `"${x}"` a string even when x is not
+    let mut node = match parts.next() {
+        Some(AstNode::String(s)) => AstNode::String(s),
+        Some(expr) => AstNode::BinaryOp(Box::new(AstNode::String(String::new())), "..".to_string(), Box::new(expr)),
+        None => AstNode::String(String::new()),
+    };
+    for part in parts {
+        node = AstNode::BinaryOp(Box::new(node), "..".to_string(), Box::new(part));
+    }
+    Ok(node)
+}
+
+/// The text of a string literal that must not contain interpolations, such
+/// as an import path or a dictionary key
+fn literal_string(pair: Pair<Rule>, verbose: bool) -> Result<String, ParseError> {
+    match parse_string(pair, verbose)? {
+        AstNode::String(s) => Ok(s),
+        _ => Err(ParseError::AstError("String interpolation is not allowed here".into())),
+    }
+}
+
+/// Decode one escape sequence such as `\n` or `\u{e9}`
+fn unescape(escape: &str) -> Result<char, ParseError> {
+    let c = match &escape[1..] {
+        "n" => '\n',
+        "t" => '\t',
+        "r" => '\r',
+        other if other.starts_with("u{") => {
+            let hex = &other[2..other.len() - 1];
+            u32::from_str_radix(hex, 16)
+                .ok()
+                .and_then(char::from_u32)
+                .ok_or_else(|| ParseError::AstError(format!("Invalid unicode escape {}", escape)))?
+        }
+        other => other.chars().next().unwrap_or('\\'),
+    };
+    Ok(c)
+}
@@ -1,15 +1,14 @@
-use crate::{compiler, parser};
+use crate::compiler;
 use rustyline::DefaultEditor;
-use std::collections::HashMap;
 
-pub fn run_repl(verbose: bool) {
+pub fn run_repl(verbose: bool, lenient: bool) {
     let mut rl = DefaultEditor::new().unwrap();
-    let mut vars: HashMap<String, compiler::Value> = HashMap::new();
+    let mut vm = new_vm(verbose, lenient);
 
     println!("Pickup REPL v0.2.0");
     println!("Type 'exit' or Ctrl+C to quit");
     println!("Use '\\' at end of line for multi-line input");
-    println!("");
+    println!();
 
     let mut buffer = String::new();
     let mut in_multiline = false;
@@ -34,18 +33,20 @@ pub fn run_repl(verbose: bool) {
 
         // Check for clear command
         if !in_multiline && line.trim() == "clear" {
-            vars.clear();
+            vm = new_vm(verbose, lenient);
             println!("Variables cleared.");
             continue;
         }
 
         // Check for vars command
         if !in_multiline && line.trim() == "vars" {
+            // Built-in and host functions are not variables the user defined
+            let vars: Vec<_> = vm.globals().iter().filter(|(_, value)| !matches!(value, compiler::Value::NativeFunction(..))).collect();
             if vars.is_empty() {
                 println!("No variables defined.");
             } else {
                 println!("Variables:");
-                for (name, value) in &vars {
+                for (name, value) in vars {
                     println!("  {} = {}", name, value);
                 }
             }
@@ -82,12 +83,10 @@ pub fn run_repl(verbose: bool) {
             continue;
         }
 
-        match execute_input(&input, verbose) {
-            Ok(result) => {
-                if let Some(val) = result {
-                    if !matches!(val, compiler::Value::Nil) {
-                        println!("=> {}", val);
-                    }
+        match vm.eval(&input) {
+            Ok(val) => {
+                if !matches!(val, compiler::Value::Nil) {
+                    println!("=> {}", val);
                 }
             }
             Err(e) => eprintln!("Error: {}", e),
@@ -103,7 +102,7 @@ fn count_open_constructs(input: &str) -> i32 {
 
     for word in words {
         match word {
-            "if" | "while" | "for" | "function" | "try" => count += 1,
+            "if" | "while" | "for" | "function" | "try" | "class" | "match" => count += 1,
             "end" => count -= 1,
             _ => {}
         }
@@ -121,14 +120,12 @@ fn count_open_constructs(input: &str) -> i32 {
     count.max(0)
 }
 
-fn execute_input(input: &str, verbose: bool) -> Result<Option<compiler::Value>, String> {
-    let tokens = parser::tokenize(input, verbose)
-        .map_err(|e| format!("{}", e))?;
-    let ast = parser::parse_to_ast(tokens, verbose)
-        .map_err(|e| format!("{}", e))?;
-    let code = compiler::Compiler::compile(&ast, verbose);
-    compiler::Vm::execute(&code, verbose);
-    Ok(None)
+/// A VM whose globals persist across REPL inputs
+fn new_vm(verbose: bool, lenient: bool) -> compiler::Vm {
+    let mut vm = compiler::Vm::new();
+    vm.set_verbose(verbose);
+    vm.set_lenient(lenient);
+    vm
 }
 
 fn print_help() {
@@ -137,7 +134,7 @@ fn print_help() {
     println!("  exit   - Exit the REPL");
     println!("  clear  - Clear all variables");
     println!("  vars   - Show all defined variables");
-    println!("");
+    println!();
     println!("Language Features:");
     println!("  Variables:    x = 10");
     println!("  Local vars:   local x = 10");
@@ -150,7 +147,7 @@ fn print_help() {
     println!("  While:        while i < 10 do i = i + 1 end");
     println!("  For:          for i = 1, 10 do print(i) end");
     println!("  Try/Catch:    try throw \"error\" catch e print(e) end");
-    println!("");
+    println!();
     println!("Standard Library Modules:");
     println!("  import \"math\"   - Math functions (floor, ceil, sqrt, sin, cos, etc.)");
     println!("  import \"string\" - String functions (upper, lower, split, replace, etc.)");
@@ -159,7 +156,7 @@ fn print_help() {
     println!("  import \"json\"   - JSON parsing (parse, stringify)");
     println!("  import \"type\"   - Type utilities (typeof, tonumber, tostring, etc.)");
     println!("  import \"os\"     - OS utilities (time, getenv, execute, sleep)");
-    println!("");
+    println!();
     println!("Multi-line input:");
     println!("  End a line with '\\' to continue on the next line");
     println!("  Or simply type if/while/for/function and press Enter");
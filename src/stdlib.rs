@@ -1,5 +1,6 @@
-use crate::compiler::Value;
+use crate::compiler::{ErrorKind, RuntimeError, Value};
 use std::collections::HashMap;
+use std::rc::Rc;
 use std::fs;
 use std::io::{Write, Read};
 
@@ -559,13 +560,14 @@ fn create_url_module() -> Value {
     Value::Module(url_funcs)
 }
 
-/// Call a native function
-pub fn call_native(module: &str, func: &str, args: Vec<Value>, verbose: bool) -> Value {
+/// Call a native function. Fails with a `NameError` when `module` has no
+/// function called `func`.
+pub fn call_native(module: &str, func: &str, args: Vec<Value>, verbose: bool) -> Result<Value, RuntimeError> {
     if verbose {
         println!("  Calling native {}.{} with {:?}", module, func, args);
     }
 
-    match module {
+    let result = match module {
         "math" => call_math(func, args),
         "string" => call_string(func, args),
         "array" => call_array(func, args),
@@ -586,12 +588,13 @@ pub fn call_native(module: &str, func: &str, args: Vec<Value>, verbose: bool) ->
         "io" => call_io(func, args),
         "log" => call_log(func, args),
         "url" => call_url(func, args),
-        _ => Value::Nil,
-    }
+        _ => None,
+    };
+    result.ok_or_else(|| RuntimeError::new(ErrorKind::NameError, format!("unknown function {}.{}", module, func)))
 }
 
-fn call_math(func: &str, args: Vec<Value>) -> Value {
-    match func {
+fn call_math(func: &str, args: Vec<Value>) -> Option<Value> {
+    let result = match func {
         "floor" => {
             if let Some(Value::Number(n)) = args.first() {
                 Value::Number(n.floor())
@@ -629,7 +632,7 @@ fn call_math(func: &str, args: Vec<Value>) -> Value {
         }
         "pow" => {
             if args.len() >= 2 {
-                if let (Some(Value::Number(base)), Some(Value::Number(exp))) = (args.get(0), args.get(1)) {
+                if let (Some(Value::Number(base)), Some(Value::Number(exp))) = (args.first(), args.get(1)) {
                     Value::Number(base.powf(*exp))
                 } else {
                     Value::Nil
@@ -682,7 +685,7 @@ fn call_math(func: &str, args: Vec<Value>) -> Value {
         }
         "atan2" => {
             if args.len() >= 2 {
-                if let (Some(Value::Number(y)), Some(Value::Number(x))) = (args.get(0), args.get(1)) {
+                if let (Some(Value::Number(y)), Some(Value::Number(x))) = (args.first(), args.get(1)) {
                     Value::Number(y.atan2(*x))
                 } else {
                     Value::Nil
@@ -714,7 +717,7 @@ fn call_math(func: &str, args: Vec<Value>) -> Value {
         }
         "min" => {
             if args.len() >= 2 {
-                if let (Some(Value::Number(a)), Some(Value::Number(b))) = (args.get(0), args.get(1)) {
+                if let (Some(Value::Number(a)), Some(Value::Number(b))) = (args.first(), args.get(1)) {
                     Value::Number(a.min(*b))
                 } else {
                     Value::Nil
@@ -725,7 +728,7 @@ fn call_math(func: &str, args: Vec<Value>) -> Value {
         }
         "max" => {
             if args.len() >= 2 {
-                if let (Some(Value::Number(a)), Some(Value::Number(b))) = (args.get(0), args.get(1)) {
+                if let (Some(Value::Number(a)), Some(Value::Number(b))) = (args.first(), args.get(1)) {
                     Value::Number(a.max(*b))
                 } else {
                     Value::Nil
@@ -746,7 +749,7 @@ fn call_math(func: &str, args: Vec<Value>) -> Value {
         }
         "randomint" => {
             if args.len() >= 2 {
-                if let (Some(Value::Number(min)), Some(Value::Number(max))) = (args.get(0), args.get(1)) {
+                if let (Some(Value::Number(min)), Some(Value::Number(max))) = (args.first(), args.get(1)) {
                     use std::time::{SystemTime, UNIX_EPOCH};
                     let seed = SystemTime::now()
                         .duration_since(UNIX_EPOCH)
@@ -773,7 +776,7 @@ fn call_math(func: &str, args: Vec<Value>) -> Value {
         "clamp" => {
             if args.len() >= 3 {
                 if let (Some(Value::Number(val)), Some(Value::Number(min)), Some(Value::Number(max))) =
-                    (args.get(0), args.get(1), args.get(2))
+                    (args.first(), args.get(1), args.get(2))
                 {
                     Value::Number(val.max(*min).min(*max))
                 } else {
@@ -792,7 +795,7 @@ fn call_math(func: &str, args: Vec<Value>) -> Value {
         }
         "hypot" => {
             if args.len() >= 2 {
-                if let (Some(Value::Number(x)), Some(Value::Number(y))) = (args.get(0), args.get(1)) {
+                if let (Some(Value::Number(x)), Some(Value::Number(y))) = (args.first(), args.get(1)) {
                     Value::Number(x.hypot(*y))
                 } else {
                     Value::Nil
@@ -839,7 +842,7 @@ fn call_math(func: &str, args: Vec<Value>) -> Value {
         "lerp" => {
             if args.len() >= 3 {
                 if let (Some(Value::Number(a)), Some(Value::Number(b)), Some(Value::Number(t))) =
-                    (args.get(0), args.get(1), args.get(2))
+                    (args.first(), args.get(1), args.get(2))
                 {
                     Value::Number(a + (b - a) * t)
                 } else {
@@ -865,7 +868,7 @@ fn call_math(func: &str, args: Vec<Value>) -> Value {
         }
         "fmod" => {
             if args.len() >= 2 {
-                if let (Some(Value::Number(x)), Some(Value::Number(y))) = (args.get(0), args.get(1)) {
+                if let (Some(Value::Number(x)), Some(Value::Number(y))) = (args.first(), args.get(1)) {
                     Value::Number(x % y)
                 } else {
                     Value::Nil
@@ -897,7 +900,7 @@ fn call_math(func: &str, args: Vec<Value>) -> Value {
         }
         "gcd" => {
             if args.len() >= 2 {
-                if let (Some(Value::Number(a)), Some(Value::Number(b))) = (args.get(0), args.get(1)) {
+                if let (Some(Value::Number(a)), Some(Value::Number(b))) = (args.first(), args.get(1)) {
                     let mut a = a.abs() as u64;
                     let mut b = b.abs() as u64;
                     while b != 0 {
@@ -915,7 +918,7 @@ fn call_math(func: &str, args: Vec<Value>) -> Value {
         }
         "lcm" => {
             if args.len() >= 2 {
-                if let (Some(Value::Number(a)), Some(Value::Number(b))) = (args.get(0), args.get(1)) {
+                if let (Some(Value::Number(a)), Some(Value::Number(b))) = (args.first(), args.get(1)) {
                     let a_abs = a.abs() as u64;
                     let b_abs = b.abs() as u64;
                     if a_abs == 0 || b_abs == 0 {
@@ -957,6 +960,7 @@ fn call_math(func: &str, args: Vec<Value>) -> Value {
         }
         "sum" => {
             if let Some(Value::Table(arr)) = args.first() {
+                let arr = arr.borrow();
                 let sum: f64 = arr.iter()
                     .filter_map(|v| if let Value::Number(n) = v { Some(*n) } else { None })
                     .sum();
@@ -967,6 +971,7 @@ fn call_math(func: &str, args: Vec<Value>) -> Value {
         }
         "product" => {
             if let Some(Value::Table(arr)) = args.first() {
+                let arr = arr.borrow();
                 let product: f64 = arr.iter()
                     .filter_map(|v| if let Value::Number(n) = v { Some(*n) } else { None })
                     .product();
@@ -977,6 +982,7 @@ fn call_math(func: &str, args: Vec<Value>) -> Value {
         }
         "mean" => {
             if let Some(Value::Table(arr)) = args.first() {
+                let arr = arr.borrow();
                 let numbers: Vec<f64> = arr.iter()
                     .filter_map(|v| if let Value::Number(n) = v { Some(*n) } else { None })
                     .collect();
@@ -992,6 +998,7 @@ fn call_math(func: &str, args: Vec<Value>) -> Value {
         }
         "median" => {
             if let Some(Value::Table(arr)) = args.first() {
+                let arr = arr.borrow();
                 let mut numbers: Vec<f64> = arr.iter()
                     .filter_map(|v| if let Value::Number(n) = v { Some(*n) } else { None })
                     .collect();
@@ -1000,7 +1007,7 @@ fn call_math(func: &str, args: Vec<Value>) -> Value {
                 } else {
                     numbers.sort_by(|a, b| a.partial_cmp(b).unwrap());
                     let mid = numbers.len() / 2;
-                    if numbers.len() % 2 == 0 {
+                    if numbers.len().is_multiple_of(2) {
                         Value::Number((numbers[mid - 1] + numbers[mid]) / 2.0)
                     } else {
                         Value::Number(numbers[mid])
@@ -1012,6 +1019,7 @@ fn call_math(func: &str, args: Vec<Value>) -> Value {
         }
         "variance" => {
             if let Some(Value::Table(arr)) = args.first() {
+                let arr = arr.borrow();
                 let numbers: Vec<f64> = arr.iter()
                     .filter_map(|v| if let Value::Number(n) = v { Some(*n) } else { None })
                     .collect();
@@ -1030,6 +1038,7 @@ fn call_math(func: &str, args: Vec<Value>) -> Value {
         }
         "stddev" => {
             if let Some(Value::Table(arr)) = args.first() {
+                let arr = arr.borrow();
                 let numbers: Vec<f64> = arr.iter()
                     .filter_map(|v| if let Value::Number(n) = v { Some(*n) } else { None })
                     .collect();
@@ -1053,13 +1062,13 @@ fn call_math(func: &str, args: Vec<Value>) -> Value {
                     Value::Boolean(false)
                 } else if n == 2 {
                     Value::Boolean(true)
-                } else if n % 2 == 0 {
+                } else if n.is_multiple_of(2) {
                     Value::Boolean(false)
                 } else {
                     let sqrt_n = (n as f64).sqrt() as u64;
                     let mut is_prime = true;
                     for i in (3..=sqrt_n).step_by(2) {
-                        if n % i == 0 {
+                        if n.is_multiple_of(i) {
                             is_prime = false;
                             break;
                         }
@@ -1096,7 +1105,7 @@ fn call_math(func: &str, args: Vec<Value>) -> Value {
             if args.len() >= 5 {
                 if let (Some(Value::Number(v)), Some(Value::Number(in_min)), Some(Value::Number(in_max)),
                         Some(Value::Number(out_min)), Some(Value::Number(out_max))) =
-                    (args.get(0), args.get(1), args.get(2), args.get(3), args.get(4))
+                    (args.first(), args.get(1), args.get(2), args.get(3), args.get(4))
                 {
                     let result = (v - in_min) * (out_max - out_min) / (in_max - in_min) + out_min;
                     Value::Number(result)
@@ -1107,12 +1116,13 @@ fn call_math(func: &str, args: Vec<Value>) -> Value {
                 Value::Nil
             }
         }
-        _ => Value::Nil,
-    }
+        _ => return None,
+    };
+    Some(result)
 }
 
-fn call_string(func: &str, args: Vec<Value>) -> Value {
-    match func {
+fn call_string(func: &str, args: Vec<Value>) -> Option<Value> {
+    let result = match func {
         "length" => {
             if let Some(Value::String(s)) = args.first() {
                 Value::Number(s.len() as f64)
@@ -1144,7 +1154,7 @@ fn call_string(func: &str, args: Vec<Value>) -> Value {
         "substring" => {
             if args.len() >= 3 {
                 if let (Some(Value::String(s)), Some(Value::Number(start)), Some(Value::Number(end))) =
-                    (args.get(0), args.get(1), args.get(2))
+                    (args.first(), args.get(1), args.get(2))
                 {
                     let start = *start as usize;
                     let end = *end as usize;
@@ -1157,7 +1167,7 @@ fn call_string(func: &str, args: Vec<Value>) -> Value {
                     Value::Nil
                 }
             } else if args.len() >= 2 {
-                if let (Some(Value::String(s)), Some(Value::Number(start))) = (args.get(0), args.get(1)) {
+                if let (Some(Value::String(s)), Some(Value::Number(start))) = (args.first(), args.get(1)) {
                     let start = *start as usize;
                     if start <= s.len() {
                         Value::String(s[start..].to_string())
@@ -1173,11 +1183,11 @@ fn call_string(func: &str, args: Vec<Value>) -> Value {
         }
         "split" => {
             if args.len() >= 2 {
-                if let (Some(Value::String(s)), Some(Value::String(delim))) = (args.get(0), args.get(1)) {
+                if let (Some(Value::String(s)), Some(Value::String(delim))) = (args.first(), args.get(1)) {
                     let parts: Vec<Value> = s.split(delim.as_str())
                         .map(|p| Value::String(p.to_string()))
                         .collect();
-                    Value::Table(parts)
+                    Value::table(parts)
                 } else {
                     Value::Nil
                 }
@@ -1188,7 +1198,7 @@ fn call_string(func: &str, args: Vec<Value>) -> Value {
         "replace" => {
             if args.len() >= 3 {
                 if let (Some(Value::String(s)), Some(Value::String(from)), Some(Value::String(to))) =
-                    (args.get(0), args.get(1), args.get(2))
+                    (args.first(), args.get(1), args.get(2))
                 {
                     Value::String(s.replace(from.as_str(), to.as_str()))
                 } else {
@@ -1200,7 +1210,7 @@ fn call_string(func: &str, args: Vec<Value>) -> Value {
         }
         "contains" => {
             if args.len() >= 2 {
-                if let (Some(Value::String(s)), Some(Value::String(sub))) = (args.get(0), args.get(1)) {
+                if let (Some(Value::String(s)), Some(Value::String(sub))) = (args.first(), args.get(1)) {
                     Value::Boolean(s.contains(sub.as_str()))
                 } else {
                     Value::Nil
@@ -1211,7 +1221,7 @@ fn call_string(func: &str, args: Vec<Value>) -> Value {
         }
         "starts_with" => {
             if args.len() >= 2 {
-                if let (Some(Value::String(s)), Some(Value::String(prefix))) = (args.get(0), args.get(1)) {
+                if let (Some(Value::String(s)), Some(Value::String(prefix))) = (args.first(), args.get(1)) {
                     Value::Boolean(s.starts_with(prefix.as_str()))
                 } else {
                     Value::Nil
@@ -1222,7 +1232,7 @@ fn call_string(func: &str, args: Vec<Value>) -> Value {
         }
         "ends_with" => {
             if args.len() >= 2 {
-                if let (Some(Value::String(s)), Some(Value::String(suffix))) = (args.get(0), args.get(1)) {
+                if let (Some(Value::String(s)), Some(Value::String(suffix))) = (args.first(), args.get(1)) {
                     Value::Boolean(s.ends_with(suffix.as_str()))
                 } else {
                     Value::Nil
@@ -1233,7 +1243,7 @@ fn call_string(func: &str, args: Vec<Value>) -> Value {
         }
         "find" => {
             if args.len() >= 2 {
-                if let (Some(Value::String(s)), Some(Value::String(sub))) = (args.get(0), args.get(1)) {
+                if let (Some(Value::String(s)), Some(Value::String(sub))) = (args.first(), args.get(1)) {
                     match s.find(sub.as_str()) {
                         Some(pos) => Value::Number(pos as f64),
                         None => Value::Number(-1.0),
@@ -1276,7 +1286,7 @@ fn call_string(func: &str, args: Vec<Value>) -> Value {
         }
         "repeat" => {
             if args.len() >= 2 {
-                if let (Some(Value::String(s)), Some(Value::Number(count))) = (args.get(0), args.get(1)) {
+                if let (Some(Value::String(s)), Some(Value::Number(count))) = (args.first(), args.get(1)) {
                     Value::String(s.repeat(*count as usize))
                 } else {
                     Value::Nil
@@ -1301,7 +1311,7 @@ fn call_string(func: &str, args: Vec<Value>) -> Value {
         }
         "pad_left" => {
             if args.len() >= 2 {
-                if let (Some(Value::String(s)), Some(Value::Number(width))) = (args.get(0), args.get(1)) {
+                if let (Some(Value::String(s)), Some(Value::Number(width))) = (args.first(), args.get(1)) {
                     let width = *width as usize;
                     let pad_char = if let Some(Value::String(c)) = args.get(2) {
                         c.chars().next().unwrap_or(' ')
@@ -1311,7 +1321,7 @@ fn call_string(func: &str, args: Vec<Value>) -> Value {
                     if s.len() >= width {
                         Value::String(s.clone())
                     } else {
-                        let padding: String = std::iter::repeat(pad_char).take(width - s.len()).collect();
+                        let padding: String = std::iter::repeat_n(pad_char, width - s.len()).collect();
                         Value::String(format!("{}{}", padding, s))
                     }
                 } else {
@@ -1323,7 +1333,7 @@ fn call_string(func: &str, args: Vec<Value>) -> Value {
         }
         "pad_right" => {
             if args.len() >= 2 {
-                if let (Some(Value::String(s)), Some(Value::Number(width))) = (args.get(0), args.get(1)) {
+                if let (Some(Value::String(s)), Some(Value::Number(width))) = (args.first(), args.get(1)) {
                     let width = *width as usize;
                     let pad_char = if let Some(Value::String(c)) = args.get(2) {
                         c.chars().next().unwrap_or(' ')
@@ -1333,7 +1343,7 @@ fn call_string(func: &str, args: Vec<Value>) -> Value {
                     if s.len() >= width {
                         Value::String(s.clone())
                     } else {
-                        let padding: String = std::iter::repeat(pad_char).take(width - s.len()).collect();
+                        let padding: String = std::iter::repeat_n(pad_char, width - s.len()).collect();
                         Value::String(format!("{}{}", s, padding))
                     }
                 } else {
@@ -1345,7 +1355,7 @@ fn call_string(func: &str, args: Vec<Value>) -> Value {
         }
         "count" => {
             if args.len() >= 2 {
-                if let (Some(Value::String(s)), Some(Value::String(sub))) = (args.get(0), args.get(1)) {
+                if let (Some(Value::String(s)), Some(Value::String(sub))) = (args.first(), args.get(1)) {
                     Value::Number(s.matches(sub.as_str()).count() as f64)
                 } else {
                     Value::Nil
@@ -1417,7 +1427,7 @@ fn call_string(func: &str, args: Vec<Value>) -> Value {
                 let lines: Vec<Value> = s.lines()
                     .map(|line| Value::String(line.to_string()))
                     .collect();
-                Value::Table(lines)
+                Value::table(lines)
             } else {
                 Value::Nil
             }
@@ -1427,14 +1437,14 @@ fn call_string(func: &str, args: Vec<Value>) -> Value {
                 let chars: Vec<Value> = s.chars()
                     .map(|c| Value::String(c.to_string()))
                     .collect();
-                Value::Table(chars)
+                Value::table(chars)
             } else {
                 Value::Nil
             }
         }
         "match" => {
             if args.len() >= 2 {
-                if let (Some(Value::String(s)), Some(Value::String(pattern))) = (args.get(0), args.get(1)) {
+                if let (Some(Value::String(s)), Some(Value::String(pattern))) = (args.first(), args.get(1)) {
                     // Simple glob-style matching (* matches any chars)
                     let regex_pattern = pattern
                         .replace(".", "\\.")
@@ -1454,7 +1464,7 @@ fn call_string(func: &str, args: Vec<Value>) -> Value {
         "replace_first" => {
             if args.len() >= 3 {
                 if let (Some(Value::String(s)), Some(Value::String(from)), Some(Value::String(to))) =
-                    (args.get(0), args.get(1), args.get(2))
+                    (args.first(), args.get(1), args.get(2))
                 {
                     Value::String(s.replacen(from.as_str(), to.as_str(), 1))
                 } else {
@@ -1467,7 +1477,7 @@ fn call_string(func: &str, args: Vec<Value>) -> Value {
         "insert" => {
             if args.len() >= 3 {
                 if let (Some(Value::String(s)), Some(Value::Number(pos)), Some(Value::String(ins))) =
-                    (args.get(0), args.get(1), args.get(2))
+                    (args.first(), args.get(1), args.get(2))
                 {
                     let pos = *pos as usize;
                     if pos <= s.len() {
@@ -1487,7 +1497,7 @@ fn call_string(func: &str, args: Vec<Value>) -> Value {
         "remove" => {
             if args.len() >= 3 {
                 if let (Some(Value::String(s)), Some(Value::Number(start)), Some(Value::Number(end))) =
-                    (args.get(0), args.get(1), args.get(2))
+                    (args.first(), args.get(1), args.get(2))
                 {
                     let start = *start as usize;
                     let end = *end as usize;
@@ -1521,7 +1531,7 @@ fn call_string(func: &str, args: Vec<Value>) -> Value {
         }
         "truncate" => {
             if args.len() >= 2 {
-                if let (Some(Value::String(s)), Some(Value::Number(max_len))) = (args.get(0), args.get(1)) {
+                if let (Some(Value::String(s)), Some(Value::Number(max_len))) = (args.first(), args.get(1)) {
                     let max_len = *max_len as usize;
                     let suffix = args.get(2)
                         .and_then(|v| if let Value::String(s) = v { Some(s.as_str()) } else { None })
@@ -1543,7 +1553,7 @@ fn call_string(func: &str, args: Vec<Value>) -> Value {
         }
         "word_wrap" => {
             if args.len() >= 2 {
-                if let (Some(Value::String(s)), Some(Value::Number(width))) = (args.get(0), args.get(1)) {
+                if let (Some(Value::String(s)), Some(Value::Number(width))) = (args.first(), args.get(1)) {
                     let width = *width as usize;
                     let mut result = String::new();
                     let mut line_len = 0;
@@ -1570,7 +1580,7 @@ fn call_string(func: &str, args: Vec<Value>) -> Value {
         }
         "center" => {
             if args.len() >= 2 {
-                if let (Some(Value::String(s)), Some(Value::Number(width))) = (args.get(0), args.get(1)) {
+                if let (Some(Value::String(s)), Some(Value::Number(width))) = (args.first(), args.get(1)) {
                     let width = *width as usize;
                     let fill = args.get(2)
                         .and_then(|v| if let Value::String(s) = v { s.chars().next() } else { None })
@@ -1621,14 +1631,16 @@ fn call_string(func: &str, args: Vec<Value>) -> Value {
                 Value::Nil
             }
         }
-        _ => Value::Nil,
-    }
+        _ => return None,
+    };
+    Some(result)
 }
 
-fn call_array(func: &str, args: Vec<Value>) -> Value {
-    match func {
+fn call_array(func: &str, args: Vec<Value>) -> Option<Value> {
+    let result = match func {
         "length" => {
             if let Some(Value::Table(arr)) = args.first() {
+                let arr = arr.borrow();
                 Value::Number(arr.len() as f64)
             } else {
                 Value::Nil
@@ -1636,10 +1648,11 @@ fn call_array(func: &str, args: Vec<Value>) -> Value {
         }
         "push" => {
             if args.len() >= 2 {
-                if let (Some(Value::Table(arr)), Some(val)) = (args.get(0), args.get(1)) {
+                if let (Some(Value::Table(arr)), Some(val)) = (args.first(), args.get(1)) {
+                    let arr = arr.borrow();
                     let mut new_arr = arr.clone();
                     new_arr.push(val.clone());
-                    Value::Table(new_arr)
+                    Value::table(new_arr)
                 } else {
                     Value::Nil
                 }
@@ -1649,19 +1662,21 @@ fn call_array(func: &str, args: Vec<Value>) -> Value {
         }
         "pop" => {
             if let Some(Value::Table(arr)) = args.first() {
+                let arr = arr.borrow();
                 let mut new_arr = arr.clone();
                 new_arr.pop();
-                Value::Table(new_arr)
+                Value::table(new_arr)
             } else {
                 Value::Nil
             }
         }
         "shift" => {
             if let Some(Value::Table(arr)) = args.first() {
+                let arr = arr.borrow();
                 if arr.is_empty() {
-                    Value::Table(Vec::new())
+                    Value::table(Vec::new())
                 } else {
-                    Value::Table(arr[1..].to_vec())
+                    Value::table(arr[1..].to_vec())
                 }
             } else {
                 Value::Nil
@@ -1669,10 +1684,11 @@ fn call_array(func: &str, args: Vec<Value>) -> Value {
         }
         "unshift" => {
             if args.len() >= 2 {
-                if let (Some(Value::Table(arr)), Some(val)) = (args.get(0), args.get(1)) {
+                if let (Some(Value::Table(arr)), Some(val)) = (args.first(), args.get(1)) {
+                    let arr = arr.borrow();
                     let mut new_arr = vec![val.clone()];
                     new_arr.extend(arr.clone());
-                    Value::Table(new_arr)
+                    Value::table(new_arr)
                 } else {
                     Value::Nil
                 }
@@ -1683,12 +1699,13 @@ fn call_array(func: &str, args: Vec<Value>) -> Value {
         "slice" => {
             if args.len() >= 3 {
                 if let (Some(Value::Table(arr)), Some(Value::Number(start)), Some(Value::Number(end))) =
-                    (args.get(0), args.get(1), args.get(2))
+                    (args.first(), args.get(1), args.get(2))
                 {
+                    let arr = arr.borrow();
                     let start = *start as usize;
                     let end = *end as usize;
                     if start <= end && end <= arr.len() {
-                        Value::Table(arr[start..end].to_vec())
+                        Value::table(arr[start..end].to_vec())
                     } else {
                         Value::Nil
                     }
@@ -1701,10 +1718,12 @@ fn call_array(func: &str, args: Vec<Value>) -> Value {
         }
         "concat" => {
             if args.len() >= 2 {
-                if let (Some(Value::Table(arr1)), Some(Value::Table(arr2))) = (args.get(0), args.get(1)) {
+                if let (Some(Value::Table(arr1)), Some(Value::Table(arr2))) = (args.first(), args.get(1)) {
+                    let arr1 = arr1.borrow();
+                    let arr2 = arr2.borrow();
                     let mut new_arr = arr1.clone();
                     new_arr.extend(arr2.clone());
-                    Value::Table(new_arr)
+                    Value::table(new_arr)
                 } else {
                     Value::Nil
                 }
@@ -1714,7 +1733,8 @@ fn call_array(func: &str, args: Vec<Value>) -> Value {
         }
         "join" => {
             if args.len() >= 2 {
-                if let (Some(Value::Table(arr)), Some(Value::String(delim))) = (args.get(0), args.get(1)) {
+                if let (Some(Value::Table(arr)), Some(Value::String(delim))) = (args.first(), args.get(1)) {
+                    let arr = arr.borrow();
                     let strings: Vec<String> = arr.iter().map(|v| v.to_string()).collect();
                     Value::String(strings.join(delim.as_str()))
                 } else {
@@ -1726,15 +1746,17 @@ fn call_array(func: &str, args: Vec<Value>) -> Value {
         }
         "reverse" => {
             if let Some(Value::Table(arr)) = args.first() {
+                let arr = arr.borrow();
                 let mut new_arr = arr.clone();
                 new_arr.reverse();
-                Value::Table(new_arr)
+                Value::table(new_arr)
             } else {
                 Value::Nil
             }
         }
         "sort" => {
             if let Some(Value::Table(arr)) = args.first() {
+                let arr = arr.borrow();
                 let mut new_arr = arr.clone();
                 new_arr.sort_by(|a, b| {
                     match (a, b) {
@@ -1743,14 +1765,15 @@ fn call_array(func: &str, args: Vec<Value>) -> Value {
                         _ => std::cmp::Ordering::Equal,
                     }
                 });
-                Value::Table(new_arr)
+                Value::table(new_arr)
             } else {
                 Value::Nil
             }
         }
         "contains" => {
             if args.len() >= 2 {
-                if let Some(Value::Table(arr)) = args.get(0) {
+                if let Some(Value::Table(arr)) = args.first() {
+                    let arr = arr.borrow();
                     let search = args.get(1).unwrap();
                     Value::Boolean(arr.iter().any(|v| v == search))
                 } else {
@@ -1762,7 +1785,8 @@ fn call_array(func: &str, args: Vec<Value>) -> Value {
         }
         "find" => {
             if args.len() >= 2 {
-                if let Some(Value::Table(arr)) = args.get(0) {
+                if let Some(Value::Table(arr)) = args.first() {
+                    let arr = arr.borrow();
                     let search = args.get(1).unwrap();
                     match arr.iter().position(|v| v == search) {
                         Some(pos) => Value::Number(pos as f64),
@@ -1777,7 +1801,7 @@ fn call_array(func: &str, args: Vec<Value>) -> Value {
         }
         "range" => {
             if args.len() >= 2 {
-                if let (Some(Value::Number(start)), Some(Value::Number(end))) = (args.get(0), args.get(1)) {
+                if let (Some(Value::Number(start)), Some(Value::Number(end))) = (args.first(), args.get(1)) {
                     let step = if args.len() >= 3 {
                         if let Some(Value::Number(s)) = args.get(2) { *s } else { 1.0 }
                     } else {
@@ -1789,7 +1813,7 @@ fn call_array(func: &str, args: Vec<Value>) -> Value {
                         arr.push(Value::Number(i));
                         i += step;
                     }
-                    Value::Table(arr)
+                    Value::table(arr)
                 } else {
                     Value::Nil
                 }
@@ -1799,6 +1823,7 @@ fn call_array(func: &str, args: Vec<Value>) -> Value {
         }
         "first" => {
             if let Some(Value::Table(arr)) = args.first() {
+                let arr = arr.borrow();
                 arr.first().cloned().unwrap_or(Value::Nil)
             } else {
                 Value::Nil
@@ -1806,6 +1831,7 @@ fn call_array(func: &str, args: Vec<Value>) -> Value {
         }
         "last" => {
             if let Some(Value::Table(arr)) = args.first() {
+                let arr = arr.borrow();
                 arr.last().cloned().unwrap_or(Value::Nil)
             } else {
                 Value::Nil
@@ -1815,7 +1841,7 @@ fn call_array(func: &str, args: Vec<Value>) -> Value {
             fn flatten_recursive(val: &Value, result: &mut Vec<Value>) {
                 match val {
                     Value::Table(arr) => {
-                        for item in arr {
+                        for item in arr.borrow().iter() {
                             flatten_recursive(item, result);
                         }
                     }
@@ -1823,48 +1849,51 @@ fn call_array(func: &str, args: Vec<Value>) -> Value {
                 }
             }
             if let Some(Value::Table(arr)) = args.first() {
+                let arr = arr.borrow();
                 let mut result = Vec::new();
-                for item in arr {
+                for item in arr.iter() {
                     flatten_recursive(item, &mut result);
                 }
-                Value::Table(result)
+                Value::table(result)
             } else {
                 Value::Nil
             }
         }
         "unique" => {
             if let Some(Value::Table(arr)) = args.first() {
+                let arr = arr.borrow();
                 let mut seen = Vec::new();
                 let mut result = Vec::new();
-                for item in arr {
+                for item in arr.iter() {
                     let key = format!("{:?}", item);
                     if !seen.contains(&key) {
                         seen.push(key);
                         result.push(item.clone());
                     }
                 }
-                Value::Table(result)
+                Value::table(result)
             } else {
                 Value::Nil
             }
         }
         "filter_nil" => {
             if let Some(Value::Table(arr)) = args.first() {
+                let arr = arr.borrow();
                 let result: Vec<Value> = arr.iter()
                     .filter(|v| !matches!(v, Value::Nil))
                     .cloned()
                     .collect();
-                Value::Table(result)
+                Value::table(result)
             } else {
                 Value::Nil
             }
         }
         "fill" => {
             if args.len() >= 2 {
-                if let (Some(Value::Number(size)), Some(val)) = (args.get(0), args.get(1)) {
+                if let (Some(Value::Number(size)), Some(val)) = (args.first(), args.get(1)) {
                     let size = *size as usize;
-                    let result: Vec<Value> = std::iter::repeat(val.clone()).take(size).collect();
-                    Value::Table(result)
+                    let result: Vec<Value> = std::iter::repeat_n(val.clone(), size).collect();
+                    Value::table(result)
                 } else {
                     Value::Nil
                 }
@@ -1875,13 +1904,14 @@ fn call_array(func: &str, args: Vec<Value>) -> Value {
         "insert" => {
             if args.len() >= 3 {
                 if let (Some(Value::Table(arr)), Some(Value::Number(index)), Some(val)) =
-                    (args.get(0), args.get(1), args.get(2))
+                    (args.first(), args.get(1), args.get(2))
                 {
+                    let arr = arr.borrow();
                     let index = *index as usize;
                     let mut result = arr.clone();
                     if index <= result.len() {
                         result.insert(index, val.clone());
-                        Value::Table(result)
+                        Value::table(result)
                     } else {
                         Value::Nil
                     }
@@ -1894,12 +1924,13 @@ fn call_array(func: &str, args: Vec<Value>) -> Value {
         }
         "remove_at" => {
             if args.len() >= 2 {
-                if let (Some(Value::Table(arr)), Some(Value::Number(index))) = (args.get(0), args.get(1)) {
+                if let (Some(Value::Table(arr)), Some(Value::Number(index))) = (args.first(), args.get(1)) {
+                    let arr = arr.borrow();
                     let index = *index as usize;
                     if index < arr.len() {
                         let mut result = arr.clone();
                         result.remove(index);
-                        Value::Table(result)
+                        Value::table(result)
                     } else {
                         Value::Nil
                     }
@@ -1912,8 +1943,9 @@ fn call_array(func: &str, args: Vec<Value>) -> Value {
         }
         "min" => {
             if let Some(Value::Table(arr)) = args.first() {
+                let arr = arr.borrow();
                 let mut min_val: Option<f64> = None;
-                for item in arr {
+                for item in arr.iter() {
                     if let Value::Number(n) = item {
                         min_val = Some(min_val.map_or(*n, |m| m.min(*n)));
                     }
@@ -1925,8 +1957,9 @@ fn call_array(func: &str, args: Vec<Value>) -> Value {
         }
         "max" => {
             if let Some(Value::Table(arr)) = args.first() {
+                let arr = arr.borrow();
                 let mut max_val: Option<f64> = None;
-                for item in arr {
+                for item in arr.iter() {
                     if let Value::Number(n) = item {
                         max_val = Some(max_val.map_or(*n, |m| m.max(*n)));
                     }
@@ -1938,6 +1971,7 @@ fn call_array(func: &str, args: Vec<Value>) -> Value {
         }
         "sum" => {
             if let Some(Value::Table(arr)) = args.first() {
+                let arr = arr.borrow();
                 let sum: f64 = arr.iter()
                     .filter_map(|v| if let Value::Number(n) = v { Some(*n) } else { None })
                     .sum();
@@ -1948,6 +1982,7 @@ fn call_array(func: &str, args: Vec<Value>) -> Value {
         }
         "avg" => {
             if let Some(Value::Table(arr)) = args.first() {
+                let arr = arr.borrow();
                 let numbers: Vec<f64> = arr.iter()
                     .filter_map(|v| if let Value::Number(n) = v { Some(*n) } else { None })
                     .collect();
@@ -1963,12 +1998,14 @@ fn call_array(func: &str, args: Vec<Value>) -> Value {
         }
         "zip" => {
             if args.len() >= 2 {
-                if let (Some(Value::Table(arr1)), Some(Value::Table(arr2))) = (args.get(0), args.get(1)) {
+                if let (Some(Value::Table(arr1)), Some(Value::Table(arr2))) = (args.first(), args.get(1)) {
+                    let arr1 = arr1.borrow();
+                    let arr2 = arr2.borrow();
                     let result: Vec<Value> = arr1.iter()
                         .zip(arr2.iter())
-                        .map(|(a, b)| Value::Table(vec![a.clone(), b.clone()]))
+                        .map(|(a, b)| Value::table(vec![a.clone(), b.clone()]))
                         .collect();
-                    Value::Table(result)
+                    Value::table(result)
                 } else {
                     Value::Nil
                 }
@@ -1978,7 +2015,8 @@ fn call_array(func: &str, args: Vec<Value>) -> Value {
         }
         "count" => {
             if args.len() >= 2 {
-                if let Some(Value::Table(arr)) = args.get(0) {
+                if let Some(Value::Table(arr)) = args.first() {
+                    let arr = arr.borrow();
                     let search = args.get(1).unwrap();
                     let count = arr.iter().filter(|v| *v == search).count();
                     Value::Number(count as f64)
@@ -1991,14 +2029,15 @@ fn call_array(func: &str, args: Vec<Value>) -> Value {
         }
         "copy" => {
             if let Some(Value::Table(arr)) = args.first() {
-                Value::Table(arr.clone())
+                let arr = arr.borrow();
+                Value::table(arr.clone())
             } else {
                 Value::Nil
             }
         }
         "clear" => {
             if args.first().map(|v| matches!(v, Value::Table(_))).unwrap_or(false) {
-                Value::Table(Vec::new())
+                Value::table(Vec::new())
             } else {
                 Value::Nil
             }
@@ -2006,14 +2045,15 @@ fn call_array(func: &str, args: Vec<Value>) -> Value {
         "swap" => {
             if args.len() >= 3 {
                 if let (Some(Value::Table(arr)), Some(Value::Number(i)), Some(Value::Number(j))) =
-                    (args.get(0), args.get(1), args.get(2))
+                    (args.first(), args.get(1), args.get(2))
                 {
+                    let arr = arr.borrow();
                     let i = *i as usize;
                     let j = *j as usize;
                     if i < arr.len() && j < arr.len() {
                         let mut result = arr.clone();
                         result.swap(i, j);
-                        Value::Table(result)
+                        Value::table(result)
                     } else {
                         Value::Nil
                     }
@@ -2027,10 +2067,11 @@ fn call_array(func: &str, args: Vec<Value>) -> Value {
         "shuffle" => {
             use rand::seq::SliceRandom;
             if let Some(Value::Table(arr)) = args.first() {
+                let arr = arr.borrow();
                 let mut result = arr.clone();
                 let mut rng = rand::thread_rng();
                 result.shuffle(&mut rng);
-                Value::Table(result)
+                Value::table(result)
             } else {
                 Value::Nil
             }
@@ -2038,6 +2079,7 @@ fn call_array(func: &str, args: Vec<Value>) -> Value {
         "sample" => {
             use rand::seq::SliceRandom;
             if let Some(Value::Table(arr)) = args.first() {
+                let arr = arr.borrow();
                 let count = args.get(1)
                     .and_then(|v| if let Value::Number(n) = v { Some(*n as usize) } else { None })
                     .unwrap_or(1);
@@ -2048,7 +2090,7 @@ fn call_array(func: &str, args: Vec<Value>) -> Value {
                 if count == 1 && sampled.len() == 1 {
                     sampled.into_iter().next().unwrap_or(Value::Nil)
                 } else {
-                    Value::Table(sampled)
+                    Value::table(sampled)
                 }
             } else {
                 Value::Nil
@@ -2056,15 +2098,16 @@ fn call_array(func: &str, args: Vec<Value>) -> Value {
         }
         "chunk" => {
             if args.len() >= 2 {
-                if let (Some(Value::Table(arr)), Some(Value::Number(size))) = (args.get(0), args.get(1)) {
+                if let (Some(Value::Table(arr)), Some(Value::Number(size))) = (args.first(), args.get(1)) {
+                    let arr = arr.borrow();
                     let size = *size as usize;
                     if size == 0 {
                         Value::Nil
                     } else {
                         let chunks: Vec<Value> = arr.chunks(size)
-                            .map(|chunk| Value::Table(chunk.to_vec()))
+                            .map(|chunk| Value::table(chunk.to_vec()))
                             .collect();
-                        Value::Table(chunks)
+                        Value::table(chunks)
                     }
                 } else {
                     Value::Nil
@@ -2076,12 +2119,13 @@ fn call_array(func: &str, args: Vec<Value>) -> Value {
         "partition" => {
             // Partition array at given index: partition(arr, index) -> [left, right]
             if args.len() >= 2 {
-                if let (Some(Value::Table(arr)), Some(Value::Number(idx))) = (args.get(0), args.get(1)) {
+                if let (Some(Value::Table(arr)), Some(Value::Number(idx))) = (args.first(), args.get(1)) {
+                    let arr = arr.borrow();
                     let idx = (*idx as usize).min(arr.len());
                     let (left, right) = arr.split_at(idx);
-                    Value::Table(vec![
-                        Value::Table(left.to_vec()),
-                        Value::Table(right.to_vec())
+                    Value::table(vec![
+                        Value::table(left.to_vec()),
+                        Value::table(right.to_vec())
                     ])
                 } else {
                     Value::Nil
@@ -2093,15 +2137,16 @@ fn call_array(func: &str, args: Vec<Value>) -> Value {
         "rotate" => {
             // Rotate array by n positions (positive = left, negative = right)
             if args.len() >= 2 {
-                if let (Some(Value::Table(arr)), Some(Value::Number(n))) = (args.get(0), args.get(1)) {
+                if let (Some(Value::Table(arr)), Some(Value::Number(n))) = (args.first(), args.get(1)) {
+                    let arr = arr.borrow();
                     if arr.is_empty() {
-                        Value::Table(arr.clone())
+                        Value::table(arr.clone())
                     } else {
                         let len = arr.len();
                         let n = (*n as i64).rem_euclid(len as i64) as usize;
                         let mut result = arr.clone();
                         result.rotate_left(n);
-                        Value::Table(result)
+                        Value::table(result)
                     }
                 } else {
                     Value::Nil
@@ -2113,9 +2158,10 @@ fn call_array(func: &str, args: Vec<Value>) -> Value {
         "take" => {
             // Take first n elements
             if args.len() >= 2 {
-                if let (Some(Value::Table(arr)), Some(Value::Number(n))) = (args.get(0), args.get(1)) {
+                if let (Some(Value::Table(arr)), Some(Value::Number(n))) = (args.first(), args.get(1)) {
+                    let arr = arr.borrow();
                     let n = (*n as usize).min(arr.len());
-                    Value::Table(arr[..n].to_vec())
+                    Value::table(arr[..n].to_vec())
                 } else {
                     Value::Nil
                 }
@@ -2126,9 +2172,10 @@ fn call_array(func: &str, args: Vec<Value>) -> Value {
         "drop" => {
             // Drop first n elements
             if args.len() >= 2 {
-                if let (Some(Value::Table(arr)), Some(Value::Number(n))) = (args.get(0), args.get(1)) {
+                if let (Some(Value::Table(arr)), Some(Value::Number(n))) = (args.first(), args.get(1)) {
+                    let arr = arr.borrow();
                     let n = (*n as usize).min(arr.len());
-                    Value::Table(arr[n..].to_vec())
+                    Value::table(arr[n..].to_vec())
                 } else {
                     Value::Nil
                 }
@@ -2139,13 +2186,14 @@ fn call_array(func: &str, args: Vec<Value>) -> Value {
         "repeat" => {
             // Repeat array n times
             if args.len() >= 2 {
-                if let (Some(Value::Table(arr)), Some(Value::Number(n))) = (args.get(0), args.get(1)) {
+                if let (Some(Value::Table(arr)), Some(Value::Number(n))) = (args.first(), args.get(1)) {
+                    let arr = arr.borrow();
                     let n = *n as usize;
                     let mut result = Vec::with_capacity(arr.len() * n);
                     for _ in 0..n {
                         result.extend(arr.iter().cloned());
                     }
-                    Value::Table(result)
+                    Value::table(result)
                 } else {
                     Value::Nil
                 }
@@ -2153,12 +2201,13 @@ fn call_array(func: &str, args: Vec<Value>) -> Value {
                 Value::Nil
             }
         }
-        _ => Value::Nil,
-    }
+        _ => return None,
+    };
+    Some(result)
 }
 
-fn call_fs(func: &str, args: Vec<Value>) -> Value {
-    match func {
+fn call_fs(func: &str, args: Vec<Value>) -> Option<Value> {
+    let result = match func {
         "read" => {
             if let Some(Value::String(path)) = args.first() {
                 match fs::read_to_string(path) {
@@ -2171,7 +2220,7 @@ fn call_fs(func: &str, args: Vec<Value>) -> Value {
         }
         "write" => {
             if args.len() >= 2 {
-                if let (Some(Value::String(path)), Some(Value::String(content))) = (args.get(0), args.get(1)) {
+                if let (Some(Value::String(path)), Some(Value::String(content))) = (args.first(), args.get(1)) {
                     match fs::write(path, content) {
                         Ok(_) => Value::Boolean(true),
                         Err(_) => Value::Boolean(false),
@@ -2185,7 +2234,7 @@ fn call_fs(func: &str, args: Vec<Value>) -> Value {
         }
         "append" => {
             if args.len() >= 2 {
-                if let (Some(Value::String(path)), Some(Value::String(content))) = (args.get(0), args.get(1)) {
+                if let (Some(Value::String(path)), Some(Value::String(content))) = (args.first(), args.get(1)) {
                     match fs::OpenOptions::new().append(true).create(true).open(path) {
                         Ok(mut file) => {
                             match file.write_all(content.as_bytes()) {
@@ -2231,7 +2280,7 @@ fn call_fs(func: &str, args: Vec<Value>) -> Value {
                             .filter_map(|e| e.ok())
                             .map(|e| Value::String(e.file_name().to_string_lossy().to_string()))
                             .collect();
-                        Value::Table(files)
+                        Value::table(files)
                     }
                     Err(_) => Value::Nil,
                 }
@@ -2267,7 +2316,7 @@ fn call_fs(func: &str, args: Vec<Value>) -> Value {
         }
         "copy" => {
             if args.len() >= 2 {
-                if let (Some(Value::String(src)), Some(Value::String(dst))) = (args.get(0), args.get(1)) {
+                if let (Some(Value::String(src)), Some(Value::String(dst))) = (args.first(), args.get(1)) {
                     match fs::copy(src, dst) {
                         Ok(_) => Value::Boolean(true),
                         Err(_) => Value::Boolean(false),
@@ -2281,7 +2330,7 @@ fn call_fs(func: &str, args: Vec<Value>) -> Value {
         }
         "rename" => {
             if args.len() >= 2 {
-                if let (Some(Value::String(src)), Some(Value::String(dst))) = (args.get(0), args.get(1)) {
+                if let (Some(Value::String(src)), Some(Value::String(dst))) = (args.first(), args.get(1)) {
                     match fs::rename(src, dst) {
                         Ok(_) => Value::Boolean(true),
                         Err(_) => Value::Boolean(false),
@@ -2407,7 +2456,7 @@ fn call_fs(func: &str, args: Vec<Value>) -> Value {
                 match fs::read(path) {
                     Ok(bytes) => {
                         let arr: Vec<Value> = bytes.iter().map(|b| Value::Number(*b as f64)).collect();
-                        Value::Table(arr)
+                        Value::table(arr)
                     }
                     Err(_) => Value::Nil,
                 }
@@ -2417,7 +2466,8 @@ fn call_fs(func: &str, args: Vec<Value>) -> Value {
         }
         "write_bytes" => {
             if args.len() >= 2 {
-                if let (Some(Value::String(path)), Some(Value::Table(arr))) = (args.get(0), args.get(1)) {
+                if let (Some(Value::String(path)), Some(Value::Table(arr))) = (args.first(), args.get(1)) {
+                    let arr = arr.borrow();
                     let bytes: Vec<u8> = arr.iter()
                         .filter_map(|v| if let Value::Number(n) = v { Some(*n as u8) } else { None })
                         .collect();
@@ -2440,9 +2490,9 @@ fn call_fs(func: &str, args: Vec<Value>) -> Value {
                             .filter_map(|p| p.ok())
                             .map(|p| Value::String(p.to_string_lossy().to_string()))
                             .collect();
-                        Value::Table(result)
+                        Value::table(result)
                     }
-                    Err(_) => Value::Table(vec![]),
+                    Err(_) => Value::table(vec![]),
                 }
             } else {
                 Value::Nil
@@ -2457,7 +2507,7 @@ fn call_fs(func: &str, args: Vec<Value>) -> Value {
                 {
                     result.push(Value::String(entry.path().to_string_lossy().to_string()));
                 }
-                Value::Table(result)
+                Value::table(result)
             } else {
                 Value::Nil
             }
@@ -2482,7 +2532,7 @@ fn call_fs(func: &str, args: Vec<Value>) -> Value {
                                 stat_dict.insert("created".to_string(), Value::Number(dur.as_secs() as f64));
                             }
                         }
-                        Value::Dictionary(stat_dict)
+                        Value::dictionary(stat_dict)
                     }
                     Err(_) => Value::Nil,
                 }
@@ -2509,7 +2559,7 @@ fn call_fs(func: &str, args: Vec<Value>) -> Value {
                         let lines: Vec<Value> = content.lines()
                             .map(|l| Value::String(l.to_string()))
                             .collect();
-                        Value::Table(lines)
+                        Value::table(lines)
                     }
                     Err(_) => Value::Nil,
                 }
@@ -2542,12 +2592,13 @@ fn call_fs(func: &str, args: Vec<Value>) -> Value {
                 Value::Nil
             }
         }
-        _ => Value::Nil,
-    }
+        _ => return None,
+    };
+    Some(result)
 }
 
-fn call_json(func: &str, args: Vec<Value>) -> Value {
-    match func {
+fn call_json(func: &str, args: Vec<Value>) -> Option<Value> {
+    let result = match func {
         "parse" => {
             if let Some(Value::String(s)) = args.first() {
                 match serde_json::from_str::<serde_json::Value>(s) {
@@ -2585,8 +2636,9 @@ fn call_json(func: &str, args: Vec<Value>) -> Value {
                 Value::Nil
             }
         }
-        _ => Value::Nil,
-    }
+        _ => return None,
+    };
+    Some(result)
 }
 
 fn json_to_value(json: serde_json::Value) -> Value {
@@ -2596,54 +2648,67 @@ fn json_to_value(json: serde_json::Value) -> Value {
         serde_json::Value::Number(n) => Value::Number(n.as_f64().unwrap_or(0.0)),
         serde_json::Value::String(s) => Value::String(s),
         serde_json::Value::Array(arr) => {
-            Value::Table(arr.into_iter().map(json_to_value).collect())
+            Value::table(arr.into_iter().map(json_to_value).collect())
         }
         serde_json::Value::Object(obj) => {
             let map: HashMap<String, Value> = obj.into_iter()
                 .map(|(k, v)| (k, json_to_value(v)))
                 .collect();
-            Value::Dictionary(map)
+            Value::dictionary(map)
         }
     }
 }
 
 fn value_to_json(val: &Value) -> serde_json::Value {
-    match val {
+    value_to_json_within(val, &mut Vec::new())
+}
+
+/// Convert a value nested in the tables and dictionaries in `outer`. A
+/// container that contains itself becomes null where it recurs.
+fn value_to_json_within(val: &Value, outer: &mut Vec<usize>) -> serde_json::Value {
+    let ptr = match val {
+        Value::Table(arr) => Rc::as_ptr(arr) as usize,
+        Value::Dictionary(map) => Rc::as_ptr(map) as usize,
+        _ => 0,
+    };
+    if ptr != 0 && outer.contains(&ptr) {
+        return serde_json::Value::Null;
+    }
+    outer.push(ptr);
+    let json = match val {
         Value::Nil => serde_json::Value::Null,
         Value::Boolean(b) => serde_json::Value::Bool(*b),
         Value::Number(n) => serde_json::json!(*n),
         Value::String(s) => serde_json::Value::String(s.clone()),
         Value::Table(arr) => {
-            serde_json::Value::Array(arr.iter().map(value_to_json).collect())
+            serde_json::Value::Array(arr.borrow().iter().map(|v| value_to_json_within(v, outer)).collect())
         }
         Value::Dictionary(map) => {
-            let obj: serde_json::Map<String, serde_json::Value> = map.iter()
-                .map(|(k, v)| (k.clone(), value_to_json(v)))
+            let obj: serde_json::Map<String, serde_json::Value> = map.borrow().iter()
+                .map(|(k, v)| (k.clone(), value_to_json_within(v, outer)))
                 .collect();
             serde_json::Value::Object(obj)
         }
-        Value::Function(_, _) => serde_json::Value::Null,
+        Value::Function(_) => serde_json::Value::Null,
         Value::NativeFunction(_, _) => serde_json::Value::Null,
         Value::Module(_) => serde_json::Value::Null,
-    }
+        Value::Iterator(_) => serde_json::Value::Null,
+        Value::Coroutine(_) => serde_json::Value::Null,
+        Value::Wrapped(_) => serde_json::Value::Null,
+    };
+    outer.pop();
+    json
 }
 
-fn call_type(func: &str, args: Vec<Value>) -> Value {
-    match func {
+fn call_type(func: &str, args: Vec<Value>) -> Option<Value> {
+    let result = match func {
         "typeof" => {
             if let Some(val) = args.first() {
-                let type_name = match val {
-                    Value::Number(_) => "number",
-                    Value::String(_) => "string",
-                    Value::Boolean(_) => "boolean",
-                    Value::Table(_) => "table",
-                    Value::Dictionary(_) => "dictionary",
-                    Value::Function(_, _) => "function",
-                    Value::NativeFunction(_, _) => "function",
-                    Value::Module(_) => "module",
-                    Value::Nil => "nil",
-                };
-                Value::String(type_name.to_string())
+                // Instances of a class report the class name
+                match val.metamethod("__name") {
+                    Some(Value::String(class)) => Value::String(class),
+                    _ => Value::String(val.type_name().to_string()),
+                }
             } else {
                 Value::Nil
             }
@@ -2716,7 +2781,7 @@ fn call_type(func: &str, args: Vec<Value>) -> Value {
         }
         "isfunction" => {
             if let Some(val) = args.first() {
-                Value::Boolean(matches!(val, Value::Function(_, _) | Value::NativeFunction(_, _)))
+                Value::Boolean(matches!(val, Value::Function(_) | Value::NativeFunction(_, _) | Value::Wrapped(_)))
             } else {
                 Value::Nil
             }
@@ -2746,8 +2811,8 @@ fn call_type(func: &str, args: Vec<Value>) -> Value {
             if let Some(val) = args.first() {
                 let empty = match val {
                     Value::String(s) => s.is_empty(),
-                    Value::Table(arr) => arr.is_empty(),
-                    Value::Dictionary(dict) => dict.is_empty(),
+                    Value::Table(arr) => arr.borrow().is_empty(),
+                    Value::Dictionary(dict) => dict.borrow().is_empty(),
                     Value::Nil => true,
                     _ => false,
                 };
@@ -2758,7 +2823,7 @@ fn call_type(func: &str, args: Vec<Value>) -> Value {
         }
         "default" => {
             if args.len() >= 2 {
-                if let Some(val) = args.get(0) {
+                if let Some(val) = args.first() {
                     let default_val = args.get(1).cloned().unwrap_or(Value::Nil);
                     if matches!(val, Value::Nil) {
                         default_val
@@ -2772,12 +2837,13 @@ fn call_type(func: &str, args: Vec<Value>) -> Value {
                 Value::Nil
             }
         }
-        _ => Value::Nil,
-    }
+        _ => return None,
+    };
+    Some(result)
 }
 
-fn call_os(func: &str, args: Vec<Value>) -> Value {
-    match func {
+fn call_os(func: &str, args: Vec<Value>) -> Option<Value> {
+    let result = match func {
         "time" => {
             use std::time::{SystemTime, UNIX_EPOCH};
             let duration = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
@@ -2872,7 +2938,7 @@ fn call_os(func: &str, args: Vec<Value>) -> Value {
         }
         "setenv" => {
             if args.len() >= 2 {
-                if let (Some(Value::String(name)), Some(Value::String(value))) = (args.get(0), args.get(1)) {
+                if let (Some(Value::String(name)), Some(Value::String(value))) = (args.first(), args.get(1)) {
                     std::env::set_var(name, value);
                     Value::Boolean(true)
                 } else {
@@ -2894,7 +2960,7 @@ fn call_os(func: &str, args: Vec<Value>) -> Value {
             let env_map: HashMap<String, Value> = std::env::vars()
                 .map(|(k, v)| (k, Value::String(v)))
                 .collect();
-            Value::Dictionary(env_map)
+            Value::dictionary(env_map)
         }
         "tmpdir" => {
             Value::String(std::env::temp_dir().to_string_lossy().to_string())
@@ -2906,7 +2972,7 @@ fn call_os(func: &str, args: Vec<Value>) -> Value {
             let args: Vec<Value> = std::env::args()
                 .map(Value::String)
                 .collect();
-            Value::Table(args)
+            Value::table(args)
         }
         "user" => {
             match std::env::var("USER").or_else(|_| std::env::var("USERNAME")) {
@@ -2968,13 +3034,13 @@ fn call_os(func: &str, args: Vec<Value>) -> Value {
                 for path in path_var.split(separator) {
                     let full_path = std::path::Path::new(path).join(cmd);
                     if full_path.exists() {
-                        return Value::String(full_path.to_string_lossy().to_string());
+                        return Some(Value::String(full_path.to_string_lossy().to_string()));
                     }
                     // On Windows, try with .exe extension
                     if cfg!(target_os = "windows") {
                         let exe_path = std::path::Path::new(path).join(format!("{}.exe", cmd));
                         if exe_path.exists() {
-                            return Value::String(exe_path.to_string_lossy().to_string());
+                            return Some(Value::String(exe_path.to_string_lossy().to_string()));
                         }
                     }
                 }
@@ -2983,43 +3049,48 @@ fn call_os(func: &str, args: Vec<Value>) -> Value {
                 Value::Nil
             }
         }
-        _ => Value::Nil,
-    }
+        _ => return None,
+    };
+    Some(result)
 }
 
-fn call_table(func: &str, args: Vec<Value>) -> Value {
-    match func {
+fn call_table(func: &str, args: Vec<Value>) -> Option<Value> {
+    let result = match func {
         "keys" => {
             if let Some(Value::Dictionary(dict)) = args.first() {
+                let dict = dict.borrow();
                 let keys: Vec<Value> = dict.keys()
                     .map(|k| Value::String(k.clone()))
                     .collect();
-                Value::Table(keys)
+                Value::table(keys)
             } else {
                 Value::Nil
             }
         }
         "values" => {
             if let Some(Value::Dictionary(dict)) = args.first() {
+                let dict = dict.borrow();
                 let values: Vec<Value> = dict.values().cloned().collect();
-                Value::Table(values)
+                Value::table(values)
             } else {
                 Value::Nil
             }
         }
         "entries" => {
             if let Some(Value::Dictionary(dict)) = args.first() {
+                let dict = dict.borrow();
                 let entries: Vec<Value> = dict.iter()
-                    .map(|(k, v)| Value::Table(vec![Value::String(k.clone()), v.clone()]))
+                    .map(|(k, v)| Value::table(vec![Value::String(k.clone()), v.clone()]))
                     .collect();
-                Value::Table(entries)
+                Value::table(entries)
             } else {
                 Value::Nil
             }
         }
         "has" => {
             if args.len() >= 2 {
-                if let (Some(Value::Dictionary(dict)), Some(Value::String(key))) = (args.get(0), args.get(1)) {
+                if let (Some(Value::Dictionary(dict)), Some(Value::String(key))) = (args.first(), args.get(1)) {
+                    let dict = dict.borrow();
                     Value::Boolean(dict.contains_key(key))
                 } else {
                     Value::Nil
@@ -3030,7 +3101,8 @@ fn call_table(func: &str, args: Vec<Value>) -> Value {
         }
         "get" => {
             if args.len() >= 2 {
-                if let (Some(Value::Dictionary(dict)), Some(Value::String(key))) = (args.get(0), args.get(1)) {
+                if let (Some(Value::Dictionary(dict)), Some(Value::String(key))) = (args.first(), args.get(1)) {
+                    let dict = dict.borrow();
                     let default = args.get(2).cloned().unwrap_or(Value::Nil);
                     dict.get(key).cloned().unwrap_or(default)
                 } else {
@@ -3043,11 +3115,12 @@ fn call_table(func: &str, args: Vec<Value>) -> Value {
         "set" => {
             if args.len() >= 3 {
                 if let (Some(Value::Dictionary(dict)), Some(Value::String(key)), Some(val)) =
-                    (args.get(0), args.get(1), args.get(2))
+                    (args.first(), args.get(1), args.get(2))
                 {
-                    let mut new_dict = dict.clone();
+                    let dict = dict.borrow();
+                    let mut new_dict = dict.entries.clone();
                     new_dict.insert(key.clone(), val.clone());
-                    Value::Dictionary(new_dict)
+                    Value::dictionary(new_dict)
                 } else {
                     Value::Nil
                 }
@@ -3057,10 +3130,11 @@ fn call_table(func: &str, args: Vec<Value>) -> Value {
         }
         "delete" => {
             if args.len() >= 2 {
-                if let (Some(Value::Dictionary(dict)), Some(Value::String(key))) = (args.get(0), args.get(1)) {
-                    let mut new_dict = dict.clone();
+                if let (Some(Value::Dictionary(dict)), Some(Value::String(key))) = (args.first(), args.get(1)) {
+                    let dict = dict.borrow();
+                    let mut new_dict = dict.entries.clone();
                     new_dict.remove(key);
-                    Value::Dictionary(new_dict)
+                    Value::dictionary(new_dict)
                 } else {
                     Value::Nil
                 }
@@ -3070,12 +3144,14 @@ fn call_table(func: &str, args: Vec<Value>) -> Value {
         }
         "merge" => {
             if args.len() >= 2 {
-                if let (Some(Value::Dictionary(dict1)), Some(Value::Dictionary(dict2))) = (args.get(0), args.get(1)) {
-                    let mut new_dict = dict1.clone();
-                    for (k, v) in dict2 {
+                if let (Some(Value::Dictionary(dict1)), Some(Value::Dictionary(dict2))) = (args.first(), args.get(1)) {
+                    let dict1 = dict1.borrow();
+                    let dict2 = dict2.borrow();
+                    let mut new_dict = dict1.entries.clone();
+                    for (k, v) in dict2.iter() {
                         new_dict.insert(k.clone(), v.clone());
                     }
-                    Value::Dictionary(new_dict)
+                    Value::dictionary(new_dict)
                 } else {
                     Value::Nil
                 }
@@ -3085,6 +3161,7 @@ fn call_table(func: &str, args: Vec<Value>) -> Value {
         }
         "size" => {
             if let Some(Value::Dictionary(dict)) = args.first() {
+                let dict = dict.borrow();
                 Value::Number(dict.len() as f64)
             } else {
                 Value::Nil
@@ -3092,23 +3169,26 @@ fn call_table(func: &str, args: Vec<Value>) -> Value {
         }
         "copy" => {
             if let Some(Value::Dictionary(dict)) = args.first() {
-                Value::Dictionary(dict.clone())
+                let dict = dict.borrow();
+                Value::dictionary(dict.entries.clone())
             } else {
                 Value::Nil
             }
         }
         "clear" => {
             if args.first().map(|v| matches!(v, Value::Dictionary(_))).unwrap_or(false) {
-                Value::Dictionary(HashMap::new())
+                Value::dictionary(HashMap::new())
             } else {
                 Value::Nil
             }
         }
         "from_entries" => {
             if let Some(Value::Table(arr)) = args.first() {
+                let arr = arr.borrow();
                 let mut dict = HashMap::new();
-                for entry in arr {
+                for entry in arr.iter() {
                     if let Value::Table(pair) = entry {
+                        let pair = pair.borrow();
                         if pair.len() >= 2 {
                             if let Value::String(key) = &pair[0] {
                                 dict.insert(key.clone(), pair[1].clone());
@@ -3116,32 +3196,34 @@ fn call_table(func: &str, args: Vec<Value>) -> Value {
                         }
                     }
                 }
-                Value::Dictionary(dict)
+                Value::dictionary(dict)
             } else {
                 Value::Nil
             }
         }
         "invert" => {
             if let Some(Value::Dictionary(dict)) = args.first() {
+                let dict = dict.borrow();
                 let mut new_dict = HashMap::new();
-                for (k, v) in dict {
+                for (k, v) in dict.iter() {
                     if let Value::String(val_str) = v {
                         new_dict.insert(val_str.clone(), Value::String(k.clone()));
                     }
                 }
-                Value::Dictionary(new_dict)
+                Value::dictionary(new_dict)
             } else {
                 Value::Nil
             }
         }
-        _ => Value::Nil,
-    }
+        _ => return None,
+    };
+    Some(result)
 }
 
-fn call_base64(func: &str, args: Vec<Value>) -> Value {
+fn call_base64(func: &str, args: Vec<Value>) -> Option<Value> {
     use base64::{Engine as _, engine::general_purpose};
 
-    match func {
+    let result = match func {
         "encode" => {
             if let Some(Value::String(s)) = args.first() {
                 Value::String(general_purpose::STANDARD.encode(s.as_bytes()))
@@ -3164,14 +3246,15 @@ fn call_base64(func: &str, args: Vec<Value>) -> Value {
                 Value::Nil
             }
         }
-        _ => Value::Nil,
-    }
+        _ => return None,
+    };
+    Some(result)
 }
 
-fn call_datetime(func: &str, args: Vec<Value>) -> Value {
+fn call_datetime(func: &str, args: Vec<Value>) -> Option<Value> {
     use chrono::{DateTime, Local, NaiveDateTime, Datelike, Timelike, Duration};
 
-    match func {
+    let result = match func {
         "now" => {
             let now = Local::now();
             Value::Number(now.timestamp() as f64)
@@ -3183,7 +3266,7 @@ fn call_datetime(func: &str, args: Vec<Value>) -> Value {
         }
         "format" => {
             if args.len() >= 2 {
-                if let (Some(Value::Number(ts)), Some(Value::String(fmt))) = (args.get(0), args.get(1)) {
+                if let (Some(Value::Number(ts)), Some(Value::String(fmt))) = (args.first(), args.get(1)) {
                     let datetime = DateTime::from_timestamp(*ts as i64, 0)
                         .map(|d| d.with_timezone(&Local));
                     if let Some(dt) = datetime {
@@ -3209,7 +3292,7 @@ fn call_datetime(func: &str, args: Vec<Value>) -> Value {
         }
         "parse" => {
             if args.len() >= 2 {
-                if let (Some(Value::String(s)), Some(Value::String(fmt))) = (args.get(0), args.get(1)) {
+                if let (Some(Value::String(s)), Some(Value::String(fmt))) = (args.first(), args.get(1)) {
                     match NaiveDateTime::parse_from_str(s, fmt) {
                         Ok(dt) => Value::Number(dt.and_utc().timestamp() as f64),
                         Err(_) => Value::Nil,
@@ -3330,7 +3413,7 @@ fn call_datetime(func: &str, args: Vec<Value>) -> Value {
         }
         "add_days" => {
             if args.len() >= 2 {
-                if let (Some(Value::Number(ts)), Some(Value::Number(days))) = (args.get(0), args.get(1)) {
+                if let (Some(Value::Number(ts)), Some(Value::Number(days))) = (args.first(), args.get(1)) {
                     let datetime = DateTime::from_timestamp(*ts as i64, 0);
                     if let Some(dt) = datetime {
                         let new_dt = dt + Duration::days(*days as i64);
@@ -3347,7 +3430,7 @@ fn call_datetime(func: &str, args: Vec<Value>) -> Value {
         }
         "add_hours" => {
             if args.len() >= 2 {
-                if let (Some(Value::Number(ts)), Some(Value::Number(hours))) = (args.get(0), args.get(1)) {
+                if let (Some(Value::Number(ts)), Some(Value::Number(hours))) = (args.first(), args.get(1)) {
                     let datetime = DateTime::from_timestamp(*ts as i64, 0);
                     if let Some(dt) = datetime {
                         let new_dt = dt + Duration::hours(*hours as i64);
@@ -3364,7 +3447,7 @@ fn call_datetime(func: &str, args: Vec<Value>) -> Value {
         }
         "add_minutes" => {
             if args.len() >= 2 {
-                if let (Some(Value::Number(ts)), Some(Value::Number(minutes))) = (args.get(0), args.get(1)) {
+                if let (Some(Value::Number(ts)), Some(Value::Number(minutes))) = (args.first(), args.get(1)) {
                     let datetime = DateTime::from_timestamp(*ts as i64, 0);
                     if let Some(dt) = datetime {
                         let new_dt = dt + Duration::minutes(*minutes as i64);
@@ -3381,7 +3464,7 @@ fn call_datetime(func: &str, args: Vec<Value>) -> Value {
         }
         "add_seconds" => {
             if args.len() >= 2 {
-                if let (Some(Value::Number(ts)), Some(Value::Number(seconds))) = (args.get(0), args.get(1)) {
+                if let (Some(Value::Number(ts)), Some(Value::Number(seconds))) = (args.first(), args.get(1)) {
                     let datetime = DateTime::from_timestamp(*ts as i64, 0);
                     if let Some(dt) = datetime {
                         let new_dt = dt + Duration::seconds(*seconds as i64);
@@ -3398,7 +3481,7 @@ fn call_datetime(func: &str, args: Vec<Value>) -> Value {
         }
         "diff" => {
             if args.len() >= 2 {
-                if let (Some(Value::Number(ts1)), Some(Value::Number(ts2))) = (args.get(0), args.get(1)) {
+                if let (Some(Value::Number(ts1)), Some(Value::Number(ts2))) = (args.first(), args.get(1)) {
                     // Returns difference in seconds
                     Value::Number(ts1 - ts2)
                 } else {
@@ -3422,7 +3505,7 @@ fn call_datetime(func: &str, args: Vec<Value>) -> Value {
                     dict.insert("second".to_string(), Value::Number(dt.second() as f64));
                     dict.insert("weekday".to_string(), Value::Number(dt.weekday().num_days_from_sunday() as f64));
                     dict.insert("day_of_year".to_string(), Value::Number(dt.ordinal() as f64));
-                    Value::Dictionary(dict)
+                    Value::dictionary(dict)
                 } else {
                     Value::Nil
                 }
@@ -3430,17 +3513,18 @@ fn call_datetime(func: &str, args: Vec<Value>) -> Value {
                 Value::Nil
             }
         }
-        _ => Value::Nil,
-    }
+        _ => return None,
+    };
+    Some(result)
 }
 
-fn call_regex(func: &str, args: Vec<Value>) -> Value {
+fn call_regex(func: &str, args: Vec<Value>) -> Option<Value> {
     use regex::Regex;
 
-    match func {
+    let result = match func {
         "match" => {
             if args.len() >= 2 {
-                if let (Some(Value::String(pattern)), Some(Value::String(text))) = (args.get(0), args.get(1)) {
+                if let (Some(Value::String(pattern)), Some(Value::String(text))) = (args.first(), args.get(1)) {
                     match Regex::new(pattern) {
                         Ok(re) => Value::Boolean(re.is_match(text)),
                         Err(_) => Value::Nil,
@@ -3454,7 +3538,7 @@ fn call_regex(func: &str, args: Vec<Value>) -> Value {
         }
         "find" => {
             if args.len() >= 2 {
-                if let (Some(Value::String(pattern)), Some(Value::String(text))) = (args.get(0), args.get(1)) {
+                if let (Some(Value::String(pattern)), Some(Value::String(text))) = (args.first(), args.get(1)) {
                     match Regex::new(pattern) {
                         Ok(re) => {
                             if let Some(m) = re.find(text) {
@@ -3462,7 +3546,7 @@ fn call_regex(func: &str, args: Vec<Value>) -> Value {
                                 dict.insert("text".to_string(), Value::String(m.as_str().to_string()));
                                 dict.insert("start".to_string(), Value::Number(m.start() as f64));
                                 dict.insert("end".to_string(), Value::Number(m.end() as f64));
-                                Value::Dictionary(dict)
+                                Value::dictionary(dict)
                             } else {
                                 Value::Nil
                             }
@@ -3478,7 +3562,7 @@ fn call_regex(func: &str, args: Vec<Value>) -> Value {
         }
         "find_all" => {
             if args.len() >= 2 {
-                if let (Some(Value::String(pattern)), Some(Value::String(text))) = (args.get(0), args.get(1)) {
+                if let (Some(Value::String(pattern)), Some(Value::String(text))) = (args.first(), args.get(1)) {
                     match Regex::new(pattern) {
                         Ok(re) => {
                             let matches: Vec<Value> = re.find_iter(text)
@@ -3487,10 +3571,10 @@ fn call_regex(func: &str, args: Vec<Value>) -> Value {
                                     dict.insert("text".to_string(), Value::String(m.as_str().to_string()));
                                     dict.insert("start".to_string(), Value::Number(m.start() as f64));
                                     dict.insert("end".to_string(), Value::Number(m.end() as f64));
-                                    Value::Dictionary(dict)
+                                    Value::dictionary(dict)
                                 })
                                 .collect();
-                            Value::Table(matches)
+                            Value::table(matches)
                         }
                         Err(_) => Value::Nil,
                     }
@@ -3504,7 +3588,7 @@ fn call_regex(func: &str, args: Vec<Value>) -> Value {
         "replace" => {
             if args.len() >= 3 {
                 if let (Some(Value::String(pattern)), Some(Value::String(text)), Some(Value::String(replacement))) =
-                    (args.get(0), args.get(1), args.get(2)) {
+                    (args.first(), args.get(1), args.get(2)) {
                     match Regex::new(pattern) {
                         Ok(re) => Value::String(re.replace(text, replacement.as_str()).to_string()),
                         Err(_) => Value::Nil,
@@ -3519,7 +3603,7 @@ fn call_regex(func: &str, args: Vec<Value>) -> Value {
         "replace_all" => {
             if args.len() >= 3 {
                 if let (Some(Value::String(pattern)), Some(Value::String(text)), Some(Value::String(replacement))) =
-                    (args.get(0), args.get(1), args.get(2)) {
+                    (args.first(), args.get(1), args.get(2)) {
                     match Regex::new(pattern) {
                         Ok(re) => Value::String(re.replace_all(text, replacement.as_str()).to_string()),
                         Err(_) => Value::Nil,
@@ -3533,13 +3617,13 @@ fn call_regex(func: &str, args: Vec<Value>) -> Value {
         }
         "split" => {
             if args.len() >= 2 {
-                if let (Some(Value::String(pattern)), Some(Value::String(text))) = (args.get(0), args.get(1)) {
+                if let (Some(Value::String(pattern)), Some(Value::String(text))) = (args.first(), args.get(1)) {
                     match Regex::new(pattern) {
                         Ok(re) => {
                             let parts: Vec<Value> = re.split(text)
                                 .map(|s| Value::String(s.to_string()))
                                 .collect();
-                            Value::Table(parts)
+                            Value::table(parts)
                         }
                         Err(_) => Value::Nil,
                     }
@@ -3566,14 +3650,14 @@ fn call_regex(func: &str, args: Vec<Value>) -> Value {
         }
         "captures" => {
             if args.len() >= 2 {
-                if let (Some(Value::String(pattern)), Some(Value::String(text))) = (args.get(0), args.get(1)) {
+                if let (Some(Value::String(pattern)), Some(Value::String(text))) = (args.first(), args.get(1)) {
                     match Regex::new(pattern) {
                         Ok(re) => {
                             if let Some(caps) = re.captures(text) {
                                 let groups: Vec<Value> = caps.iter()
                                     .map(|m| m.map(|m| Value::String(m.as_str().to_string())).unwrap_or(Value::Nil))
                                     .collect();
-                                Value::Table(groups)
+                                Value::table(groups)
                             } else {
                                 Value::Nil
                             }
@@ -3587,18 +3671,19 @@ fn call_regex(func: &str, args: Vec<Value>) -> Value {
                 Value::Nil
             }
         }
-        _ => Value::Nil,
-    }
+        _ => return None,
+    };
+    Some(result)
 }
 
-fn call_crypto(func: &str, args: Vec<Value>) -> Value {
+fn call_crypto(func: &str, args: Vec<Value>) -> Option<Value> {
     use sha2::{Sha256, Sha512, Digest};
     use md5::Md5;
     use sha1::Sha1;
     use hmac::{Hmac, Mac};
     use rand::Rng;
 
-    match func {
+    let result = match func {
         "md5" => {
             if let Some(Value::String(s)) = args.first() {
                 let result = Md5::digest(s.as_bytes());
@@ -3633,7 +3718,7 @@ fn call_crypto(func: &str, args: Vec<Value>) -> Value {
         }
         "hmac_sha256" => {
             if args.len() >= 2 {
-                if let (Some(Value::String(key)), Some(Value::String(msg))) = (args.get(0), args.get(1)) {
+                if let (Some(Value::String(key)), Some(Value::String(msg))) = (args.first(), args.get(1)) {
                     type HmacSha256 = Hmac<Sha256>;
                     match HmacSha256::new_from_slice(key.as_bytes()) {
                         Ok(mut mac) => {
@@ -3658,7 +3743,7 @@ fn call_crypto(func: &str, args: Vec<Value>) -> Value {
                 let n = *n as usize;
                 let mut rng = rand::thread_rng();
                 let bytes: Vec<Value> = (0..n).map(|_| Value::Number(rng.gen::<u8>() as f64)).collect();
-                Value::Table(bytes)
+                Value::table(bytes)
             } else {
                 Value::Nil
             }
@@ -3676,7 +3761,7 @@ fn call_crypto(func: &str, args: Vec<Value>) -> Value {
         }
         "hash" => {
             if args.len() >= 2 {
-                if let (Some(Value::String(algo)), Some(Value::String(data))) = (args.get(0), args.get(1)) {
+                if let (Some(Value::String(algo)), Some(Value::String(data))) = (args.first(), args.get(1)) {
                     match algo.to_lowercase().as_str() {
                         "md5" => {
                             let result = Md5::digest(data.as_bytes());
@@ -3703,12 +3788,13 @@ fn call_crypto(func: &str, args: Vec<Value>) -> Value {
                 Value::Nil
             }
         }
-        _ => Value::Nil,
-    }
+        _ => return None,
+    };
+    Some(result)
 }
 
-fn call_http(func: &str, args: Vec<Value>) -> Value {
-    match func {
+fn call_http(func: &str, args: Vec<Value>) -> Option<Value> {
+    let result = match func {
         "get" => {
             if let Some(Value::String(url)) = args.first() {
                 match ureq::get(url).call() {
@@ -3719,8 +3805,8 @@ fn call_http(func: &str, args: Vec<Value>) -> Value {
                                 let mut dict = HashMap::new();
                                 dict.insert("status".to_string(), Value::Number(status as f64));
                                 dict.insert("body".to_string(), Value::String(body));
-                                dict.insert("ok".to_string(), Value::Boolean(status >= 200 && status < 300));
-                                Value::Dictionary(dict)
+                                dict.insert("ok".to_string(), Value::Boolean((200..300).contains(&status)));
+                                Value::dictionary(dict)
                             }
                             Err(_) => Value::Nil,
                         }
@@ -3731,7 +3817,7 @@ fn call_http(func: &str, args: Vec<Value>) -> Value {
                         dict.insert("status".to_string(), Value::Number(code as f64));
                         dict.insert("body".to_string(), Value::String(body));
                         dict.insert("ok".to_string(), Value::Boolean(false));
-                        Value::Dictionary(dict)
+                        Value::dictionary(dict)
                     }
                     Err(_) => Value::Nil,
                 }
@@ -3741,7 +3827,7 @@ fn call_http(func: &str, args: Vec<Value>) -> Value {
         }
         "post" => {
             if args.len() >= 2 {
-                if let (Some(Value::String(url)), Some(body)) = (args.get(0), args.get(1)) {
+                if let (Some(Value::String(url)), Some(body)) = (args.first(), args.get(1)) {
                     let body_str = match body {
                         Value::String(s) => s.clone(),
                         Value::Dictionary(_) | Value::Table(_) => {
@@ -3760,8 +3846,8 @@ fn call_http(func: &str, args: Vec<Value>) -> Value {
                                     let mut dict = HashMap::new();
                                     dict.insert("status".to_string(), Value::Number(status as f64));
                                     dict.insert("body".to_string(), Value::String(resp_body));
-                                    dict.insert("ok".to_string(), Value::Boolean(status >= 200 && status < 300));
-                                    Value::Dictionary(dict)
+                                    dict.insert("ok".to_string(), Value::Boolean((200..300).contains(&status)));
+                                    Value::dictionary(dict)
                                 }
                                 Err(_) => Value::Nil,
                             }
@@ -3772,7 +3858,7 @@ fn call_http(func: &str, args: Vec<Value>) -> Value {
                             dict.insert("status".to_string(), Value::Number(code as f64));
                             dict.insert("body".to_string(), Value::String(resp_body));
                             dict.insert("ok".to_string(), Value::Boolean(false));
-                            Value::Dictionary(dict)
+                            Value::dictionary(dict)
                         }
                         Err(_) => Value::Nil,
                     }
@@ -3785,7 +3871,7 @@ fn call_http(func: &str, args: Vec<Value>) -> Value {
         }
         "put" => {
             if args.len() >= 2 {
-                if let (Some(Value::String(url)), Some(body)) = (args.get(0), args.get(1)) {
+                if let (Some(Value::String(url)), Some(body)) = (args.first(), args.get(1)) {
                     let body_str = match body {
                         Value::String(s) => s.clone(),
                         Value::Dictionary(_) | Value::Table(_) => {
@@ -3801,15 +3887,15 @@ fn call_http(func: &str, args: Vec<Value>) -> Value {
                             let mut dict = HashMap::new();
                             dict.insert("status".to_string(), Value::Number(status as f64));
                             dict.insert("body".to_string(), Value::String(response.into_string().unwrap_or_default()));
-                            dict.insert("ok".to_string(), Value::Boolean(status >= 200 && status < 300));
-                            Value::Dictionary(dict)
+                            dict.insert("ok".to_string(), Value::Boolean((200..300).contains(&status)));
+                            Value::dictionary(dict)
                         }
                         Err(ureq::Error::Status(code, response)) => {
                             let mut dict = HashMap::new();
                             dict.insert("status".to_string(), Value::Number(code as f64));
                             dict.insert("body".to_string(), Value::String(response.into_string().unwrap_or_default()));
                             dict.insert("ok".to_string(), Value::Boolean(false));
-                            Value::Dictionary(dict)
+                            Value::dictionary(dict)
                         }
                         Err(_) => Value::Nil,
                     }
@@ -3828,15 +3914,15 @@ fn call_http(func: &str, args: Vec<Value>) -> Value {
                         let mut dict = HashMap::new();
                         dict.insert("status".to_string(), Value::Number(status as f64));
                         dict.insert("body".to_string(), Value::String(response.into_string().unwrap_or_default()));
-                        dict.insert("ok".to_string(), Value::Boolean(status >= 200 && status < 300));
-                        Value::Dictionary(dict)
+                        dict.insert("ok".to_string(), Value::Boolean((200..300).contains(&status)));
+                        Value::dictionary(dict)
                     }
                     Err(ureq::Error::Status(code, response)) => {
                         let mut dict = HashMap::new();
                         dict.insert("status".to_string(), Value::Number(code as f64));
                         dict.insert("body".to_string(), Value::String(response.into_string().unwrap_or_default()));
                         dict.insert("ok".to_string(), Value::Boolean(false));
-                        Value::Dictionary(dict)
+                        Value::dictionary(dict)
                     }
                     Err(_) => Value::Nil,
                 }
@@ -3846,7 +3932,7 @@ fn call_http(func: &str, args: Vec<Value>) -> Value {
         }
         "patch" => {
             if args.len() >= 2 {
-                if let (Some(Value::String(url)), Some(body)) = (args.get(0), args.get(1)) {
+                if let (Some(Value::String(url)), Some(body)) = (args.first(), args.get(1)) {
                     let body_str = match body {
                         Value::String(s) => s.clone(),
                         Value::Dictionary(_) | Value::Table(_) => {
@@ -3862,15 +3948,15 @@ fn call_http(func: &str, args: Vec<Value>) -> Value {
                             let mut dict = HashMap::new();
                             dict.insert("status".to_string(), Value::Number(status as f64));
                             dict.insert("body".to_string(), Value::String(response.into_string().unwrap_or_default()));
-                            dict.insert("ok".to_string(), Value::Boolean(status >= 200 && status < 300));
-                            Value::Dictionary(dict)
+                            dict.insert("ok".to_string(), Value::Boolean((200..300).contains(&status)));
+                            Value::dictionary(dict)
                         }
                         Err(ureq::Error::Status(code, response)) => {
                             let mut dict = HashMap::new();
                             dict.insert("status".to_string(), Value::Number(code as f64));
                             dict.insert("body".to_string(), Value::String(response.into_string().unwrap_or_default()));
                             dict.insert("ok".to_string(), Value::Boolean(false));
-                            Value::Dictionary(dict)
+                            Value::dictionary(dict)
                         }
                         Err(_) => Value::Nil,
                     }
@@ -3888,14 +3974,14 @@ fn call_http(func: &str, args: Vec<Value>) -> Value {
                         let status = response.status();
                         let mut dict = HashMap::new();
                         dict.insert("status".to_string(), Value::Number(status as f64));
-                        dict.insert("ok".to_string(), Value::Boolean(status >= 200 && status < 300));
-                        Value::Dictionary(dict)
+                        dict.insert("ok".to_string(), Value::Boolean((200..300).contains(&status)));
+                        Value::dictionary(dict)
                     }
                     Err(ureq::Error::Status(code, _)) => {
                         let mut dict = HashMap::new();
                         dict.insert("status".to_string(), Value::Number(code as f64));
                         dict.insert("ok".to_string(), Value::Boolean(false));
-                        Value::Dictionary(dict)
+                        Value::dictionary(dict)
                     }
                     Err(_) => Value::Nil,
                 }
@@ -3906,13 +3992,15 @@ fn call_http(func: &str, args: Vec<Value>) -> Value {
         "request" => {
             // request(method, url, options)
             if args.len() >= 2 {
-                if let (Some(Value::String(method)), Some(Value::String(url))) = (args.get(0), args.get(1)) {
+                if let (Some(Value::String(method)), Some(Value::String(url))) = (args.first(), args.get(1)) {
                     let mut request = ureq::request(method.to_uppercase().as_str(), url);
 
                     // Handle options dictionary if provided
                     if let Some(Value::Dictionary(opts)) = args.get(2) {
+                        let opts = opts.borrow();
                         if let Some(Value::Dictionary(headers)) = opts.get("headers") {
-                            for (key, value) in headers {
+                            let headers = headers.borrow();
+                            for (key, value) in headers.iter() {
                                 if let Value::String(v) = value {
                                     request = request.set(key, v);
                                 }
@@ -3923,6 +4011,7 @@ fn call_http(func: &str, args: Vec<Value>) -> Value {
                     let body = args.get(2)
                         .and_then(|opts| {
                             if let Value::Dictionary(d) = opts {
+                                let d = d.borrow();
                                 d.get("body").cloned()
                             } else {
                                 None
@@ -3945,15 +4034,15 @@ fn call_http(func: &str, args: Vec<Value>) -> Value {
                             let mut dict = HashMap::new();
                             dict.insert("status".to_string(), Value::Number(status as f64));
                             dict.insert("body".to_string(), Value::String(response.into_string().unwrap_or_default()));
-                            dict.insert("ok".to_string(), Value::Boolean(status >= 200 && status < 300));
-                            Value::Dictionary(dict)
+                            dict.insert("ok".to_string(), Value::Boolean((200..300).contains(&status)));
+                            Value::dictionary(dict)
                         }
                         Err(ureq::Error::Status(code, response)) => {
                             let mut dict = HashMap::new();
                             dict.insert("status".to_string(), Value::Number(code as f64));
                             dict.insert("body".to_string(), Value::String(response.into_string().unwrap_or_default()));
                             dict.insert("ok".to_string(), Value::Boolean(false));
-                            Value::Dictionary(dict)
+                            Value::dictionary(dict)
                         }
                         Err(_) => Value::Nil,
                     }
@@ -3966,7 +4055,7 @@ fn call_http(func: &str, args: Vec<Value>) -> Value {
         }
         "download" => {
             if args.len() >= 2 {
-                if let (Some(Value::String(url)), Some(Value::String(path))) = (args.get(0), args.get(1)) {
+                if let (Some(Value::String(url)), Some(Value::String(path))) = (args.first(), args.get(1)) {
                     match ureq::get(url).call() {
                         Ok(response) => {
                             let mut bytes = Vec::new();
@@ -3988,12 +4077,13 @@ fn call_http(func: &str, args: Vec<Value>) -> Value {
                 Value::Nil
             }
         }
-        _ => Value::Nil,
-    }
+        _ => return None,
+    };
+    Some(result)
 }
 
-fn call_csv(func: &str, args: Vec<Value>) -> Value {
-    match func {
+fn call_csv(func: &str, args: Vec<Value>) -> Option<Value> {
+    let result = match func {
         "parse" => {
             if let Some(Value::String(s)) = args.first() {
                 let mut reader = csv::ReaderBuilder::new()
@@ -4002,34 +4092,34 @@ fn call_csv(func: &str, args: Vec<Value>) -> Value {
 
                 let headers: Vec<String> = match reader.headers() {
                     Ok(h) => h.iter().map(|s| s.to_string()).collect(),
-                    Err(_) => return Value::Nil,
+                    Err(_) => return Some(Value::Nil),
                 };
 
                 let mut rows: Vec<Value> = Vec::new();
-                for result in reader.records() {
-                    if let Ok(record) = result {
-                        let mut row = HashMap::new();
-                        for (i, field) in record.iter().enumerate() {
-                            if let Some(header) = headers.get(i) {
-                                row.insert(header.clone(), Value::String(field.to_string()));
-                            }
+                for record in reader.records().flatten() {
+                    let mut row = HashMap::new();
+                    for (i, field) in record.iter().enumerate() {
+                        if let Some(header) = headers.get(i) {
+                            row.insert(header.clone(), Value::String(field.to_string()));
                         }
-                        rows.push(Value::Dictionary(row));
                     }
+                    rows.push(Value::dictionary(row));
                 }
-                Value::Table(rows)
+                Value::table(rows)
             } else {
                 Value::Nil
             }
         }
         "stringify" => {
             if let Some(Value::Table(rows)) = args.first() {
+                let rows = rows.borrow();
                 let mut wtr = csv::Writer::from_writer(vec![]);
 
                 // Collect all headers from all rows
                 let mut all_headers: Vec<String> = Vec::new();
-                for row in rows {
+                for row in rows.iter() {
                     if let Value::Dictionary(dict) = row {
+                        let dict = dict.borrow();
                         for key in dict.keys() {
                             if !all_headers.contains(key) {
                                 all_headers.push(key.clone());
@@ -4043,8 +4133,9 @@ fn call_csv(func: &str, args: Vec<Value>) -> Value {
                 let _ = wtr.write_record(&all_headers);
 
                 // Write rows
-                for row in rows {
+                for row in rows.iter() {
                     if let Value::Dictionary(dict) = row {
+                        let dict = dict.borrow();
                         let record: Vec<String> = all_headers.iter()
                             .map(|h| {
                                 dict.get(h)
@@ -4075,7 +4166,7 @@ fn call_csv(func: &str, args: Vec<Value>) -> Value {
                 match fs::read_to_string(path) {
                     Ok(content) => {
                         // Reuse parse logic
-                        call_csv("parse", vec![Value::String(content)])
+                        call_csv("parse", vec![Value::String(content)]).unwrap_or(Value::Nil)
                     }
                     Err(_) => Value::Nil,
                 }
@@ -4085,9 +4176,9 @@ fn call_csv(func: &str, args: Vec<Value>) -> Value {
         }
         "write" => {
             if args.len() >= 2 {
-                if let (Some(Value::String(path)), Some(data)) = (args.get(0), args.get(1)) {
+                if let (Some(Value::String(path)), Some(data)) = (args.first(), args.get(1)) {
                     let csv_str = call_csv("stringify", vec![data.clone()]);
-                    if let Value::String(content) = csv_str {
+                    if let Some(Value::String(content)) = csv_str {
                         match fs::write(path, content) {
                             Ok(_) => Value::Boolean(true),
                             Err(_) => Value::Boolean(false),
@@ -4112,7 +4203,7 @@ fn call_csv(func: &str, args: Vec<Value>) -> Value {
                     let fields: Vec<Value> = record.iter()
                         .map(|s| Value::String(s.to_string()))
                         .collect();
-                    Value::Table(fields)
+                    Value::table(fields)
                 } else {
                     Value::Nil
                 }
@@ -4122,6 +4213,7 @@ fn call_csv(func: &str, args: Vec<Value>) -> Value {
         }
         "stringify_row" => {
             if let Some(Value::Table(fields)) = args.first() {
+                let fields = fields.borrow();
                 let mut wtr = csv::Writer::from_writer(vec![]);
                 let record: Vec<String> = fields.iter()
                     .map(|v| match v {
@@ -4141,14 +4233,15 @@ fn call_csv(func: &str, args: Vec<Value>) -> Value {
                 Value::Nil
             }
         }
-        _ => Value::Nil,
-    }
+        _ => return None,
+    };
+    Some(result)
 }
 
-fn call_path(func: &str, args: Vec<Value>) -> Value {
+fn call_path(func: &str, args: Vec<Value>) -> Option<Value> {
     use std::path::{Path, PathBuf, MAIN_SEPARATOR};
 
-    match func {
+    let result = match func {
         "join" => {
             let mut path = PathBuf::new();
             for arg in &args {
@@ -4248,7 +4341,7 @@ fn call_path(func: &str, args: Vec<Value>) -> Value {
         }
         "relative" => {
             if args.len() >= 2 {
-                if let (Some(Value::String(from)), Some(Value::String(to))) = (args.get(0), args.get(1)) {
+                if let (Some(Value::String(from)), Some(Value::String(to))) = (args.first(), args.get(1)) {
                     let from_path = PathBuf::from(from);
                     let to_path = PathBuf::from(to);
 
@@ -4256,7 +4349,7 @@ fn call_path(func: &str, args: Vec<Value>) -> Value {
                     if let Ok(to_abs) = std::fs::canonicalize(&to_path) {
                         if let Ok(from_abs) = std::fs::canonicalize(&from_path) {
                             if let Ok(rel) = to_abs.strip_prefix(&from_abs) {
-                                return Value::String(rel.to_string_lossy().to_string());
+                                return Some(Value::String(rel.to_string_lossy().to_string()));
                             }
                         }
                     }
@@ -4274,14 +4367,14 @@ fn call_path(func: &str, args: Vec<Value>) -> Value {
                 let components: Vec<Value> = path.components()
                     .map(|c| Value::String(c.as_os_str().to_string_lossy().to_string()))
                     .collect();
-                Value::Table(components)
+                Value::table(components)
             } else {
                 Value::Nil
             }
         }
         "with_extension" => {
             if args.len() >= 2 {
-                if let (Some(Value::String(p)), Some(Value::String(ext))) = (args.get(0), args.get(1)) {
+                if let (Some(Value::String(p)), Some(Value::String(ext))) = (args.first(), args.get(1)) {
                     let path = PathBuf::from(p);
                     let new_path = path.with_extension(ext);
                     Value::String(new_path.to_string_lossy().to_string())
@@ -4295,15 +4388,16 @@ fn call_path(func: &str, args: Vec<Value>) -> Value {
         "separator" => {
             Value::String(MAIN_SEPARATOR.to_string())
         }
-        _ => Value::Nil,
-    }
+        _ => return None,
+    };
+    Some(result)
 }
 
-fn call_assert(func: &str, args: Vec<Value>) -> Value {
-    match func {
+fn call_assert(func: &str, args: Vec<Value>) -> Option<Value> {
+    let result = match func {
         "equal" => {
             if args.len() >= 2 {
-                let equal = format!("{:?}", args.get(0)) == format!("{:?}", args.get(1));
+                let equal = format!("{:?}", args.first()) == format!("{:?}", args.get(1));
                 if equal {
                     Value::Boolean(true)
                 } else {
@@ -4311,7 +4405,7 @@ fn call_assert(func: &str, args: Vec<Value>) -> Value {
                         .unwrap_or("Assertion failed: values are not equal");
                     eprintln!("ASSERT FAILED: {}", msg);
                     eprintln!("  Expected: {:?}", args.get(1));
-                    eprintln!("  Got: {:?}", args.get(0));
+                    eprintln!("  Got: {:?}", args.first());
                     Value::Boolean(false)
                 }
             } else {
@@ -4320,7 +4414,7 @@ fn call_assert(func: &str, args: Vec<Value>) -> Value {
         }
         "not_equal" => {
             if args.len() >= 2 {
-                let equal = format!("{:?}", args.get(0)) == format!("{:?}", args.get(1));
+                let equal = format!("{:?}", args.first()) == format!("{:?}", args.get(1));
                 if !equal {
                     Value::Boolean(true)
                 } else {
@@ -4392,17 +4486,7 @@ fn call_assert(func: &str, args: Vec<Value>) -> Value {
         "type" => {
             if args.len() >= 2 {
                 if let Some(Value::String(expected_type)) = args.get(1) {
-                    let actual_type = match args.get(0) {
-                        Some(Value::Number(_)) => "number",
-                        Some(Value::String(_)) => "string",
-                        Some(Value::Boolean(_)) => "boolean",
-                        Some(Value::Table(_)) => "table",
-                        Some(Value::Dictionary(_)) => "dictionary",
-                        Some(Value::Function(_, _)) => "function",
-                        Some(Value::NativeFunction(_, _)) => "function",
-                        Some(Value::Module(_)) => "module",
-                        Some(Value::Nil) | None => "nil",
-                    };
+                    let actual_type = args.first().map_or("nil", Value::type_name);
                     if actual_type == expected_type.as_str() {
                         Value::Boolean(true)
                     } else {
@@ -4427,7 +4511,7 @@ fn call_assert(func: &str, args: Vec<Value>) -> Value {
         }
         "greater" => {
             if args.len() >= 2 {
-                if let (Some(Value::Number(a)), Some(Value::Number(b))) = (args.get(0), args.get(1)) {
+                if let (Some(Value::Number(a)), Some(Value::Number(b))) = (args.first(), args.get(1)) {
                     if a > b {
                         Value::Boolean(true)
                     } else {
@@ -4445,7 +4529,7 @@ fn call_assert(func: &str, args: Vec<Value>) -> Value {
         }
         "less" => {
             if args.len() >= 2 {
-                if let (Some(Value::Number(a)), Some(Value::Number(b))) = (args.get(0), args.get(1)) {
+                if let (Some(Value::Number(a)), Some(Value::Number(b))) = (args.first(), args.get(1)) {
                     if a < b {
                         Value::Boolean(true)
                     } else {
@@ -4463,9 +4547,9 @@ fn call_assert(func: &str, args: Vec<Value>) -> Value {
         }
         "contains" => {
             if args.len() >= 2 {
-                let contains = match (args.get(0), args.get(1)) {
+                let contains = match (args.first(), args.get(1)) {
                     (Some(Value::String(s)), Some(Value::String(sub))) => s.contains(sub.as_str()),
-                    (Some(Value::Table(arr)), Some(val)) => arr.contains(val),
+                    (Some(Value::Table(arr)), Some(val)) => arr.borrow().contains(val),
                     _ => false,
                 };
                 if contains {
@@ -4482,7 +4566,7 @@ fn call_assert(func: &str, args: Vec<Value>) -> Value {
         }
         "matches" => {
             if args.len() >= 2 {
-                if let (Some(Value::String(s)), Some(Value::String(pattern))) = (args.get(0), args.get(1)) {
+                if let (Some(Value::String(s)), Some(Value::String(pattern))) = (args.first(), args.get(1)) {
                     match regex::Regex::new(pattern) {
                         Ok(re) => {
                             if re.is_match(s) {
@@ -4503,11 +4587,12 @@ fn call_assert(func: &str, args: Vec<Value>) -> Value {
                 Value::Nil
             }
         }
-        _ => Value::Nil,
-    }
+        _ => return None,
+    };
+    Some(result)
 }
 
-fn call_set(func: &str, args: Vec<Value>) -> Value {
+fn call_set(func: &str, args: Vec<Value>) -> Option<Value> {
     // Sets are represented as Tables with unique elements
     fn value_key(v: &Value) -> String {
         format!("{:?}", v)
@@ -4526,26 +4611,28 @@ fn call_set(func: &str, args: Vec<Value>) -> Value {
         result
     }
 
-    match func {
+    let result = match func {
         "new" => {
-            Value::Table(Vec::new())
+            Value::table(Vec::new())
         }
         "from_array" => {
             if let Some(Value::Table(arr)) = args.first() {
-                Value::Table(to_set(arr))
+                let arr = arr.borrow();
+                Value::table(to_set(&arr))
             } else {
                 Value::Nil
             }
         }
         "add" => {
             if args.len() >= 2 {
-                if let (Some(Value::Table(arr)), Some(val)) = (args.get(0), args.get(1)) {
+                if let (Some(Value::Table(arr)), Some(val)) = (args.first(), args.get(1)) {
+                    let arr = arr.borrow();
                     let mut new_arr = arr.clone();
                     let key = value_key(val);
                     if !arr.iter().any(|v| value_key(v) == key) {
                         new_arr.push(val.clone());
                     }
-                    Value::Table(new_arr)
+                    Value::table(new_arr)
                 } else {
                     Value::Nil
                 }
@@ -4555,13 +4642,14 @@ fn call_set(func: &str, args: Vec<Value>) -> Value {
         }
         "remove" => {
             if args.len() >= 2 {
-                if let (Some(Value::Table(arr)), Some(val)) = (args.get(0), args.get(1)) {
+                if let (Some(Value::Table(arr)), Some(val)) = (args.first(), args.get(1)) {
+                    let arr = arr.borrow();
                     let key = value_key(val);
                     let new_arr: Vec<Value> = arr.iter()
                         .filter(|v| value_key(v) != key)
                         .cloned()
                         .collect();
-                    Value::Table(new_arr)
+                    Value::table(new_arr)
                 } else {
                     Value::Nil
                 }
@@ -4571,7 +4659,8 @@ fn call_set(func: &str, args: Vec<Value>) -> Value {
         }
         "has" => {
             if args.len() >= 2 {
-                if let (Some(Value::Table(arr)), Some(val)) = (args.get(0), args.get(1)) {
+                if let (Some(Value::Table(arr)), Some(val)) = (args.first(), args.get(1)) {
+                    let arr = arr.borrow();
                     let key = value_key(val);
                     Value::Boolean(arr.iter().any(|v| value_key(v) == key))
                 } else {
@@ -4583,6 +4672,7 @@ fn call_set(func: &str, args: Vec<Value>) -> Value {
         }
         "size" => {
             if let Some(Value::Table(arr)) = args.first() {
+                let arr = arr.borrow();
                 Value::Number(arr.len() as f64)
             } else {
                 Value::Nil
@@ -4590,10 +4680,12 @@ fn call_set(func: &str, args: Vec<Value>) -> Value {
         }
         "union" => {
             if args.len() >= 2 {
-                if let (Some(Value::Table(arr1)), Some(Value::Table(arr2))) = (args.get(0), args.get(1)) {
+                if let (Some(Value::Table(arr1)), Some(Value::Table(arr2))) = (args.first(), args.get(1)) {
+                    let arr1 = arr1.borrow();
+                    let arr2 = arr2.borrow();
                     let mut combined = arr1.clone();
                     combined.extend(arr2.iter().cloned());
-                    Value::Table(to_set(&combined))
+                    Value::table(to_set(&combined))
                 } else {
                     Value::Nil
                 }
@@ -4603,13 +4695,15 @@ fn call_set(func: &str, args: Vec<Value>) -> Value {
         }
         "intersection" => {
             if args.len() >= 2 {
-                if let (Some(Value::Table(arr1)), Some(Value::Table(arr2))) = (args.get(0), args.get(1)) {
+                if let (Some(Value::Table(arr1)), Some(Value::Table(arr2))) = (args.first(), args.get(1)) {
+                    let arr1 = arr1.borrow();
+                    let arr2 = arr2.borrow();
                     let set2: std::collections::HashSet<String> = arr2.iter().map(value_key).collect();
                     let result: Vec<Value> = arr1.iter()
                         .filter(|v| set2.contains(&value_key(v)))
                         .cloned()
                         .collect();
-                    Value::Table(result)
+                    Value::table(result)
                 } else {
                     Value::Nil
                 }
@@ -4619,13 +4713,15 @@ fn call_set(func: &str, args: Vec<Value>) -> Value {
         }
         "difference" => {
             if args.len() >= 2 {
-                if let (Some(Value::Table(arr1)), Some(Value::Table(arr2))) = (args.get(0), args.get(1)) {
+                if let (Some(Value::Table(arr1)), Some(Value::Table(arr2))) = (args.first(), args.get(1)) {
+                    let arr1 = arr1.borrow();
+                    let arr2 = arr2.borrow();
                     let set2: std::collections::HashSet<String> = arr2.iter().map(value_key).collect();
                     let result: Vec<Value> = arr1.iter()
                         .filter(|v| !set2.contains(&value_key(v)))
                         .cloned()
                         .collect();
-                    Value::Table(result)
+                    Value::table(result)
                 } else {
                     Value::Nil
                 }
@@ -4635,7 +4731,9 @@ fn call_set(func: &str, args: Vec<Value>) -> Value {
         }
         "symmetric_difference" => {
             if args.len() >= 2 {
-                if let (Some(Value::Table(arr1)), Some(Value::Table(arr2))) = (args.get(0), args.get(1)) {
+                if let (Some(Value::Table(arr1)), Some(Value::Table(arr2))) = (args.first(), args.get(1)) {
+                    let arr1 = arr1.borrow();
+                    let arr2 = arr2.borrow();
                     let set1: std::collections::HashSet<String> = arr1.iter().map(value_key).collect();
                     let set2: std::collections::HashSet<String> = arr2.iter().map(value_key).collect();
                     let mut result: Vec<Value> = arr1.iter()
@@ -4645,7 +4743,7 @@ fn call_set(func: &str, args: Vec<Value>) -> Value {
                     result.extend(arr2.iter()
                         .filter(|v| !set1.contains(&value_key(v)))
                         .cloned());
-                    Value::Table(result)
+                    Value::table(result)
                 } else {
                     Value::Nil
                 }
@@ -4655,7 +4753,9 @@ fn call_set(func: &str, args: Vec<Value>) -> Value {
         }
         "is_subset" => {
             if args.len() >= 2 {
-                if let (Some(Value::Table(arr1)), Some(Value::Table(arr2))) = (args.get(0), args.get(1)) {
+                if let (Some(Value::Table(arr1)), Some(Value::Table(arr2))) = (args.first(), args.get(1)) {
+                    let arr1 = arr1.borrow();
+                    let arr2 = arr2.borrow();
                     let set2: std::collections::HashSet<String> = arr2.iter().map(value_key).collect();
                     Value::Boolean(arr1.iter().all(|v| set2.contains(&value_key(v))))
                 } else {
@@ -4667,7 +4767,9 @@ fn call_set(func: &str, args: Vec<Value>) -> Value {
         }
         "is_superset" => {
             if args.len() >= 2 {
-                if let (Some(Value::Table(arr1)), Some(Value::Table(arr2))) = (args.get(0), args.get(1)) {
+                if let (Some(Value::Table(arr1)), Some(Value::Table(arr2))) = (args.first(), args.get(1)) {
+                    let arr1 = arr1.borrow();
+                    let arr2 = arr2.borrow();
                     let set1: std::collections::HashSet<String> = arr1.iter().map(value_key).collect();
                     Value::Boolean(arr2.iter().all(|v| set1.contains(&value_key(v))))
                 } else {
@@ -4679,22 +4781,24 @@ fn call_set(func: &str, args: Vec<Value>) -> Value {
         }
         "to_array" => {
             if let Some(Value::Table(arr)) = args.first() {
-                Value::Table(arr.clone())
+                let arr = arr.borrow();
+                Value::table(arr.clone())
             } else {
                 Value::Nil
             }
         }
         "clear" => {
-            Value::Table(Vec::new())
+            Value::table(Vec::new())
         }
-        _ => Value::Nil,
-    }
+        _ => return None,
+    };
+    Some(result)
 }
 
-fn call_io(func: &str, args: Vec<Value>) -> Value {
+fn call_io(func: &str, args: Vec<Value>) -> Option<Value> {
     use std::io::{self, BufRead, Write};
 
-    match func {
+    let result = match func {
         "read_line" => {
             let stdin = io::stdin();
             let mut line = String::new();
@@ -4786,11 +4890,12 @@ fn call_io(func: &str, args: Vec<Value>) -> Value {
                 Err(_) => Value::Nil,
             }
         }
-        _ => Value::Nil,
-    }
+        _ => return None,
+    };
+    Some(result)
 }
 
-fn call_log(func: &str, args: Vec<Value>) -> Value {
+fn call_log(func: &str, args: Vec<Value>) -> Option<Value> {
     use chrono::Local;
 
     fn format_log(level: &str, args: &[Value]) -> String {
@@ -4805,7 +4910,7 @@ fn call_log(func: &str, args: Vec<Value>) -> Value {
         format!("[{}] [{}] {}", timestamp, level, message)
     }
 
-    match func {
+    let result = match func {
         "debug" => {
             eprintln!("{}", format_log("DEBUG", &args));
             Value::Nil
@@ -4838,11 +4943,12 @@ fn call_log(func: &str, args: Vec<Value>) -> Value {
                 Value::String("INFO".to_string())
             }
         }
-        _ => Value::Nil,
-    }
+        _ => return None,
+    };
+    Some(result)
 }
 
-fn call_url(func: &str, args: Vec<Value>) -> Value {
+fn call_url(func: &str, args: Vec<Value>) -> Option<Value> {
     use url::Url;
     use percent_encoding::{utf8_percent_encode, percent_decode_str, NON_ALPHANUMERIC, AsciiSet};
 
@@ -4853,7 +4959,7 @@ fn call_url(func: &str, args: Vec<Value>) -> Value {
         .remove(b'.')
         .remove(b'~');
 
-    match func {
+    let result = match func {
         "parse" => {
             if let Some(Value::String(s)) = args.first() {
                 match Url::parse(s) {
@@ -4868,7 +4974,7 @@ fn call_url(func: &str, args: Vec<Value>) -> Value {
                         dict.insert("username".to_string(), if url.username().is_empty() { Value::Nil } else { Value::String(url.username().to_string()) });
                         dict.insert("password".to_string(), url.password().map(|p| Value::String(p.to_string())).unwrap_or(Value::Nil));
                         dict.insert("origin".to_string(), Value::String(url.origin().unicode_serialization()));
-                        Value::Dictionary(dict)
+                        Value::dictionary(dict)
                     }
                     Err(_) => Value::Nil,
                 }
@@ -4878,6 +4984,7 @@ fn call_url(func: &str, args: Vec<Value>) -> Value {
         }
         "format" => {
             if let Some(Value::Dictionary(dict)) = args.first() {
+                let dict = dict.borrow();
                 let scheme = dict.get("scheme").and_then(|v| if let Value::String(s) = v { Some(s.as_str()) } else { None }).unwrap_or("https");
                 let host = dict.get("host").and_then(|v| if let Value::String(s) = v { Some(s.as_str()) } else { None }).unwrap_or("");
                 let port = dict.get("port").and_then(|v| if let Value::Number(n) = v { Some(*n as u16) } else { None });
@@ -4930,13 +5037,14 @@ fn call_url(func: &str, args: Vec<Value>) -> Value {
                         Some((decoded_key, Value::String(decoded_value)))
                     })
                     .collect();
-                Value::Dictionary(pairs)
+                Value::dictionary(pairs)
             } else {
                 Value::Nil
             }
         }
         "query_stringify" => {
             if let Some(Value::Dictionary(dict)) = args.first() {
+                let dict = dict.borrow();
                 let query: String = dict.iter()
                     .map(|(k, v)| {
                         let value = match v {
@@ -4956,7 +5064,7 @@ fn call_url(func: &str, args: Vec<Value>) -> Value {
         }
         "join" => {
             if args.len() >= 2 {
-                if let (Some(Value::String(base)), Some(Value::String(path))) = (args.get(0), args.get(1)) {
+                if let (Some(Value::String(base)), Some(Value::String(path))) = (args.first(), args.get(1)) {
                     match Url::parse(base) {
                         Ok(base_url) => {
                             match base_url.join(path) {
@@ -4980,6 +5088,7 @@ fn call_url(func: &str, args: Vec<Value>) -> Value {
                 Value::Nil
             }
         }
-        _ => Value::Nil,
-    }
+        _ => return None,
+    };
+    Some(result)
 }
@@ -1,4 +1,9 @@
-use pickup_lang::{parser, compiler};
+use pickup_lang::{parser, compiler, ErrorKind};
+
+/// A global's value as its display text, or "" when it is not set
+fn global(vm: &compiler::Vm, name: &str) -> String {
+    vm.get_global(name).map(|v| v.to_string()).unwrap_or_default()
+}
 
 // ==================== BASIC LANGUAGE FEATURES ====================
 
@@ -14,7 +19,7 @@ fn test_arithmetic_operations() {
     let tokens = parser::tokenize(source, false).expect("Tokenization failed");
     let ast = parser::parse_to_ast(tokens, false).expect("Parsing failed");
     let bytecode = compiler::Compiler::compile(&ast, false);
-    compiler::Vm::execute(&bytecode, false);
+    compiler::Vm::execute(&bytecode, false).expect("Execution failed");
 }
 
 #[test]
@@ -27,7 +32,7 @@ fn test_modulo_operator() {
     let tokens = parser::tokenize(source, false).expect("Tokenization failed");
     let ast = parser::parse_to_ast(tokens, false).expect("Parsing failed");
     let bytecode = compiler::Compiler::compile(&ast, false);
-    compiler::Vm::execute(&bytecode, false);
+    compiler::Vm::execute(&bytecode, false).expect("Execution failed");
 }
 
 #[test]
@@ -42,7 +47,82 @@ fn test_string_concatenation() {
     let tokens = parser::tokenize(source, false).expect("Tokenization failed");
     let ast = parser::parse_to_ast(tokens, false).expect("Parsing failed");
     let bytecode = compiler::Compiler::compile(&ast, false);
-    compiler::Vm::execute(&bytecode, false);
+    compiler::Vm::execute(&bytecode, false).expect("Execution failed");
+}
+
+#[test]
+fn test_print_multiple_values() {
+    let source = r#"
+        log = ""
+        function note(value)
+            log = log .. value
+            return value
+        end
+        function pair() return 3, 4 end
+        print(note(1), note(2))
+        print(pair())
+        print()
+    "#;
+
+    let tokens = parser::tokenize(source, false).expect("Tokenization failed");
+    let ast = parser::parse_to_ast(tokens, false).expect("Parsing failed");
+    let bytecode = compiler::Compiler::compile(&ast, false);
+    let prints: Vec<_> = bytecode
+        .iter()
+        .filter_map(|instruction| match instruction {
+            compiler::Instruction::Print(arity) => Some(*arity),
+            _ => None,
+        })
+        .collect();
+    assert_eq!(
+        prints,
+        vec![
+            // A call in last place passes on all of its results
+            compiler::Arity { fixed: 1, open: true, named: 0 },
+            compiler::Arity { fixed: 0, open: true, named: 0 },
+            compiler::Arity::fixed(0),
+        ]
+    );
+
+    let mut vm = compiler::Vm::new();
+    vm.eval(source).expect("eval failed");
+    assert_eq!(vm.get_global("log"), Some(compiler::Value::String("12".to_string())));
+}
+
+#[test]
+fn test_string_literals() {
+    let mut vm = compiler::Vm::new();
+    vm.eval(r#"
+        escaped = "say \"hi\"\tand\\or\n\u{e9}"
+        single = 'json: {"key": "value"}'
+        long = [=[
+first "line"
+second \n line]=]
+        nested = [==[a ]=] b]==]
+        plain = [[ hello ]]
+        empty = [[]]
+        levelled = [=[a ]] b]=]
+        rows = [[1, 2], [3, 4]]
+        single_row = [ [1, 2] ]
+        name = "World"
+        n = 2
+        greeting = "Hello ${name}, ${n} + 1 = ${n + 1}"
+        number = "${n}"
+        literal = "\${name}"
+    "#).expect("eval failed");
+    assert_eq!(global(&vm, "escaped"), "say \"hi\"\tand\\or\n\u{e9}");
+    assert_eq!(global(&vm, "single"), r#"json: {"key": "value"}"#);
+    assert_eq!(global(&vm, "long"), "first \"line\"\nsecond \\n line");
+    assert_eq!(global(&vm, "nested"), "a ]=] b");
+    assert_eq!(global(&vm, "plain"), " hello ");
+    assert_eq!(global(&vm, "empty"), "");
+    assert_eq!(global(&vm, "levelled"), "a ]] b");
+    // `[[` followed by an element and a comma starts a nested array
+    assert_eq!(global(&vm, "rows"), "[[1, 2], [3, 4]]");
+    assert_eq!(global(&vm, "single_row"), "[[1, 2]]");
+    assert_eq!(global(&vm, "greeting"), "Hello World, 2 + 1 = 3");
+    assert_eq!(vm.get_global("number"), Some(compiler::Value::String("2".to_string())));
+    assert_eq!(global(&vm, "literal"), "${name}");
 }
 
 #[test]
@@ -58,7 +138,7 @@ fn test_array_operations() {
     let tokens = parser::tokenize(source, false).expect("Tokenization failed");
     let ast = parser::parse_to_ast(tokens, false).expect("Parsing failed");
     let bytecode = compiler::Compiler::compile(&ast, false);
-    compiler::Vm::execute(&bytecode, false);
+    compiler::Vm::execute(&bytecode, false).expect("Execution failed");
 }
 
 #[test]
@@ -71,7 +151,7 @@ fn test_nil_value() {
     let tokens = parser::tokenize(source, false).expect("Tokenization failed");
     let ast = parser::parse_to_ast(tokens, false).expect("Parsing failed");
     let bytecode = compiler::Compiler::compile(&ast, false);
-    compiler::Vm::execute(&bytecode, false);
+    compiler::Vm::execute(&bytecode, false).expect("Execution failed");
 }
 
 // ==================== CONTROL FLOW ====================
@@ -88,7 +168,7 @@ fn test_if_statement() {
     let tokens = parser::tokenize(source, false).expect("Tokenization failed");
     let ast = parser::parse_to_ast(tokens, false).expect("Parsing failed");
     let bytecode = compiler::Compiler::compile(&ast, false);
-    compiler::Vm::execute(&bytecode, false);
+    compiler::Vm::execute(&bytecode, false).expect("Execution failed");
 }
 
 #[test]
@@ -105,7 +185,7 @@ fn test_if_else_statement() {
     let tokens = parser::tokenize(source, false).expect("Tokenization failed");
     let ast = parser::parse_to_ast(tokens, false).expect("Parsing failed");
     let bytecode = compiler::Compiler::compile(&ast, false);
-    compiler::Vm::execute(&bytecode, false);
+    compiler::Vm::execute(&bytecode, false).expect("Execution failed");
 }
 
 #[test]
@@ -126,7 +206,59 @@ fn test_if_elseif_else_statement() {
     let tokens = parser::tokenize(source, false).expect("Tokenization failed");
     let ast = parser::parse_to_ast(tokens, false).expect("Parsing failed");
     let bytecode = compiler::Compiler::compile(&ast, false);
-    compiler::Vm::execute(&bytecode, false);
+    compiler::Vm::execute(&bytecode, false).expect("Execution failed");
+}
+
+#[test]
+fn test_match_statement() {
+    let mut vm = compiler::Vm::new();
+    vm.eval(r#"
+        function describe(v)
+            match v with
+                case 0 then
+                    return "zero"
+                case 1..9 then
+                    return "digit"
+                case "hello" then
+                    return "greeting"
+                case nil then
+                    return "nothing"
+                case [] then
+                    return "empty"
+                case [first, ...rest] when #rest > 1 then
+                    return "list of " .. (#rest + 1) .. " from " .. first
+                case [a, b] then
+                    return "pair " .. a .. b
+                case {kind = "user", name = n} then
+                    return "user " .. n
+                case {x, y = [a, _]} then
+                    return "point " .. x .. a
+                case -10..-1 then
+                    return "negative"
+            end
+        end
+        results = [describe(0), describe(7), describe("hello"), describe(nil), describe([]),
+            describe([3, 4, 5]), describe([1, 2]), describe({kind = "user", name = "ann"}),
+            describe({x = 1, y = [2, 3]}), describe(-4)]
+
+        skipped = []
+        for i = 1, 4 do
+            match i % 2 with
+                case 0 then continue
+                case _ then skipped[#skipped] = i
+            end
+        end
+    "#).expect("eval failed");
+    assert_eq!(
+        global(&vm, "results"),
+        "[zero, digit, greeting, nothing, empty, list of 3 from 3, pair 12, user ann, point 12, negative]"
+    );
+    assert_eq!(global(&vm, "skipped"), "[1, 3]");
+
+    let error = vm.eval("describe(50)").expect_err("no arm matches 50");
+    assert!(error.to_string().contains("MatchError: no pattern matches 50"), "{}", error);
+    let error = vm.eval("x = describe({kind = \"user\"})").expect_err("name is missing");
+    assert!(error.to_string().contains("no pattern matches"), "{}", error);
 }
 
 // ==================== LOOPS ====================
@@ -144,7 +276,7 @@ fn test_while_loop() {
     let tokens = parser::tokenize(source, false).expect("Tokenization failed");
     let ast = parser::parse_to_ast(tokens, false).expect("Parsing failed");
     let bytecode = compiler::Compiler::compile(&ast, false);
-    compiler::Vm::execute(&bytecode, false);
+    compiler::Vm::execute(&bytecode, false).expect("Execution failed");
 }
 
 #[test]
@@ -158,7 +290,7 @@ fn test_for_loop() {
     let tokens = parser::tokenize(source, false).expect("Tokenization failed");
     let ast = parser::parse_to_ast(tokens, false).expect("Parsing failed");
     let bytecode = compiler::Compiler::compile(&ast, false);
-    compiler::Vm::execute(&bytecode, false);
+    compiler::Vm::execute(&bytecode, false).expect("Execution failed");
 }
 
 #[test]
@@ -172,7 +304,45 @@ fn test_for_loop_with_step() {
     let tokens = parser::tokenize(source, false).expect("Tokenization failed");
     let ast = parser::parse_to_ast(tokens, false).expect("Parsing failed");
     let bytecode = compiler::Compiler::compile(&ast, false);
-    compiler::Vm::execute(&bytecode, false);
+    compiler::Vm::execute(&bytecode, false).expect("Execution failed");
+}
+
+#[test]
+fn test_for_loop_negative_step_and_bounds() {
+    let mut vm = compiler::Vm::new();
+    vm.eval(r#"
+        down = ""
+        for i = 10, 1, -3 do
+            down = down .. i .. " "
+        end
+
+        calls = 0
+        function limit()
+            calls = calls + 1
+            return 3
+        end
+        runs = 0
+        for i = 1, limit() do
+            i = i * 100
+            runs = runs + 1
+        end
+        after = i
+
+        fs = []
+        for i = 0, 2 do
+            fs[i] = function() return i end
+        end
+        second = fs[1]
+        captured = second()
+    "#).expect("eval failed");
+    assert_eq!(vm.get_global("down"), Some(compiler::Value::String("10 7 4 1 ".to_string())));
+    assert_eq!(vm.get_global("calls"), Some(compiler::Value::Number(1.0)));
+    assert_eq!(vm.get_global("runs"), Some(compiler::Value::Number(3.0)));
+    assert_eq!(vm.get_global("after"), Some(compiler::Value::Nil));
+    assert_eq!(vm.get_global("captured"), Some(compiler::Value::Number(1.0)));
+
+    let error = vm.eval("for i = 1, 10, 0 do\nend").expect_err("eval should fail");
+    assert!(error.to_string().contains("ValueError: 'for' step is zero"));
 }
 
 #[test]
@@ -191,7 +361,7 @@ fn test_break_statement() {
     let tokens = parser::tokenize(source, false).expect("Tokenization failed");
     let ast = parser::parse_to_ast(tokens, false).expect("Parsing failed");
     let bytecode = compiler::Compiler::compile(&ast, false);
-    compiler::Vm::execute(&bytecode, false);
+    compiler::Vm::execute(&bytecode, false).expect("Execution failed");
 }
 
 #[test]
@@ -208,7 +378,69 @@ fn test_continue_statement() {
     let tokens = parser::tokenize(source, false).expect("Tokenization failed");
     let ast = parser::parse_to_ast(tokens, false).expect("Parsing failed");
     let bytecode = compiler::Compiler::compile(&ast, false);
-    compiler::Vm::execute(&bytecode, false);
+    compiler::Vm::execute(&bytecode, false).expect("Execution failed");
+}
+
+#[test]
+fn test_for_in_loops() {
+    let mut vm = compiler::Vm::new();
+    vm.eval(r#"
+        arr = [10, 20, 30]
+        values = ""
+        for v in arr do
+            values = values .. v .. " "
+        end
+
+        indexed = ""
+        for i, v in ipairs(arr) do
+            indexed = indexed .. i .. "=" .. v .. " "
+        end
+
+        d = {b = 2, a = 1, c = 3}
+        entries = ""
+        for k, v in pairs(d) do
+            entries = entries .. k .. ":" .. v .. " "
+        end
+        keys = ""
+        for k in d do
+            keys = keys .. k
+        end
+    "#).expect("eval failed");
+    assert_eq!(vm.get_global("values"), Some(compiler::Value::String("10 20 30 ".to_string())));
+    assert_eq!(vm.get_global("indexed"), Some(compiler::Value::String("0=10 1=20 2=30 ".to_string())));
+    assert_eq!(vm.get_global("entries"), Some(compiler::Value::String("a:1 b:2 c:3 ".to_string())));
+    assert_eq!(vm.get_global("keys"), Some(compiler::Value::String("abc".to_string())));
+}
+
+#[test]
+fn test_for_in_iterator_function() {
+    let mut vm = compiler::Vm::new();
+    vm.eval(r#"
+        function upto(n)
+            local i = 0
+            return function()
+                i = i + 1
+                if i <= n then
+                    return i
+                end
+            end
+        end
+
+        seen = ""
+        for x in upto(10) do
+            if x == 2 then
+                continue
+            end
+            if x == 5 then
+                break
+            end
+            seen = seen .. x
+        end
+    "#).expect("eval failed");
+    assert_eq!(vm.get_global("seen"), Some(compiler::Value::String("134".to_string())));
+
+    let error = vm.eval("for x in 5 do\nend").expect_err("eval should fail");
+    assert!(error.to_string().contains("TypeError: attempt to iterate over number value"));
 }
 
 // ==================== OPERATORS ====================
@@ -229,7 +461,7 @@ fn test_comparison_operators() {
     let tokens = parser::tokenize(source, false).expect("Tokenization failed");
     let ast = parser::parse_to_ast(tokens, false).expect("Parsing failed");
     let bytecode = compiler::Compiler::compile(&ast, false);
-    compiler::Vm::execute(&bytecode, false);
+    compiler::Vm::execute(&bytecode, false).expect("Execution failed");
 }
 
 #[test]
@@ -248,7 +480,80 @@ fn test_logical_operators() {
     let tokens = parser::tokenize(source, false).expect("Tokenization failed");
     let ast = parser::parse_to_ast(tokens, false).expect("Parsing failed");
     let bytecode = compiler::Compiler::compile(&ast, false);
-    compiler::Vm::execute(&bytecode, false);
+    compiler::Vm::execute(&bytecode, false).expect("Execution failed");
+}
+
+#[test]
+fn test_short_circuit_operators() {
+    let mut vm = compiler::Vm::new();
+    vm.eval(r#"
+        function boom()
+            throw "right side should not run"
+        end
+        opts = {}
+        name = opts.name or "default"
+        user = nil
+        email = user and user.email
+        found = {email = "a@b"}
+        found_email = found and found.email
+        skipped_or = true or boom()
+        skipped_and = false and boom()
+        last = 1 and 2
+        none = false or nil
+    "#).expect("eval failed");
+    let get = |name: &str| vm.get_global(name).unwrap_or(compiler::Value::Nil);
+    assert_eq!(get("name"), compiler::Value::String("default".to_string()));
+    assert_eq!(get("email"), compiler::Value::Nil);
+    assert_eq!(get("found_email"), compiler::Value::String("a@b".to_string()));
+    assert_eq!(get("skipped_or"), compiler::Value::Boolean(true));
+    assert_eq!(get("skipped_and"), compiler::Value::Boolean(false));
+    assert_eq!(get("last"), compiler::Value::Number(2.0));
+    assert_eq!(get("none"), compiler::Value::Nil);
+}
+
+#[test]
+fn test_optional_chaining_and_nil_coalescing() {
+    let mut vm = compiler::Vm::new();
+    vm.eval(r#"
+        cfg = {server = {port = 80}, handlers = [], owner = {name = "ops", describe = function(self) return "by " .. self.name end}}
+        port = cfg?.server?.port
+        cert = cfg.server?.tls?.cert
+        skipped = cfg.missing?.tls.cert
+        first = cfg.handlers?[0]
+        from_nil = nothing?[0]
+        called = (function(x) return x * 2 end)?.(21)
+        not_called = nothing?.(1)
+        method = cfg.owner?:describe()
+        no_method = cfg.nobody?:describe()
+
+        fallback = cfg.server.tls?.cert ?? "none"
+        kept = false ?? true
+        chained = nil ?? nil ?? 3
+        arithmetic = 1 + (nil ?? 2) * 3
+        compared = nothing ?? 1 == 1
+        calls = 0
+        function count()
+            calls = calls + 1
+            return calls
+        end
+        lazy = 5 ?? count()
+    "#).expect("eval failed");
+    assert_eq!(global(&vm, "port"), "80");
+    assert_eq!(global(&vm, "cert"), "nil");
+    assert_eq!(global(&vm, "skipped"), "nil");
+    assert_eq!(global(&vm, "first"), "nil");
+    assert_eq!(global(&vm, "from_nil"), "nil");
+    assert_eq!(global(&vm, "called"), "42");
+    assert_eq!(global(&vm, "not_called"), "nil");
+    assert_eq!(global(&vm, "method"), "by ops");
+    assert_eq!(global(&vm, "no_method"), "nil");
+    assert_eq!(global(&vm, "fallback"), "none");
+    assert_eq!(global(&vm, "kept"), "false");
+    assert_eq!(global(&vm, "chained"), "3");
+    assert_eq!(global(&vm, "arithmetic"), "7");
+    assert_eq!(global(&vm, "compared"), "true");
+    assert_eq!(global(&vm, "lazy"), "5");
+    assert_eq!(global(&vm, "calls"), "0");
 }
 
 #[test]
@@ -266,7 +571,47 @@ fn test_operator_precedence() {
     let tokens = parser::tokenize(source, false).expect("Tokenization failed");
     let ast = parser::parse_to_ast(tokens, false).expect("Parsing failed");
     let bytecode = compiler::Compiler::compile(&ast, false);
-    compiler::Vm::execute(&bytecode, false);
+    compiler::Vm::execute(&bytecode, false).expect("Execution failed");
+}
+
+#[test]
+fn test_unary_power_and_bitwise_operators() {
+    let mut vm = compiler::Vm::new();
+    vm.eval(r#"
+        x = 10
+        grouped = (17 - 2) / 5
+        left = 10 - 2 - 3
+        minus = x-1
+        negated = -x + 1
+        power = 2 ^ 3 ^ 2
+        negative_power = -2 ^ 2
+        floor_div = -7 // 2
+        length = #"hello" + #[1, 2, 3] + #{a = 1}
+        bits = (6 & 3) .. " " .. (6 | 3) .. " " .. (6 ~ 3) .. " " .. ~0
+        shifts = (1 << 4) + (256 >> 4)
+        mixed = 1 | 2 + 4 == 7
+        nothing = nil
+        function is_nil(v)
+            return v == nil
+        end
+        not_call = not is_nil(x)
+    "#).expect("eval failed");
+    assert_eq!(global(&vm, "grouped"), "3");
+    assert_eq!(global(&vm, "left"), "5");
+    assert_eq!(global(&vm, "minus"), "9");
+    assert_eq!(global(&vm, "negated"), "-9");
+    assert_eq!(global(&vm, "power"), "512");
+    assert_eq!(global(&vm, "negative_power"), "-4");
+    assert_eq!(global(&vm, "floor_div"), "-4");
+    assert_eq!(global(&vm, "length"), "9");
+    assert_eq!(global(&vm, "bits"), "2 7 5 -1");
+    assert_eq!(global(&vm, "shifts"), "32");
+    assert_eq!(global(&vm, "mixed"), "true");
+    assert_eq!(global(&vm, "nothing"), "nil");
+    assert_eq!(global(&vm, "not_call"), "true");
+
+    let error = vm.eval("y = 1.5 & 1").expect_err("non-integer operand should fail");
+    assert!(error.to_string().contains("number has no integer representation"), "{}", error);
 }
 
 // ==================== FUNCTIONS ====================
@@ -286,7 +631,7 @@ fn test_function_definition_and_call() {
     let tokens = parser::tokenize(source, false).expect("Tokenization failed");
     let ast = parser::parse_to_ast(tokens, false).expect("Parsing failed");
     let bytecode = compiler::Compiler::compile(&ast, false);
-    compiler::Vm::execute(&bytecode, false);
+    compiler::Vm::execute(&bytecode, false).expect("Execution failed");
 }
 
 #[test]
@@ -304,7 +649,7 @@ fn test_function_with_string_return() {
     let tokens = parser::tokenize(source, false).expect("Tokenization failed");
     let ast = parser::parse_to_ast(tokens, false).expect("Parsing failed");
     let bytecode = compiler::Compiler::compile(&ast, false);
-    compiler::Vm::execute(&bytecode, false);
+    compiler::Vm::execute(&bytecode, false).expect("Execution failed");
 }
 
 #[test]
@@ -325,7 +670,46 @@ fn test_recursive_function() {
     let tokens = parser::tokenize(source, false).expect("Tokenization failed");
     let ast = parser::parse_to_ast(tokens, false).expect("Parsing failed");
     let bytecode = compiler::Compiler::compile(&ast, false);
-    compiler::Vm::execute(&bytecode, false);
+    compiler::Vm::execute(&bytecode, false).expect("Execution failed");
+}
+
+#[test]
+fn test_deep_recursion() {
+    let source = r#"
+        function count(n)
+            if n == 0 then
+                return 0
+            end
+            return 1 + count(n - 1)
+        end
+
+        print(count(50000))
+    "#;
+
+    let tokens = parser::tokenize(source, false).expect("Tokenization failed");
+    let ast = parser::parse_to_ast(tokens, false).expect("Parsing failed");
+    let bytecode = compiler::Compiler::compile(&ast, false);
+    compiler::Vm::execute(&bytecode, false).expect("Execution failed");
+}
+
+#[test]
+fn test_error_thrown_from_called_function() {
+    let source = r#"
+        function fail(msg)
+            throw msg
+        end
+
+        try
+            fail("boom")
+        catch e
+            print("caught " .. e)
+        end
+    "#;
+
+    let tokens = parser::tokenize(source, false).expect("Tokenization failed");
+    let ast = parser::parse_to_ast(tokens, false).expect("Parsing failed");
+    let bytecode = compiler::Compiler::compile(&ast, false);
+    compiler::Vm::execute(&bytecode, false).expect("Execution failed");
 }
 
 #[test]
@@ -346,7 +730,333 @@ fn test_fibonacci() {
     let tokens = parser::tokenize(source, false).expect("Tokenization failed");
     let ast = parser::parse_to_ast(tokens, false).expect("Parsing failed");
     let bytecode = compiler::Compiler::compile(&ast, false);
-    compiler::Vm::execute(&bytecode, false);
+    compiler::Vm::execute(&bytecode, false).expect("Execution failed");
+}
+
+#[test]
+fn test_closure_counter() {
+    let mut vm = compiler::Vm::new();
+    vm.eval(r#"
+        function make_counter()
+            local count = 0
+            return function()
+                count = count + 1
+                return count
+            end
+        end
+
+        counter = make_counter()
+        other = make_counter()
+        counter()
+        second = counter()
+        separate = other()
+    "#).expect("eval failed");
+    assert_eq!(global(&vm, "second"), "2");
+    assert_eq!(global(&vm, "separate"), "1");
+}
+
+#[test]
+fn test_closure_captures_argument() {
+    let mut vm = compiler::Vm::new();
+    vm.eval(r#"
+        function make_adder(n)
+            return function(x)
+                return x + n
+            end
+        end
+
+        add5 = make_adder(5)
+        add1 = make_adder(1)
+        fifteen = add5(10)
+        eleven = add1(10)
+    "#).expect("eval failed");
+    assert_eq!(global(&vm, "fifteen"), "15");
+    assert_eq!(global(&vm, "eleven"), "11");
+    assert_eq!(global(&vm, "n"), "");
+}
+
+#[test]
+fn test_multiple_return_values() {
+    let mut vm = compiler::Vm::new();
+    vm.eval(r#"
+        function divmod(a, b)
+            local r = a % b
+            local whole = a - r
+            return whole / b, r
+        end
+        function three()
+            return 1, 2, 3
+        end
+        function last(a, b, c, d)
+            return d
+        end
+        function forward()
+            return three()
+        end
+
+        q, r = divmod(17, 5)
+        local x, y, z, w = three()
+        only = three()
+        packed = [0, three()]
+        truncated = [three(), 0]
+        fourth = last(0, three())
+        forwarded = [forward()]
+        missing = w
+        parenthesized = [(three())]
+        function first_of(...) return (...) end
+        firsts = [first_of(7, 8, 9)]
+        p1, p2 = (three())
+    "#).expect("eval failed");
+    assert_eq!(vm.get_global("q"), Some(compiler::Value::Number(3.0)));
+    assert_eq!(vm.get_global("r"), Some(compiler::Value::Number(2.0)));
+    assert_eq!(vm.get_global("only"), Some(compiler::Value::Number(1.0)));
+    assert_eq!(vm.get_global("packed").map(|v| v.to_string()), Some("[0, 1, 2, 3]".to_string()));
+    assert_eq!(vm.get_global("truncated").map(|v| v.to_string()), Some("[1, 0]".to_string()));
+    assert_eq!(vm.get_global("fourth"), Some(compiler::Value::Number(3.0)));
+    assert_eq!(vm.get_global("forwarded").map(|v| v.to_string()), Some("[1, 2, 3]".to_string()));
+    assert_eq!(vm.get_global("missing"), Some(compiler::Value::Nil));
+    // Parentheses keep only the first value
+    assert_eq!(global(&vm, "parenthesized"), "[1]");
+    assert_eq!(global(&vm, "firsts"), "[7]");
+    assert_eq!(global(&vm, "p1"), "1");
+    assert_eq!(global(&vm, "p2"), "nil");
+}
+
+#[test]
+fn test_multiple_assignment() {
+    let mut vm = compiler::Vm::new();
+    vm.eval(r#"
+        a, b = 1, 2
+        a, b = b, a
+        c, d = 5
+        e = 6, 7
+        t = [0, 0]
+        p = {}
+        t[0], p.name = "first", "second"
+        i = 0
+        arr = [10, 20]
+        i, arr[i] = i + 1, 99
+        key = "x"
+        box = {inner = {}}
+        key, box.inner[key] = "y", 5
+    "#).expect("eval failed");
+    assert_eq!(vm.get_global("a"), Some(compiler::Value::Number(2.0)));
+    assert_eq!(vm.get_global("b"), Some(compiler::Value::Number(1.0)));
+    assert_eq!(vm.get_global("c"), Some(compiler::Value::Number(5.0)));
+    assert_eq!(vm.get_global("d"), Some(compiler::Value::Nil));
+    assert_eq!(vm.get_global("e"), Some(compiler::Value::Number(6.0)));
+    assert_eq!(vm.get_global("t").map(|v| v.to_string()), Some("[first, 0]".to_string()));
+    assert_eq!(vm.eval("return p.name").expect("eval failed"), compiler::Value::String("second".to_string()));
+    // Targets are evaluated before any value is stored
+    assert_eq!(global(&vm, "i"), "1");
+    assert_eq!(global(&vm, "arr"), "[99, 20]");
+    assert_eq!(global(&vm, "key"), "y");
+    assert_eq!(vm.eval("return box.inner.x").expect("eval failed"), compiler::Value::Number(5.0));
+}
+
+#[test]
+fn test_postfix_calls_and_indexing() {
+    let mut vm = compiler::Vm::new();
+    vm.eval(r#"
+        function make_adder(n)
+            return function(x) return x + n end
+        end
+        function get_list()
+            return [10, 20, 30]
+        end
+        handlers = {greet = function(who) return "hi " .. who end}
+        obj = {build = function() return {run = function() return "ran" end} end}
+        fs = [function() return "first" end]
+
+        curried = make_adder(1)(2)
+        greeting = handlers["greet"]("bob")
+        second = get_list()[1]
+        chained = obj.build().run()
+        invoked = (function(x) return x * 2 end)(21)
+        from_table = fs[0]()
+        grid = [[1, 2], [3, 4]]
+        grid[1][0] = 7
+        cell = grid[1][0]
+    "#).expect("eval failed");
+    assert_eq!(global(&vm, "curried"), "3");
+    assert_eq!(global(&vm, "greeting"), "hi bob");
+    assert_eq!(global(&vm, "second"), "20");
+    assert_eq!(global(&vm, "chained"), "ran");
+    assert_eq!(global(&vm, "invoked"), "42");
+    assert_eq!(global(&vm, "from_table"), "first");
+    assert_eq!(global(&vm, "cell"), "7");
+
+    let error = vm.eval("x = get_list()(1)").expect_err("calling a table should fail");
+    assert!(error.to_string().contains("is not a function"), "{}", error);
+}
+
+#[test]
+fn test_call_evaluation_order() {
+    let mut vm = compiler::Vm::new();
+    vm.eval(r#"
+        log = ""
+        function note(what, value)
+            log = log .. what .. " "
+            return value
+        end
+        runner = {run = function(x) return x end}
+        builder = {build = function(x) return runner end}
+        maker = {make = function(self, x) return x end}
+        function getobj() return note("getobj", builder) end
+        function a() return note("a", 1) end
+        function b() return note("b", 2) end
+
+        first = getobj().build(b())
+        log = log .. "| "
+        second = builder.build(a()).run(b())
+        log = log .. "| "
+        third = note("receiver", maker):make(note("arg", 3))
+    "#).expect("eval failed");
+    assert_eq!(
+        vm.get_global("log"),
+        Some(compiler::Value::String("getobj b | a b | receiver arg ".to_string()))
+    );
+    assert_eq!(vm.get_global("second"), Some(compiler::Value::Number(2.0)));
+
+    // The function is found before the arguments run, even if they replace it
+    vm.eval(r#"
+        function old_f() return "old" end
+        function new_f() return "new" end
+        function reset()
+            t = {f = old_f}
+            u = {f = function(self) return "old" end}
+            gf = old_f
+        end
+        function swap()
+            t.f = new_f
+            u.f = function(self) return "new" end
+            gf = new_f
+        end
+        reset()
+        member_call = t.f(swap())
+        reset()
+        method_call = u:f(swap())
+        reset()
+        global_call = gf(swap())
+    "#).expect("eval failed");
+    assert_eq!(global(&vm, "member_call"), "old");
+    assert_eq!(global(&vm, "method_call"), "old");
+    assert_eq!(global(&vm, "global_call"), "old");
+}
+
+#[test]
+fn test_method_definitions_and_colon_calls() {
+    let mut vm = compiler::Vm::new();
+    vm.eval(r#"
+        Account = {}
+        function Account.new(balance)
+            return {balance = balance, deposit = Account.deposit, report = Account.report}
+        end
+        function Account:deposit(n)
+            self.balance = self.balance + n
+            return self
+        end
+        function Account:report(label)
+            return label .. ": " .. self.balance
+        end
+
+        acct = Account.new(100)
+        acct:deposit(10)
+        report = acct:deposit(5):report("total")
+        other = Account.new(1)
+        other_report = other:report("other")
+        try
+            acct:withdraw(1)
+        catch e
+            missing = e.message
+        end
+    "#).expect("eval failed");
+    assert_eq!(global(&vm, "report"), "total: 115");
+    assert_eq!(global(&vm, "other_report"), "other: 1");
+    assert_eq!(global(&vm, "missing"), "attempt to call nil value (method 'withdraw')");
+}
+
+#[test]
+fn test_default_varargs_and_named_arguments() {
+    let mut vm = compiler::Vm::new();
+    vm.eval(r##"
+        function connect(host, port = 80, timeout = port * 10)
+            return host .. ":" .. port .. " " .. timeout
+        end
+        defaults = connect("a")
+        given = connect("b", 8080)
+        named = connect(timeout = 5, host = "c")
+        explicit_nil = connect("d", nil, 1)
+
+        function count(...)
+            return select("#", ...)
+        end
+        function gather(first, ...rest)
+            return first .. ":" .. #rest .. ":" .. select(-1, ...)
+        end
+        function pass(...)
+            return ...
+        end
+        local x, y = pass(7, 8, 9)
+        spread = x .. y
+        listed = #[pass(1, 2, 3)]
+        none = count()
+        three = count(nil, nil, nil)
+        gathered = gather("g", 1, 2, 3)
+
+        scale = function(n, by = 2) return n * by end
+        lambda_default = scale(4)
+        shown = tostring(gather)
+
+        try
+            connect()
+        catch e
+            too_few = e.message
+        end
+        try
+            connect("a", 1, 2, 3)
+        catch e
+            too_many = e.message
+        end
+        try
+            connect("a", host = "b")
+        catch e
+            twice = e.message
+        end
+        try
+            connect(hots = "a")
+        catch e
+            unknown = e.message
+        end
+    "##).expect("eval failed");
+    assert_eq!(global(&vm, "defaults"), "a:80 800");
+    assert_eq!(global(&vm, "given"), "b:8080 80800");
+    assert_eq!(global(&vm, "named"), "c:80 5");
+    assert_eq!(global(&vm, "explicit_nil"), "d:80 1");
+    assert_eq!(global(&vm, "spread"), "78");
+    assert_eq!(global(&vm, "listed"), "3");
+    assert_eq!(global(&vm, "none"), "0");
+    assert_eq!(global(&vm, "three"), "3");
+    assert_eq!(global(&vm, "gathered"), "g:3:3");
+    assert_eq!(global(&vm, "lambda_default"), "8");
+    assert_eq!(global(&vm, "shown"), "<function(first, ...rest)>");
+    assert_eq!(global(&vm, "too_few"), "Function connect expects 1 to 3 arguments, got 0");
+    assert_eq!(global(&vm, "too_many"), "Function connect expects 1 to 3 arguments, got 4");
+    assert_eq!(global(&vm, "twice"), "Function connect got argument 'host' by position and by name");
+    assert_eq!(global(&vm, "unknown"), "Function connect has no parameter named 'hots'");
+
+    // Calls through a value, a member or a method check the count too
+    vm.eval(r#"
+        local f = connect
+        api = {connect = connect}
+        function api:ping(n) return n end
+        try f() catch e value_call = e.message end
+        try api.connect() catch e member_call = e.message end
+        try api:ping(1, 2) catch e method_call = e.message end
+    "#).expect("eval failed");
+    assert_eq!(global(&vm, "value_call"), "Function connect expects 1 to 3 arguments, got 0");
+    assert_eq!(global(&vm, "member_call"), "Function connect expects 1 to 3 arguments, got 0");
+    assert_eq!(global(&vm, "method_call"), "Function api:ping expects 2 arguments, got 3");
 }
 
 // ==================== LOCAL VARIABLES ====================
@@ -363,7 +1073,96 @@ fn test_local_variable() {
     let tokens = parser::tokenize(source, false).expect("Tokenization failed");
     let ast = parser::parse_to_ast(tokens, false).expect("Parsing failed");
     let bytecode = compiler::Compiler::compile(&ast, false);
-    compiler::Vm::execute(&bytecode, false);
+    compiler::Vm::execute(&bytecode, false).expect("Execution failed");
+}
+
+#[test]
+fn test_block_scoped_local() {
+    let mut vm = compiler::Vm::new();
+    vm.eval(r#"
+        x = 1
+        if true then
+            local x = 2
+            inner = x
+        end
+        while true do
+            local hidden = 3
+            break
+        end
+        outer = x
+    "#).expect("eval failed");
+    assert_eq!(global(&vm, "inner"), "2");
+    assert_eq!(global(&vm, "outer"), "1");
+    assert_eq!(global(&vm, "x"), "1");
+    assert_eq!(global(&vm, "hidden"), "");
+}
+
+#[test]
+fn test_global_assigned_in_function() {
+    let mut vm = compiler::Vm::new();
+    vm.eval(r#"
+        total = 0
+        function set_total()
+            local scratch = 1
+            total = 42
+            created = "in function"
+        end
+
+        set_total()
+        after_call = total
+    "#).expect("eval failed");
+    // Globals assigned inside a function survive its return; its locals do not
+    assert_eq!(global(&vm, "after_call"), "42");
+    assert_eq!(global(&vm, "created"), "in function");
+    assert_eq!(global(&vm, "scratch"), "");
+}
+
+#[test]
+fn test_destructuring() {
+    let mut vm = compiler::Vm::new();
+    vm.eval(r#"
+        local [a, b = 5, ...rest] = [1, nil, 3, 4]
+        array = a .. " " .. b .. " " .. #rest .. " " .. rest[0]
+        local {name, age = 0, address: {city}} = {name = "Ann", address = {city = "Oslo"}}
+        dict = name .. " " .. age .. " " .. city
+        local [x, [y, z]], w = [1, [2, 3]], 9
+        nested = x .. y .. z .. w
+
+        pairs_seen = ""
+        for [k, v] in [[1, "one"], [2, "two"]] do
+            pairs_seen = pairs_seen .. k .. v
+        end
+        names = ""
+        for i, {name} in ipairs([{name = "a"}, {name = "b"}]) do
+            names = names .. i .. name
+        end
+
+        function greet({name, greeting = "Hi"}, [suffix] = ["?"])
+            return greeting .. " " .. name .. suffix
+        end
+        greeted = greet({name = "Bo"})
+        greeted_all = greet({name = "Bo", greeting = "Yo"}, ["!"])
+        shown = tostring(greet)
+        product = (function([p, q]) return p * q end)([6, 7])
+
+        try
+            local [m] = nil
+        catch e
+            from_nil = e.message
+        end
+    "#).expect("eval failed");
+    assert_eq!(global(&vm, "array"), "1 5 2 3");
+    assert_eq!(global(&vm, "dict"), "Ann 0 Oslo");
+    assert_eq!(global(&vm, "nested"), "1239");
+    assert_eq!(global(&vm, "pairs_seen"), "1one2two");
+    assert_eq!(global(&vm, "names"), "0a1b");
+    assert_eq!(global(&vm, "greeted"), "Hi Bo?");
+    assert_eq!(global(&vm, "greeted_all"), "Yo Bo!");
+    assert_eq!(global(&vm, "shown"), "<function({name, greeting = \"Hi\"}, [suffix])>");
+    assert_eq!(global(&vm, "product"), "42");
+    assert_eq!(global(&vm, "from_nil"), "attempt to index nil value");
+    // Destructured names are locals, not globals
+    assert_eq!(global(&vm, "city"), "");
 }
 
 // ==================== DICTIONARIES ====================
@@ -378,7 +1177,336 @@ fn test_dictionary_literal() {
     let tokens = parser::tokenize(source, false).expect("Tokenization failed");
     let ast = parser::parse_to_ast(tokens, false).expect("Parsing failed");
     let bytecode = compiler::Compiler::compile(&ast, false);
-    compiler::Vm::execute(&bytecode, false);
+    compiler::Vm::execute(&bytecode, false).expect("Execution failed");
+}
+
+#[test]
+fn test_index_and_member_assignment() {
+    let mut vm = compiler::Vm::new();
+    vm.eval(r#"
+        arr = [1, 2, 3]
+        arr[0] = 5
+        arr[3] = 4
+        person = {name = "John", info = {tags = ["a", "b", "c"]}}
+        person.name = "Jane"
+        person.info.tags[2] = "z"
+        person["age"] = 30
+    "#).expect("eval failed");
+    assert_eq!(global(&vm, "arr"), "[5, 2, 3, 4]");
+    assert_eq!(vm.eval("return person.name").expect("eval failed").to_string(), "Jane");
+    assert_eq!(vm.eval("return person.info.tags").expect("eval failed").to_string(), "[a, b, z]");
+    assert_eq!(vm.eval("return person.age").expect("eval failed").to_string(), "30");
+
+    // Writes a table or dictionary cannot hold are errors, not silent no-ops
+    let message = |vm: &mut compiler::Vm, source: &str| {
+        vm.eval(source).expect_err("assignment should fail").to_string().lines().next().unwrap_or_default().to_string()
+    };
+    assert!(message(&mut vm, "arr[10000000000] = 1").ends_with("ValueError: table index 10000000000 is out of range (length 4)"));
+    assert!(message(&mut vm, "arr[6] = 1").ends_with("table index 6 is out of range (length 4)"));
+    assert!(message(&mut vm, "arr[-1] = 1").ends_with("table index -1 is out of range (length 4)"));
+    assert!(message(&mut vm, "arr[1.5] = 1").ends_with("table index 1.5 is out of range (length 4)"));
+    assert!(message(&mut vm, r#"arr["k"] = 1"#).ends_with("TypeError: table index must be a number, got string"));
+    assert!(message(&mut vm, r#"person[1] = "x""#).ends_with("TypeError: dictionary key must be a string, got number"));
+    assert_eq!(global(&vm, "arr"), "[5, 2, 3, 4]");
+
+    vm.eval(r#"
+        try
+            arr[9] = 1
+        catch e
+            kind = e.kind
+        end
+    "#).expect("eval failed");
+    assert_eq!(global(&vm, "kind"), "ValueError");
+}
+
+#[test]
+fn test_table_aliasing() {
+    let mut vm = compiler::Vm::new();
+    vm.eval(r#"
+        function fill(t)
+            t.count = t.count + 1
+            t.items[0] = "first"
+        end
+
+        obj = {count = 0, items = [0]}
+        alias = obj
+        alias.count = 10
+        fill(obj)
+    "#).expect("eval failed");
+    assert_eq!(vm.eval("return obj.count").expect("eval failed").to_string(), "11");
+    assert_eq!(vm.eval("return obj.items").expect("eval failed").to_string(), "[first]");
+    assert_eq!(vm.eval("return alias.items").expect("eval failed").to_string(), "[first]");
+}
+
+#[test]
+fn test_table_equality_is_by_reference() {
+    let mut vm = compiler::Vm::new();
+    vm.eval(r#"
+        a = []
+        a[0] = a
+        b = []
+        b[0] = b
+        self_refs = a == b
+        same = a == a[0]
+        copies = [1, 2] == [1, 2]
+        d = {}
+        d.me = d
+        dicts = d == {me = d}
+        alias = d
+        aliased = d == alias
+    "#).expect("eval failed");
+    assert_eq!(global(&vm, "self_refs"), "false");
+    assert_eq!(global(&vm, "same"), "true");
+    assert_eq!(global(&vm, "copies"), "false");
+    assert_eq!(global(&vm, "dicts"), "false");
+    assert_eq!(global(&vm, "aliased"), "true");
+}
+
+#[test]
+fn test_metatables() {
+    let mut vm = compiler::Vm::new();
+    vm.eval(r#"
+        Vec = {}
+        Vec.__index = Vec
+        function Vec.new(x, y)
+            return setmetatable({x = x, y = y}, Vec)
+        end
+        function Vec:length2()
+            return self.x * self.x + self.y * self.y
+        end
+        Vec.__add = function(a, b) return Vec.new(a.x + b.x, a.y + b.y) end
+        Vec.__unm = function(v) return Vec.new(-v.x, -v.y) end
+        Vec.__eq = function(a, b) return a.x == b.x and a.y == b.y end
+        Vec.__lt = function(a, b) return a:length2() < b:length2() end
+        Vec.__len = function(v) return 2 end
+        Vec.__call = function(v, key) return v[key] end
+        Vec.__tostring = function(v) return "(" .. v.x .. ", " .. v.y .. ")" end
+
+        a = Vec.new(1, 2)
+        b = Vec.new(3, 4)
+        sum = tostring(a + b)
+        negated = tostring(-a)
+        same = a == Vec.new(1, 2)
+        ordered = a < b and b > a
+        size = #a
+        called = a("y")
+        shown = "a = ${a}"
+        nested = tostring([a, {b = b}])
+        inherited = a:length2()
+        meta_is_vec = getmetatable(a) == Vec
+
+        counts = setmetatable({}, {__index = function(t, key) return 0 end})
+        counts.hits = counts.hits + 1
+        misses = counts.misses
+
+        writes = []
+        watched = setmetatable({}, {__newindex = function(t, key, value)
+            writes[#writes] = key
+            rawset(t, key, value)
+        end})
+        watched.a = 1
+        watched.a = 2
+        watched.b = 3
+    "#).expect("eval failed");
+    assert_eq!(global(&vm, "sum"), "(4, 6)");
+    assert_eq!(global(&vm, "negated"), "(-1, -2)");
+    assert_eq!(global(&vm, "same"), "true");
+    assert_eq!(global(&vm, "ordered"), "true");
+    assert_eq!(global(&vm, "size"), "2");
+    assert_eq!(global(&vm, "called"), "2");
+    assert_eq!(global(&vm, "shown"), "a = (1, 2)");
+    assert_eq!(global(&vm, "nested"), "[(1, 2), {b = (3, 4)}]");
+    assert_eq!(global(&vm, "inherited"), "5");
+    assert_eq!(global(&vm, "meta_is_vec"), "true");
+    assert_eq!(global(&vm, "counts"), "{hits = 1}");
+    assert_eq!(global(&vm, "misses"), "0");
+    assert_eq!(global(&vm, "writes"), "[a, b]");
+    assert_eq!(global(&vm, "Vec").matches("__index = {...}").count(), 1);
+    // `Display` runs no metamethods; `Vm::tostring` does
+    let a = vm.get_global("a").expect("a is defined");
+    assert_eq!(vm.tostring(&compiler::Value::table(vec![a.clone()])).expect("tostring failed"), "[(1, 2)]");
+    assert_ne!(a.to_string(), "(1, 2)");
+
+    let error = vm.eval("x = Vec.new(1, 2) * 2").expect_err("no __mul metamethod");
+    assert!(error.to_string().contains("attempt to multiply dictionary and number"), "{}", error);
+}
+
+#[test]
+fn test_classes() {
+    let mut vm = compiler::Vm::new();
+    vm.eval(r#"
+        import "type"
+        class Shape
+            created = 0
+            function init(name)
+                self.name = name
+                Shape.created = Shape.created + 1
+            end
+            function describe()
+                return self.name .. " with area " .. self:area()
+            end
+            function area()
+                return 0
+            end
+            function __tostring()
+                return "<" .. self.name .. ">"
+            end
+        end
+
+        class Rect extends Shape
+            function init(w, h)
+                super:init("rect")
+                self.w = w
+                self.h = h
+            end
+            function area()
+                return self.w * self.h
+            end
+        end
+
+        class Square extends Rect
+            function init(side)
+                super.init(self, side, side)
+                self.name = "square"
+            end
+        end
+
+        shape = Shape.new("blob")
+        rect = Rect.new(2, 3)
+        square = Square(4)
+        blob = shape:describe()
+        described = rect:describe()
+        square_area = square:area()
+        shown = tostring(square)
+        kind = type.typeof(square)
+        base_kind = type.typeof(shape)
+        plain_kind = type.typeof({})
+        created = Shape.created
+        is_square = getmetatable(square) == Square
+    "#).expect("eval failed");
+    assert_eq!(global(&vm, "blob"), "blob with area 0");
+    assert_eq!(global(&vm, "described"), "rect with area 6");
+    assert_eq!(global(&vm, "square_area"), "16");
+    assert_eq!(global(&vm, "shown"), "<square>");
+    assert_eq!(global(&vm, "kind"), "Square");
+    assert_eq!(global(&vm, "base_kind"), "Shape");
+    assert_eq!(global(&vm, "plain_kind"), "dictionary");
+    assert_eq!(global(&vm, "created"), "3");
+    assert_eq!(global(&vm, "is_square"), "true");
+
+    vm.eval(r#"
+        class Factory
+            function new(n)
+                return n * 2
+            end
+        end
+        made = Factory:new(5)
+        square_new = square.new
+        sub_made = Square.new(2)
+        sub_area = sub_made:area()
+    "#).expect("eval failed");
+    assert_eq!(global(&vm, "made"), "10");
+    assert_eq!(global(&vm, "square_new"), "nil");
+    assert_eq!(global(&vm, "sub_area"), "4");
+
+    let error = vm.eval("class Broken extends missing\nend").expect_err("base class is nil");
+    assert!(error.to_string().contains("class Broken cannot extend nil value"), "{}", error);
+}
+
+#[test]
+fn test_coroutines() {
+    let mut vm = compiler::Vm::new();
+    vm.eval(r#"
+        import "coroutine"
+        import "type"
+        co = coroutine.create(function(a, b)
+            local c = coroutine.yield(a + b)
+            local d, e = coroutine.yield(c * 2)
+            return d + e
+        end)
+        before = coroutine.status(co)
+        ok1, first = coroutine.resume(co, 1, 2)
+        middle = coroutine.status(co)
+        ok2, second = coroutine.resume(co, 10)
+        ok3, last = coroutine.resume(co, 3, 4)
+        after = coroutine.status(co)
+        ok4, err = coroutine.resume(co)
+        dead_message = err.message
+
+        function range(n)
+            return coroutine.create(function()
+                for i = 1, n do
+                    coroutine.yield(i)
+                end
+                return "ignored"
+            end)
+        end
+        squares = []
+        for i in range(4) do
+            squares[#squares] = i * i
+        end
+        evens = []
+        for n in coroutine.wrap(function() for i = 0, 4, 2 do coroutine.yield(i) end end) do
+            evens[#evens] = n
+        end
+
+        failing = coroutine.create(function() throw "boom" end)
+        ok5, thrown = coroutine.resume(failing)
+        failed_status = coroutine.status(failing)
+
+        guarded = coroutine.wrap(function()
+            try
+                coroutine.yield("in try")
+                throw "after yield"
+            catch e
+                coroutine.yield("caught " .. e)
+            end
+        end)
+        guarded_first = guarded()
+        guarded_second = guarded()
+        wrapped_type = type.typeof(guarded)
+        exploding = coroutine.wrap(function() throw {code = 7} end)
+        try
+            exploding()
+        catch e
+            wrapped_error = e.code
+        end
+        try
+            co()
+        catch e
+            call_error = e.message
+        end
+
+        try
+            coroutine.yield(1)
+        catch e
+            outside = e.message
+        end
+        blocked = coroutine.create(function()
+            local t = setmetatable({}, {__index = function(t, k) return coroutine.yield(k) end})
+            return t.x
+        end)
+        ok6, crossing = coroutine.resume(blocked)
+        crossing = crossing.message
+    "#).expect("eval failed");
+    assert_eq!(global(&vm, "before"), "suspended");
+    assert_eq!((global(&vm, "ok1"), global(&vm, "first")), ("true".to_string(), "3".to_string()));
+    assert_eq!(global(&vm, "middle"), "suspended");
+    assert_eq!((global(&vm, "ok2"), global(&vm, "second")), ("true".to_string(), "20".to_string()));
+    assert_eq!((global(&vm, "ok3"), global(&vm, "last")), ("true".to_string(), "7".to_string()));
+    assert_eq!(global(&vm, "after"), "dead");
+    assert_eq!((global(&vm, "ok4"), global(&vm, "dead_message")), ("false".to_string(), "cannot resume dead coroutine".to_string()));
+    assert_eq!(global(&vm, "squares"), "[1, 4, 9, 16]");
+    assert_eq!((global(&vm, "ok5"), global(&vm, "thrown"), global(&vm, "failed_status")), ("false".to_string(), "boom".to_string(), "dead".to_string()));
+    assert_eq!(global(&vm, "guarded_first"), "in try");
+    assert_eq!(global(&vm, "guarded_second"), "caught after yield");
+    assert_eq!(global(&vm, "evens"), "[0, 2, 4]");
+    assert_eq!(global(&vm, "wrapped_type"), "function");
+    assert_eq!(global(&vm, "wrapped_error"), "7");
+    assert_eq!(global(&vm, "call_error"), "<coroutine> is not a function");
+    assert_eq!(global(&vm, "outside"), "attempt to yield from outside a coroutine");
+    assert_eq!(global(&vm, "ok6"), "false");
+    assert_eq!(global(&vm, "crossing"), "attempt to yield across a metamethod or host function call");
 }
 
 // ==================== MODULES AND DOT NOTATION ====================
@@ -398,7 +1526,7 @@ fn test_module_import() {
     let tokens = parser::tokenize(source, false).expect("Tokenization failed");
     let ast = parser::parse_to_ast(tokens, false).expect("Parsing failed");
     let bytecode = compiler::Compiler::compile(&ast, false);
-    compiler::Vm::execute(&bytecode, false);
+    compiler::Vm::execute(&bytecode, false).expect("Execution failed");
 }
 
 #[test]
@@ -414,7 +1542,7 @@ fn test_math_module_constants() {
     let tokens = parser::tokenize(source, false).expect("Tokenization failed");
     let ast = parser::parse_to_ast(tokens, false).expect("Parsing failed");
     let bytecode = compiler::Compiler::compile(&ast, false);
-    compiler::Vm::execute(&bytecode, false);
+    compiler::Vm::execute(&bytecode, false).expect("Execution failed");
 }
 
 #[test]
@@ -434,7 +1562,7 @@ fn test_math_module_functions() {
     let tokens = parser::tokenize(source, false).expect("Tokenization failed");
     let ast = parser::parse_to_ast(tokens, false).expect("Parsing failed");
     let bytecode = compiler::Compiler::compile(&ast, false);
-    compiler::Vm::execute(&bytecode, false);
+    compiler::Vm::execute(&bytecode, false).expect("Execution failed");
 }
 
 #[test]
@@ -452,7 +1580,7 @@ fn test_string_module_functions() {
     let tokens = parser::tokenize(source, false).expect("Tokenization failed");
     let ast = parser::parse_to_ast(tokens, false).expect("Parsing failed");
     let bytecode = compiler::Compiler::compile(&ast, false);
-    compiler::Vm::execute(&bytecode, false);
+    compiler::Vm::execute(&bytecode, false).expect("Execution failed");
 }
 
 #[test]
@@ -466,7 +1594,7 @@ fn test_string_split() {
     let tokens = parser::tokenize(source, false).expect("Tokenization failed");
     let ast = parser::parse_to_ast(tokens, false).expect("Parsing failed");
     let bytecode = compiler::Compiler::compile(&ast, false);
-    compiler::Vm::execute(&bytecode, false);
+    compiler::Vm::execute(&bytecode, false).expect("Execution failed");
 }
 
 #[test]
@@ -480,7 +1608,7 @@ fn test_string_contains() {
     let tokens = parser::tokenize(source, false).expect("Tokenization failed");
     let ast = parser::parse_to_ast(tokens, false).expect("Parsing failed");
     let bytecode = compiler::Compiler::compile(&ast, false);
-    compiler::Vm::execute(&bytecode, false);
+    compiler::Vm::execute(&bytecode, false).expect("Execution failed");
 }
 
 #[test]
@@ -494,7 +1622,7 @@ fn test_string_replace() {
     let tokens = parser::tokenize(source, false).expect("Tokenization failed");
     let ast = parser::parse_to_ast(tokens, false).expect("Parsing failed");
     let bytecode = compiler::Compiler::compile(&ast, false);
-    compiler::Vm::execute(&bytecode, false);
+    compiler::Vm::execute(&bytecode, false).expect("Execution failed");
 }
 
 #[test]
@@ -513,7 +1641,7 @@ fn test_array_module() {
     let tokens = parser::tokenize(source, false).expect("Tokenization failed");
     let ast = parser::parse_to_ast(tokens, false).expect("Parsing failed");
     let bytecode = compiler::Compiler::compile(&ast, false);
-    compiler::Vm::execute(&bytecode, false);
+    compiler::Vm::execute(&bytecode, false).expect("Execution failed");
 }
 
 #[test]
@@ -527,7 +1655,7 @@ fn test_array_range() {
     let tokens = parser::tokenize(source, false).expect("Tokenization failed");
     let ast = parser::parse_to_ast(tokens, false).expect("Parsing failed");
     let bytecode = compiler::Compiler::compile(&ast, false);
-    compiler::Vm::execute(&bytecode, false);
+    compiler::Vm::execute(&bytecode, false).expect("Execution failed");
 }
 
 #[test]
@@ -542,7 +1670,7 @@ fn test_array_sort() {
     let tokens = parser::tokenize(source, false).expect("Tokenization failed");
     let ast = parser::parse_to_ast(tokens, false).expect("Parsing failed");
     let bytecode = compiler::Compiler::compile(&ast, false);
-    compiler::Vm::execute(&bytecode, false);
+    compiler::Vm::execute(&bytecode, false).expect("Execution failed");
 }
 
 #[test]
@@ -560,7 +1688,7 @@ fn test_type_module() {
     let tokens = parser::tokenize(source, false).expect("Tokenization failed");
     let ast = parser::parse_to_ast(tokens, false).expect("Parsing failed");
     let bytecode = compiler::Compiler::compile(&ast, false);
-    compiler::Vm::execute(&bytecode, false);
+    compiler::Vm::execute(&bytecode, false).expect("Execution failed");
 }
 
 #[test]
@@ -576,7 +1704,7 @@ fn test_type_conversions() {
     let tokens = parser::tokenize(source, false).expect("Tokenization failed");
     let ast = parser::parse_to_ast(tokens, false).expect("Parsing failed");
     let bytecode = compiler::Compiler::compile(&ast, false);
-    compiler::Vm::execute(&bytecode, false);
+    compiler::Vm::execute(&bytecode, false).expect("Execution failed");
 }
 
 #[test]
@@ -590,7 +1718,20 @@ fn test_json_module() {
     let tokens = parser::tokenize(source, false).expect("Tokenization failed");
     let ast = parser::parse_to_ast(tokens, false).expect("Parsing failed");
     let bytecode = compiler::Compiler::compile(&ast, false);
-    compiler::Vm::execute(&bytecode, false);
+    compiler::Vm::execute(&bytecode, false).expect("Execution failed");
+
+    // A container that holds itself is written as null where it recurs
+    let mut vm = compiler::Vm::new();
+    vm.eval(r#"
+        import "json"
+        local t = {}
+        t.me = t
+        looped = json.stringify(t)
+        shared = [1]
+        repeated = json.stringify([shared, shared])
+    "#).expect("eval failed");
+    assert_eq!(global(&vm, "looped"), r#"{"me":null}"#);
+    assert_eq!(global(&vm, "repeated"), "[[1.0],[1.0]]");
 }
 
 #[test]
@@ -604,7 +1745,7 @@ fn test_os_module() {
     let tokens = parser::tokenize(source, false).expect("Tokenization failed");
     let ast = parser::parse_to_ast(tokens, false).expect("Parsing failed");
     let bytecode = compiler::Compiler::compile(&ast, false);
-    compiler::Vm::execute(&bytecode, false);
+    compiler::Vm::execute(&bytecode, false).expect("Execution failed");
 }
 
 // ==================== ERROR HANDLING ====================
@@ -623,7 +1764,7 @@ fn test_try_catch() {
     let tokens = parser::tokenize(source, false).expect("Tokenization failed");
     let ast = parser::parse_to_ast(tokens, false).expect("Parsing failed");
     let bytecode = compiler::Compiler::compile(&ast, false);
-    compiler::Vm::execute(&bytecode, false);
+    compiler::Vm::execute(&bytecode, false).expect("Execution failed");
 }
 
 #[test]
@@ -640,7 +1781,390 @@ fn test_try_catch_no_throw() {
     let tokens = parser::tokenize(source, false).expect("Tokenization failed");
     let ast = parser::parse_to_ast(tokens, false).expect("Parsing failed");
     let bytecode = compiler::Compiler::compile(&ast, false);
-    compiler::Vm::execute(&bytecode, false);
+    compiler::Vm::execute(&bytecode, false).expect("Execution failed");
+}
+
+#[test]
+fn test_thrown_values_and_error_objects() {
+    let mut vm = compiler::Vm::new();
+    vm.eval(r#"
+        try
+            throw {code = 404, msg = "missing"}
+        catch e
+            code = e.code
+        end
+
+        function divide(a, b)
+            return a / b
+        end
+
+        try
+            divide(1, 0)
+        catch e
+            kind = e.kind
+            message = e.message
+            line = e.line
+            where = e.traceback[0]
+        end
+    "#).expect("eval failed");
+    assert_eq!(vm.get_global("code"), Some(compiler::Value::Number(404.0)));
+    assert_eq!(vm.get_global("kind"), Some(compiler::Value::String("DivisionByZero".to_string())));
+    assert_eq!(vm.get_global("message"), Some(compiler::Value::String("Division by zero".to_string())));
+    assert_eq!(vm.get_global("line"), Some(compiler::Value::Number(9.0)));
+    assert_eq!(vm.get_global("where"), Some(compiler::Value::String("<eval>:9:22: in function 'divide'".to_string())));
+}
+
+#[test]
+fn test_try_finally() {
+    let mut vm = compiler::Vm::new();
+    vm.eval(r#"
+        log = ""
+        try
+            log = log .. "try "
+        catch e
+            log = log .. "catch "
+        finally
+            log = log .. "finally "
+        end
+
+        try
+            try
+                throw "inner"
+            finally
+                log = log .. "cleanup "
+            end
+        catch e
+            log = log .. "caught " .. e .. " "
+        end
+
+        try
+            try
+                throw "first"
+            catch e
+                throw "second"
+            finally
+                log = log .. "cleanup "
+            end
+        catch e
+            log = log .. "caught " .. e
+        end
+    "#).expect("eval failed");
+    assert_eq!(
+        vm.get_global("log"),
+        Some(compiler::Value::String("try finally cleanup caught inner cleanup caught second".to_string()))
+    );
+
+    // A rethrown error is the original one, with its kind and location
+    let error = vm.eval("try\n    x = 1 / 0\nfinally\n    y = 1\nend").expect_err("eval should fail");
+    assert_eq!(error.to_string().lines().next(), Some("<eval>:2:11: DivisionByZero: Division by zero"));
+    assert_eq!(vm.get_global("y"), Some(compiler::Value::Number(1.0)));
+}
+
+#[test]
+fn test_finally_runs_on_exits() {
+    let mut vm = compiler::Vm::new();
+    vm.eval(r#"
+        log = ""
+        function early()
+            try
+                log = log .. "try "
+                return "result"
+            finally
+                log = log .. "finally "
+            end
+        end
+        result = early()
+
+        for i = 1, 3 do
+            try
+                if i == 2 then
+                    continue
+                end
+                if i == 3 then
+                    break
+                end
+                log = log .. i .. " "
+            finally
+                log = log .. "cleanup" .. i .. " "
+            end
+        end
+
+        -- Handlers left by break and continue no longer catch errors
+        caught = "none"
+        try
+            while true do
+                try
+                    break
+                catch e
+                    caught = "inner"
+                end
+            end
+            throw "after"
+        catch e
+            caught = e
+        end
+
+        -- Nested finally blocks run innermost first
+        function nested()
+            try
+                try
+                    return 1
+                finally
+                    log = log .. "inner "
+                end
+            finally
+                log = log .. "outer"
+            end
+        end
+        nested()
+
+        -- An error a finally block raises again keeps its kind
+        function divide()
+            try
+                return 1 / 0
+            finally
+                log = log .. " cleanup"
+            end
+        end
+        try
+            divide()
+        catch e
+            kind = e.kind
+        end
+    "#).expect("eval failed");
+    assert_eq!(vm.get_global("kind"), Some(compiler::Value::String("DivisionByZero".to_string())));
+    assert_eq!(vm.get_global("result"), Some(compiler::Value::String("result".to_string())));
+    assert_eq!(vm.get_global("caught"), Some(compiler::Value::String("after".to_string())));
+    assert_eq!(
+        vm.get_global("log"),
+        Some(compiler::Value::String("try finally 1 cleanup1 cleanup2 cleanup3 inner outer cleanup".to_string()))
+    );
+}
+
+#[test]
+fn test_uncaught_errors_are_returned() {
+    let run = |source: &str| {
+        let tokens = parser::tokenize(source, false).expect("Tokenization failed");
+        let ast = parser::parse_to_ast(tokens, false).expect("Parsing failed");
+        let bytecode = compiler::Compiler::compile(&ast, false);
+        compiler::Vm::execute(&bytecode, false).expect_err("Execution should fail")
+    };
+
+    let error = run(r#"throw "boom""#);
+    assert_eq!(error.kind, ErrorKind::UserThrow(compiler::Value::String("boom".to_string())));
+    assert_eq!(run("x = 1 / 0").kind, ErrorKind::DivisionByZero);
+    assert_eq!(run("missing()").kind, ErrorKind::NameError);
+    assert_eq!(run("function f(a) return a end\nf(1, 2)").kind, ErrorKind::ArityError);
+    assert_eq!(run("x = 5\ny = x(1)").kind, ErrorKind::TypeError);
+}
+
+#[test]
+fn test_runtime_error_caught_by_try() {
+    let source = r#"
+        function divide(a, b)
+            return a / b
+        end
+
+        try
+            divide(1, 0)
+        catch e
+            print("Caught error: " .. e)
+        end
+    "#;
+
+    let tokens = parser::tokenize(source, false).expect("Tokenization failed");
+    let ast = parser::parse_to_ast(tokens, false).expect("Parsing failed");
+    let bytecode = compiler::Compiler::compile(&ast, false);
+    compiler::Vm::execute(&bytecode, false).expect("Execution failed");
+}
+
+#[test]
+fn test_operand_type_errors() {
+    let message = |source: &str| {
+        let tokens = parser::tokenize(source, false).expect("Tokenization failed");
+        let ast = parser::parse_to_ast(tokens, false).expect("Parsing failed");
+        let bytecode = compiler::Compiler::compile(&ast, false);
+        let error = compiler::Vm::execute(&bytecode, false).expect_err("Execution should fail");
+        assert_eq!(error.kind, ErrorKind::TypeError);
+        error.message
+    };
+
+    assert_eq!(message(r#"x = "a" + 1"#), "attempt to add string and number");
+    assert_eq!(message("x = nil * 2"), "attempt to multiply nil and number");
+    assert_eq!(message("x = cfg[1]"), "attempt to index nil value 'cfg'");
+    assert_eq!(message("x = cfg.server.port"), "attempt to get member 'server' of nil value 'cfg'");
+    assert_eq!(message("cfg = {}\nx = cfg.server.tls.cert"), "attempt to get member 'tls' of nil value 'cfg.server'");
+    assert_eq!(message("co = coroutine.create(f)"), "attempt to get member 'create' of nil value 'coroutine'");
+    assert_eq!(message("cfg = {}\ncfg.server.start()"), "attempt to call nil value 'cfg.server.start'");
+    assert_eq!(message("local t = {}\nt.a.b = 1"), "attempt to set member 'b' of nil value 't.a'");
+    assert_eq!(message("local n = 5\nn.x = 2"), "attempt to set member 'x' of number value 'n'");
+    assert_eq!(message("t = [1]\nt.size = 2"), "attempt to set member 'size' of table value 't'");
+    assert_eq!(message("t = {a = 1}\nt.a[0] = 2"), "attempt to index number value 't.a'");
+    assert_eq!(message(r#"x = 1 < "b""#), "attempt to compare number with string");
+    assert_eq!(message(r#"x = -"a""#), "attempt to negate string value");
+    assert_eq!(message("x = #5"), "attempt to get length of number value");
+    assert_eq!(message(r#"x = 1 & "2""#), "attempt to perform bitwise operation on number and string");
+
+    // Unknown native functions are errors too, and all of them can be caught
+    let source = r#"
+        import "math"
+        try
+            math.nope(1)
+        catch e
+            print("Caught: " .. e)
+        end
+        try
+            x = {} - 1
+        catch e
+            print("Caught: " .. e)
+        end
+    "#;
+    let tokens = parser::tokenize(source, false).expect("Tokenization failed");
+    let ast = parser::parse_to_ast(tokens, false).expect("Parsing failed");
+    let bytecode = compiler::Compiler::compile(&ast, false);
+    compiler::Vm::execute(&bytecode, false).expect("Execution failed");
+}
+
+#[test]
+fn test_lenient_mode_yields_nil() {
+    let mut vm = compiler::Vm::new();
+    vm.set_lenient(true);
+    vm.eval(r#"
+        import "math"
+        a = "a" + 1
+        b = cfg[1]
+        c = math.nope(1)
+        d = 1 < "b"
+        e = cfg.server.port
+        n = 5
+        n.x = 2
+        f = "still running"
+    "#).expect("eval failed");
+    assert_eq!(vm.get_global("a"), Some(compiler::Value::Nil));
+    assert_eq!(vm.get_global("b"), Some(compiler::Value::Nil));
+    assert_eq!(vm.get_global("c"), Some(compiler::Value::Nil));
+    assert_eq!(vm.get_global("d"), Some(compiler::Value::Boolean(false)));
+    assert_eq!(vm.get_global("e"), Some(compiler::Value::Nil));
+    assert_eq!(vm.get_global("f"), Some(compiler::Value::String("still running".to_string())));
+}
+
+#[test]
+fn test_runtime_error_location_and_traceback() {
+    let source = "function divide(a, b)\n    return a / b\nend\n\nx = divide(1, 0)\n";
+
+    let tokens = parser::tokenize(source, false).expect("Tokenization failed");
+    let ast = parser::parse_to_ast(tokens, false).expect("Parsing failed");
+    let chunk = compiler::Compiler::compile_chunk(&ast, "script.up", false);
+    let error = compiler::Vm::execute_chunk(chunk, false).expect_err("Execution should fail");
+
+    assert_eq!(error.location().as_deref(), Some("script.up:2:14"));
+    let trace: Vec<String> = error.traceback.iter().map(|entry| entry.to_string()).collect();
+    assert_eq!(trace, ["script.up:2:14: in function 'divide'", "script.up:5:5: in main chunk"]);
+}
+
+#[test]
+fn test_stack_overflow_is_an_error() {
+    let mut vm = compiler::Vm::new();
+    vm.eval(r#"
+        function down(n) return down(n + 1) + 1 end
+        try down(0) catch e recursion = e.message end
+        looped = setmetatable({}, {__index = function(t, key) return t[key] end})
+        try x = looped.missing catch e lookup = e.message end
+        still_running = down ~= nil
+    "#).expect("eval failed");
+    assert_eq!(global(&vm, "recursion"), "stack overflow");
+    assert_eq!(global(&vm, "lookup"), "stack overflow");
+    assert_eq!(global(&vm, "still_running"), "true");
+
+    let error = vm.eval("down(0)").expect_err("recursion never ends");
+    let text = error.to_string();
+    assert!(text.contains("StackOverflow: stack overflow"), "{}", text);
+    assert!(text.contains("... (skipping"), "{}", text);
+}
+
+// ==================== EMBEDDING API ====================
+
+#[test]
+fn test_vm_globals_persist_across_eval() {
+    let mut vm = compiler::Vm::new();
+    vm.set_global("base", compiler::Value::Number(10.0));
+    vm.eval("total = base * 2").expect("eval failed");
+    vm.eval("total = total + 1").expect("eval failed");
+    assert_eq!(vm.get_global("total"), Some(compiler::Value::Number(21.0)));
+
+    let result = vm.eval("return total").expect("eval failed");
+    assert_eq!(result, compiler::Value::Number(21.0));
+}
+
+#[test]
+fn test_vm_call_function() {
+    let mut vm = compiler::Vm::new();
+    vm.eval(r#"
+        function handler(name, count)
+            return name .. ":" .. count
+        end
+    "#).expect("eval failed");
+
+    let args = [compiler::Value::String("jobs".to_string()), compiler::Value::Number(3.0)];
+    let result = vm.call_function("handler", &args).expect("call failed");
+    assert_eq!(result, compiler::Value::String("jobs:3".to_string()));
+    assert!(vm.call_function("missing", &[]).is_err());
+}
+
+#[test]
+fn test_vm_eval_errors() {
+    let mut vm = compiler::Vm::new();
+    assert!(matches!(vm.eval("x = = 1"), Err(pickup_lang::Error::Parse(_))));
+    assert!(matches!(vm.eval(r#"throw "bad""#), Err(pickup_lang::Error::Runtime(_))));
+
+    // The VM stays usable after an uncaught error
+    let result = vm.eval("return 1 + 1").expect("eval failed");
+    assert_eq!(result, compiler::Value::Number(2.0));
+}
+
+#[test]
+fn test_vm_host_functions_and_modules() {
+    let mut vm = compiler::Vm::new();
+    vm.register_function("double", |_vm, args| match args.first() {
+        Some(compiler::Value::Number(n)) => Ok(compiler::Value::Number(n * 2.0)),
+        _ => Err(compiler::RuntimeError::new(pickup_lang::ErrorKind::TypeError, "double expects a number").into()),
+    });
+    vm.register_native("config", "get", |vm, args| {
+        let key = args.first().map(|v| v.to_string()).unwrap_or_default();
+        Ok(vm.get_global(&format!("cfg_{}", key)).unwrap_or(compiler::Value::Nil))
+    });
+    vm.register_module_value("config", "version", compiler::Value::Number(2.0));
+    vm.set_global("cfg_name", compiler::Value::String("pickup".to_string()));
+
+    let result = vm.eval(r#"
+        import "config"
+        return config.get("name") .. " v" .. config.version .. " " .. double(21)
+    "#).expect("eval failed");
+    assert_eq!(result, compiler::Value::String("pickup v2 42".to_string()));
+
+    // Host errors can be caught by scripts
+    let result = vm.eval(r#"
+        try
+            double("x")
+        catch e
+            return e.kind .. ": " .. e.message
+        end
+    "#).expect("eval failed");
+    assert_eq!(result, compiler::Value::String("TypeError: double expects a number".to_string()));
+}
+
+#[test]
+fn test_vm_host_function_calls_back_into_script() {
+    let mut vm = compiler::Vm::new();
+    vm.register_function("apply", |vm, mut args| {
+        let func = args.remove(0);
+        Ok(vm.call(func, args)?)
+    });
+
+    let result = vm.eval(r#"
+        return apply(function(a, b) return a * b end, 6, 7)
+    "#).expect("eval failed");
+    assert_eq!(result, compiler::Value::Number(42.0));
 }
 
 // ==================== COMPLEX PROGRAMS ====================
@@ -673,7 +2197,7 @@ fn test_prime_checker() {
     let tokens = parser::tokenize(source, false).expect("Tokenization failed");
     let ast = parser::parse_to_ast(tokens, false).expect("Parsing failed");
     let bytecode = compiler::Compiler::compile(&ast, false);
-    compiler::Vm::execute(&bytecode, false);
+    compiler::Vm::execute(&bytecode, false).expect("Execution failed");
 }
 
 #[test]
@@ -688,7 +2212,7 @@ fn test_complex_expressions() {
     let tokens = parser::tokenize(source, false).expect("Tokenization failed");
     let ast = parser::parse_to_ast(tokens, false).expect("Parsing failed");
     let bytecode = compiler::Compiler::compile(&ast, false);
-    compiler::Vm::execute(&bytecode, false);
+    compiler::Vm::execute(&bytecode, false).expect("Execution failed");
 }
 
 #[test]
@@ -709,7 +2233,7 @@ fn test_nested_function_calls() {
     let tokens = parser::tokenize(source, false).expect("Tokenization failed");
     let ast = parser::parse_to_ast(tokens, false).expect("Parsing failed");
     let bytecode = compiler::Compiler::compile(&ast, false);
-    compiler::Vm::execute(&bytecode, false);
+    compiler::Vm::execute(&bytecode, false).expect("Execution failed");
 }
 
 #[test]
@@ -725,7 +2249,7 @@ fn test_nested_loops() {
     let tokens = parser::tokenize(source, false).expect("Tokenization failed");
     let ast = parser::parse_to_ast(tokens, false).expect("Parsing failed");
     let bytecode = compiler::Compiler::compile(&ast, false);
-    compiler::Vm::execute(&bytecode, false);
+    compiler::Vm::execute(&bytecode, false).expect("Execution failed");
 }
 
 #[test]
@@ -744,5 +2268,5 @@ fn test_array_sum() {
     let tokens = parser::tokenize(source, false).expect("Tokenization failed");
     let ast = parser::parse_to_ast(tokens, false).expect("Parsing failed");
     let bytecode = compiler::Compiler::compile(&ast, false);
-    compiler::Vm::execute(&bytecode, false);
+    compiler::Vm::execute(&bytecode, false).expect("Execution failed");
 }